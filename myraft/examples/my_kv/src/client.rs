@@ -1,33 +1,634 @@
-mod clientpb {
-    tonic::include_proto!("clientpb");
-}
-use log::debug;
-use clientpb::{client_rpc_client::ClientRpcClient, ReadRpcReq, WriteRpcReq};
+//! The command line client of my_kv: the keys are raw bytes, given and shown
+//! as strings, and the output a table or JSON lines. It exits with 1 on errors.
+
+use anyhow::{anyhow, bail, Result};
+use my_kv::clientpb::admin_rpc_client::AdminRpcClient;
+use my_kv::clientpb::client_rpc_client::ClientRpcClient;
+use my_kv::clientpb::{read_rpc_req, txn_guard, txn_rpc_op, watch_event, write_rpc_req};
+use my_kv::clientpb::{
+    BackupRpcReq, Consistency, MemberAddReq, MemberRemoveReq, ReadRpcReq, ReqKind, ScanRpcReq,
+    StatusRpcReq, StatusRpcRsp, TxnGuard, TxnRpcOp, TxnRpcReq, WatchRpcReq, WriteRpcReq,
+};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::process;
+use std::str::FromStr;
 use structopt::StructOpt;
-use tonic::Request;
+use tonic::transport::Channel;
+use tonic::Status;
+
+type KvRpcClient = ClientRpcClient<Channel>;
+type KvAdminClient = AdminRpcClient<Channel>;
 
 #[derive(Debug, StructOpt)]
 struct Opt {
-    #[structopt(short, long)]
-    client_addr: String,
+    /// client addresses of the nodes, comma separated; writes go to the
+    /// leader among them, reads to the first one reachable
+    #[structopt(
+        short,
+        long,
+        default_value = "127.0.0.1:11112",
+        require_delimiter = true
+    )]
+    endpoints: Vec<String>,
+    /// admin addresses of the nodes, comma separated, for the member and
+    /// snapshot commands; they go to the first one reachable
+    #[structopt(
+        short,
+        long,
+        default_value = "127.0.0.1:11114",
+        require_delimiter = true
+    )]
+    admin_endpoints: Vec<String>,
+    /// table or json
+    #[structopt(short, long, default_value = "table")]
+    output: Output,
+    #[structopt(subcommand)]
+    cmd: Command,
 }
 
-#[tokio::main]
-async fn main() {
-    let opt = Opt::from_args();
-    debug!("connecting to {}", opt.client_addr);
-    let mut client = ClientRpcClient::connect(opt.client_addr).await.unwrap();
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// the value of a key
+    Get {
+        key: String,
+        /// the value after the write of this revision
+        #[structopt(long)]
+        revision: Option<u64>,
+        /// from the node's store, which may be behind the leader
+        #[structopt(long)]
+        stale: bool,
+    },
+    /// sets the value of a key
+    Put {
+        key: String,
+        value: String,
+        /// kept for as long as the lease lives
+        #[structopt(long)]
+        lease: Option<u64>,
+    },
+    /// deletes a key
+    Delete { key: String },
+    /// the keys from start to end, in byte order
+    Scan {
+        #[structopt(long, default_value = "")]
+        prefix: String,
+        #[structopt(long, default_value = "")]
+        start: String,
+        /// excluded, empty for the last key
+        #[structopt(long, default_value = "")]
+        end: String,
+        /// at most this many keys, 0 for all of them
+        #[structopt(long, default_value = "0")]
+        limit: u64,
+        #[structopt(long)]
+        reverse: bool,
+        #[structopt(long)]
+        revision: Option<u64>,
+        #[structopt(long)]
+        stale: bool,
+    },
+    /// the changes of a key, or of the keys under a prefix, until interrupted
+    Watch {
+        key: String,
+        #[structopt(long)]
+        prefix: bool,
+        /// replay the changes from this revision on
+        #[structopt(long)]
+        start_revision: Option<u64>,
+    },
+    /// a transaction, as JSON, e.g.
+    /// {"guards": [{"key": "a", "version": 0}], "success": [{"op": "put", "key": "a", "value": "1"}], "failure": [{"op": "get", "key": "a"}]};
+    /// a guard with a value checks the value, with a version the version,
+    /// with neither that the key exists
+    Txn { txn: String },
+    /// the state of the cluster
+    Cluster(ClusterCommand),
+    /// changes the nodes of the cluster, registered in zookeeper, through
+    /// the admin endpoints
+    Member(MemberCommand),
+    /// backs the node of the first admin endpoint reachable up to a file
+    /// under its backup directory
+    Snapshot { path: String },
+}
+
+#[derive(Debug, StructOpt)]
+enum ClusterCommand {
+    /// the raft state of every endpoint
+    Status,
+}
+
+#[derive(Debug, StructOpt)]
+enum MemberCommand {
+    /// registers a node, for the leader to add it
+    Add {
+        id: u64,
+        raft_addr: String,
+        /// voter, learner or witness
+        #[structopt(long, default_value = "voter")]
+        role: String,
+    },
+    /// unregisters a node, for the leader to remove it
+    Remove { id: u64 },
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Output {
+    Table,
+    Json,
+}
+
+impl FromStr for Output {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "table" => Ok(Output::Table),
+            "json" => Ok(Output::Json),
+            _ => Err(anyhow!("unknown output {}, table or json", s)),
+        }
+    }
+}
 
-    let req = Request::new(WriteRpcReq {
-        kind: 0,
-        key: 1,
-        data: "ccc".to_string(),
-    });
-    let rsp = client.write(req).await.unwrap();
-    println!("{:?}", rsp.into_inner());
+/// Rows of a command, lined up under the column names or one JSON object each.
+struct Printer {
+    output: Output,
+    columns: &'static [&'static str],
+    rows: Vec<Vec<Value>>,
+    // of the table columns, which only grow for the rows that follow
+    widths: Vec<usize>,
+    header_printed: bool,
+}
+
+impl Printer {
+    fn new(output: Output, columns: &'static [&'static str]) -> Self {
+        Self {
+            output,
+            columns,
+            rows: vec![],
+            widths: columns.iter().map(|column| column.len()).collect(),
+            header_printed: false,
+        }
+    }
 
-    let req = Request::new(ReadRpcReq { id: 1 });
-    let rsp = client.read(req).await.unwrap();
-    println!("{:?}", rsp.into_inner());
+    fn row(&mut self, values: Vec<Value>) {
+        self.rows.push(values);
+    }
+
+    // prints the rows so far, the header of a table before the first ones
+    fn flush(&mut self) {
+        let rows: Vec<Vec<Value>> = self.rows.drain(..).collect();
+        match self.output {
+            Output::Json => {
+                for row in rows {
+                    let object: Map<String, Value> = self
+                        .columns
+                        .iter()
+                        .map(|column| column.to_string())
+                        .zip(row)
+                        .collect();
+                    println!("{}", Value::Object(object));
+                }
+            }
+            Output::Table => {
+                let cells: Vec<Vec<String>> = rows
+                    .iter()
+                    .map(|row| row.iter().map(cell).collect())
+                    .collect();
+                let widths = &mut self.widths;
+                for row in &cells {
+                    for (width, cell) in widths.iter_mut().zip(row) {
+                        *width = (*width).max(cell.len());
+                    }
+                }
+                let line = |row: Vec<String>| {
+                    let padded: Vec<String> = row
+                        .iter()
+                        .zip(widths.iter())
+                        .map(|(cell, width)| format!("{:width$}", cell, width = width))
+                        .collect();
+                    println!("{}", padded.join("  ").trim_end());
+                };
+                if !self.header_printed {
+                    line(self.columns.iter().map(|c| c.to_uppercase()).collect());
+                    self.header_printed = true;
+                }
+                for row in cells {
+                    line(row);
+                }
+            }
+        }
+    }
 }
 
+fn cell(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        value => value.to_string(),
+    }
+}
+
+fn text(bytes: &[u8]) -> Value {
+    Value::String(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// The nodes the client was given.
+struct Endpoints {
+    addrs: Vec<String>,
+}
+
+impl Endpoints {
+    async fn connect(addr: &str) -> Result<KvRpcClient> {
+        Ok(KvRpcClient::connect(url(addr)).await?)
+    }
+
+    // the first node reachable
+    async fn any(&self) -> Result<KvRpcClient> {
+        for addr in &self.addrs {
+            match Self::connect(addr).await {
+                Ok(client) => return Ok(client),
+                Err(err) => log::warn!("{} is unreachable: {}", addr, err),
+            }
+        }
+        bail!("none of the endpoints {:?} is reachable", self.addrs)
+    }
+
+    // the node that says it leads
+    async fn leader(&self) -> Result<KvRpcClient> {
+        for addr in &self.addrs {
+            match self.status(addr).await {
+                Ok((client, status)) if status.leader == status.id => return Ok(client),
+                Ok(_) => {}
+                Err(err) => log::warn!("{} is unreachable: {}", addr, err),
+            }
+        }
+        bail!("no leader among the endpoints {:?}", self.addrs)
+    }
+
+    async fn status(&self, addr: &str) -> Result<(KvRpcClient, StatusRpcRsp)> {
+        let mut client = Self::connect(addr).await?;
+        let status = client.status(StatusRpcReq {}).await?.into_inner();
+        Ok((client, status))
+    }
+}
+
+fn url(addr: &str) -> String {
+    match addr.contains("://") {
+        true => addr.to_string(),
+        false => format!("http://{}", addr),
+    }
+}
+
+// the first admin endpoint reachable
+async fn admin(addrs: &[String]) -> Result<KvAdminClient> {
+    for addr in addrs {
+        match KvAdminClient::connect(url(addr)).await {
+            Ok(client) => return Ok(client),
+            Err(err) => log::warn!("{} is unreachable: {}", addr, err),
+        }
+    }
+    bail!("none of the admin endpoints {:?} is reachable", addrs)
+}
+
+fn consistency(stale: bool) -> i32 {
+    match stale {
+        true => Consistency::Stale as i32,
+        false => Consistency::ReadIndex as i32,
+    }
+}
+
+fn write_req(kind: ReqKind, key: String, value: Option<String>, lease: u64) -> WriteRpcReq {
+    WriteRpcReq {
+        kind: kind as i32,
+        target: Some(write_rpc_req::Target::RawKey(key.into_bytes())),
+        value: value.map(|v| write_rpc_req::Value::RawData(v.into_bytes())),
+        lease,
+        ..Default::default()
+    }
+}
+
+#[derive(Deserialize)]
+struct Txn {
+    #[serde(default)]
+    guards: Vec<Guard>,
+    #[serde(default)]
+    success: Vec<Op>,
+    #[serde(default)]
+    failure: Vec<Op>,
+}
+
+#[derive(Deserialize)]
+struct Guard {
+    key: String,
+    value: Option<String>,
+    version: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Op {
+    Get {
+        key: String,
+    },
+    /// sets the value of a key
+    Put {
+        key: String,
+        value: String,
+        #[serde(default)]
+        lease: u64,
+    },
+    Delete {
+        key: String,
+    },
+}
+
+impl Guard {
+    fn into_rpc(self) -> TxnGuard {
+        let (kind, value, version) = match (self.value, self.version) {
+            (Some(value), None) => (txn_guard::Kind::ValueEquals, value, 0),
+            (None, Some(version)) => (txn_guard::Kind::VersionEquals, String::new(), version),
+            _ => (txn_guard::Kind::Exists, String::new(), 0),
+        };
+        TxnGuard {
+            kind: kind as i32,
+            key: self.key.into_bytes(),
+            value: value.into_bytes(),
+            version,
+        }
+    }
+}
+
+impl Op {
+    fn into_rpc(self) -> TxnRpcOp {
+        let (kind, key, value, lease) = match self {
+            Op::Get { key } => (txn_rpc_op::Kind::Get, key, String::new(), 0),
+            Op::Put { key, value, lease } => (txn_rpc_op::Kind::Put, key, value, lease),
+            Op::Delete { key } => (txn_rpc_op::Kind::Delete, key, String::new(), 0),
+        };
+        TxnRpcOp {
+            kind: kind as i32,
+            key: key.into_bytes(),
+            value: value.into_bytes(),
+            lease,
+        }
+    }
+}
+
+fn op_name(kind: i32) -> &'static str {
+    match txn_rpc_op::Kind::from_i32(kind) {
+        Some(txn_rpc_op::Kind::Get) => "get",
+        Some(txn_rpc_op::Kind::Put) => "put",
+        Some(txn_rpc_op::Kind::Delete) => "delete",
+        None => "unknown",
+    }
+}
+
+async fn run(opt: Opt) -> Result<()> {
+    let endpoints = Endpoints {
+        addrs: opt.endpoints,
+    };
+    let output = opt.output;
+    match opt.cmd {
+        Command::Get {
+            key,
+            revision,
+            stale,
+        } => {
+            let req = ReadRpcReq {
+                key: Some(read_rpc_req::Key::RawKey(key.clone().into_bytes())),
+                consistency: consistency(stale),
+                revision: revision.unwrap_or(0),
+            };
+            let rsp = endpoints.any().await?.read(req).await?.into_inner();
+            let mut printer = Printer::new(output, &["key", "value", "version", "revision"]);
+            if rsp.found {
+                printer.row(vec![
+                    json!(key),
+                    text(&rsp.raw_data),
+                    json!(rsp.version),
+                    json!(rsp.revision),
+                ]);
+            }
+            printer.flush();
+        }
+        Command::Put { key, value, lease } => {
+            let req = write_req(
+                ReqKind::Insert,
+                key.clone(),
+                Some(value),
+                lease.unwrap_or(0),
+            );
+            let rsp = endpoints.leader().await?.write(req).await?.into_inner();
+            if !rsp.succeeded {
+                bail!("lease {} is gone", lease.unwrap_or(0));
+            }
+            let mut printer = Printer::new(output, &["key", "prev"]);
+            let prev = match rsp.found {
+                true => text(&rsp.raw_prev),
+                false => Value::Null,
+            };
+            printer.row(vec![json!(key), prev]);
+            printer.flush();
+        }
+        Command::Delete { key } => {
+            let req = write_req(ReqKind::Remove, key.clone(), None, 0);
+            let rsp = endpoints.leader().await?.write(req).await?.into_inner();
+            let mut printer = Printer::new(output, &["key", "deleted", "prev"]);
+            let prev = match rsp.found {
+                true => text(&rsp.raw_prev),
+                false => Value::Null,
+            };
+            printer.row(vec![json!(key), json!(rsp.found), prev]);
+            printer.flush();
+        }
+        Command::Scan {
+            prefix,
+            start,
+            end,
+            limit,
+            reverse,
+            revision,
+            stale,
+        } => {
+            let req = ScanRpcReq {
+                start: start.into_bytes(),
+                end: end.into_bytes(),
+                prefix: prefix.into_bytes(),
+                limit,
+                reverse,
+                page_token: vec![],
+                consistency: consistency(stale),
+                revision: revision.unwrap_or(0),
+            };
+            let mut pairs = endpoints.any().await?.scan(req).await?.into_inner();
+            let mut printer = Printer::new(output, &["key", "value"]);
+            while let Some(pair) = pairs.message().await? {
+                printer.row(vec![text(&pair.key), text(&pair.value)]);
+            }
+            printer.flush();
+        }
+        Command::Watch {
+            key,
+            prefix,
+            start_revision,
+        } => {
+            let req = WatchRpcReq {
+                key: key.into_bytes(),
+                prefix,
+                start_revision: start_revision.unwrap_or(0),
+            };
+            let mut changes = endpoints.any().await?.watch(req).await?.into_inner();
+            let columns = &["revision", "kind", "key", "value", "version"];
+            let mut printer = Printer::new(output, columns);
+            while let Some(rsp) = changes.message().await? {
+                for event in rsp.events {
+                    let (kind, value) = match event.kind == watch_event::Kind::Delete as i32 {
+                        true => ("delete", Value::Null),
+                        false => ("put", text(&event.value)),
+                    };
+                    printer.row(vec![
+                        json!(rsp.revision),
+                        json!(kind),
+                        text(&event.key),
+                        value,
+                        json!(event.version),
+                    ]);
+                }
+                printer.flush();
+            }
+            bail!("the watch was cut off")
+        }
+        Command::Txn { txn } => {
+            let txn: Txn = serde_json::from_str(&txn)?;
+            let req = TxnRpcReq {
+                guards: txn.guards.into_iter().map(Guard::into_rpc).collect(),
+                success: txn.success.into_iter().map(Op::into_rpc).collect(),
+                failure: txn.failure.into_iter().map(Op::into_rpc).collect(),
+                ..Default::default()
+            };
+            let ops = req.clone();
+            let rsp = endpoints.leader().await?.txn(req).await?.into_inner();
+            if rsp.lease_gone {
+                bail!("a lease the transaction puts with is gone, neither branch ran");
+            }
+            let ran = match rsp.succeeded {
+                true => ops.success,
+                false => ops.failure,
+            };
+            let columns = &[
+                "succeeded",
+                "revision",
+                "op",
+                "key",
+                "found",
+                "value",
+                "version",
+            ];
+            let mut printer = Printer::new(output, columns);
+            if rsp.results.is_empty() {
+                printer.row(vec![json!(rsp.succeeded), json!(rsp.revision)]);
+            }
+            for (op, result) in ran.iter().zip(&rsp.results) {
+                printer.row(vec![
+                    json!(rsp.succeeded),
+                    json!(rsp.revision),
+                    json!(op_name(result.kind)),
+                    text(&op.key),
+                    json!(result.found),
+                    text(&result.value),
+                    json!(result.version),
+                ]);
+            }
+            printer.flush();
+        }
+        Command::Cluster(ClusterCommand::Status) => {
+            let columns = &[
+                "endpoint",
+                "id",
+                "state",
+                "leader",
+                "term",
+                "last_log_index",
+                "last_applied",
+                "members",
+                "revision",
+            ];
+            let mut printer = Printer::new(output, columns);
+            let mut reachable = false;
+            for addr in &endpoints.addrs {
+                match endpoints.status(addr).await {
+                    Ok((_, status)) => {
+                        reachable = true;
+                        printer.row(vec![
+                            json!(addr),
+                            json!(status.id),
+                            json!(status.state),
+                            json!(status.leader),
+                            json!(status.term),
+                            json!(status.last_log_index),
+                            json!(status.last_applied),
+                            json!(status.members),
+                            json!(status.revision),
+                        ]);
+                    }
+                    Err(err) => printer.row(vec![
+                        json!(addr),
+                        Value::Null,
+                        json!(format!("unreachable: {}", err)),
+                    ]),
+                }
+            }
+            printer.flush();
+            if !reachable {
+                bail!("none of the endpoints {:?} is reachable", endpoints.addrs);
+            }
+        }
+        Command::Member(cmd) => {
+            let mut client = admin(&opt.admin_endpoints).await?;
+            let (id, rsp) = match cmd {
+                MemberCommand::Add {
+                    id,
+                    raft_addr,
+                    role,
+                } => {
+                    let req = MemberAddReq {
+                        id,
+                        raft_addr,
+                        role,
+                    };
+                    (id, client.member_add(req).await?.into_inner())
+                }
+                MemberCommand::Remove { id } => {
+                    let req = MemberRemoveReq { id };
+                    (id, client.member_remove(req).await?.into_inner())
+                }
+            };
+            let mut printer = Printer::new(output, &["id", "changed"]);
+            printer.row(vec![json!(id), json!(rsp.changed)]);
+            printer.flush();
+        }
+        Command::Snapshot { path } => {
+            let req = BackupRpcReq { path: path.clone() };
+            let mut client = admin(&opt.admin_endpoints).await?;
+            let rsp = client.backup(req).await?.into_inner();
+            let mut printer = Printer::new(output, &["path", "index", "term"]);
+            printer.row(vec![json!(path), json!(rsp.index), json!(rsp.term)]);
+            printer.flush();
+        }
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    let opt = Opt::from_args();
+    if let Err(err) = run(opt).await {
+        match err.downcast_ref::<Status>() {
+            Some(status) => eprintln!("error: {:?}: {}", status.code(), status.message()),
+            None => eprintln!("error: {:#}", err),
+        }
+        process::exit(1);
+    }
+}
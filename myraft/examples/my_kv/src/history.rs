@@ -0,0 +1,173 @@
+//! How a key is kept: its state now, and the states before it that reads at
+//! a revision can still see.
+
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::iter;
+use std::mem;
+
+/// A key from `revision` on, the log index of the write; no value once deleted.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct State {
+    pub(crate) revision: u64,
+    /// the writes since the key was last created, 0 once deleted
+    pub(crate) version: u64,
+    pub(crate) value: Option<Vec<u8>>,
+}
+
+/// A key as kept in the db: its current state, and the earlier ones, oldest
+/// first. A deleted key is kept until compacted, for the reads of before.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Entry {
+    pub(crate) current: State,
+    pub(crate) history: Vec<State>,
+    /// states older than the history were dropped to keep it short
+    pub(crate) trimmed: bool,
+}
+
+/// A read at a revision the history no longer goes back to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RevisionCompacted {
+    pub revision: u64,
+    /// the oldest revision still readable
+    pub oldest: u64,
+}
+
+impl fmt::Display for RevisionCompacted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "revision {} is compacted, the oldest readable is {}",
+            self.revision, self.oldest
+        )
+    }
+}
+
+impl Error for RevisionCompacted {}
+
+/// A read at a revision not written yet, which could still change.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RevisionAhead {
+    pub revision: u64,
+    /// the revision of the last write
+    pub last: u64,
+}
+
+impl fmt::Display for RevisionAhead {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "revision {} is ahead of the last write {}",
+            self.revision, self.last
+        )
+    }
+}
+
+impl Error for RevisionAhead {}
+
+impl Entry {
+    /// A value of the stores of before revisions, as if written once at revision 0.
+    pub(crate) fn legacy(value: Vec<u8>) -> Self {
+        Self {
+            current: State {
+                revision: 0,
+                version: 1,
+                value: Some(value),
+            },
+            history: vec![],
+            trimmed: false,
+        }
+    }
+
+    /// The current state, unless the key is deleted.
+    pub(crate) fn live(&self) -> Option<&State> {
+        Some(&self.current).filter(|state| state.value.is_some())
+    }
+
+    /// The state the key had at `revision`, None if it had none then; the
+    /// oldest revision the entry knows of if it does not go back that far.
+    pub(crate) fn at(&self, revision: u64) -> Result<Option<&State>, u64> {
+        let mut states = iter::once(&self.current).chain(self.history.iter().rev());
+        match states.find(|state| state.revision <= revision) {
+            Some(state) => Ok(Some(state).filter(|state| state.value.is_some())),
+            None if self.trimmed => Err(self.history.first().unwrap_or(&self.current).revision),
+            None => Ok(None),
+        }
+    }
+
+    /// The states the key took from `revision` on, oldest first; the oldest
+    /// revision the entry knows of if states since `revision` were trimmed.
+    pub(crate) fn since(&self, revision: u64) -> Result<impl Iterator<Item = &State>, u64> {
+        let oldest = self.history.first().unwrap_or(&self.current).revision;
+        if self.trimmed && oldest > revision {
+            return Err(oldest);
+        }
+        let states = self.history.iter().chain(iter::once(&self.current));
+        Ok(states.filter(move |state| state.revision >= revision))
+    }
+
+    /// The entry after `value` is written at `revision`, None for a delete,
+    /// keeping `keep` states before; None if the write changes nothing.
+    pub(crate) fn write(
+        prev: Option<&Entry>,
+        revision: u64,
+        value: Option<Vec<u8>>,
+        keep: usize,
+    ) -> Option<Entry> {
+        let live_version = prev.and_then(Entry::live).map_or(0, |state| state.version);
+        if value.is_none() && live_version == 0 {
+            return None;
+        }
+        let version = if value.is_some() { live_version + 1 } else { 0 };
+        let current = State {
+            revision,
+            version,
+            value,
+        };
+        let mut entry = match prev {
+            Some(prev) => prev.clone(),
+            None => {
+                return Some(Entry {
+                    current,
+                    history: vec![],
+                    trimmed: false,
+                })
+            }
+        };
+        let old = mem::replace(&mut entry.current, current);
+        // the writes of a transaction share its revision, the last one counts
+        if old.revision != revision {
+            entry.history.push(old);
+        }
+        if entry.history.len() > keep {
+            let dropped = entry.history.len() - keep;
+            entry.history.drain(..dropped);
+            entry.trimmed = true;
+        }
+        Some(entry)
+    }
+
+    /// The entry without the states older than the one current at
+    /// `revision`; None if nothing is left of it, a key deleted by then.
+    pub(crate) fn compact(mut self, revision: u64) -> Option<Entry> {
+        if self.current.revision <= revision {
+            self.history.clear();
+            self.trimmed = false;
+            return self.live().is_some().then_some(self);
+        }
+        if let Some(at) = self
+            .history
+            .iter()
+            .rposition(|state| state.revision <= revision)
+        {
+            self.history.drain(..at);
+            self.trimmed = false;
+            // a deleted state opening the history says no more than none
+            if self.history[0].value.is_none() {
+                self.history.remove(0);
+            }
+        }
+        Some(self)
+    }
+}
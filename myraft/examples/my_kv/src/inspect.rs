@@ -0,0 +1,6 @@
+use my_kv::kv_app::WriteRequest;
+use myraft::inspect::{run, AppFormatter};
+
+fn main() -> anyhow::Result<()> {
+    run(&AppFormatter::<WriteRequest>::new())
+}
@@ -0,0 +1,1391 @@
+use crate::history::{Entry, State};
+use crate::lease::{lease_key, Lease, LEASE_PREFIX};
+use crate::watch::{Event, Watch, WatchRequest};
+use anyhow::{anyhow, bail, Result};
+use bincode::{deserialize, serialize};
+use myraft::raft::{RaftApp, SnapshotSink, SnapshotSource};
+use myraft::{async_trait::async_trait, AppData, AppDataResponse};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError, Transactional,
+    TransactionalTree,
+};
+use sled::{Db, IVec, Tree};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::iter;
+use std::ops::Bound;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
+use tokio::sync::broadcast;
+
+pub use crate::history::{RevisionAhead, RevisionCompacted};
+
+/// Earlier states of a key kept for reads at a revision, unless compacted.
+pub const DEFAULT_HISTORY: usize = 16;
+
+/// Keys a compaction rewrites per transaction.
+pub const DEFAULT_COMPACTION_BATCH: usize = 1000;
+
+// writes a watch may fall behind by before it fails
+const WATCH_BUFFER: usize = 1024;
+
+// snapshot records: the last and the compacted revisions; a lease; a key and
+// its entry, each field led by its length; or the end
+const SNAPSHOT_LEASE: u8 = 5;
+const SNAPSHOT_REVISIONS: u8 = 4;
+const SNAPSHOT_ENTRY: u8 = 3;
+const SNAPSHOT_END: u8 = 0;
+
+// the entries are in the tree named in the meta tree; the stores of before
+// revisions have bare values in the default tree. A snapshot is installed into
+// a new tree, `kv_<id>`, which then takes the place of the old one, and so are
+// the values of before migrated.
+const META_TREE: &str = "meta";
+const HISTORY_TREE_KEY: &str = "history_tree";
+const DATA_TREE_PREFIX: &str = "kv_";
+// the revision of the last write, and the one the history was compacted to
+const REVISION_KEY: &str = "revision";
+const COMPACTED_KEY: &str = "compacted";
+// how far the compaction under way got, see `Compacting`
+const COMPACTING_KEY: &str = "compacting";
+// the responses of the last writes, for those the raft hands over again
+const RESPONSE_PREFIX: &[u8] = b"response/";
+const KEPT_RESPONSES: u64 = 1024;
+
+/// The bytes a typed key or value is kept as: its bincode encoding, as the
+/// u64 keys and String values of the first, typed API always were.
+pub fn encode<T: Serialize>(t: &T) -> Result<Vec<u8>> {
+    Ok(serialize(t)?)
+}
+
+/// The typed key or value kept as `bytes`.
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    Ok(deserialize(bytes)?)
+}
+
+/// A value as the typed API returns it: bytes not written as a String read as
+/// lossy utf-8, rather than failing the typed write that replaced them.
+pub fn decode_string(bytes: &[u8]) -> String {
+    decode(bytes).unwrap_or_else(|_| String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// What a conditional write requires of its key.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Condition {
+    Exists,
+    /// the key has this value, None for absent
+    Value(Option<Vec<u8>>),
+    /// the key has this version, 0 for absent
+    Version(u64),
+}
+
+impl Condition {
+    fn holds(&self, live: Option<&State>) -> bool {
+        match self {
+            Condition::Exists => live.is_some(),
+            Condition::Value(value) => {
+                live.and_then(|state| state.value.as_ref()) == value.as_ref()
+            }
+            Condition::Version(version) => live.map_or(0, |state| state.version) == *version,
+        }
+    }
+}
+
+/// A check of a transaction: `key` meets `condition`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Guard {
+    pub key: Vec<u8>,
+    pub condition: Condition,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum TxnOp {
+    Get {
+        key: Vec<u8>,
+    },
+    Put {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Delete {
+        key: Vec<u8>,
+    },
+    /// a put as `WriteRequest::PutWithLease`; a transaction with the lease of
+    /// one gone runs neither branch
+    PutWithLease {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        lease: u64,
+    },
+}
+
+/// The result of a `TxnOp`: the value and version got, or the value replaced.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum TxnResult {
+    Get {
+        value: Option<Vec<u8>>,
+        version: u64,
+    },
+    Put {
+        prev: Option<Vec<u8>>,
+    },
+    Delete {
+        prev: Option<Vec<u8>>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReadRequest {
+    pub key: Vec<u8>,
+    /// the key as it was after the write of this revision, None for now
+    pub revision: Option<u64>,
+}
+
+impl ReadRequest {
+    /// A read of a typed key, see `encode`.
+    pub fn typed<K: Serialize>(key: &K) -> Result<Self> {
+        Ok(Self {
+            key: encode(key)?,
+            revision: None,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReadResponse {
+    pub data: Option<Vec<u8>>,
+    /// 0 if the key is absent
+    pub version: u64,
+    /// of the write that gave the key the value read, 0 if it is absent
+    pub revision: u64,
+}
+
+impl ReadResponse {
+    /// The value read as a typed value, see `decode`.
+    pub fn typed<V: DeserializeOwned>(&self) -> Result<Option<V>> {
+        self.data.as_deref().map(decode).transpose()
+    }
+}
+
+/// The keys from `start` to `end` that start with `prefix`, in order or
+/// reversed, at most `limit` of them, going on from a previous page that ended
+/// with the key `after`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ScanRequest {
+    pub start: Vec<u8>,
+    /// excluded, None to scan to the last key
+    pub end: Option<Vec<u8>>,
+    pub prefix: Vec<u8>,
+    pub limit: Option<usize>,
+    pub reverse: bool,
+    pub after: Option<Vec<u8>>,
+    /// the keys as they were after the write of this revision, None for now
+    pub revision: Option<u64>,
+}
+
+pub type ScanPairs = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + Send>;
+
+/// `Insert` and `Remove` are the typed writes of the first API, kept for the
+/// logs that have them; `WriteRequest::typed_insert` and `typed_remove` are
+/// the byte writes of the same. The conditional writes are answered with
+/// `WriteResponse::Conditional`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum WriteRequest {
+    Insert {
+        key: u64,
+        value: String,
+    },
+    Remove {
+        key: u64,
+    },
+    Put {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Delete {
+        key: Vec<u8>,
+    },
+    /// puts `new` if the key has the value `expected`, None for absent
+    CompareAndSwap {
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Vec<u8>,
+    },
+    PutIfAbsent {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    /// puts `value` if the key has the version `version`, 0 for absent
+    PutIfVersion {
+        key: Vec<u8>,
+        version: u64,
+        value: Vec<u8>,
+    },
+    DeleteIfVersion {
+        key: Vec<u8>,
+        version: u64,
+    },
+    /// runs the `success` ops if every guard holds, else the `failure` ops,
+    /// all at once: a read sees none or all of a transaction's writes
+    Txn {
+        guards: Vec<Guard>,
+        success: Vec<TxnOp>,
+        failure: Vec<TxnOp>,
+    },
+    /// drops the states of before `revision` but those still current then;
+    /// reads at an earlier revision fail from now on
+    Compact {
+        revision: u64,
+    },
+    /// grants a lease of `ttl` seconds; its id is the revision of the grant
+    GrantLease {
+        ttl: u64,
+    },
+    /// puts `value` for as long as the lease lives, if it does
+    PutWithLease {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        lease: u64,
+    },
+    /// starts the lease's ttl over
+    KeepAlive {
+        lease: u64,
+    },
+    /// ends the lease, deleting its keys
+    RevokeLease {
+        lease: u64,
+    },
+    /// ends the lease as RevokeLease if it was not kept alive since
+    /// `renewed`; the leader writes it once the ttl passed
+    ExpireLease {
+        lease: u64,
+        renewed: u64,
+    },
+}
+
+impl WriteRequest {
+    pub fn typed_insert<K: Serialize, V: Serialize>(key: &K, value: &V) -> Result<Self> {
+        Ok(WriteRequest::Put {
+            key: encode(key)?,
+            value: encode(value)?,
+        })
+    }
+
+    pub fn typed_remove<K: Serialize>(key: &K) -> Result<Self> {
+        Ok(WriteRequest::Delete { key: encode(key)? })
+    }
+}
+
+impl AppData for WriteRequest {}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum WriteResponse {
+    Insert {
+        prev: Option<String>,
+    },
+    Remove {
+        prev: Option<String>,
+    },
+    Put {
+        prev: Option<Vec<u8>>,
+    },
+    Delete {
+        prev: Option<Vec<u8>>,
+    },
+    /// whether the condition held, and the value and version of the key it
+    /// was checked against; `revision` is the write's, whether it wrote or not
+    Conditional {
+        succeeded: bool,
+        value: Option<Vec<u8>>,
+        version: u64,
+        revision: u64,
+    },
+    /// whether the guards held, and the results of the ops run; none ran if
+    /// `lease_gone`, a lease of a `TxnOp::PutWithLease` being gone
+    Txn {
+        succeeded: bool,
+        results: Vec<TxnResult>,
+        revision: u64,
+        lease_gone: bool,
+    },
+    /// the revision the history starts at now, past the one asked for if it
+    /// was compacted already, never past the compaction itself
+    Compacted {
+        revision: u64,
+    },
+    /// the ttl of the lease the write found, None if there was none
+    Lease {
+        lease: u64,
+        ttl: Option<u64>,
+    },
+}
+
+impl AppDataResponse for WriteResponse {}
+
+/// How far a view got in streaming the db, in key order.
+#[derive(Default)]
+struct ViewCursor {
+    // the last key streamed, the writes up to it no longer matter to the view
+    position: Option<IVec>,
+    // past the db, the view has what it needs
+    done: bool,
+    // the values the writes to keys ahead replaced, None for new keys
+    replaced: HashMap<IVec, Option<IVec>>,
+}
+
+type SharedCursor = Mutex<ViewCursor>;
+
+/// The kv pairs as they were when the view was taken: the db, but for the
+/// keys written since, whose old values the view keeps until it streams them.
+pub struct KvSnapshotView {
+    data: Tree,
+    cursor: Arc<SharedCursor>,
+    revision: u64,
+    compacted: u64,
+    leases: Vec<(IVec, IVec)>,
+}
+
+// a compaction cut short: the log index of its write and the last key it did
+#[derive(Serialize, Deserialize)]
+struct Compacting {
+    index: u64,
+    after: Option<Vec<u8>>,
+}
+
+pub struct KvApp {
+    db: Db,
+    meta: Tree,
+    // the tree of the kv pairs
+    data: RwLock<Tree>,
+    // the views being serialized, dropped ones are pruned on the next write
+    views: Mutex<Vec<Weak<SharedCursor>>>,
+    // earlier states kept per key
+    history: usize,
+    // keys compacted per transaction
+    compaction_batch: usize,
+    // the changes of each write, to the watches; replaced on a snapshot install
+    watchers: Mutex<broadcast::Sender<Vec<Event>>>,
+    // when each lease ends unless kept alive, by the clock of this node
+    deadlines: Mutex<HashMap<u64, Instant>>,
+}
+
+impl KvApp {
+    pub fn new(db: Db) -> Result<Self> {
+        let meta = db.open_tree(META_TREE)?;
+        let data = match meta.get(HISTORY_TREE_KEY)? {
+            Some(name) => db.open_tree(name)?,
+            None => migrate(&db)?,
+        };
+        let app = Self {
+            db,
+            meta,
+            data: RwLock::new(data),
+            views: Mutex::new(vec![]),
+            history: DEFAULT_HISTORY,
+            compaction_batch: DEFAULT_COMPACTION_BATCH,
+            watchers: Mutex::new(broadcast::channel(WATCH_BUFFER).0),
+            deadlines: Mutex::new(HashMap::new()),
+        };
+        app.drop_stale_trees()?;
+        app.restart_deadlines()?;
+        Ok(app)
+    }
+
+    /// Keeps `states` earlier states of each key for reads at a revision,
+    /// the same on every node for them to answer alike.
+    pub fn keep_history(mut self, states: usize) -> Self {
+        self.history = states;
+        self
+    }
+
+    /// Compacts the history `keys` keys at a time, each batch a transaction
+    /// of its own; the reads go on in between.
+    pub fn compaction_batch(mut self, keys: usize) -> Self {
+        self.compaction_batch = keys.max(1);
+        self
+    }
+
+    /// The revision of the last write, 0 before the first.
+    pub fn revision(&self) -> Result<u64> {
+        self.meta_u64(REVISION_KEY)
+    }
+
+    /// The oldest revision reads can be at.
+    pub fn compacted(&self) -> Result<u64> {
+        self.meta_u64(COMPACTED_KEY)
+    }
+
+    fn meta_u64(&self, key: &str) -> Result<u64> {
+        meta_u64(&self.meta, key)
+    }
+
+    // a read at a revision the history does not go back to fails, and so
+    // does one at a revision not written yet, which could still change
+    fn check_revision(&self, revision: u64) -> Result<()> {
+        let compacted = self.compacted()?;
+        if revision < compacted {
+            return Err(RevisionCompacted {
+                revision,
+                oldest: compacted,
+            }
+            .into());
+        }
+        let last = self.revision()?;
+        if revision > last {
+            return Err(RevisionAhead { revision, last }.into());
+        }
+        Ok(())
+    }
+
+    fn data(&self) -> Tree {
+        self.data.read().unwrap().clone()
+    }
+
+    // the trees replaced by a snapshot, and those of installs cut short
+    fn drop_stale_trees(&self) -> Result<()> {
+        let current = self.data().name();
+        for name in self.db.tree_names() {
+            if name.starts_with(DATA_TREE_PREFIX.as_bytes()) && name != current {
+                self.db.drop_tree(name)?;
+            }
+        }
+        if current != self.db.name() {
+            self.db.clear()?;
+        }
+        Ok(())
+    }
+
+    // copy on write: the views that have not streamed `key` yet get `value`,
+    // the one it has before it changes
+    fn save_for_views(&self, key: &[u8], value: Option<&IVec>) {
+        let mut views = self.views.lock().unwrap();
+        views.retain(|view| view.strong_count() > 0);
+        for view in views.iter().filter_map(Weak::upgrade) {
+            let mut cursor = view.lock().unwrap();
+            let streamed = cursor.done || cursor.position.as_deref().is_some_and(|p| key <= p);
+            if !streamed && !cursor.replaced.contains_key(key) {
+                cursor.replaced.insert(key.into(), value.cloned());
+            }
+        }
+    }
+
+    fn entry(&self, key: &[u8]) -> Result<Option<Entry>> {
+        match self.data().get(key)? {
+            Some(entry) => Ok(Some(decode(&entry)?)),
+            None => Ok(None),
+        }
+    }
+
+    // runs `write` on the data and meta trees in one transaction, giving it
+    // the list of the changes of the keys
+    fn transact<R>(
+        &self,
+        write: impl Fn(&TransactionalTree, &TransactionalTree, &mut Vec<Event>) -> TxResult<R>,
+    ) -> Result<(R, Vec<Event>)> {
+        let done = (&self.data(), &self.meta).transaction(|(tx, meta)| {
+            let mut events = vec![];
+            let r = write(tx, meta, &mut events)?;
+            Ok((r, events))
+        });
+        match done {
+            Ok(done) => Ok(done),
+            Err(TransactionError::Abort(err)) => Err(err),
+            Err(TransactionError::Storage(err)) => Err(err.into()),
+        }
+    }
+
+    // applies the write of the log entry `index`: its changes, its revision
+    // and its response, kept for a replay, all at once; then puts it on disk
+    // and sends its changes to the watches
+    async fn commit(
+        &self,
+        index: u64,
+        write: impl Fn(
+            &TransactionalTree,
+            &TransactionalTree,
+            &mut Vec<Event>,
+        ) -> TxResult<WriteResponse>,
+    ) -> Result<WriteResponse> {
+        let (rsp, mut events) = self.transact(|tx, meta, events| {
+            let rsp = write(tx, meta, events)?;
+            meta.insert(REVISION_KEY, &index.to_be_bytes())?;
+            meta.insert(response_key(index), encode(&rsp).map_err(abort)?)?;
+            if let Some(old) = index.checked_sub(KEPT_RESPONSES) {
+                meta.remove(response_key(old))?;
+            }
+            Ok(rsp)
+        })?;
+        self.db.flush_async().await?;
+        // the last change of a key written twice, as a replay sees it
+        events.reverse();
+        events.sort_by(|a, b| a.key.cmp(&b.key));
+        events.dedup_by(|a, b| a.key == b.key);
+        self.notify(events);
+        Ok(rsp)
+    }
+
+    // A write of the log at an index applied already comes again when a
+    // restart lost the raft's record of having applied it. It is not applied
+    // twice: it gets the response kept for it, or if that is gone the one it
+    // would get from the state now, its changes undone.
+    fn replayed(&self, index: u64, req: &WriteRequest) -> Result<WriteResponse> {
+        if let Some(rsp) = self.meta.get(response_key(index))? {
+            return decode(&rsp);
+        }
+        if let WriteRequest::Compact { .. } = req {
+            let revision = self.compacted()?;
+            return Ok(WriteResponse::Compacted { revision });
+        }
+        let answer = RefCell::new(None);
+        let undone = self.transact(|tx, meta, events| {
+            *answer.borrow_mut() = Some(self.tx_write(tx, meta, index, req, events)?);
+            Err::<(), _>(abort(anyhow!("replayed write {} undone", index)))
+        });
+        match answer.into_inner() {
+            Some(rsp) => Ok(rsp),
+            None => Err(undone.unwrap_err()),
+        }
+    }
+
+    // puts `value`, or deletes the key for None, at the revision `index`,
+    // adding the change to `events`; returns the key's live state before. A
+    // transaction reads its own writes, a key written twice gets two versions.
+    fn tx_put(
+        &self,
+        tx: &TransactionalTree,
+        index: u64,
+        key: &[u8],
+        value: Option<Vec<u8>>,
+        events: &mut Vec<Event>,
+    ) -> TxResult<Option<State>> {
+        // read in the transaction: the tree itself is locked until it ends
+        let raw = tx.get(key)?;
+        let prev: Option<Entry> = raw.as_deref().map(decode).transpose().map_err(abort)?;
+        if let Some(entry) = Entry::write(prev.as_ref(), index, value, self.history) {
+            self.save_for_views(key, raw.as_ref());
+            events.push(event(key, &entry.current));
+            tx.insert(key, encode(&entry).map_err(abort)?)?;
+        }
+        Ok(prev.as_ref().and_then(Entry::live).cloned())
+    }
+
+    // the changes of a write but a compaction, within the transaction of `commit`
+    fn tx_write(
+        &self,
+        tx: &TransactionalTree,
+        meta: &TransactionalTree,
+        index: u64,
+        req: &WriteRequest,
+        events: &mut Vec<Event>,
+    ) -> TxResult<WriteResponse> {
+        let prev_value = |prev: Option<State>| prev.and_then(|prev| prev.value);
+        let typed_key = |key: &u64| encode(key).map_err(abort);
+        Ok(match req {
+            WriteRequest::Insert { key, value } => {
+                let value = Some(encode(value).map_err(abort)?);
+                let prev = self.tx_put(tx, index, &typed_key(key)?, value, events)?;
+                WriteResponse::Insert {
+                    prev: prev_value(prev).as_deref().map(decode_string),
+                }
+            }
+            WriteRequest::Remove { key } => {
+                let prev = self.tx_put(tx, index, &typed_key(key)?, None, events)?;
+                WriteResponse::Remove {
+                    prev: prev_value(prev).as_deref().map(decode_string),
+                }
+            }
+            WriteRequest::Put { key, value } => WriteResponse::Put {
+                prev: prev_value(self.tx_put(tx, index, key, Some(value.clone()), events)?),
+            },
+            WriteRequest::Delete { key } => WriteResponse::Delete {
+                prev: prev_value(self.tx_put(tx, index, key, None, events)?),
+            },
+            WriteRequest::CompareAndSwap { key, expected, new } => {
+                let condition = Condition::Value(expected.clone());
+                self.tx_write_if(tx, index, key, condition, Some(new.clone()), events)?
+            }
+            WriteRequest::PutIfAbsent { key, value } => {
+                let condition = Condition::Value(None);
+                self.tx_write_if(tx, index, key, condition, Some(value.clone()), events)?
+            }
+            WriteRequest::PutIfVersion {
+                key,
+                version,
+                value,
+            } => {
+                let condition = Condition::Version(*version);
+                self.tx_write_if(tx, index, key, condition, Some(value.clone()), events)?
+            }
+            WriteRequest::DeleteIfVersion { key, version } => {
+                let condition = Condition::Version(*version);
+                self.tx_write_if(tx, index, key, condition, None, events)?
+            }
+            WriteRequest::Txn {
+                guards,
+                success,
+                failure,
+            } => self.tx_txn(tx, meta, index, guards, success, failure, events)?,
+            WriteRequest::GrantLease { ttl } => {
+                let lease = Lease {
+                    ttl: *ttl,
+                    renewed: index,
+                    keys: BTreeMap::new(),
+                };
+                tx_set_lease(meta, index, &lease)?;
+                WriteResponse::Lease {
+                    lease: index,
+                    ttl: Some(*ttl),
+                }
+            }
+            WriteRequest::PutWithLease { key, value, lease } => {
+                self.tx_put_with_lease(tx, meta, index, key, value, *lease, events)?
+            }
+            WriteRequest::KeepAlive { lease: id } => {
+                let lease = tx_lease(meta, *id)?;
+                if let Some(mut lease) = lease.clone() {
+                    lease.renewed = index;
+                    tx_set_lease(meta, *id, &lease)?;
+                }
+                WriteResponse::Lease {
+                    lease: *id,
+                    ttl: lease.map(|lease| lease.ttl),
+                }
+            }
+            WriteRequest::RevokeLease { lease } => {
+                self.tx_end_lease(tx, meta, index, *lease, None, events)?
+            }
+            WriteRequest::ExpireLease { lease, renewed } => {
+                self.tx_end_lease(tx, meta, index, *lease, Some(*renewed), events)?
+            }
+            WriteRequest::Compact { .. } => {
+                return Err(abort(anyhow!("a compaction is not a transaction")))
+            }
+        })
+    }
+
+    // the watches get the changes of a write once it is on disk
+    fn notify(&self, events: Vec<Event>) {
+        if !events.is_empty() {
+            // no watch is listening
+            let _ = self.watchers.lock().unwrap().send(events);
+        }
+    }
+
+    /// Starts a watch, replaying the changes kept since its start revision.
+    pub fn watch(&self, req: WatchRequest) -> Result<Watch> {
+        let writes = self.watchers.lock().unwrap().subscribe();
+        let last = self.revision()?;
+        let start = match req.start_revision {
+            Some(start) if start <= last => start,
+            Some(start) => return Ok(Watch::new(req, vec![], writes, start)),
+            None => return Ok(Watch::new(req, vec![], writes, last + 1)),
+        };
+        let entries = match req.prefix {
+            true => self
+                .data()
+                .scan_prefix(&req.key)
+                .collect::<sled::Result<_>>()?,
+            false => match self.data().get(&req.key)? {
+                Some(entry) => vec![(IVec::from(req.key.as_slice()), entry)],
+                None => vec![],
+            },
+        };
+        // the deletes up to the compacted revision are gone with their keys;
+        // checked after the entries are read, a compaction may have begun
+        let compacted = self.compacted()?;
+        if compacted > 0 && start <= compacted {
+            return Err(RevisionCompacted {
+                revision: start,
+                oldest: compacted + 1,
+            }
+            .into());
+        }
+        let mut replayed = vec![];
+        for (key, entry) in entries {
+            let entry: Entry = decode(&entry)?;
+            let states = entry.since(start).map_err(|oldest| RevisionCompacted {
+                revision: start,
+                oldest,
+            })?;
+            // the later writes come through `writes`
+            let states = states.filter(|state| state.revision <= last);
+            replayed.extend(states.map(|state| event(&key, state)));
+        }
+        replayed.sort_by(|a, b| (a.revision, &a.key).cmp(&(b.revision, &b.key)));
+        Ok(Watch::new(req, replayed, writes, last + 1))
+    }
+
+    // puts `new`, or deletes the key for None, if the key meets `condition`
+    fn tx_write_if(
+        &self,
+        tx: &TransactionalTree,
+        index: u64,
+        key: &[u8],
+        condition: Condition,
+        new: Option<Vec<u8>>,
+        events: &mut Vec<Event>,
+    ) -> TxResult<WriteResponse> {
+        let entry = tx_entry(tx, key)?;
+        let current = entry.as_ref().and_then(Entry::live).cloned();
+        let succeeded = condition.holds(current.as_ref());
+        if succeeded {
+            self.tx_put(tx, index, key, new, events)?;
+        }
+        let (value, version) = match current {
+            Some(state) => (state.value, state.version),
+            None => (None, 0),
+        };
+        Ok(WriteResponse::Conditional {
+            succeeded,
+            value,
+            version,
+            revision: index,
+        })
+    }
+
+    fn lease(&self, id: u64) -> Result<Option<Lease>> {
+        match self.meta.get(lease_key(id))? {
+            Some(lease) => Ok(Some(decode(&lease)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn renew(&self, id: u64, ttl: u64) {
+        let deadline = Instant::now() + Duration::from_secs(ttl);
+        self.deadlines.lock().unwrap().insert(id, deadline);
+    }
+
+    // on a start or a snapshot install the clocks of the leases start over:
+    // they may end later than on the leader of before, never earlier
+    fn restart_deadlines(&self) -> Result<()> {
+        self.deadlines.lock().unwrap().clear();
+        for kv in self.meta.scan_prefix(LEASE_PREFIX) {
+            let (key, lease) = kv?;
+            let id = u64::from_be_bytes(key[LEASE_PREFIX.len()..].try_into()?);
+            self.renew(id, decode::<Lease>(&lease)?.ttl);
+        }
+        Ok(())
+    }
+
+    /// The writes that end the leases whose ttl passed on this node, for the
+    /// leader to submit.
+    pub fn expired_leases(&self) -> Result<Vec<WriteRequest>> {
+        let now = Instant::now();
+        let expired: Vec<u64> = {
+            let deadlines = self.deadlines.lock().unwrap();
+            let expired = deadlines.iter().filter(|(_, deadline)| **deadline <= now);
+            expired.map(|(id, _)| *id).collect()
+        };
+        let mut writes = vec![];
+        for id in expired {
+            if let Some(lease) = self.lease(id)? {
+                writes.push(WriteRequest::ExpireLease {
+                    lease: id,
+                    renewed: lease.renewed,
+                });
+            }
+        }
+        Ok(writes)
+    }
+
+    // the clock of a lease a write granted, kept alive or ended, once applied
+    fn track_deadline(&self, index: u64, req: &WriteRequest, rsp: &WriteResponse) -> Result<()> {
+        match (req, rsp) {
+            (WriteRequest::GrantLease { ttl }, _) => self.renew(index, *ttl),
+            (WriteRequest::KeepAlive { lease }, WriteResponse::Lease { ttl: Some(ttl), .. }) => {
+                self.renew(*lease, *ttl)
+            }
+            (WriteRequest::RevokeLease { lease }, _)
+            | (WriteRequest::ExpireLease { lease, .. }, _)
+                if self.lease(*lease)?.is_none() =>
+            {
+                self.deadlines.lock().unwrap().remove(lease);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn tx_put_with_lease(
+        &self,
+        tx: &TransactionalTree,
+        meta: &TransactionalTree,
+        index: u64,
+        key: &[u8],
+        value: &[u8],
+        id: u64,
+        events: &mut Vec<Event>,
+    ) -> TxResult<WriteResponse> {
+        let mut lease = tx_lease(meta, id)?;
+        let current = match &mut lease {
+            Some(lease) => {
+                let prev = self.tx_put(tx, index, key, Some(value.to_vec()), events)?;
+                lease.keys.insert(key.to_vec(), index);
+                tx_set_lease(meta, id, lease)?;
+                prev
+            }
+            None => tx_entry(tx, key)?.as_ref().and_then(Entry::live).cloned(),
+        };
+        let (value, version) = match current {
+            Some(state) => (state.value, state.version),
+            None => (None, 0),
+        };
+        Ok(WriteResponse::Conditional {
+            succeeded: lease.is_some(),
+            value,
+            version,
+            revision: index,
+        })
+    }
+
+    // ends the lease unless it was renewed since `renewed`, if given, deleting
+    // the keys it put that were not written since
+    fn tx_end_lease(
+        &self,
+        tx: &TransactionalTree,
+        meta: &TransactionalTree,
+        index: u64,
+        id: u64,
+        renewed: Option<u64>,
+        events: &mut Vec<Event>,
+    ) -> TxResult<WriteResponse> {
+        let lease = tx_lease(meta, id)?;
+        let ttl = lease.as_ref().map(|lease| lease.ttl);
+        if let Some(lease) = lease.filter(|lease| renewed.is_none_or(|r| r == lease.renewed)) {
+            for (key, revision) in &lease.keys {
+                let entry = tx_entry(tx, key)?;
+                if entry
+                    .as_ref()
+                    .and_then(Entry::live)
+                    .map(|state| state.revision)
+                    == Some(*revision)
+                {
+                    self.tx_put(tx, index, key, None, events)?;
+                }
+            }
+            meta.remove(lease_key(id))?;
+        }
+        Ok(WriteResponse::Lease { lease: id, ttl })
+    }
+
+    async fn compact(&self, index: u64, revision: u64) -> Result<WriteResponse> {
+        let compacted = self.compacted()?;
+        // the history up to the compaction itself is all there is
+        let revision = revision.min(index);
+        // a restart cut this one short, it goes on after the last key done
+        let resumed = match self.meta.get(COMPACTING_KEY)? {
+            Some(bytes) => Some(decode::<Compacting>(&bytes)?).filter(|c| c.index == index),
+            None => None,
+        };
+        if revision > compacted || resumed.is_some() {
+            let mut after = resumed.and_then(|c| c.after);
+            while let Some(last) = self.compact_batch(index, revision, after.as_deref())? {
+                after = Some(last);
+                // the reads and views of the other tasks go on in between
+                tokio::task::yield_now().await;
+            }
+        }
+        self.commit(index, |_, meta, _| {
+            meta.remove(COMPACTING_KEY)?;
+            Ok(WriteResponse::Compacted {
+                revision: revision.max(compacted),
+            })
+        })
+        .await
+    }
+
+    // compacts the keys of a batch after `after` in one transaction, which
+    // also records how far the compaction got and refuses the reads below
+    // `revision` from the first batch on; the last key if there may be more
+    fn compact_batch(
+        &self,
+        index: u64,
+        revision: u64,
+        after: Option<&[u8]>,
+    ) -> Result<Option<Vec<u8>>> {
+        let range: KeyRange = match after {
+            Some(key) => (Bound::Excluded(key.to_vec()), Bound::Unbounded),
+            None => (Bound::Unbounded, Bound::Unbounded),
+        };
+        let keys = self
+            .data()
+            .range::<Vec<u8>, _>(range)
+            .keys()
+            .take(self.compaction_batch)
+            .collect::<sled::Result<Vec<_>>>()?;
+        let last = keys.last().map(|key| key.to_vec());
+        let compacting = Compacting {
+            index,
+            after: last.clone().or_else(|| after.map(<[u8]>::to_vec)),
+        };
+        let compacting = encode(&compacting)?;
+        self.transact(|tx, meta, _| {
+            for key in &keys {
+                let raw = match tx.get(key)? {
+                    Some(raw) => raw,
+                    None => continue,
+                };
+                let entry: Entry = decode(&raw).map_err(abort)?;
+                let left = entry.clone().compact(revision);
+                if left.as_ref() == Some(&entry) {
+                    continue;
+                }
+                self.save_for_views(key, Some(&raw));
+                match left {
+                    Some(left) => tx.insert(key, encode(&left).map_err(abort)?)?,
+                    None => tx.remove(key)?,
+                };
+            }
+            // never below the compacted one: a compaction to it is done
+            // already, or this one was resumed
+            meta.insert(COMPACTED_KEY, &revision.to_be_bytes())?;
+            meta.insert(COMPACTING_KEY, compacting.as_slice())?;
+            Ok(())
+        })?;
+        Ok(last.filter(|_| keys.len() == self.compaction_batch))
+    }
+
+    // the keys, the leases they are put with and the revision change at once
+    #[allow(clippy::too_many_arguments)]
+    fn tx_txn(
+        &self,
+        tx: &TransactionalTree,
+        meta: &TransactionalTree,
+        index: u64,
+        guards: &[Guard],
+        success: &[TxnOp],
+        failure: &[TxnOp],
+        events: &mut Vec<Event>,
+    ) -> TxResult<WriteResponse> {
+        for op in success.iter().chain(failure) {
+            if let TxnOp::PutWithLease { lease, .. } = op {
+                if tx_lease(meta, *lease)?.is_none() {
+                    return Ok(WriteResponse::Txn {
+                        succeeded: false,
+                        results: vec![],
+                        revision: index,
+                        lease_gone: true,
+                    });
+                }
+            }
+        }
+        let mut succeeded = true;
+        for guard in guards {
+            let entry = tx_entry(tx, &guard.key)?;
+            if !guard.condition.holds(entry.as_ref().and_then(Entry::live)) {
+                succeeded = false;
+                break;
+            }
+        }
+        let ops = if succeeded { success } else { failure };
+        let mut results = vec![];
+        for op in ops {
+            results.push(self.tx_apply(tx, op, index, events)?);
+            if let TxnOp::PutWithLease { key, lease: id, .. } = op {
+                // checked above, and the transaction does not end leases
+                if let Some(mut lease) = tx_lease(meta, *id)? {
+                    lease.keys.insert(key.clone(), index);
+                    tx_set_lease(meta, *id, &lease)?;
+                }
+            }
+        }
+        Ok(WriteResponse::Txn {
+            succeeded,
+            results,
+            revision: index,
+            lease_gone: false,
+        })
+    }
+
+    fn tx_apply(
+        &self,
+        tx: &TransactionalTree,
+        op: &TxnOp,
+        index: u64,
+        events: &mut Vec<Event>,
+    ) -> TxResult<TxnResult> {
+        let (key, value) = match op {
+            TxnOp::Get { key } => {
+                let entry = tx_entry(tx, key)?;
+                return Ok(match entry.as_ref().and_then(Entry::live) {
+                    Some(state) => TxnResult::Get {
+                        value: state.value.clone(),
+                        version: state.version,
+                    },
+                    None => TxnResult::Get {
+                        value: None,
+                        version: 0,
+                    },
+                });
+            }
+            TxnOp::Put { key, value } | TxnOp::PutWithLease { key, value, .. } => {
+                (key, Some(value.clone()))
+            }
+            TxnOp::Delete { key } => (key, None),
+        };
+        let deleted = value.is_none();
+        let prev = self.tx_put(tx, index, key, value, events)?;
+        let prev = prev.and_then(|prev| prev.value);
+        Ok(match deleted {
+            true => TxnResult::Delete { prev },
+            false => TxnResult::Put { prev },
+        })
+    }
+
+    /// The pairs of the scan, read from the db as they are iterated, as they
+    /// were at the revision of the request or else at the one the scan starts
+    /// at, whatever is written meanwhile.
+    pub fn scan(&self, req: &ScanRequest) -> Result<ScanPairs> {
+        let revision = match req.revision {
+            Some(revision) => {
+                self.check_revision(revision)?;
+                revision
+            }
+            None => self.revision()?,
+        };
+        let range = match scan_range(req) {
+            Some(range) => range,
+            None => return Ok(Box::new(iter::empty())),
+        };
+        let entries = self.data().range::<Vec<u8>, _>(range);
+        let limit = req.limit.unwrap_or(usize::MAX);
+        let (meta, at) = (self.meta.clone(), req.revision);
+        let pairs = move |kv| {
+            let pair = scan_pair(kv, Some(revision));
+            // a compaction started after the check may have dropped the
+            // state the entry had at the revision asked for
+            if let (Ok(None), Some(revision)) = (&pair, at) {
+                match meta_u64(&meta, COMPACTED_KEY) {
+                    Ok(oldest) if revision < oldest => {
+                        return Some(Err(RevisionCompacted { revision, oldest }.into()))
+                    }
+                    Ok(_) => {}
+                    Err(err) => return Some(Err(err)),
+                }
+            }
+            pair.transpose()
+        };
+        Ok(if req.reverse {
+            Box::new(entries.rev().filter_map(pairs).take(limit))
+        } else {
+            Box::new(entries.filter_map(pairs).take(limit))
+        })
+    }
+
+    pub async fn handle_read(&self, req: ReadRequest) -> Result<ReadResponse> {
+        let entry = self.entry(&req.key)?;
+        let state = match (req.revision, &entry) {
+            (None, entry) => entry.as_ref().and_then(Entry::live),
+            (Some(revision), entry) => {
+                self.check_revision(revision)?;
+                match entry {
+                    Some(entry) => entry
+                        .at(revision)
+                        .map_err(|oldest| RevisionCompacted { revision, oldest })?,
+                    None => None,
+                }
+            }
+        };
+        Ok(match state {
+            Some(state) => ReadResponse {
+                data: state.value.clone(),
+                version: state.version,
+                revision: state.revision,
+            },
+            None => ReadResponse {
+                data: None,
+                version: 0,
+                revision: 0,
+            },
+        })
+    }
+}
+
+#[async_trait]
+impl RaftApp for KvApp {
+    async fn handle_write(&self, index: u64, req: WriteRequest) -> Result<WriteResponse> {
+        if index <= self.revision()? {
+            return self.replayed(index, &req);
+        }
+        if let WriteRequest::Compact { revision } = req {
+            return self.compact(index, revision).await;
+        }
+        let rsp = self
+            .commit(index, |tx, meta, events| {
+                self.tx_write(tx, meta, index, &req, events)
+            })
+            .await?;
+        self.track_deadline(index, &req, &rsp)?;
+        Ok(rsp)
+    }
+
+    fn snapshot_view(&self) -> Result<KvSnapshotView> {
+        let cursor = Arc::new(Mutex::new(ViewCursor::default()));
+        self.views.lock().unwrap().push(Arc::downgrade(&cursor));
+        Ok(KvSnapshotView {
+            data: self.data(),
+            cursor,
+            revision: self.revision()?,
+            compacted: self.compacted()?,
+            leases: self
+                .meta
+                .scan_prefix(LEASE_PREFIX)
+                .collect::<sled::Result<_>>()?,
+        })
+    }
+
+    // streams the db in key order, so that memory holds only what is written meanwhile
+    async fn make_snapshot(&self, view: KvSnapshotView, sink: &mut SnapshotSink<'_>) -> Result<()> {
+        let mut sink = BufWriter::new(sink);
+        sink.write_u8(SNAPSHOT_REVISIONS).await?;
+        sink.write_u64(view.revision).await?;
+        sink.write_u64(view.compacted).await?;
+        for (key, lease) in &view.leases {
+            write_record(&mut sink, SNAPSHOT_LEASE, key, lease).await?;
+        }
+        for kv in view.data.iter() {
+            let (k, v) = kv?;
+            // a key is saved before it is written, so if the write is seen here
+            // its old value is in the view
+            let v = {
+                let mut cursor = view.cursor.lock().unwrap();
+                cursor.position = Some(k.clone());
+                match cursor.replaced.remove(&k) {
+                    Some(prev) => prev,
+                    None => Some(v),
+                }
+            };
+            if let Some(v) = v {
+                write_record(&mut sink, SNAPSHOT_ENTRY, &k, &v).await?;
+            }
+        }
+        // the keys removed ahead of the iteration
+        let rest: Vec<_> = {
+            let mut cursor = view.cursor.lock().unwrap();
+            cursor.done = true;
+            cursor.replaced.drain().collect()
+        };
+        for (k, v) in rest {
+            if let Some(v) = v {
+                write_record(&mut sink, SNAPSHOT_ENTRY, &k, &v).await?;
+            }
+        }
+        sink.write_u8(SNAPSHOT_END).await?;
+        sink.flush().await?;
+        Ok(())
+    }
+
+    async fn handle_snapshot(&self, source: &mut SnapshotSource<'_>) -> Result<()> {
+        let name = format!("{}{}", DATA_TREE_PREFIX, self.db.generate_id()?);
+        let data = self.db.open_tree(&name)?;
+        let (mut revision, mut compacted) = (0, 0);
+        // the leases of the snapshot take the place of these
+        let mut batch = sled::Batch::default();
+        for kv in self.meta.scan_prefix(LEASE_PREFIX) {
+            batch.remove(kv?.0);
+        }
+        // and the responses are of writes of before
+        for kv in self.meta.scan_prefix(RESPONSE_PREFIX) {
+            batch.remove(kv?.0);
+        }
+        loop {
+            let entry = match source.read_u8().await? {
+                SNAPSHOT_REVISIONS => {
+                    revision = source.read_u64().await?;
+                    compacted = source.read_u64().await?;
+                    continue;
+                }
+                SNAPSHOT_LEASE => {
+                    let key = read_field(source).await?;
+                    if !key.starts_with(LEASE_PREFIX) {
+                        bail!("snapshot lease of a key out of the leases");
+                    }
+                    batch.insert(key, read_field(source).await?);
+                    continue;
+                }
+                SNAPSHOT_ENTRY => {
+                    let k = read_field(source).await?;
+                    (k, read_field(source).await?)
+                }
+                SNAPSHOT_END => break,
+                record => bail!("unknown snapshot record {}", record),
+            };
+            data.insert(entry.0, entry.1)?;
+        }
+        self.db.flush_async().await?;
+        // the one write that swaps the trees, and the leases, on disk
+        batch.insert(HISTORY_TREE_KEY, name.as_bytes());
+        batch.insert(REVISION_KEY, &revision.to_be_bytes());
+        batch.insert(COMPACTED_KEY, &compacted.to_be_bytes());
+        // a compaction cut short is in the snapshot whole or not at all
+        batch.remove(COMPACTING_KEY);
+        self.meta.apply_batch(batch)?;
+        self.db.flush_async().await?;
+        // the watches can not tell what changed, they resume from the history
+        *self.watchers.lock().unwrap() = broadcast::channel(WATCH_BUFFER).0;
+        *self.data.write().unwrap() = data;
+        self.restart_deadlines()?;
+        // the views still streaming go on with the old tree, which no write
+        // touches any more; it is dropped on the next install or start
+        let streaming = {
+            let mut views = self.views.lock().unwrap();
+            let streaming = views.iter().any(|view| view.strong_count() > 0);
+            views.clear();
+            streaming
+        };
+        if !streaming {
+            self.drop_stale_trees()?;
+        }
+        Ok(())
+    }
+
+    type WriteReq = WriteRequest;
+    type WriteRsp = WriteResponse;
+    type SnapshotView = KvSnapshotView;
+}
+
+type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+// the bounds of a scan in the db, None if no key is within them; but for the
+// start all the bounds exclude their key
+fn scan_range(req: &ScanRequest) -> Option<KeyRange> {
+    let mut lower = Bound::Included(req.start.clone().max(req.prefix.clone()));
+    let mut uppers: Vec<Vec<u8>> = req.end.iter().cloned().collect();
+    uppers.extend(prefix_end(&req.prefix));
+    match &req.after {
+        Some(after) if req.reverse => uppers.push(after.clone()),
+        Some(after) => {
+            if let Bound::Included(start) = &lower {
+                if after >= start {
+                    lower = Bound::Excluded(after.clone());
+                }
+            }
+        }
+        None => {}
+    }
+    let upper = match uppers.into_iter().min() {
+        Some(upper) => upper,
+        None => return Some((lower, Bound::Unbounded)),
+    };
+    match &lower {
+        Bound::Included(start) | Bound::Excluded(start) if *start >= upper => None,
+        _ => Some((lower, Bound::Excluded(upper))),
+    }
+}
+
+// the first key past those that start with `prefix`, None if there is none
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+// the key and value of a scanned entry, None if it has no value at `revision`
+fn scan_pair(
+    kv: sled::Result<(IVec, IVec)>,
+    revision: Option<u64>,
+) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    let (k, entry) = kv?;
+    let entry: Entry = decode(&entry)?;
+    let state = match revision {
+        Some(revision) => entry
+            .at(revision)
+            .map_err(|oldest| RevisionCompacted { revision, oldest })?,
+        None => entry.live(),
+    };
+    Ok(state.and_then(|state| Some((k.to_vec(), state.value.clone()?))))
+}
+
+type TxResult<T> = ConflictableTransactionResult<T, anyhow::Error>;
+
+fn abort(err: anyhow::Error) -> ConflictableTransactionError<anyhow::Error> {
+    ConflictableTransactionError::Abort(err)
+}
+
+fn meta_u64(meta: &Tree, key: &str) -> Result<u64> {
+    match meta.get(key)? {
+        Some(bytes) => Ok(u64::from_be_bytes(bytes.as_ref().try_into()?)),
+        None => Ok(0),
+    }
+}
+
+fn tx_entry(tx: &TransactionalTree, key: &[u8]) -> TxResult<Option<Entry>> {
+    match tx.get(key)? {
+        Some(entry) => Ok(Some(decode(&entry).map_err(abort)?)),
+        None => Ok(None),
+    }
+}
+
+fn tx_lease(meta: &TransactionalTree, id: u64) -> TxResult<Option<Lease>> {
+    match meta.get(lease_key(id))? {
+        Some(lease) => Ok(Some(decode(&lease).map_err(abort)?)),
+        None => Ok(None),
+    }
+}
+
+fn tx_set_lease(meta: &TransactionalTree, id: u64, lease: &Lease) -> TxResult<()> {
+    meta.insert(lease_key(id), encode(lease).map_err(abort)?)?;
+    Ok(())
+}
+
+// the response of the write of a revision, under the prefix and the revision
+fn response_key(revision: u64) -> Vec<u8> {
+    [RESPONSE_PREFIX, &revision.to_be_bytes()].concat()
+}
+
+fn event(key: &[u8], state: &State) -> Event {
+    Event {
+        revision: state.revision,
+        key: key.to_vec(),
+        value: state.value.clone(),
+        version: state.version,
+    }
+}
+
+// the values of the stores of before revisions, made entries in a new tree
+// that takes the place of the default one; the default tree is cleared once
+// the new one is in use
+fn migrate(db: &Db) -> Result<Tree> {
+    let name = format!("{}{}", DATA_TREE_PREFIX, db.generate_id()?);
+    let data = db.open_tree(&name)?;
+    for kv in db.iter() {
+        let (k, value) = kv?;
+        data.insert(k, encode(&Entry::legacy(value.to_vec()))?)?;
+    }
+    db.flush()?;
+    db.open_tree(META_TREE)?
+        .insert(HISTORY_TREE_KEY, name.as_bytes())?;
+    db.flush()?;
+    Ok(data)
+}
+
+async fn write_record(
+    sink: &mut BufWriter<&mut SnapshotSink<'_>>,
+    record: u8,
+    k: &[u8],
+    v: &[u8],
+) -> Result<()> {
+    sink.write_u8(record).await?;
+    for field in [k, v] {
+        sink.write_u64(field.len() as u64).await?;
+        sink.write_all(field).await?;
+    }
+    Ok(())
+}
+
+async fn read_field(source: &mut SnapshotSource<'_>) -> Result<Vec<u8>> {
+    let len = source.read_u64().await?;
+    let mut field = vec![0; len as usize];
+    source.read_exact(&mut field).await?;
+    Ok(field)
+}
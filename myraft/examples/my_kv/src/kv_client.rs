@@ -0,0 +1,535 @@
+//! A client of a my_kv cluster that finds the leader among its endpoints,
+//! follows it as it moves, and retries what is safe to retry.
+//!
+//! Writes go to the leader, each in a client session of its own for its
+//! retries to be applied once. Linearizable reads go to the leader too, stale
+//! ones to the followers in turn.
+
+use crate::clientpb::client_rpc_client::ClientRpcClient;
+use crate::clientpb::{read_rpc_req, write_rpc_req};
+use crate::clientpb::{
+    Consistency, LeaseGrantReq, LeaseReq, ReadRpcReq, ReqKind, ScanRpcReq, StatusRpcReq, TxnRpcReq,
+    TxnRpcRsp, WatchRpcReq, WatchRpcRsp, WriteRpcReq, WriteRpcRsp,
+};
+use crate::service::LEADER_METADATA;
+use anyhow::{anyhow, Result};
+use log::{debug, warn};
+use myraft::ClientSession;
+use std::cmp::min;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::{sleep, timeout};
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Code, Status, Streaming};
+
+type KvRpcClient = ClientRpcClient<Channel>;
+
+/// How a read is served.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReadConsistency {
+    /// by the leader, after the writes committed before the read
+    #[default]
+    Linearizable,
+    /// by a follower from its store, which may be behind the leader
+    Stale,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ReadOptions {
+    pub consistency: ReadConsistency,
+    /// the keys as they were after the write of this revision
+    pub revision: Option<u64>,
+    /// for the read and its retries, the client's timeout if None
+    pub timeout: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WriteOptions {
+    /// of a put, kept for as long as the lease lives
+    pub lease: Option<u64>,
+    /// for the write and its retries, the client's timeout if None
+    pub timeout: Option<Duration>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyValue {
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+    pub version: u64,
+    /// of the write that gave the key its value
+    pub revision: u64,
+}
+
+pub struct KvClientBuilder {
+    endpoints: Vec<String>,
+    retries: usize,
+    backoff: Duration,
+    max_backoff: Duration,
+    timeout: Duration,
+    connect_timeout: Duration,
+    attempt_timeout: Duration,
+}
+
+impl KvClientBuilder {
+    /// `endpoints` are the client addresses of the nodes, with or without
+    /// the http:// scheme.
+    pub fn new(endpoints: Vec<String>) -> Self {
+        Self {
+            endpoints,
+            retries: 5,
+            backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+            timeout: Duration::from_secs(10),
+            connect_timeout: Duration::from_secs(1),
+            attempt_timeout: Duration::from_secs(3),
+        }
+    }
+
+    /// Attempts after the first one of a request that failed for a reason
+    /// that may pass, e.g. a node down or an election.
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// The wait before the first retry, doubled for each next one up to `max`.
+    pub fn backoff(mut self, first: Duration, max: Duration) -> Self {
+        self.backoff = first;
+        self.max_backoff = max;
+        self
+    }
+
+    /// For a request and its retries, unless it sets its own.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// For connecting to a node; one that does not answer is given up on
+    /// for the next, instead of holding the request up to its timeout.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// For each attempt of a request on a node, retried on another one once
+    /// it passes.
+    pub fn attempt_timeout(mut self, timeout: Duration) -> Self {
+        self.attempt_timeout = timeout;
+        self
+    }
+
+    /// The nodes are connected to as they are first called.
+    pub fn build(self) -> Result<KvClient> {
+        let mut nodes = vec![];
+        for addr in self.endpoints {
+            let url = match addr.contains("://") {
+                true => addr.clone(),
+                false => format!("http://{}", addr),
+            };
+            let channel = Endpoint::from_shared(url)?
+                .connect_timeout(self.connect_timeout)
+                .timeout(self.attempt_timeout)
+                .connect_lazy()?;
+            nodes.push(Node {
+                addr,
+                rpc: ClientRpcClient::new(channel),
+                id: Mutex::new(None),
+            });
+        }
+        if nodes.is_empty() {
+            return Err(anyhow!("no endpoints"));
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        Ok(KvClient {
+            inner: Arc::new(Inner {
+                nodes,
+                leader: Mutex::new(None),
+                next_follower: AtomicUsize::new(0),
+                sessions: Mutex::new(vec![]),
+                next_client_id: AtomicU64::new(now.as_nanos() as u64),
+                retries: self.retries,
+                backoff: self.backoff,
+                max_backoff: self.max_backoff,
+                timeout: self.timeout,
+            }),
+        })
+    }
+}
+
+struct Node {
+    addr: String,
+    rpc: KvRpcClient,
+    // learned from its status
+    id: Mutex<Option<u64>>,
+}
+
+struct Inner {
+    nodes: Vec<Node>,
+    // the node last known to lead
+    leader: Mutex<Option<usize>>,
+    next_follower: AtomicUsize,
+    // the sessions of no write in flight, as a session has one at a time
+    sessions: Mutex<Vec<ClientSession>>,
+    next_client_id: AtomicU64,
+    retries: usize,
+    backoff: Duration,
+    max_backoff: Duration,
+    timeout: Duration,
+}
+
+// which node a request goes to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Target {
+    Leader,
+    Follower,
+}
+
+// how a failed attempt is dealt with
+enum Failure {
+    // to the leader of this id, or of none known
+    Redirect(Option<u64>),
+    // the request may go through later, or on another node
+    Transient,
+    Fatal,
+}
+
+fn failure(status: &Status) -> Failure {
+    if status.code() == Code::FailedPrecondition {
+        if let Some(leader) = status.metadata().get(LEADER_METADATA) {
+            let leader = leader.to_str().ok().and_then(|id| id.parse().ok());
+            return Failure::Redirect(leader);
+        }
+    }
+    match status.code() {
+        // transport errors are unknown, the server sends no Unknown of its own
+        Code::Unavailable | Code::Unknown | Code::Cancelled | Code::DeadlineExceeded => {
+            Failure::Transient
+        }
+        _ => Failure::Fatal,
+    }
+}
+
+/// A client of the nodes of a cluster, cheap to clone.
+#[derive(Clone)]
+pub struct KvClient {
+    inner: Arc<Inner>,
+}
+
+impl KvClient {
+    pub async fn get(&self, key: &[u8], opts: &ReadOptions) -> Result<Option<KeyValue>> {
+        let req = ReadRpcReq {
+            key: Some(read_rpc_req::Key::RawKey(key.to_vec())),
+            consistency: consistency(opts.consistency),
+            revision: opts.revision.unwrap_or(0),
+        };
+        let target = target(opts.consistency);
+        let rsp = self
+            .call(target, opts.timeout, true, move |mut rpc| {
+                let req = req.clone();
+                async move { Ok(rpc.read(req).await?.into_inner()) }
+            })
+            .await?;
+        Ok(match rsp.found {
+            true => Some(KeyValue {
+                key: key.to_vec(),
+                value: rsp.raw_data,
+                version: rsp.version,
+                revision: rsp.revision,
+            }),
+            false => None,
+        })
+    }
+
+    /// The keys that start with `prefix` and their values, in byte order.
+    pub async fn scan(&self, prefix: &[u8], opts: &ReadOptions) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let req = ScanRpcReq {
+            prefix: prefix.to_vec(),
+            consistency: consistency(opts.consistency),
+            revision: opts.revision.unwrap_or(0),
+            ..Default::default()
+        };
+        let target = target(opts.consistency);
+        self.call(target, opts.timeout, true, move |mut rpc| {
+            let req = req.clone();
+            async move {
+                let mut pairs = rpc.scan(req).await?.into_inner();
+                let mut scanned = vec![];
+                while let Some(pair) = pairs.message().await? {
+                    scanned.push((pair.key, pair.value));
+                }
+                Ok(scanned)
+            }
+        })
+        .await
+    }
+
+    /// The value replaced, if any.
+    pub async fn put(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        opts: &WriteOptions,
+    ) -> Result<Option<Vec<u8>>> {
+        let lease = opts.lease.unwrap_or(0);
+        let req = WriteRpcReq {
+            kind: ReqKind::Insert as i32,
+            target: Some(write_rpc_req::Target::RawKey(key.to_vec())),
+            value: Some(write_rpc_req::Value::RawData(value.to_vec())),
+            lease,
+            ..Default::default()
+        };
+        let rsp = self.write(req, opts.timeout).await?;
+        if !rsp.succeeded {
+            return Err(anyhow!("lease {} is gone", lease));
+        }
+        Ok(prev(rsp))
+    }
+
+    /// The value deleted, if any.
+    pub async fn delete(&self, key: &[u8], opts: &WriteOptions) -> Result<Option<Vec<u8>>> {
+        let req = WriteRpcReq {
+            kind: ReqKind::Remove as i32,
+            target: Some(write_rpc_req::Target::RawKey(key.to_vec())),
+            ..Default::default()
+        };
+        Ok(prev(self.write(req, opts.timeout).await?))
+    }
+
+    /// Runs `req`, in a session of the client's whatever its client_id and seq.
+    pub async fn txn(&self, req: TxnRpcReq, opts: &WriteOptions) -> Result<TxnRpcRsp> {
+        let session = self.session();
+        let req = TxnRpcReq {
+            client_id: session.client_id,
+            seq: session.seq,
+            ..req
+        };
+        let rsp = self
+            .call(Target::Leader, opts.timeout, true, move |mut rpc| {
+                let req = req.clone();
+                async move { Ok(rpc.txn(req).await?.into_inner()) }
+            })
+            .await;
+        self.release(session);
+        rsp
+    }
+
+    /// The changes of `key`, or of the keys under it, from `start_revision`
+    /// on, or from now with None. A follower serves it.
+    pub async fn watch(
+        &self,
+        key: &[u8],
+        prefix: bool,
+        start_revision: Option<u64>,
+    ) -> Result<Streaming<WatchRpcRsp>> {
+        let req = WatchRpcReq {
+            key: key.to_vec(),
+            prefix,
+            start_revision: start_revision.unwrap_or(0),
+        };
+        self.call(Target::Follower, None, true, move |mut rpc| {
+            let req = req.clone();
+            async move { Ok(rpc.watch(req).await?.into_inner()) }
+        })
+        .await
+    }
+
+    /// The id of a new lease of `ttl` seconds. A grant is not retried, a
+    /// lease granted but not answered would be left to expire.
+    pub async fn lease_grant(&self, ttl: u64) -> Result<u64> {
+        let rsp = self
+            .call(Target::Leader, None, false, move |mut rpc| async move {
+                Ok(rpc.lease_grant(LeaseGrantReq { ttl }).await?.into_inner())
+            })
+            .await?;
+        Ok(rsp.id)
+    }
+
+    /// Whether the lease was still alive.
+    pub async fn lease_keep_alive(&self, id: u64) -> Result<bool> {
+        let rsp = self
+            .call(Target::Leader, None, true, move |mut rpc| async move {
+                Ok(rpc.lease_keep_alive(LeaseReq { id }).await?.into_inner())
+            })
+            .await?;
+        Ok(rsp.found)
+    }
+
+    /// Whether the lease was still alive; its keys are deleted.
+    pub async fn lease_revoke(&self, id: u64) -> Result<bool> {
+        let rsp = self
+            .call(Target::Leader, None, true, move |mut rpc| async move {
+                Ok(rpc.lease_revoke(LeaseReq { id }).await?.into_inner())
+            })
+            .await?;
+        Ok(rsp.found)
+    }
+
+    /// The endpoint of the leader, found if not known.
+    pub async fn leader(&self) -> Result<String> {
+        let leader = self.pick(Target::Leader).await?;
+        Ok(self.inner.nodes[leader].addr.clone())
+    }
+
+    async fn write(&self, req: WriteRpcReq, deadline: Option<Duration>) -> Result<WriteRpcRsp> {
+        let session = self.session();
+        let req = WriteRpcReq {
+            client_id: session.client_id,
+            seq: session.seq,
+            ..req
+        };
+        let rsp = self
+            .call(Target::Leader, deadline, true, move |mut rpc| {
+                let req = req.clone();
+                async move { Ok(rpc.write(req).await?.into_inner()) }
+            })
+            .await;
+        self.release(session);
+        rsp
+    }
+
+    // the next seq of an idle session, or of a new one
+    fn session(&self) -> ClientSession {
+        let idle = self.inner.sessions.lock().unwrap().pop();
+        let mut session = idle.unwrap_or_else(|| ClientSession {
+            client_id: self.inner.next_client_id.fetch_add(1, Ordering::Relaxed),
+            seq: 0,
+        });
+        session.seq += 1;
+        session
+    }
+
+    fn release(&self, session: ClientSession) {
+        self.inner.sessions.lock().unwrap().push(session);
+    }
+
+    // sends the request by `attempt` to the target, retrying it on another
+    // node or later if it is `idempotent`; redirects are always followed, as
+    // the node redirecting did not apply the request
+    async fn call<T, F, Fut>(
+        &self,
+        target: Target,
+        deadline: Option<Duration>,
+        idempotent: bool,
+        mut attempt: F,
+    ) -> Result<T>
+    where
+        F: FnMut(KvRpcClient) -> Fut,
+        Fut: Future<Output = Result<T, Status>>,
+    {
+        let deadline = deadline.unwrap_or(self.inner.timeout);
+        let calls = async {
+            let mut backoff = self.inner.backoff;
+            let mut attempts = 0;
+            loop {
+                let status = match self.pick(target).await {
+                    Ok(node) => match attempt(self.inner.nodes[node].rpc.clone()).await {
+                        Ok(rsp) => return Ok(rsp),
+                        Err(status) => {
+                            debug!("{} failed: {}", self.inner.nodes[node].addr, status);
+                            status
+                        }
+                    },
+                    Err(status) => status,
+                };
+                attempts += 1;
+                if attempts > self.inner.retries {
+                    return Err(status.into());
+                }
+                match failure(&status) {
+                    Failure::Redirect(Some(leader)) if self.follow(leader).is_some() => continue,
+                    Failure::Redirect(_) => self.forget_leader(),
+                    Failure::Transient if idempotent => {
+                        if target == Target::Leader {
+                            self.forget_leader();
+                        }
+                    }
+                    _ => return Err(status.into()),
+                }
+                sleep(backoff).await;
+                backoff = min(backoff * 2, self.inner.max_backoff);
+            }
+        };
+        match timeout(deadline, calls).await {
+            Ok(rsp) => rsp,
+            Err(_) => Err(anyhow!("deadline of {:?} exceeded", deadline)),
+        }
+    }
+
+    async fn pick(&self, target: Target) -> Result<usize, Status> {
+        let leader = *self.inner.leader.lock().unwrap();
+        let nodes = self.inner.nodes.len();
+        match (target, leader) {
+            (Target::Leader, Some(leader)) => Ok(leader),
+            (Target::Leader, None) => self.discover_leader().await,
+            // the leader only if it is the only node
+            (Target::Follower, leader) => {
+                let next = self.inner.next_follower.fetch_add(1, Ordering::Relaxed);
+                match leader {
+                    Some(leader) if nodes > 1 => Ok((leader + 1 + next % (nodes - 1)) % nodes),
+                    _ => Ok(next % nodes),
+                }
+            }
+        }
+    }
+
+    // asks the nodes until one says it leads, or names a leader known
+    async fn discover_leader(&self) -> Result<usize, Status> {
+        for (i, node) in self.inner.nodes.iter().enumerate() {
+            let status = match node.rpc.clone().status(StatusRpcReq {}).await {
+                Ok(status) => status.into_inner(),
+                Err(err) => {
+                    warn!("{} has no status: {}", node.addr, err);
+                    continue;
+                }
+            };
+            *node.id.lock().unwrap() = Some(status.id);
+            if status.leader == status.id {
+                *self.inner.leader.lock().unwrap() = Some(i);
+                return Ok(i);
+            }
+            if let Some(leader) = self.follow(status.leader) {
+                return Ok(leader);
+            }
+        }
+        Err(Status::unavailable("no leader among the endpoints"))
+    }
+
+    // the endpoint of the node of id `leader`, if its id was learned
+    fn follow(&self, leader: u64) -> Option<usize> {
+        let found = self
+            .inner
+            .nodes
+            .iter()
+            .position(|node| *node.id.lock().unwrap() == Some(leader));
+        *self.inner.leader.lock().unwrap() = found;
+        found
+    }
+
+    fn forget_leader(&self) {
+        *self.inner.leader.lock().unwrap() = None;
+    }
+}
+
+fn consistency(consistency: ReadConsistency) -> i32 {
+    match consistency {
+        ReadConsistency::Linearizable => Consistency::ReadIndex as i32,
+        ReadConsistency::Stale => Consistency::Stale as i32,
+    }
+}
+
+fn target(consistency: ReadConsistency) -> Target {
+    match consistency {
+        ReadConsistency::Linearizable => Target::Leader,
+        ReadConsistency::Stale => Target::Follower,
+    }
+}
+
+fn prev(rsp: WriteRpcRsp) -> Option<Vec<u8>> {
+    match rsp.found {
+        true => Some(rsp.raw_prev),
+        false => None,
+    }
+}
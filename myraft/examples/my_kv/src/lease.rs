@@ -0,0 +1,27 @@
+//! Leases: the keys put with one are deleted when it is revoked, or when it
+//! is not kept alive for its ttl.
+//!
+//! Every node times the leases from the grants and keep alives it applies,
+//! but only the leader ends them, through a write of the log, so that all the
+//! nodes delete the same keys at the same revision.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+// the leases are kept in the meta tree under the prefix and their id
+pub(crate) const LEASE_PREFIX: &[u8] = b"lease/";
+
+pub(crate) fn lease_key(id: u64) -> Vec<u8> {
+    [LEASE_PREFIX, &id.to_be_bytes()].concat()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct Lease {
+    /// seconds
+    pub(crate) ttl: u64,
+    /// the revision of the grant or of the last keep alive
+    pub(crate) renewed: u64,
+    /// the keys put with the lease, and the revision of the put; a key
+    /// written since is no longer the lease's
+    pub(crate) keys: BTreeMap<Vec<u8>, u64>,
+}
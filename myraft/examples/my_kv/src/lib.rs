@@ -0,0 +1,11 @@
+#[allow(non_camel_case_types)]
+pub mod clientpb {
+    tonic::include_proto!("clientpb");
+}
+mod history;
+pub mod kv_app;
+pub mod kv_client;
+mod lease;
+pub mod recipes;
+pub mod service;
+pub mod watch;
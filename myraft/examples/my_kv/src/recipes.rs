@@ -0,0 +1,540 @@
+//! Locks and leader election for the clients of my_kv, built on its leases,
+//! transactions and watches.
+//!
+//! A holder puts its key with the lease of its `Session`: if the holder dies,
+//! the lease is not kept alive and the leader deletes the key. The revision a
+//! lock or leadership was taken at grows with every taking, and serves as a
+//! fencing token for the resources it guards. The writes go to the leader.
+
+use crate::clientpb::client_rpc_client::ClientRpcClient;
+use crate::clientpb::{txn_guard, txn_rpc_op, watch_event};
+use crate::clientpb::{
+    Consistency, LeaseGrantReq, LeaseReq, ReadRpcReq, ScanRpcReq, TxnGuard, TxnRpcOp, TxnRpcReq,
+    TxnRpcRsp, WatchRpcReq, WatchRpcRsp,
+};
+use crate::kv_app::{decode, encode};
+use anyhow::{anyhow, Result};
+use std::collections::HashSet;
+use std::time::Duration;
+use tokio::spawn;
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+use tonic::transport::Channel;
+use tonic::Streaming;
+
+pub type KvRpcClient = ClientRpcClient<Channel>;
+
+/// A lease kept alive in the background for as long as the session lives.
+pub struct Session {
+    client: KvRpcClient,
+    lease: u64,
+    keep_alive: JoinHandle<()>,
+}
+
+impl Session {
+    /// Grants a lease of `ttl` seconds, kept alive every third of it.
+    pub async fn new(mut client: KvRpcClient, ttl: u64) -> Result<Self> {
+        let lease = client.lease_grant(LeaseGrantReq { ttl }).await?;
+        let lease = lease.into_inner().id;
+        let keep_alive = spawn(keep_alive(client.clone(), lease, ttl));
+        Ok(Self {
+            client,
+            lease,
+            keep_alive,
+        })
+    }
+
+    pub fn lease(&self) -> u64 {
+        self.lease
+    }
+
+    /// Revokes the lease, which frees at once what the session holds.
+    pub async fn close(mut self) -> Result<()> {
+        self.keep_alive.abort();
+        let id = self.lease;
+        self.client.lease_revoke(LeaseReq { id }).await?;
+        Ok(())
+    }
+}
+
+impl Drop for Session {
+    // what the session holds is freed once the lease expires
+    fn drop(&mut self) {
+        self.keep_alive.abort();
+    }
+}
+
+async fn keep_alive(mut client: KvRpcClient, id: u64, ttl: u64) {
+    let every = Duration::from_secs(ttl) / 3;
+    loop {
+        sleep(every).await;
+        match client.lease_keep_alive(LeaseReq { id }).await {
+            Ok(rsp) if !rsp.get_ref().found => {
+                log::warn!("lease {} of the session is gone", id);
+                return;
+            }
+            Ok(_) => {}
+            // the next one may get through
+            Err(err) => log::warn!("keep alive of lease {} error: {}", id, err),
+        }
+    }
+}
+
+fn guard(key: &[u8], kind: txn_guard::Kind, value: Vec<u8>) -> TxnGuard {
+    TxnGuard {
+        kind: kind as i32,
+        key: key.to_vec(),
+        value,
+        version: 0,
+    }
+}
+
+// the key is absent: at version 0
+fn absent(key: &[u8]) -> TxnGuard {
+    guard(key, txn_guard::Kind::VersionEquals, vec![])
+}
+
+fn op(kind: txn_rpc_op::Kind, key: &[u8], value: Vec<u8>, lease: u64) -> TxnRpcOp {
+    TxnRpcOp {
+        kind: kind as i32,
+        key: key.to_vec(),
+        value,
+        lease,
+    }
+}
+
+async fn txn(
+    client: &mut KvRpcClient,
+    guards: Vec<TxnGuard>,
+    success: Vec<TxnRpcOp>,
+    failure: Vec<TxnRpcOp>,
+) -> Result<TxnRpcRsp> {
+    let req = TxnRpcReq {
+        guards,
+        success,
+        failure,
+        ..Default::default()
+    };
+    Ok(client.txn(req).await?.into_inner())
+}
+
+// what putting a key if absent found
+enum PutIfAbsent {
+    // the revision of the put
+    Taken(u64),
+    // the value of the key, there at `revision`
+    Held { value: Vec<u8>, revision: u64 },
+}
+
+// puts `value` at `key` with the lease if the key is absent
+async fn put_if_absent(
+    client: &mut KvRpcClient,
+    key: &[u8],
+    value: Vec<u8>,
+    lease: u64,
+) -> Result<PutIfAbsent> {
+    let put = op(txn_rpc_op::Kind::Put, key, value, lease);
+    let get = op(txn_rpc_op::Kind::Get, key, vec![], 0);
+    let rsp = txn(client, vec![absent(key)], vec![put], vec![get]).await?;
+    if rsp.lease_gone {
+        return Err(anyhow!("the lease {} of the session is gone", lease));
+    }
+    if rsp.succeeded {
+        return Ok(PutIfAbsent::Taken(rsp.revision));
+    }
+    let value = rsp
+        .results
+        .into_iter()
+        .next()
+        .map(|get| get.value)
+        .unwrap_or_default();
+    Ok(PutIfAbsent::Held {
+        value,
+        revision: rsp.revision,
+    })
+}
+
+// a lock is not reentrant: waiting for its own key, a session would wait forever
+fn held_already() -> anyhow::Error {
+    anyhow!("the session holds the lock already")
+}
+
+// deletes `key` if it still has `value`, so only its holder frees it
+async fn delete_if(client: &mut KvRpcClient, key: &[u8], value: Vec<u8>) -> Result<bool> {
+    let guards = vec![guard(key, txn_guard::Kind::ValueEquals, value)];
+    let delete = op(txn_rpc_op::Kind::Delete, key, vec![], 0);
+    Ok(txn(client, guards, vec![delete], vec![]).await?.succeeded)
+}
+
+async fn watch(
+    client: &mut KvRpcClient,
+    key: &[u8],
+    prefix: bool,
+    start_revision: u64,
+) -> Result<Streaming<WatchRpcRsp>> {
+    let req = WatchRpcReq {
+        key: key.to_vec(),
+        prefix,
+        start_revision,
+    };
+    Ok(client.watch(req).await?.into_inner())
+}
+
+// waits for `key` to be deleted after `revision`, at which it was there
+async fn wait_deleted(client: &mut KvRpcClient, key: &[u8], revision: u64) -> Result<()> {
+    let mut events = watch(client, key, false, revision + 1).await?;
+    while let Some(rsp) = events.message().await? {
+        let deleted = rsp.events.iter().any(|event| {
+            event.kind == watch_event::Kind::Delete as i32 && event.key.as_slice() == key
+        });
+        if deleted {
+            return Ok(());
+        }
+    }
+    Err(anyhow!("the watch of the lock ended"))
+}
+
+// takes `key` with the session's lease, waiting for the holders before;
+// returns the revision it was taken at. Fails if the session holds it.
+async fn acquire(client: &mut KvRpcClient, key: &[u8], session: &Session) -> Result<u64> {
+    let holder = encode(&session.lease)?;
+    loop {
+        match put_if_absent(client, key, holder.clone(), session.lease).await? {
+            PutIfAbsent::Taken(revision) => return Ok(revision),
+            PutIfAbsent::Held { value, .. } if value == holder => return Err(held_already()),
+            PutIfAbsent::Held { revision, .. } => wait_deleted(client, key, revision).await?,
+        }
+    }
+}
+
+/// A lock held by one session at a time.
+pub struct Mutex {
+    client: KvRpcClient,
+    key: Vec<u8>,
+}
+
+impl Mutex {
+    /// The lock is the key `name`.
+    pub fn new(client: KvRpcClient, name: &[u8]) -> Self {
+        let key = name.to_vec();
+        Self { client, key }
+    }
+
+    /// Waits for the lock, held until unlocked or the session ends. Fails if
+    /// the session holds it already.
+    pub async fn lock(&self, session: &Session) -> Result<LockGuard> {
+        let mut client = self.client.clone();
+        let revision = acquire(&mut client, &self.key, session).await?;
+        LockGuard::new(client, &[&self.key], session, revision)
+    }
+
+    /// The lock if it is free; fails if the session holds it already.
+    pub async fn try_lock(&self, session: &Session) -> Result<Option<LockGuard>> {
+        let mut client = self.client.clone();
+        let holder = encode(&session.lease)?;
+        match put_if_absent(&mut client, &self.key, holder.clone(), session.lease).await? {
+            PutIfAbsent::Taken(revision) => {
+                let guard = LockGuard::new(client, &[&self.key], session, revision)?;
+                Ok(Some(guard))
+            }
+            PutIfAbsent::Held { value, .. } if value == holder => Err(held_already()),
+            PutIfAbsent::Held { .. } => Ok(None),
+        }
+    }
+}
+
+/// A lock held, by a `Mutex` or a `RwLock`.
+pub struct LockGuard {
+    client: KvRpcClient,
+    keys: Vec<Vec<u8>>,
+    holder: Vec<u8>,
+    revision: u64,
+}
+
+impl LockGuard {
+    fn new(client: KvRpcClient, keys: &[&[u8]], session: &Session, revision: u64) -> Result<Self> {
+        Ok(Self {
+            client,
+            keys: keys.iter().map(|key| key.to_vec()).collect(),
+            holder: encode(&session.lease)?,
+            revision,
+        })
+    }
+
+    /// The revision the lock was taken at, greater than that of any holder
+    /// before; the resources behind the lock can turn down older ones.
+    pub fn fencing_token(&self) -> u64 {
+        self.revision
+    }
+
+    /// Frees the lock; false if the session lost it meanwhile.
+    pub async fn unlock(mut self) -> Result<bool> {
+        let mut held = true;
+        for key in &self.keys {
+            held &= delete_if(&mut self.client, key, self.holder.clone()).await?;
+        }
+        Ok(held)
+    }
+}
+
+/// A lock held by one writer, or by readers, at a time. A writer waiting
+/// keeps new readers out. A session holds one read lock of a `RwLock` at most.
+pub struct RwLock {
+    client: KvRpcClient,
+    writer: Vec<u8>,
+    readers: Vec<u8>,
+}
+
+impl RwLock {
+    /// The lock is the keys under `name`.
+    pub fn new(client: KvRpcClient, name: &[u8]) -> Self {
+        Self {
+            client,
+            writer: [name, b"/w"].concat(),
+            readers: [name, b"/r/"].concat(),
+        }
+    }
+
+    fn reader(&self, session: &Session) -> Vec<u8> {
+        [&self.readers[..], &session.lease.to_be_bytes()].concat()
+    }
+
+    /// Waits for no writer to hold or wait for the lock.
+    pub async fn read(&self, session: &Session) -> Result<LockGuard> {
+        let mut client = self.client.clone();
+        let reader = self.reader(session);
+        let holder = encode(&session.lease)?;
+        loop {
+            let put = op(
+                txn_rpc_op::Kind::Put,
+                &reader,
+                holder.clone(),
+                session.lease,
+            );
+            let get = op(txn_rpc_op::Kind::Get, &self.writer, vec![], 0);
+            let guards = vec![absent(&self.writer)];
+            let rsp = txn(&mut client, guards, vec![put], vec![get]).await?;
+            if rsp.lease_gone {
+                return Err(anyhow!(
+                    "the lease {} of the session is gone",
+                    session.lease
+                ));
+            }
+            if rsp.succeeded {
+                return LockGuard::new(client, &[&reader], session, rsp.revision);
+            }
+            wait_deleted(&mut client, &self.writer, rsp.revision).await?;
+        }
+    }
+
+    /// Waits for the writer before, then for the readers to go. Fails if the
+    /// session holds the lock already, to read or to write.
+    pub async fn write(&self, session: &Session) -> Result<LockGuard> {
+        let mut client = self.client.clone();
+        let revision = acquire(&mut client, &self.writer, session).await?;
+        let guard = LockGuard::new(client.clone(), &[&self.writer], session, revision)?;
+        match self.wait_readers(&mut client, session, revision).await {
+            Ok(()) => Ok(guard),
+            Err(err) => {
+                // else the readers wait for the writer until its lease expires
+                if let Err(unlock) = guard.unlock().await {
+                    log::warn!(
+                        "unlock of the writer of a failed write lock error: {}",
+                        unlock
+                    );
+                }
+                Err(err)
+            }
+        }
+    }
+
+    // no reader comes in after the writer taken at `revision`, the ones of
+    // then are waited for
+    async fn wait_readers(
+        &self,
+        client: &mut KvRpcClient,
+        session: &Session,
+        revision: u64,
+    ) -> Result<()> {
+        let req = ScanRpcReq {
+            prefix: self.readers.clone(),
+            revision,
+            ..Default::default()
+        };
+        let mut pairs = client.scan(req).await?.into_inner();
+        let mut readers = HashSet::new();
+        while let Some(pair) = pairs.message().await? {
+            readers.insert(pair.key);
+        }
+        if readers.contains(&self.reader(session)) {
+            return Err(held_already());
+        }
+        if readers.is_empty() {
+            return Ok(());
+        }
+        let mut events = watch(client, &self.readers, true, revision + 1).await?;
+        while let Some(rsp) = events.message().await? {
+            for event in rsp.events {
+                if event.kind == watch_event::Kind::Delete as i32 {
+                    readers.remove(&event.key);
+                }
+            }
+            if readers.is_empty() {
+                return Ok(());
+            }
+        }
+        Err(anyhow!("the watch of the readers ended"))
+    }
+}
+
+/// An election of one leader among the sessions campaigning.
+pub struct Election {
+    client: KvRpcClient,
+    key: Vec<u8>,
+}
+
+impl Election {
+    /// The leader is the one holding the key `name`.
+    pub fn new(client: KvRpcClient, name: &[u8]) -> Self {
+        let key = name.to_vec();
+        Self { client, key }
+    }
+
+    /// Waits to be the leader, and proclaims `value` to the observers.
+    pub async fn campaign(&self, session: &Session, value: Vec<u8>) -> Result<Leadership> {
+        let mut client = self.client.clone();
+        let proclaimed = encode(&(session.lease, value))?;
+        loop {
+            let taken = put_if_absent(&mut client, &self.key, proclaimed.clone(), session.lease);
+            match taken.await? {
+                PutIfAbsent::Taken(revision) => {
+                    return Ok(Leadership {
+                        client,
+                        key: self.key.clone(),
+                        lease: session.lease,
+                        proclaimed,
+                        revision,
+                    })
+                }
+                PutIfAbsent::Held { revision, .. } => {
+                    wait_deleted(&mut client, &self.key, revision).await?
+                }
+            }
+        }
+    }
+
+    /// The value the leader proclaimed, None without a leader.
+    pub async fn leader(&self) -> Result<Option<Vec<u8>>> {
+        leader(&mut self.client.clone(), &self.key).await
+    }
+
+    /// Follows the leaders, starting from the current one.
+    pub async fn observe(&self) -> Result<Observer> {
+        let mut client = self.client.clone();
+        // watched before it is read, no change between is missed
+        let events = watch(&mut client, &self.key, false, 0).await?;
+        let current = leader(&mut client, &self.key).await?;
+        Ok(Observer {
+            events,
+            next: Some(current),
+            last: None,
+        })
+    }
+}
+
+async fn leader(client: &mut KvRpcClient, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    let req = ReadRpcReq {
+        key: Some(crate::clientpb::read_rpc_req::Key::RawKey(key.to_vec())),
+        consistency: Consistency::ReadIndex as i32,
+        revision: 0,
+    };
+    let rsp = client.read(req).await?.into_inner();
+    match rsp.found {
+        true => Ok(Some(proclaimed(&rsp.raw_data)?)),
+        false => Ok(None),
+    }
+}
+
+// the value a leader proclaimed, kept with its lease
+fn proclaimed(data: &[u8]) -> Result<Vec<u8>> {
+    let (_, value): (u64, Vec<u8>) = decode(data)?;
+    Ok(value)
+}
+
+/// The leadership of a session, until it resigns or the session ends.
+pub struct Leadership {
+    client: KvRpcClient,
+    key: Vec<u8>,
+    lease: u64,
+    proclaimed: Vec<u8>,
+    revision: u64,
+}
+
+impl Leadership {
+    /// The revision the leadership was won at, as `LockGuard::fencing_token`.
+    pub fn fencing_token(&self) -> u64 {
+        self.revision
+    }
+
+    /// Proclaims a new value; fails if the leadership was lost.
+    pub async fn proclaim(&mut self, value: Vec<u8>) -> Result<()> {
+        let proclaimed = encode(&(self.lease, value))?;
+        let guards = vec![guard(
+            &self.key,
+            txn_guard::Kind::ValueEquals,
+            self.proclaimed.clone(),
+        )];
+        let put = op(
+            txn_rpc_op::Kind::Put,
+            &self.key,
+            proclaimed.clone(),
+            self.lease,
+        );
+        let rsp = txn(&mut self.client, guards, vec![put], vec![]).await?;
+        if !rsp.succeeded {
+            return Err(anyhow!("the leadership was lost"));
+        }
+        self.proclaimed = proclaimed;
+        Ok(())
+    }
+
+    /// Steps down, for the next candidate to be elected; false if the
+    /// leadership was lost meanwhile.
+    pub async fn resign(mut self) -> Result<bool> {
+        delete_if(&mut self.client, &self.key, self.proclaimed.clone()).await
+    }
+}
+
+/// The leaders of an election, as they change.
+pub struct Observer {
+    events: Streaming<WatchRpcRsp>,
+    // the leader read as the observer started, not yet returned
+    next: Option<Option<Vec<u8>>>,
+    last: Option<Option<Vec<u8>>>,
+}
+
+impl Observer {
+    /// The value of the next leader, or of the new value it proclaimed; None
+    /// while there is no leader.
+    pub async fn next(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            let leader = match self.next.take() {
+                Some(leader) => leader,
+                None => {
+                    let rsp = self.events.message().await?;
+                    let rsp = rsp.ok_or_else(|| anyhow!("the watch of the election ended"))?;
+                    // the changes of a write are of one key here
+                    let event = &rsp.events[rsp.events.len() - 1];
+                    match event.kind == watch_event::Kind::Put as i32 {
+                        true => Some(proclaimed(&event.value)?),
+                        false => None,
+                    }
+                }
+            };
+            if self.last.as_ref() != Some(&leader) {
+                self.last = Some(leader.clone());
+                return Ok(leader);
+            }
+        }
+    }
+}
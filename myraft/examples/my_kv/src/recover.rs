@@ -0,0 +1,41 @@
+use anyhow::Result;
+use my_kv::kv_app::KvApp;
+use myraft::default_store_dir;
+use myraft::raft::MyRaft;
+use std::collections::HashSet;
+use structopt::StructOpt;
+
+/// Forces a new membership on a stopped node after the majority of its
+/// cluster is lost for good. Writes committed only on the lost nodes are gone.
+#[derive(Debug, StructOpt)]
+struct Opt {
+    #[structopt(short, long)]
+    id: u64,
+    /// surviving nodes forming the new cluster, must include --id
+    #[structopt(short, long, use_delimiter = true)]
+    members: Vec<u64>,
+    /// why the recovery was done, kept in the store's recovery audit
+    #[structopt(short, long)]
+    reason: String,
+    /// also drop the lost nodes from this cluster's zookeeper directory
+    #[structopt(short, long)]
+    group_id: Option<u64>,
+    /// raft store of the node, store/node_<id> by default
+    #[structopt(long)]
+    store_dir: Option<String>,
+}
+
+fn main() -> Result<()> {
+    env_logger::init();
+    let opt = Opt::from_args();
+    let members: HashSet<u64> = opt.members.into_iter().collect();
+    let id = opt.id;
+    let dir = opt.store_dir.unwrap_or_else(|| default_store_dir(id));
+    let record = MyRaft::<KvApp>::force_membership(opt.id, &dir, members.clone(), opt.reason)?;
+    println!("{:?}", record);
+    if let Some(group_id) = opt.group_id {
+        let forgotten = MyRaft::<KvApp>::forget_lost_members(group_id, &members)?;
+        println!("forgot lost members {:?}", forgotten);
+    }
+    Ok(())
+}
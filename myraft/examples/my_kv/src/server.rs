@@ -1,115 +1,13 @@
-mod clientpb {
-    tonic::include_proto!("clientpb");
-}
-use anyhow::Result;
-use bincode::{deserialize, serialize};
-use clientpb::client_rpc_server::{ClientRpc, ClientRpcServer};
-use clientpb::{ReadRpcReq, ReadRpcRsp, WriteRpcReq, WriteRpcRsp};
-use log::info;
-use myraft::raft::MyRaft;
-use myraft::{async_trait::async_trait, raft::RaftApp, AppData, AppDataResponse};
-use serde::{Deserialize, Serialize};
-use sled::Db;
-use std::collections::HashMap;
+use anyhow::{anyhow, Result};
+use my_kv::kv_app::KvApp;
+use my_kv::service::{self, expire_leases, MyKvRaft};
+use myraft::default_store_dir;
+use myraft::raft::{MyRaftBuilder, NodeRole, PromotionConfig};
+use std::path::PathBuf;
 use std::sync::Arc;
-use std::u64;
+use std::time::Duration;
 use structopt::StructOpt;
-use tokio::sync::RwLock;
 use tokio::{self, spawn};
-use tonic::Code;
-use tonic::{transport::Server, Request, Response, Status};
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct ReadRequest {
-    pub key: u64,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct ReadResponse {
-    pub data: Option<String>,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub enum WriteRequest {
-    Insert { key: u64, value: String },
-    Remove { key: u64 },
-}
-
-impl AppData for WriteRequest {}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub enum WriteResponse {
-    Insert { prev: Option<String> },
-    Remove { prev: Option<String> },
-}
-
-impl AppDataResponse for WriteResponse {}
-
-pub struct KvApp {
-    db: Db,
-}
-
-impl KvApp {
-    async fn handle_read(&self, req: ReadRequest) -> Result<ReadResponse> {
-        let rsp = self.db.get(&serialize(&req.key)?)?;
-        let rsp = match rsp {
-            Some(rsp) => Some(deserialize(&rsp)?),
-            None => None,
-        };
-        Ok(ReadResponse { data: rsp })
-    }
-}
-
-#[async_trait]
-impl RaftApp for KvApp {
-    async fn handle_write(&mut self, req: WriteRequest) -> Result<WriteResponse> {
-        match req {
-            WriteRequest::Insert { key, value } => {
-                let prev = self.db.insert(serialize(&key)?, serialize(&value)?)?;
-                self.db.flush_async().await?;
-                Ok(WriteResponse::Insert {
-                    prev: match prev {
-                        Some(prev) => Some(deserialize(&prev)?),
-                        None => None,
-                    },
-                })
-            }
-            WriteRequest::Remove { key } => {
-                let prev = self.db.remove(serialize(&key)?)?;
-                self.db.flush_async().await?;
-                Ok(WriteResponse::Remove {
-                    prev: match prev {
-                        Some(prev) => Some(deserialize(&prev)?),
-                        None => None,
-                    },
-                })
-            }
-        }
-    }
-
-    async fn make_snapshot(&self) -> Result<Vec<u8>> {
-        let mut map = HashMap::new();
-        for kv in self.db.into_iter() {
-            let (k, v) = kv?;
-            map.insert(k.to_vec(), v.to_vec());
-        }
-        Ok(serialize(&map)?)
-    }
-
-    async fn handle_snapshot(&self, snap: &Vec<u8>) -> Result<()> {
-        let map: HashMap<Vec<u8>, Vec<u8>> = deserialize(snap)?;
-        for (k, v) in map {
-            self.db.insert(k, v)?;
-        }
-        self.db.flush_async().await?;
-        Ok(())
-    }
-
-    type WriteReq = WriteRequest;
-    type WriteRsp = WriteResponse;
-}
-
-//////////////////////////
 
 #[derive(Debug, StructOpt)]
 struct Opt {
@@ -117,111 +15,94 @@ struct Opt {
     id: u64,
     #[structopt(short, long)]
     raft_addr: String,
+    /// cluster of the node, the one of its store by default; a restored node
+    /// starts a new cluster of its own
     #[structopt(short, long)]
-    group_id: u64,
+    group_id: Option<u64>,
     #[structopt(short, long)]
     client_addr: Option<String>,
+    /// serve the operator RPCs, e.g. the chaos, on this address
+    #[structopt(long)]
+    admin_addr: Option<String>,
+    /// directory the backups asked for on the admin address are written to,
+    /// no backups are taken without it
+    #[structopt(long)]
+    backup_dir: Option<PathBuf>,
     #[structopt(short, long)]
     as_init: bool,
+    /// voter, learner (read replica) or witness (votes and acks writes, keeps no data)
+    #[structopt(long, default_value = "voter")]
+    role: NodeRole,
+    /// seed this new node from a backup, as the only member of a new cluster
+    #[structopt(long)]
+    restore_from: Option<String>,
+    /// serve the prometheus metrics on http://<metrics-addr>/metrics
+    #[structopt(long)]
+    metrics_addr: Option<String>,
+    /// log entries a new member may be behind the leader when it is promoted
+    #[structopt(long)]
+    catch_up_lag: Option<u64>,
+    /// seconds a new member gets to catch up before it is logged as behind
+    #[structopt(long)]
+    catch_up_timeout: Option<u64>,
+    /// earlier values kept per key for reads at a revision
+    #[structopt(long, default_value = "16")]
+    history: usize,
+    /// raft store of the node, store/node_<id> by default
+    #[structopt(long)]
+    store_dir: Option<String>,
 }
 
-type MyKvRaft = MyRaft<KvApp>;
-
-struct MyClientRpc {
-    core: MyKvRaft,
-    storage: Arc<RwLock<KvApp>>,
-}
-
-#[async_trait]
-impl ClientRpc for MyClientRpc {
-    async fn read(&self, request: Request<ReadRpcReq>) -> Result<Response<ReadRpcRsp>, Status> {
-        let req = request.into_inner();
-        let req = ReadRequest { key: req.id };
-        info!("read: {:?}", req);
-        match self.storage.read().await.handle_read(req).await {
-            Ok(rsp) => {
-                let rsp = ReadRpcRsp {
-                    found: rsp.data.is_some(),
-                    data: rsp.data.unwrap_or("".to_string()),
-                };
-                Ok(Response::new(rsp))
-            }
-            Err(_) => Err(Status::new(Code::Unknown, "")),
+#[tokio::main]
+async fn main() -> Result<()> {
+    myraft::telemetry::init("my_kv")?;
+    let opt = Opt::from_args();
+    let kv_path = format!("kv_store/node_{}", opt.id);
+    let kv_app = KvApp::new(sled::open(kv_path)?)?.keep_history(opt.history);
+    let kv_app = Arc::new(kv_app);
+    let id = opt.id;
+    let store_dir = opt.store_dir.unwrap_or_else(|| default_store_dir(id));
+    if let Some(backup) = opt.restore_from {
+        if opt.group_id.is_some() {
+            return Err(anyhow!(
+                "a restored node starts a new cluster, drop --group-id"
+            ));
         }
+        let identity = MyKvRaft::restore(opt.id, &store_dir, backup, &kv_app).await?;
+        println!("restored into the new cluster {}", identity.cluster_id);
     }
-
-    async fn write(&self, request: Request<WriteRpcReq>) -> Result<Response<WriteRpcRsp>, Status> {
-        let req = request.into_inner();
-        let req = if req.kind == 0 {
-            WriteRequest::Insert {
-                key: req.key,
-                value: req.data,
-            }
-        } else {
-            WriteRequest::Remove { key: req.key }
-        };
-        info!("write: {:?}", req);
-        match self.core.client_write(req).await {
-            Ok(rsp) => {
-                let rsp = match rsp {
-                    WriteResponse::Insert { prev } => WriteRpcRsp {
-                        kind: 0,
-                        found: prev.is_some(),
-                        prev: prev.unwrap_or("".to_string()),
-                    },
-                    WriteResponse::Remove { prev } => WriteRpcRsp {
-                        kind: 1,
-                        found: prev.is_some(),
-                        prev: prev.unwrap_or("".to_string()),
-                    },
-                };
-                Ok(Response::new(rsp))
-            }
-            Err(err) => Err(Status::new(
-                Code::Unknown,
-                format!("call core write error: {}", err),
-            )),
-        }
+    let mut promotion = PromotionConfig::default();
+    if let Some(lag) = opt.catch_up_lag {
+        promotion.max_lag = lag;
+    }
+    if let Some(secs) = opt.catch_up_timeout {
+        promotion.timeout = Duration::from_secs(secs);
+    }
+    let mut builder = MyRaftBuilder::new(opt.id, opt.raft_addr, kv_app.clone())
+        .role(opt.role)
+        .store_dir(store_dir)
+        .promotion(promotion);
+    if let Some(group_id) = opt.group_id {
+        builder = builder.cluster_id(group_id);
+    }
+    if let Some(metrics_addr) = opt.metrics_addr {
+        builder = builder.metrics_addr(metrics_addr);
+    }
+    let my_raft = Arc::new(builder.build().await?);
+    my_raft
+        .join_cluster(my_raft.identity().cluster_id, opt.as_init)
+        .await?;
+    spawn(expire_leases(my_raft.clone(), kv_app.clone()));
+    if let Some(admin_addr) = opt.admin_addr {
+        let admin_addr = admin_addr.parse()?;
+        spawn(service::serve_admin(
+            my_raft.clone(),
+            admin_addr,
+            opt.backup_dir,
+        ));
     }
-}
-
-async fn start_client_service(
-    raft: MyKvRaft,
-    sm: Arc<RwLock<KvApp>>,
-    client_addr: String,
-) -> Result<()> {
-    let client_rpc = MyClientRpc {
-        core: raft,
-        storage: sm,
-    };
-    let client_addr = client_addr.parse().unwrap();
-    info!("listenning client addr: {:?}", client_addr);
-    spawn(async move {
-        Server::builder()
-            .add_service(ClientRpcServer::new(client_rpc))
-            .serve(client_addr)
-            .await
-            .unwrap();
-    })
-    .await
-    .unwrap();
-    Ok(())
-}
-
-#[tokio::main]
-async fn main() {
-    env_logger::init();
-    let opt = Opt::from_args();
-    let kv_path = format!("kv_store/node_{}", opt.id.to_string());
-    let kv_app = KvApp {
-        db: sled::open(kv_path).unwrap(),
-    };
-    let kv_app = Arc::new(RwLock::new(kv_app));
-    let my_raft = MyKvRaft::new(opt.id, opt.raft_addr, kv_app.clone()).await;
-    my_raft.join_cluster(opt.group_id, opt.as_init).await;
     if let Some(client_addr) = opt.client_addr {
-        start_client_service(my_raft, kv_app, client_addr)
-            .await
-            .unwrap();
+        service::serve(my_raft, kv_app, client_addr.parse()?).await?;
     }
+    Ok(())
 }
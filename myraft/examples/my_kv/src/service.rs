@@ -0,0 +1,744 @@
+//! The client RPCs of a node, served over gRPC.
+
+use crate::clientpb::admin_rpc_server::{AdminRpc, AdminRpcServer};
+use crate::clientpb::client_rpc_server::{ClientRpc, ClientRpcServer};
+use crate::clientpb::{read_rpc_req, txn_guard, txn_rpc_op, watch_event, write_rpc_req};
+use crate::clientpb::{
+    BackupRpcReq, BackupRpcRsp, ChaosRpcReq, ChaosRpcRsp, CompactRpcReq, CompactRpcRsp,
+    Consistency, LeaseGrantReq, LeaseReq, LeaseRsp, MemberAddReq, MemberRemoveReq, MemberRsp,
+    ReadRpcReq, ReadRpcRsp, ReqKind, ScanRpcReq, ScanRpcRsp, StatusRpcReq, StatusRpcRsp, TxnGuard,
+    TxnOpResult, TxnRpcOp, TxnRpcReq, TxnRpcRsp, WatchEvent, WatchRpcReq, WatchRpcRsp, WriteRpcReq,
+    WriteRpcRsp,
+};
+use crate::kv_app::{
+    decode_string, encode, Condition, Guard, KvApp, ReadRequest, RevisionAhead, RevisionCompacted,
+    ScanRequest, TxnOp, TxnResult, WriteRequest, WriteResponse,
+};
+use crate::watch::{Event, WatchRequest};
+use anyhow::Result;
+use lazy_static::lazy_static;
+use log::{info, warn};
+use myraft::async_trait::async_trait;
+#[cfg(feature = "chaos")]
+use myraft::chaos::ChaosSchedule;
+use myraft::raft::{MyRaft, NodeInfo, NodeRole, NotLeader};
+use myraft::ClientSession;
+use prometheus::{register_histogram_vec, register_int_counter_vec, HistogramVec, IntCounterVec};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs;
+use tokio::sync::mpsc;
+use tokio::task::spawn_blocking;
+use tokio::time::sleep;
+use tokio::{self, spawn};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::metadata::MetadataMap;
+use tonic::Code;
+use tonic::{transport::Server, Request, Response, Status};
+
+lazy_static! {
+    static ref REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "my_kv_client_request_duration_seconds",
+        "Latency of the client requests, by method.",
+        &["method"]
+    )
+    .unwrap();
+    static ref REQUEST_FAILURES: IntCounterVec = register_int_counter_vec!(
+        "my_kv_client_request_failures_total",
+        "Client requests that failed, by method.",
+        &["method"]
+    )
+    .unwrap();
+}
+
+// times a client request, counting it as failed if it errors
+async fn observe<R>(
+    method: &str,
+    call: impl Future<Output = Result<R, Status>>,
+) -> Result<R, Status> {
+    let timer = REQUEST_DURATION.with_label_values(&[method]).start_timer();
+    let rsp = call.await;
+    timer.observe_duration();
+    if rsp.is_err() {
+        REQUEST_FAILURES.with_label_values(&[method]).inc();
+    }
+    rsp
+}
+
+pub type MyKvRaft = MyRaft<KvApp>;
+
+// pairs of a scan read ahead of the client
+const SCAN_BUFFER: usize = 64;
+// writes a watch sends ahead of the client
+const WATCH_BUFFER: usize = 64;
+// how often the leader looks for the leases to end
+const LEASE_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+/// The metadata key of the error of a write sent to a node that does not
+/// lead: the leader's id, empty if the node knows of none.
+pub const LEADER_METADATA: &str = "leader";
+
+struct MyClientRpc {
+    core: Arc<MyKvRaft>,
+    storage: Arc<KvApp>,
+}
+
+impl MyClientRpc {
+    // writes through raft; a client retrying with the same client_id and seq
+    // gets its write applied once, client_id 0 writes without a session
+    async fn submit(
+        &self,
+        client_id: u64,
+        seq: u64,
+        req: WriteRequest,
+    ) -> Result<WriteResponse, Status> {
+        let session = match client_id {
+            0 => None,
+            client_id => Some(ClientSession { client_id, seq }),
+        };
+        info!("write: {:?} in {:?}", req, session);
+        let rsp = match session {
+            Some(session) => self.core.client_write_in_session(session, req).await,
+            None => self.core.client_write(req).await,
+        };
+        rsp.map_err(|err| match err.downcast_ref::<NotLeader>() {
+            Some(not_leader) => redirect(not_leader),
+            // raft stopped, or failed to apply the write; another node may not
+            None => Status::new(Code::Unavailable, format!("call core write error: {}", err)),
+        })
+    }
+
+    async fn lease(&self, method: &str, req: WriteRequest) -> Result<Response<LeaseRsp>, Status> {
+        observe(method, async move {
+            match self.submit(0, 0, req).await? {
+                WriteResponse::Lease { lease, ttl } => Ok(Response::new(LeaseRsp {
+                    id: lease,
+                    found: ttl.is_some(),
+                    ttl: ttl.unwrap_or_default(),
+                })),
+                _ => Err(seq_reused()),
+            }
+        })
+        .await
+    }
+
+    // with ReadIndex, waits for the writes committed before the read
+    async fn ensure_consistency(&self, consistency: i32) -> Result<(), Status> {
+        if consistency == Consistency::ReadIndex as i32 {
+            if let Err(err) = self.core.read_barrier().await {
+                return Err(Status::new(
+                    Code::Unavailable,
+                    format!("call core read index error: {}", err),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ClientRpc for MyClientRpc {
+    type scanStream = ReceiverStream<Result<ScanRpcRsp, Status>>;
+    type watchStream = ReceiverStream<Result<WatchRpcRsp, Status>>;
+
+    async fn read(&self, request: Request<ReadRpcReq>) -> Result<Response<ReadRpcRsp>, Status> {
+        observe("read", async move {
+            let req = request.into_inner();
+            self.ensure_consistency(req.consistency).await?;
+            // typed keys are kept encoded, and proto3 leaves a 0 id out
+            let key = match req.key {
+                Some(read_rpc_req::Key::RawKey(key)) => key,
+                Some(read_rpc_req::Key::Id(id)) => encode(&id).unwrap(),
+                None => encode(&0u64).unwrap(),
+            };
+            let req = ReadRequest {
+                key,
+                revision: Some(req.revision).filter(|revision| *revision > 0),
+            };
+            info!("read: {:?}", req);
+            match self.storage.handle_read(req).await {
+                Ok(rsp) => {
+                    let rsp = ReadRpcRsp {
+                        found: rsp.data.is_some(),
+                        data: rsp.data.as_deref().map(decode_string).unwrap_or_default(),
+                        raw_data: rsp.data.unwrap_or_default(),
+                        version: rsp.version,
+                        revision: rsp.revision,
+                    };
+                    Ok(Response::new(rsp))
+                }
+                Err(err) => Err(read_error("read", err)),
+            }
+        })
+        .await
+    }
+
+    async fn scan(
+        &self,
+        request: Request<ScanRpcReq>,
+    ) -> Result<Response<Self::scanStream>, Status> {
+        observe("scan", async move {
+            let req = request.into_inner();
+            self.ensure_consistency(req.consistency).await?;
+            let non_empty = |bytes: Vec<u8>| Some(bytes).filter(|bytes| !bytes.is_empty());
+            let req = ScanRequest {
+                start: req.start,
+                end: non_empty(req.end),
+                prefix: req.prefix,
+                limit: Some(req.limit as usize).filter(|limit| *limit > 0),
+                reverse: req.reverse,
+                after: non_empty(req.page_token),
+                revision: Some(req.revision).filter(|revision| *revision > 0),
+            };
+            info!("scan: {:?}", req);
+            let limit = req.limit;
+            // a pair past the page tells whether there is a next one
+            let req = ScanRequest {
+                limit: limit.map(|limit| limit + 1),
+                ..req
+            };
+            let pairs = self
+                .storage
+                .scan(&req)
+                .map_err(|err| read_error("scan", err))?;
+            let (sender, receiver) = mpsc::channel(SCAN_BUFFER);
+            spawn(async move {
+                let mut pairs = pairs.peekable();
+                let mut count = 0;
+                while let Some(pair) = pairs.next() {
+                    count += 1;
+                    let last = Some(count) == limit;
+                    let rsp = match pair {
+                        Ok((key, value)) => Ok(ScanRpcRsp {
+                            next_page_token: match last && pairs.peek().is_some() {
+                                true => key.clone(),
+                                false => vec![],
+                            },
+                            key,
+                            value,
+                        }),
+                        Err(err) => Err(read_error("scan", err)),
+                    };
+                    let failed = rsp.is_err();
+                    // the client is gone, or the scan ended with the error or the page
+                    if sender.send(rsp).await.is_err() || failed || last {
+                        break;
+                    }
+                }
+            });
+            Ok(Response::new(ReceiverStream::new(receiver)))
+        })
+        .await
+    }
+
+    async fn write(&self, request: Request<WriteRpcReq>) -> Result<Response<WriteRpcRsp>, Status> {
+        observe("write", async move {
+            let req = request.into_inner();
+            let (client_id, seq, kind) = (req.client_id, req.seq, req.kind);
+            let req = match write_request(req) {
+                Some(req) => req,
+                None => {
+                    return Err(Status::new(
+                        Code::InvalidArgument,
+                        format!("unknown write kind {}", kind),
+                    ))
+                }
+            };
+            let (succeeded, prev, version, revision) = match self
+                .submit(client_id, seq, req)
+                .await?
+            {
+                WriteResponse::Put { prev } | WriteResponse::Delete { prev } => (true, prev, 0, 0),
+                // retries answered from a session of before the byte writes
+                WriteResponse::Insert { prev } | WriteResponse::Remove { prev } => {
+                    (true, prev.map(|p| encode(&p).unwrap()), 0, 0)
+                }
+                WriteResponse::Conditional {
+                    succeeded,
+                    value,
+                    version,
+                    revision,
+                } => (succeeded, value, version, revision),
+                WriteResponse::Txn { .. }
+                | WriteResponse::Compacted { .. }
+                | WriteResponse::Lease { .. } => return Err(seq_reused()),
+            };
+            let rsp = WriteRpcRsp {
+                kind,
+                found: prev.is_some(),
+                prev: prev.as_deref().map(decode_string).unwrap_or_default(),
+                raw_prev: prev.unwrap_or_default(),
+                succeeded,
+                version,
+                revision,
+            };
+            Ok(Response::new(rsp))
+        })
+        .await
+    }
+
+    async fn txn(&self, request: Request<TxnRpcReq>) -> Result<Response<TxnRpcRsp>, Status> {
+        observe("txn", async move {
+            let req = request.into_inner();
+            let (client_id, seq) = (req.client_id, req.seq);
+            let guards = req.guards.into_iter().map(guard).collect::<Option<_>>();
+            let success = req.success.into_iter().map(txn_op).collect::<Option<_>>();
+            let failure = req.failure.into_iter().map(txn_op).collect::<Option<_>>();
+            let req = match (guards, success, failure) {
+                (Some(guards), Some(success), Some(failure)) => WriteRequest::Txn {
+                    guards,
+                    success,
+                    failure,
+                },
+                _ => {
+                    return Err(Status::new(
+                        Code::InvalidArgument,
+                        "unknown guard or op kind",
+                    ))
+                }
+            };
+            match self.submit(client_id, seq, req).await? {
+                WriteResponse::Txn {
+                    succeeded,
+                    results,
+                    revision,
+                    lease_gone,
+                } => Ok(Response::new(TxnRpcRsp {
+                    succeeded,
+                    results: results.into_iter().map(txn_op_result).collect(),
+                    revision,
+                    lease_gone,
+                })),
+                _ => Err(seq_reused()),
+            }
+        })
+        .await
+    }
+
+    async fn compact(
+        &self,
+        request: Request<CompactRpcReq>,
+    ) -> Result<Response<CompactRpcRsp>, Status> {
+        observe("compact", async move {
+            let revision = request.into_inner().revision;
+            match self
+                .submit(0, 0, WriteRequest::Compact { revision })
+                .await?
+            {
+                WriteResponse::Compacted { revision } => {
+                    Ok(Response::new(CompactRpcRsp { revision }))
+                }
+                _ => Err(seq_reused()),
+            }
+        })
+        .await
+    }
+
+    async fn watch(
+        &self,
+        request: Request<WatchRpcReq>,
+    ) -> Result<Response<Self::watchStream>, Status> {
+        let req = request.into_inner();
+        let req = WatchRequest {
+            key: req.key,
+            prefix: req.prefix,
+            start_revision: Some(req.start_revision).filter(|revision| *revision > 0),
+        };
+        info!("watch: {:?}", req);
+        let mut watch = self
+            .storage
+            .watch(req)
+            .map_err(|err| read_error("watch", err))?;
+        let (sender, receiver) = mpsc::channel(WATCH_BUFFER);
+        spawn(async move {
+            loop {
+                let events = tokio::select! {
+                    events = watch.next() => events,
+                    // the client is gone
+                    _ = sender.closed() => break,
+                };
+                let rsp = match events {
+                    Ok(events) => Ok(WatchRpcRsp {
+                        revision: events[0].revision,
+                        events: events.into_iter().map(watch_event).collect(),
+                    }),
+                    Err(err) => Err(Status::new(Code::Aborted, format!("watch error: {}", err))),
+                };
+                let failed = rsp.is_err();
+                if sender.send(rsp).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    async fn lease_grant(
+        &self,
+        request: Request<LeaseGrantReq>,
+    ) -> Result<Response<LeaseRsp>, Status> {
+        let ttl = request.into_inner().ttl;
+        self.lease("lease_grant", WriteRequest::GrantLease { ttl })
+            .await
+    }
+
+    async fn lease_keep_alive(
+        &self,
+        request: Request<LeaseReq>,
+    ) -> Result<Response<LeaseRsp>, Status> {
+        let lease = request.into_inner().id;
+        self.lease("lease_keep_alive", WriteRequest::KeepAlive { lease })
+            .await
+    }
+
+    async fn lease_revoke(&self, request: Request<LeaseReq>) -> Result<Response<LeaseRsp>, Status> {
+        let lease = request.into_inner().id;
+        self.lease("lease_revoke", WriteRequest::RevokeLease { lease })
+            .await
+    }
+
+    async fn status(
+        &self,
+        _request: Request<StatusRpcReq>,
+    ) -> Result<Response<StatusRpcRsp>, Status> {
+        let metrics = self.core.metrics();
+        let mut members: Vec<u64> = metrics.membership_config.members.into_iter().collect();
+        members.sort_unstable();
+        Ok(Response::new(StatusRpcRsp {
+            id: metrics.id,
+            state: format!("{:?}", metrics.state),
+            leader: metrics.current_leader.unwrap_or(0),
+            term: metrics.current_term,
+            last_log_index: metrics.last_log_index,
+            last_applied: metrics.last_applied,
+            members,
+            revision: self.storage.revision().map_err(storage_error)?,
+            compacted: self.storage.compacted().map_err(storage_error)?,
+        }))
+    }
+}
+
+// the write a request asks for, typed keys and values encoded as they are kept;
+// proto3 leaves out a 0 key or an empty string. None for an unknown kind.
+fn write_request(req: WriteRpcReq) -> Option<WriteRequest> {
+    let key = match req.target {
+        Some(write_rpc_req::Target::RawKey(key)) => key,
+        Some(write_rpc_req::Target::Key(key)) => encode(&key).unwrap(),
+        None => encode(&0u64).unwrap(),
+    };
+    let value = match req.value {
+        Some(write_rpc_req::Value::RawData(value)) => value,
+        Some(write_rpc_req::Value::Data(data)) => encode(&data).unwrap(),
+        None => encode(&String::new()).unwrap(),
+    };
+    let expected = match req.expected {
+        Some(write_rpc_req::Expected::RawExpected(expected)) => expected,
+        Some(write_rpc_req::Expected::ExpectedData(data)) => encode(&data).unwrap(),
+        None => encode(&String::new()).unwrap(),
+    };
+    let version = req.version;
+    Some(match ReqKind::from_i32(req.kind)? {
+        ReqKind::Insert if req.lease != 0 => WriteRequest::PutWithLease {
+            key,
+            value,
+            lease: req.lease,
+        },
+        ReqKind::Insert => WriteRequest::Put { key, value },
+        ReqKind::Remove => WriteRequest::Delete { key },
+        ReqKind::CompareAndSwap => WriteRequest::CompareAndSwap {
+            key,
+            expected: Some(expected),
+            new: value,
+        },
+        ReqKind::PutIfAbsent => WriteRequest::PutIfAbsent { key, value },
+        ReqKind::PutIfVersion => WriteRequest::PutIfVersion {
+            key,
+            version,
+            value,
+        },
+        ReqKind::RemoveIfVersion => WriteRequest::DeleteIfVersion { key, version },
+    })
+}
+
+// the write was not applied, the client can send it to the leader
+fn redirect(not_leader: &NotLeader) -> Status {
+    let leader = not_leader
+        .leader
+        .map(|id| id.to_string())
+        .unwrap_or_default();
+    let mut metadata = MetadataMap::new();
+    metadata.insert(LEADER_METADATA, leader.parse().unwrap());
+    Status::with_metadata(Code::FailedPrecondition, not_leader.to_string(), metadata)
+}
+
+// `path` under the backup directory `dir`, none if it is not a file there
+fn backup_path(dir: &Path, path: &str) -> Option<PathBuf> {
+    let relative = Path::new(path);
+    let inside = relative
+        .components()
+        .all(|part| matches!(part, Component::Normal(_) | Component::CurDir));
+    relative.file_name().filter(|_| inside)?;
+    Some(dir.join(relative))
+}
+
+// the session answered with the response of another kind of write
+fn seq_reused() -> Status {
+    Status::new(
+        Code::InvalidArgument,
+        "the session seq was used by another kind of write",
+    )
+}
+
+// reads at a compacted revision or one not written yet are out of range, the
+// client can retry at others; the other errors are of the store. Neither is
+// Unknown, which clients take for a transport error worth retrying.
+fn read_error(method: &str, err: anyhow::Error) -> Status {
+    let out_of_range = err.is::<RevisionCompacted>() || err.is::<RevisionAhead>();
+    let code = match out_of_range {
+        true => Code::OutOfRange,
+        false => Code::Internal,
+    };
+    Status::new(code, format!("{} error: {}", method, err))
+}
+
+// the store of the node failed
+fn storage_error(err: anyhow::Error) -> Status {
+    Status::new(Code::Internal, format!("storage error: {}", err))
+}
+
+fn guard(guard: TxnGuard) -> Option<Guard> {
+    let condition = match txn_guard::Kind::from_i32(guard.kind)? {
+        txn_guard::Kind::Exists => Condition::Exists,
+        txn_guard::Kind::ValueEquals => Condition::Value(Some(guard.value)),
+        txn_guard::Kind::VersionEquals => Condition::Version(guard.version),
+    };
+    Some(Guard {
+        key: guard.key,
+        condition,
+    })
+}
+
+fn txn_op(op: TxnRpcOp) -> Option<TxnOp> {
+    let key = op.key;
+    Some(match txn_rpc_op::Kind::from_i32(op.kind)? {
+        txn_rpc_op::Kind::Get => TxnOp::Get { key },
+        txn_rpc_op::Kind::Put if op.lease != 0 => TxnOp::PutWithLease {
+            key,
+            value: op.value,
+            lease: op.lease,
+        },
+        txn_rpc_op::Kind::Put => TxnOp::Put {
+            key,
+            value: op.value,
+        },
+        txn_rpc_op::Kind::Delete => TxnOp::Delete { key },
+    })
+}
+
+fn txn_op_result(result: TxnResult) -> TxnOpResult {
+    let (kind, value, version) = match result {
+        TxnResult::Get { value, version } => (txn_rpc_op::Kind::Get, value, version),
+        TxnResult::Put { prev } => (txn_rpc_op::Kind::Put, prev, 0),
+        TxnResult::Delete { prev } => (txn_rpc_op::Kind::Delete, prev, 0),
+    };
+    TxnOpResult {
+        kind: kind as i32,
+        found: value.is_some(),
+        value: value.unwrap_or_default(),
+        version,
+    }
+}
+
+fn watch_event(event: Event) -> WatchEvent {
+    let kind = match event.value {
+        Some(_) => watch_event::Kind::Put,
+        None => watch_event::Kind::Delete,
+    };
+    WatchEvent {
+        kind: kind as i32,
+        key: event.key,
+        value: event.value.unwrap_or_default(),
+        version: event.version,
+    }
+}
+
+/// Ends the leases whose ttl passed, through the log, while the node leads.
+pub async fn expire_leases(raft: Arc<MyKvRaft>, sm: Arc<KvApp>) {
+    loop {
+        sleep(LEASE_CHECK_INTERVAL).await;
+        let metrics = raft.metrics();
+        if metrics.current_leader != Some(metrics.id) {
+            continue;
+        }
+        let writes = match sm.expired_leases() {
+            Ok(writes) => writes,
+            Err(err) => {
+                warn!("expired leases error: {}", err);
+                continue;
+            }
+        };
+        for req in writes {
+            info!("lease expired: {:?}", req);
+            if let Err(err) = raft.client_write(req).await {
+                warn!("expire lease error: {}", err);
+            }
+        }
+    }
+}
+
+/// The operator RPCs of a node, kept off the client port.
+pub struct MyAdminRpc {
+    core: Arc<MyKvRaft>,
+    // where the backups go, none taken without it
+    backup_dir: Option<PathBuf>,
+}
+
+#[async_trait]
+impl AdminRpc for MyAdminRpc {
+    #[cfg(feature = "chaos")]
+    async fn chaos(&self, request: Request<ChaosRpcReq>) -> Result<Response<ChaosRpcRsp>, Status> {
+        let req = request.into_inner();
+        let schedule = if req.schedule.is_empty() {
+            None
+        } else {
+            match serde_json::from_str::<ChaosSchedule>(&req.schedule) {
+                Ok(schedule) => Some(schedule),
+                Err(err) => {
+                    return Err(Status::new(
+                        Code::InvalidArgument,
+                        format!("bad chaos schedule: {}", err),
+                    ))
+                }
+            }
+        };
+        self.core.set_chaos(schedule);
+        let schedule = match self.core.chaos() {
+            Some(schedule) => serde_json::to_string(&schedule).unwrap(),
+            None => String::new(),
+        };
+        Ok(Response::new(ChaosRpcRsp { schedule }))
+    }
+
+    #[cfg(not(feature = "chaos"))]
+    async fn chaos(&self, _request: Request<ChaosRpcReq>) -> Result<Response<ChaosRpcRsp>, Status> {
+        Err(Status::new(
+            Code::Unimplemented,
+            "the server was built without the chaos feature",
+        ))
+    }
+
+    async fn member_add(
+        &self,
+        request: Request<MemberAddReq>,
+    ) -> Result<Response<MemberRsp>, Status> {
+        let req = request.into_inner();
+        let role = match req.role.as_str() {
+            "" => NodeRole::Voter,
+            role => role
+                .parse()
+                .map_err(|err| Status::new(Code::InvalidArgument, format!("{}", err)))?,
+        };
+        let info = NodeInfo {
+            addr: req.raft_addr,
+            role,
+        };
+        let (cluster_id, id) = (self.core.identity().cluster_id, req.id);
+        info!("add node {} as {:?}", id, info);
+        let registered =
+            spawn_blocking(move || MyKvRaft::register_node(cluster_id, id, &info)).await;
+        match registered.unwrap() {
+            Ok(changed) => Ok(Response::new(MemberRsp { changed })),
+            Err(err) => Err(Status::new(
+                Code::Unavailable,
+                format!("register node error: {}", err),
+            )),
+        }
+    }
+
+    async fn member_remove(
+        &self,
+        request: Request<MemberRemoveReq>,
+    ) -> Result<Response<MemberRsp>, Status> {
+        let id = request.into_inner().id;
+        info!("remove node {}", id);
+        let cluster_id = self.core.identity().cluster_id;
+        let unregistered = spawn_blocking(move || MyKvRaft::unregister_node(cluster_id, id)).await;
+        match unregistered.unwrap() {
+            Ok(changed) => Ok(Response::new(MemberRsp { changed })),
+            Err(err) => Err(Status::new(
+                Code::Unavailable,
+                format!("unregister node error: {}", err),
+            )),
+        }
+    }
+
+    async fn backup(
+        &self,
+        request: Request<BackupRpcReq>,
+    ) -> Result<Response<BackupRpcRsp>, Status> {
+        let req = request.into_inner();
+        let dir = self.backup_dir.as_deref().ok_or_else(|| {
+            Status::new(
+                Code::FailedPrecondition,
+                "the server takes no backups, it has no backup directory",
+            )
+        })?;
+        let path = backup_path(dir, &req.path).ok_or_else(|| {
+            Status::new(
+                Code::InvalidArgument,
+                format!(
+                    "backup path {:?} is not a file under the backup directory",
+                    req.path
+                ),
+            )
+        })?;
+        info!("backup to {}", path.display());
+        let backed_up = async {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir).await?;
+            }
+            self.core.backup(&path).await
+        };
+        match backed_up.await {
+            Ok((index, term)) => Ok(Response::new(BackupRpcRsp { index, term })),
+            Err(err) => Err(Status::new(
+                Code::Internal,
+                format!("call core backup error: {}", err),
+            )),
+        }
+    }
+}
+
+/// Serves the client RPCs of the node at `addr`.
+pub async fn serve(raft: Arc<MyKvRaft>, sm: Arc<KvApp>, addr: SocketAddr) -> Result<()> {
+    let client_rpc = MyClientRpc {
+        core: raft,
+        storage: sm,
+    };
+    info!("listenning client addr: {:?}", addr);
+    Server::builder()
+        .add_service(ClientRpcServer::new(client_rpc))
+        .serve(addr)
+        .await?;
+    Ok(())
+}
+
+/// Serves the operator RPCs of the node at `addr`.
+pub async fn serve_admin(
+    raft: Arc<MyKvRaft>,
+    addr: SocketAddr,
+    backup_dir: Option<PathBuf>,
+) -> Result<()> {
+    let admin_rpc = MyAdminRpc {
+        core: raft,
+        backup_dir,
+    };
+    info!("listening admin addr: {:?}", addr);
+    Server::builder()
+        .add_service(AdminRpcServer::new(admin_rpc))
+        .serve(addr)
+        .await?;
+    Ok(())
+}
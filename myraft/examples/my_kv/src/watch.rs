@@ -0,0 +1,107 @@
+//! Watches of the puts and deletes of a key, or of the keys under a prefix.
+//!
+//! A watch replays the changes kept in the history from its start revision,
+//! then follows the writes as they are applied, in log order either way.
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+
+/// A change of a key by the write of `revision`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub revision: u64,
+    pub key: Vec<u8>,
+    /// None for a delete
+    pub value: Option<Vec<u8>>,
+    /// 0 for a delete
+    pub version: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct WatchRequest {
+    pub key: Vec<u8>,
+    /// every key that starts with `key`
+    pub prefix: bool,
+    /// the first revision to send the changes of, None for the writes applied
+    /// after the watch starts; resume with the last revision received + 1
+    pub start_revision: Option<u64>,
+}
+
+impl WatchRequest {
+    pub(crate) fn matches(&self, key: &[u8]) -> bool {
+        match self.prefix {
+            true => key.starts_with(&self.key),
+            false => key == self.key.as_slice(),
+        }
+    }
+}
+
+/// The changes of a watch, one write at a time.
+pub struct Watch {
+    req: WatchRequest,
+    // the changes replayed from the history, by revision
+    replay: VecDeque<Vec<Event>>,
+    writes: Receiver<Vec<Event>>,
+    // the writes before it were replayed or sent
+    next_revision: u64,
+}
+
+impl Watch {
+    pub(crate) fn new(
+        req: WatchRequest,
+        replayed: Vec<Event>,
+        writes: Receiver<Vec<Event>>,
+        next_revision: u64,
+    ) -> Self {
+        let mut replay: VecDeque<Vec<Event>> = VecDeque::new();
+        for event in replayed {
+            match replay.back_mut() {
+                Some(last) if last[0].revision == event.revision => last.push(event),
+                _ => replay.push_back(vec![event]),
+            }
+        }
+        Self {
+            req,
+            replay,
+            writes,
+            next_revision,
+        }
+    }
+
+    /// The changes of the next write to the keys watched, sorted by key.
+    /// Fails once the watch fell behind the writes or a snapshot replaced the
+    /// store; it can be resumed from the revision of the error.
+    pub async fn next(&mut self) -> Result<Vec<Event>> {
+        if let Some(events) = self.replay.pop_front() {
+            return Ok(events);
+        }
+        loop {
+            let events = match self.writes.recv().await {
+                Ok(events) => events,
+                Err(RecvError::Lagged(_)) => bail!(
+                    "the watch fell behind the writes, resume it from revision {}",
+                    self.next_revision
+                ),
+                Err(RecvError::Closed) => bail!(
+                    "the store was replaced by a snapshot, resume the watch from revision {}",
+                    self.next_revision
+                ),
+            };
+            let revision = events[0].revision;
+            // replayed already
+            if revision < self.next_revision {
+                continue;
+            }
+            self.next_revision = revision + 1;
+            let events: Vec<_> = events
+                .into_iter()
+                .filter(|event| self.req.matches(&event.key))
+                .collect();
+            if !events.is_empty() {
+                return Ok(events);
+            }
+        }
+    }
+}
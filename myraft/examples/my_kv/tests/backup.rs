@@ -0,0 +1,142 @@
+mod cluster;
+
+use my_kv::clientpb::admin_rpc_client::AdminRpcClient;
+use my_kv::clientpb::BackupRpcReq;
+use my_kv::kv_app::{KvApp, ReadRequest, WriteRequest, WriteResponse};
+use my_kv::service::{self, MyKvRaft};
+use myraft::raft::{MyRaftBuilder, NodeInfo, NodeRole};
+use myraft::{default_store_dir, ClientSession, DEFAULT_CLUSTER_ID};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::spawn;
+use tokio::time::sleep;
+use tonic::Code;
+
+fn put_if_absent(value: &[u8]) -> WriteRequest {
+    WriteRequest::PutIfAbsent {
+        key: b"k".to_vec(),
+        value: value.to_vec(),
+    }
+}
+
+fn succeeded(rsp: &WriteResponse) -> bool {
+    match rsp {
+        WriteResponse::Conditional { succeeded, .. } => *succeeded,
+        _ => panic!("not a conditional write: {:?}", rsp),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn a_restored_node_starts_a_new_cluster_with_the_data_and_sessions() {
+    let cluster = cluster::start(&[81, 82, 83]).await;
+    let leader = &cluster.nodes[cluster.leader];
+    let session = ClientSession {
+        client_id: 9,
+        seq: 1,
+    };
+    let first = leader
+        .client_write_in_session(session, put_if_absent(b"a"))
+        .await
+        .unwrap();
+    assert!(succeeded(&first));
+    let path = "backup-81-83";
+    let (index, _) = leader.backup(path).await.unwrap();
+    assert!(index > 0);
+
+    let id = 84;
+    let dir = default_store_dir(id);
+    let kv_path = format!("kv_store/node_{}", id);
+    let _ = fs::remove_dir_all(&dir);
+    let _ = fs::remove_dir_all(&kv_path);
+    let app = Arc::new(KvApp::new(sled::open(&kv_path).unwrap()).unwrap());
+    let identity = MyKvRaft::restore(id, &dir, path, &app).await.unwrap();
+    assert_ne!(identity.cluster_id, DEFAULT_CLUSTER_ID);
+    assert_ne!(identity.cluster_id, leader.identity().cluster_id);
+    // the store is the one of a new node now
+    assert!(MyKvRaft::restore(id, &dir, path, &app).await.is_err());
+    let read = app
+        .handle_read(ReadRequest {
+            key: b"k".to_vec(),
+            revision: None,
+        })
+        .await
+        .unwrap();
+    assert_eq!(read.data, Some(b"a".to_vec()));
+
+    let info = NodeInfo {
+        addr: cluster::free_local_addr(),
+        role: NodeRole::Voter,
+    };
+    let raft = MyRaftBuilder::new(id, info.addr.clone(), app.clone())
+        .build()
+        .await
+        .unwrap();
+    assert_eq!(raft.identity(), &identity);
+    raft.join_static(vec![(id, info)].into_iter().collect(), true)
+        .await;
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while raft.metrics().current_leader != Some(id) {
+        assert!(Instant::now() < deadline, "node {} did not lead", id);
+        sleep(Duration::from_millis(100)).await;
+    }
+    // the retry is answered as the write was, not applied again over it
+    let retry = raft
+        .client_write_in_session(session, put_if_absent(b"b"))
+        .await
+        .unwrap();
+    assert!(succeeded(&retry));
+    let other = raft
+        .client_write_in_session(
+            ClientSession {
+                client_id: 9,
+                seq: 2,
+            },
+            put_if_absent(b"b"),
+        )
+        .await
+        .unwrap();
+    assert!(!succeeded(&other));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn backups_are_only_written_under_the_backup_directory() {
+    let cluster = cluster::start(&[85, 86, 87]).await;
+    let dir = Path::new("backups-85-87");
+    let _ = fs::remove_dir_all(dir);
+    let (with_dir, without_dir) = (cluster::free_local_addr(), cluster::free_local_addr());
+    let leader = &cluster.nodes[cluster.leader];
+    spawn(service::serve_admin(
+        leader.clone(),
+        with_dir.parse().unwrap(),
+        Some(dir.to_path_buf()),
+    ));
+    spawn(service::serve_admin(
+        leader.clone(),
+        without_dir.parse().unwrap(),
+        None,
+    ));
+    sleep(Duration::from_millis(200)).await;
+    let backup = |addr: String, path: &str| {
+        let req = BackupRpcReq {
+            path: path.to_string(),
+        };
+        async move {
+            let mut client = AdminRpcClient::connect(format!("http://{}", addr))
+                .await
+                .unwrap();
+            client.backup(req).await
+        }
+    };
+
+    backup(with_dir.clone(), "daily/first").await.unwrap();
+    assert!(dir.join("daily/first").is_file());
+    for path in &["/tmp/first", "../first", "daily/../../first", "", "."] {
+        let err = backup(with_dir.clone(), path).await.unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument, "{:?}: {}", path, err);
+    }
+    assert!(!Path::new("first").exists());
+    let err = backup(without_dir, "first").await.unwrap_err();
+    assert_eq!(err.code(), Code::FailedPrecondition, "{}", err);
+}
@@ -0,0 +1,64 @@
+mod cluster;
+
+use my_kv::service;
+use std::process::{Command, Output};
+use tokio::spawn;
+use tokio::task::spawn_blocking;
+
+// runs raft_client with `args`, off the runtime the cluster runs on
+async fn raft_client(args: &[&str]) -> Output {
+    let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+    spawn_blocking(move || {
+        Command::new(env!("CARGO_BIN_EXE_raft_client"))
+            .args(&args)
+            .output()
+            .unwrap()
+    })
+    .await
+    .unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn the_client_exits_with_0_on_success_and_1_on_errors() {
+    let cluster = cluster::start(&[131, 132, 133]).await;
+    let endpoints = cluster.client_addrs.join(",");
+    let admin_addr = cluster::free_local_addr();
+    spawn(service::serve_admin(
+        cluster.nodes[0].clone(),
+        admin_addr.parse().unwrap(),
+        None,
+    ));
+
+    let put = raft_client(&["-e", &endpoints, "put", "k", "v"]).await;
+    assert_eq!(put.status.code(), Some(0), "{}", stderr(&put));
+    let get = raft_client(&["-e", &endpoints, "-o", "json", "get", "k"]).await;
+    assert_eq!(get.status.code(), Some(0), "{}", stderr(&get));
+    assert!(stdout(&get).contains("\"v\""), "{}", stdout(&get));
+
+    // no node to send to
+    let unreachable = cluster::free_local_addr();
+    let get = raft_client(&["-e", &unreachable, "get", "k"]).await;
+    assert_eq!(get.status.code(), Some(1));
+    assert!(stderr(&get).starts_with("error: "), "{}", stderr(&get));
+    // a request that does not parse
+    let txn = raft_client(&["-e", &endpoints, "txn", "not json"]).await;
+    assert_eq!(txn.status.code(), Some(1));
+    // the client port does not serve the member RPCs, the admin one checks them
+    let member = ["member", "add", "134", "127.0.0.1:1", "--role=leader"];
+    let client_port = [&["-a", &cluster.client_addrs[0]][..], &member].concat();
+    let add = raft_client(&client_port).await;
+    assert_eq!(add.status.code(), Some(1));
+    assert!(stderr(&add).contains("Unimplemented"), "{}", stderr(&add));
+    let admin_port = [&["-a", admin_addr.as_str()][..], &member].concat();
+    let add = raft_client(&admin_port).await;
+    assert_eq!(add.status.code(), Some(1));
+    assert!(stderr(&add).contains("InvalidArgument"), "{}", stderr(&add));
+}
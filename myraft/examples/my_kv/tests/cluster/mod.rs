@@ -0,0 +1,129 @@
+//! A my_kv cluster whose nodes all run in the test process.
+
+// each test file uses a part of it
+#![allow(dead_code)]
+
+use my_kv::kv_app::KvApp;
+use my_kv::service::{self, expire_leases, MyKvRaft};
+use myraft::raft::{MyRaftBuilder, NodeInfo, NodeRole};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::net::TcpListener;
+use std::sync::{Arc, Once};
+use std::time::{Duration, Instant};
+use tokio::spawn;
+use tokio::time::sleep;
+
+static SCRATCH_DIR: Once = Once::new();
+
+// the nodes keep their stores under the working directory; the tests of the
+// process share one scratch directory and tell their clusters apart by ids
+fn enter_scratch_dir() {
+    SCRATCH_DIR.call_once(|| {
+        let dir = env::temp_dir().join(format!("my_kv_tests-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        env::set_current_dir(&dir).unwrap();
+    });
+}
+
+pub fn free_local_addr() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+/// The nodes of a cluster in the test process, their apps and their client addresses.
+pub struct Cluster {
+    pub nodes: Vec<Arc<MyKvRaft>>,
+    pub apps: Vec<Arc<KvApp>>,
+    pub client_addrs: Vec<String>,
+    /// of the node that leads
+    pub leader: usize,
+}
+
+/// Starts a cluster of the voters `ids`, returned once a node has kept leading a while.
+pub async fn start(ids: &[u64]) -> Cluster {
+    let nodes: Vec<_> = ids.iter().map(|id| (*id, NodeRole::Voter)).collect();
+    start_with_roles(&nodes).await
+}
+
+/// Starts a cluster of `nodes`, the first a voter, returned once a node has
+/// kept leading the members a while.
+pub async fn start_with_roles(nodes: &[(u64, NodeRole)]) -> Cluster {
+    enter_scratch_dir();
+    let ids: Vec<u64> = nodes.iter().map(|(id, _)| *id).collect();
+    let infos: HashMap<_, _> = nodes
+        .iter()
+        .map(|(id, role)| {
+            let info = NodeInfo {
+                addr: free_local_addr(),
+                role: *role,
+            };
+            (*id, info)
+        })
+        .collect();
+    let (mut nodes, mut apps, mut client_addrs) = (vec![], vec![], vec![]);
+    for (i, id) in ids.iter().enumerate() {
+        let _ = fs::remove_dir_all(format!("store/node_{}", id));
+        let kv_path = format!("kv_store/node_{}", id);
+        let _ = fs::remove_dir_all(&kv_path);
+        let app = Arc::new(KvApp::new(sled::open(&kv_path).unwrap()).unwrap());
+        let raft = MyRaftBuilder::new(*id, infos[id].addr.clone(), app.clone())
+            .role(infos[id].role)
+            .build()
+            .await
+            .unwrap();
+        raft.join_static(infos.clone(), i == 0).await;
+        let raft = Arc::new(raft);
+        spawn(expire_leases(raft.clone(), app.clone()));
+        let client_addr = free_local_addr();
+        spawn(service::serve(
+            raft.clone(),
+            app.clone(),
+            client_addr.parse().unwrap(),
+        ));
+        nodes.push(raft);
+        apps.push(app);
+        client_addrs.push(client_addr);
+    }
+
+    let all: HashSet<u64> = ids
+        .iter()
+        .filter(|id| infos[id].role.is_member())
+        .copied()
+        .collect();
+    let deadline = Instant::now() + Duration::from_secs(30);
+    let mut stable = None;
+    while Instant::now() < deadline {
+        // the leader that added the others steps down, a leader of a later
+        // term has to keep leading
+        let leader = nodes.iter().enumerate().find_map(|(i, raft)| {
+            let metrics = raft.metrics();
+            let ready = metrics.state.is_leader()
+                && metrics.membership_config.members == all
+                && metrics.membership_config.members_after_consensus.is_none();
+            if ready {
+                Some((i, metrics.current_term))
+            } else {
+                None
+            }
+        });
+        stable = match (stable, leader) {
+            (Some((since, seen)), Some(now)) if seen == now => {
+                if since + Duration::from_secs(1) <= Instant::now() {
+                    return Cluster {
+                        nodes,
+                        apps,
+                        client_addrs,
+                        leader: now.0,
+                    };
+                }
+                Some((since, seen))
+            }
+            (_, Some(now)) => Some((Instant::now(), now)),
+            (_, None) => None,
+        };
+        sleep(Duration::from_millis(100)).await;
+    }
+    panic!("cluster {:?} has no leader", ids);
+}
@@ -0,0 +1,16 @@
+//! The fixture of the tests of the app on its own, without raft.
+
+// each test file uses a part of it
+#![allow(dead_code)]
+
+use my_kv::kv_app::KvApp;
+
+/// An app on a db that is gone with it.
+pub fn temporary_app() -> KvApp {
+    KvApp::new(sled::Config::new().temporary(true).open().unwrap()).unwrap()
+}
+
+/// The index of the next log entry, the writes of a test are one per entry.
+pub fn next_index(app: &KvApp) -> u64 {
+    app.revision().unwrap() + 1
+}
@@ -0,0 +1,101 @@
+mod common;
+
+use common::{next_index, temporary_app};
+use my_kv::kv_app::{KvApp, ReadRequest, WriteRequest, WriteResponse};
+use myraft::raft::RaftApp;
+
+async fn write(app: &KvApp, req: WriteRequest) -> (bool, Option<Vec<u8>>, u64) {
+    match app.handle_write(next_index(app), req).await.unwrap() {
+        WriteResponse::Conditional {
+            succeeded,
+            value,
+            version,
+            ..
+        } => (succeeded, value, version),
+        rsp => panic!("unconditional response {:?}", rsp),
+    }
+}
+
+async fn read(app: &KvApp, key: &[u8]) -> (Option<Vec<u8>>, u64) {
+    let key = key.to_vec();
+    let rsp = app
+        .handle_read(ReadRequest {
+            key,
+            revision: None,
+        })
+        .await
+        .unwrap();
+    (rsp.data, rsp.version)
+}
+
+#[tokio::test]
+async fn writes_only_what_the_condition_allows() {
+    let app = temporary_app();
+    let (key, a, b) = (b"k".to_vec(), b"a".to_vec(), b"b".to_vec());
+    let put_if_absent = |value: &Vec<u8>| WriteRequest::PutIfAbsent {
+        key: key.clone(),
+        value: value.clone(),
+    };
+    assert_eq!(write(&app, put_if_absent(&a)).await, (true, None, 0));
+    assert_eq!(
+        write(&app, put_if_absent(&b)).await,
+        (false, Some(a.clone()), 1)
+    );
+
+    let cas = |expected: Option<&Vec<u8>>, new: &Vec<u8>| WriteRequest::CompareAndSwap {
+        key: key.clone(),
+        expected: expected.cloned(),
+        new: new.clone(),
+    };
+    assert_eq!(
+        write(&app, cas(Some(&b), &a)).await,
+        (false, Some(a.clone()), 1)
+    );
+    assert_eq!(
+        write(&app, cas(None, &b)).await,
+        (false, Some(a.clone()), 1)
+    );
+    assert_eq!(
+        write(&app, cas(Some(&a), &b)).await,
+        (true, Some(a.clone()), 1)
+    );
+    assert_eq!(read(&app, &key).await, (Some(b.clone()), 2));
+
+    let put_if_version = |version| WriteRequest::PutIfVersion {
+        key: key.clone(),
+        version,
+        value: a.clone(),
+    };
+    assert_eq!(
+        write(&app, put_if_version(1)).await,
+        (false, Some(b.clone()), 2)
+    );
+    assert_eq!(
+        write(&app, put_if_version(2)).await,
+        (true, Some(b.clone()), 2)
+    );
+    let delete_if_version = |version| WriteRequest::DeleteIfVersion {
+        key: key.clone(),
+        version,
+    };
+    assert_eq!(
+        write(&app, delete_if_version(2)).await,
+        (false, Some(a.clone()), 3)
+    );
+    assert_eq!(
+        write(&app, delete_if_version(3)).await,
+        (true, Some(a.clone()), 3)
+    );
+    assert_eq!(read(&app, &key).await, (None, 0));
+    // a key created again starts over
+    assert_eq!(write(&app, put_if_version(0)).await, (true, None, 0));
+    assert_eq!(read(&app, &key).await, (Some(a), 1));
+}
+
+#[tokio::test]
+async fn values_of_stores_of_before_are_at_version_1() {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    db.insert(b"k", b"old".to_vec()).unwrap();
+    let app = KvApp::new(db).unwrap();
+    assert_eq!(read(&app, b"k").await, (Some(b"old".to_vec()), 1));
+}
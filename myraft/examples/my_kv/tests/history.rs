@@ -0,0 +1,260 @@
+mod common;
+
+use common::{next_index, temporary_app};
+use my_kv::kv_app::{
+    KvApp, ReadRequest, RevisionCompacted, ScanRequest, WriteRequest, WriteResponse,
+};
+use myraft::raft::RaftApp;
+use std::env;
+use std::fs;
+use std::sync::Arc;
+use tokio::task::{spawn, yield_now};
+
+async fn put(app: &KvApp, key: &str, value: &str) -> u64 {
+    let index = next_index(app);
+    let (key, value) = (key.as_bytes().to_vec(), value.as_bytes().to_vec());
+    let req = WriteRequest::Put { key, value };
+    app.handle_write(index, req).await.unwrap();
+    index
+}
+
+async fn delete(app: &KvApp, key: &str) -> u64 {
+    let index = next_index(app);
+    let key = key.as_bytes().to_vec();
+    app.handle_write(index, WriteRequest::Delete { key })
+        .await
+        .unwrap();
+    index
+}
+
+async fn compact(app: &KvApp, revision: u64) -> u64 {
+    let req = WriteRequest::Compact { revision };
+    match app.handle_write(next_index(app), req).await.unwrap() {
+        WriteResponse::Compacted { revision } => revision,
+        rsp => panic!("not a compaction response {:?}", rsp),
+    }
+}
+
+async fn read_at(app: &KvApp, key: &str, revision: Option<u64>) -> anyhow::Result<Option<String>> {
+    let key = key.as_bytes().to_vec();
+    let rsp = app.handle_read(ReadRequest { key, revision }).await?;
+    Ok(rsp.data.map(|data| String::from_utf8(data).unwrap()))
+}
+
+fn some(value: &str) -> Option<String> {
+    Some(value.to_string())
+}
+
+#[tokio::test]
+async fn reads_see_the_value_of_their_revision() {
+    let app = temporary_app();
+    let first = put(&app, "k", "a").await;
+    let second = put(&app, "k", "b").await;
+    put(&app, "other", "x").await;
+    let deleted = delete(&app, "k").await;
+
+    assert_eq!(read_at(&app, "k", Some(first - 1)).await.unwrap(), None);
+    assert_eq!(read_at(&app, "k", Some(first)).await.unwrap(), some("a"));
+    assert_eq!(
+        read_at(&app, "k", Some(deleted - 1)).await.unwrap(),
+        some("b")
+    );
+    assert_eq!(read_at(&app, "k", Some(deleted)).await.unwrap(), None);
+    assert_eq!(read_at(&app, "k", None).await.unwrap(), None);
+    assert!(read_at(&app, "k", Some(deleted + 1)).await.is_err());
+
+    let rsp = app.handle_read(ReadRequest::typed(&0u64).unwrap()).await;
+    assert_eq!(rsp.unwrap().revision, 0);
+    let key = b"other".to_vec();
+    let rsp = app
+        .handle_read(ReadRequest {
+            key,
+            revision: None,
+        })
+        .await;
+    assert_eq!(rsp.unwrap().revision, second + 1);
+
+    let req = ScanRequest {
+        revision: Some(second),
+        ..Default::default()
+    };
+    let pairs: Vec<_> = app.scan(&req).unwrap().map(Result::unwrap).collect();
+    assert_eq!(pairs, [(b"k".to_vec(), b"b".to_vec())]);
+    let pairs = app.scan(&ScanRequest::default()).unwrap().count();
+    assert_eq!(pairs, 1);
+}
+
+#[tokio::test]
+async fn compaction_drops_what_is_not_current_at_the_revision() {
+    let app = temporary_app();
+    let first = put(&app, "k", "a").await;
+    let second = put(&app, "k", "b").await;
+    put(&app, "gone", "x").await;
+    delete(&app, "gone").await;
+    let third = put(&app, "k", "c").await;
+
+    assert_eq!(compact(&app, second).await, second);
+    assert_eq!(compact(&app, first).await, second);
+    let err = read_at(&app, "k", Some(first)).await.unwrap_err();
+    let compacted = err.downcast_ref::<RevisionCompacted>().unwrap();
+    assert_eq!(compacted.oldest, second);
+    assert_eq!(read_at(&app, "k", Some(second)).await.unwrap(), some("b"));
+    assert_eq!(read_at(&app, "k", Some(third)).await.unwrap(), some("c"));
+
+    // a compaction goes no further than itself
+    let index = next_index(&app);
+    assert_eq!(compact(&app, u64::MAX).await, index);
+    assert_eq!(read_at(&app, "gone", Some(index)).await.unwrap(), None);
+    assert_eq!(read_at(&app, "k", Some(index)).await.unwrap(), some("c"));
+}
+
+// the values of a scan at `revision`, None if it was refused as compacted
+fn values_at(app: &KvApp, revision: Option<u64>) -> Option<Vec<String>> {
+    let req = ScanRequest {
+        revision,
+        ..Default::default()
+    };
+    let values: anyhow::Result<Vec<_>> = app.scan(&req).and_then(|pairs| pairs.collect());
+    match values {
+        Ok(pairs) => Some(
+            pairs
+                .into_iter()
+                .map(|(_, value)| String::from_utf8(value).unwrap())
+                .collect(),
+        ),
+        Err(err) => {
+            assert!(err.downcast_ref::<RevisionCompacted>().is_some());
+            None
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn a_scan_during_a_compaction_sees_every_key_or_is_refused() {
+    let app = Arc::new(temporary_app().compaction_batch(10));
+    for i in 0..500 {
+        put(&app, &format!("k{:03}", i), "a").await;
+    }
+    let before = app.revision().unwrap();
+    for i in 0..500 {
+        put(&app, &format!("k{:03}", i), "b").await;
+    }
+
+    // a scan checked before the compaction, and read on through it
+    let req = ScanRequest {
+        revision: Some(before),
+        ..Default::default()
+    };
+    let mut begun = app.scan(&req).unwrap();
+    assert_eq!(
+        begun.next().unwrap().unwrap(),
+        (b"k000".to_vec(), b"a".to_vec())
+    );
+
+    let compacting = {
+        let app = app.clone();
+        spawn(async move { compact(&app, u64::MAX).await })
+    };
+    let mut refused = false;
+    while !compacting.is_finished() {
+        assert_eq!(values_at(&app, None).unwrap(), vec!["b"; 500]);
+        match values_at(&app, Some(before)) {
+            Some(values) => assert_eq!(values, vec!["a"; 500]),
+            None => refused = true,
+        }
+    }
+    compacting.await.unwrap();
+    let err = begun.collect::<anyhow::Result<Vec<_>>>().unwrap_err();
+    assert!(err.downcast_ref::<RevisionCompacted>().is_some());
+    assert!(refused || values_at(&app, Some(before)).is_none());
+    assert_eq!(values_at(&app, None).unwrap(), vec!["b"; 500]);
+}
+
+#[tokio::test]
+async fn a_compaction_cut_short_by_a_restart_goes_on_after_it() {
+    let path = env::temp_dir().join(format!("my_kv-compaction-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&path);
+    let app = Arc::new(
+        KvApp::new(sled::open(&path).unwrap())
+            .unwrap()
+            .compaction_batch(1),
+    );
+    let mut firsts = vec![];
+    for key in ["a", "b", "c", "d", "e"] {
+        firsts.push(put(&app, key, "1").await);
+        put(&app, key, "2").await;
+    }
+    let last = app.revision().unwrap();
+
+    // the compaction yields between its batches, and is dropped at the second
+    let index = next_index(&app);
+    let compacting = {
+        let app = app.clone();
+        spawn(async move {
+            let req = WriteRequest::Compact { revision: last };
+            app.handle_write(index, req).await.unwrap();
+        })
+    };
+    yield_now().await;
+    yield_now().await;
+    compacting.abort();
+    assert!(compacting.await.unwrap_err().is_cancelled());
+    assert_eq!(app.revision().unwrap(), last);
+    assert_eq!(app.compacted().unwrap(), last);
+    drop(app);
+
+    // the raft applies the write again after the restart
+    let app = KvApp::new(sled::open(&path).unwrap()).unwrap();
+    let req = WriteRequest::Compact { revision: last };
+    match app.handle_write(index, req).await.unwrap() {
+        WriteResponse::Compacted { revision } => assert_eq!(revision, last),
+        rsp => panic!("not a compaction response {:?}", rsp),
+    }
+    assert_eq!(app.revision().unwrap(), index);
+    for (key, first) in ["a", "b", "c", "d", "e"].iter().zip(firsts) {
+        assert!(read_at(&app, key, Some(first)).await.is_err());
+        assert_eq!(read_at(&app, key, Some(last)).await.unwrap(), some("2"));
+    }
+    // and the next compaction starts from the first key
+    put(&app, "a", "3").await;
+    let next = next_index(&app);
+    assert_eq!(compact(&app, next).await, next);
+    assert_eq!(read_at(&app, "a", None).await.unwrap(), some("3"));
+    drop(app);
+    fs::remove_dir_all(&path).unwrap();
+}
+
+#[tokio::test]
+async fn history_is_kept_to_its_length() {
+    let app = temporary_app().keep_history(1);
+    let first = put(&app, "k", "a").await;
+    let second = put(&app, "k", "b").await;
+    put(&app, "k", "c").await;
+
+    let err = read_at(&app, "k", Some(first)).await.unwrap_err();
+    assert!(err.downcast_ref::<RevisionCompacted>().is_some());
+    assert_eq!(read_at(&app, "k", Some(second)).await.unwrap(), some("b"));
+}
+
+#[tokio::test]
+async fn history_and_compaction_go_through_a_snapshot() {
+    let leader = temporary_app();
+    let follower = temporary_app();
+    let first = put(&leader, "k", "a").await;
+    let second = put(&leader, "k", "b").await;
+    put(&leader, "k", "c").await;
+    compact(&leader, second).await;
+
+    let mut snapshot = vec![];
+    let view = leader.snapshot_view().unwrap();
+    leader.make_snapshot(view, &mut snapshot).await.unwrap();
+    follower.handle_snapshot(&mut &snapshot[..]).await.unwrap();
+    assert_eq!(follower.revision().unwrap(), leader.revision().unwrap());
+    assert_eq!(follower.compacted().unwrap(), second);
+    assert!(read_at(&follower, "k", Some(first)).await.is_err());
+    assert_eq!(
+        read_at(&follower, "k", Some(second)).await.unwrap(),
+        some("b")
+    );
+    assert_eq!(read_at(&follower, "k", None).await.unwrap(), some("c"));
+}
@@ -0,0 +1,214 @@
+mod cluster;
+
+use my_kv::clientpb::client_rpc_client::ClientRpcClient;
+use my_kv::clientpb::{write_rpc_req, ScanRpcReq, WriteRpcReq};
+use my_kv::kv_client::{KvClient, KvClientBuilder, ReadConsistency, ReadOptions, WriteOptions};
+use my_kv::service::LEADER_METADATA;
+use std::net::TcpListener;
+use std::time::{Duration, Instant};
+use tokio::spawn;
+use tokio::time::sleep;
+use tonic::{Code, Status};
+
+// the followers first, so the leader has to be looked for
+fn followers_first(cluster: &cluster::Cluster) -> Vec<String> {
+    let mut endpoints = cluster.client_addrs.clone();
+    let leader = endpoints.remove(cluster.leader);
+    endpoints.push(leader);
+    endpoints
+}
+
+fn stale() -> ReadOptions {
+    ReadOptions {
+        consistency: ReadConsistency::Stale,
+        ..Default::default()
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn writes_find_the_leader_among_the_endpoints() {
+    let cluster = cluster::start(&[51, 52, 53]).await;
+    let client = KvClientBuilder::new(followers_first(&cluster))
+        .build()
+        .unwrap();
+    let opts = WriteOptions::default();
+    assert_eq!(client.put(b"k", b"a", &opts).await.unwrap(), None);
+    assert_eq!(
+        client.put(b"k", b"b", &opts).await.unwrap(),
+        Some(b"a".to_vec())
+    );
+    assert_eq!(
+        client.leader().await.unwrap(),
+        cluster.client_addrs[cluster.leader]
+    );
+
+    let read = client.get(b"k", &ReadOptions::default()).await.unwrap();
+    let read = read.unwrap();
+    assert_eq!((read.value, read.version), (b"b".to_vec(), 2));
+    assert_eq!(
+        client.delete(b"k", &opts).await.unwrap(),
+        Some(b"b".to_vec())
+    );
+    assert_eq!(
+        client.get(b"k", &ReadOptions::default()).await.unwrap(),
+        None
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn a_follower_names_the_leader_of_a_write() {
+    let cluster = cluster::start(&[61, 62, 63]).await;
+    let follower = (cluster.leader + 1) % cluster.nodes.len();
+    let addr = format!("http://{}", cluster.client_addrs[follower]);
+    let mut rpc = ClientRpcClient::connect(addr).await.unwrap();
+    let req = WriteRpcReq {
+        target: Some(write_rpc_req::Target::RawKey(b"k".to_vec())),
+        value: Some(write_rpc_req::Value::RawData(b"v".to_vec())),
+        ..Default::default()
+    };
+    let status = rpc.write(req).await.unwrap_err();
+    assert_eq!(status.code(), Code::FailedPrecondition);
+    let leader = status.metadata().get(LEADER_METADATA).unwrap();
+    let leader_id = cluster.nodes[cluster.leader].metrics().id;
+    assert_eq!(leader.to_str().unwrap(), leader_id.to_string());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_writes_each_get_a_session() {
+    let cluster = cluster::start(&[71, 72, 73]).await;
+    let client = KvClientBuilder::new(cluster.client_addrs.clone())
+        .build()
+        .unwrap();
+    let writes: Vec<_> = (0..20)
+        .map(|i| {
+            let client: KvClient = client.clone();
+            spawn(async move {
+                let key = format!("k/{:02}", i);
+                let opts = WriteOptions::default();
+                client.put(key.as_bytes(), b"v", &opts).await.unwrap()
+            })
+        })
+        .collect();
+    for write in writes {
+        assert_eq!(write.await.unwrap(), None);
+    }
+    let pairs = client.scan(b"k/", &ReadOptions::default()).await.unwrap();
+    assert_eq!(pairs.len(), 20);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn only_a_page_followed_by_more_keys_has_a_next_one() {
+    let cluster = cluster::start(&[91, 92, 93]).await;
+    let client = KvClientBuilder::new(cluster.client_addrs.clone())
+        .build()
+        .unwrap();
+    for key in ["k/1", "k/2", "k/3"].iter() {
+        let opts = WriteOptions::default();
+        client.put(key.as_bytes(), b"v", &opts).await.unwrap();
+    }
+    let addr = format!("http://{}", cluster.client_addrs[cluster.leader]);
+    let mut rpc = ClientRpcClient::connect(addr).await.unwrap();
+    let mut tokens = vec![];
+    for limit in 2..=4 {
+        let req = ScanRpcReq {
+            prefix: b"k/".to_vec(),
+            limit,
+            ..Default::default()
+        };
+        let mut pairs = rpc.scan(req).await.unwrap().into_inner();
+        let mut last = None;
+        while let Some(pair) = pairs.message().await.unwrap() {
+            last = Some(pair);
+        }
+        tokens.push(last.unwrap().next_page_token);
+    }
+    assert_eq!(tokens, [b"k/2".to_vec(), vec![], vec![]]);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn stale_reads_go_around_an_unreachable_endpoint() {
+    let cluster = cluster::start(&[81, 82, 83]).await;
+    let mut endpoints = cluster.client_addrs.clone();
+    endpoints.insert(0, cluster::free_local_addr());
+    let client = KvClientBuilder::new(endpoints)
+        .backoff(Duration::from_millis(10), Duration::from_millis(10))
+        .build()
+        .unwrap();
+    client
+        .put(b"k", b"v", &WriteOptions::default())
+        .await
+        .unwrap();
+
+    // each node in turn, until they all applied the put
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let mut applied = 0;
+    while applied < 10 {
+        assert!(Instant::now() < deadline);
+        match client.get(b"k", &stale()).await.unwrap() {
+            Some(read) if read.value == b"v" => applied += 1,
+            _ => {
+                applied = 0;
+                sleep(Duration::from_millis(50)).await;
+            }
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn requests_go_around_an_endpoint_that_never_answers() {
+    let cluster = cluster::start(&[101, 102, 103]).await;
+    // connections are taken into its backlog, and never answered
+    let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut endpoints = cluster.client_addrs.clone();
+    endpoints.insert(0, silent.local_addr().unwrap().to_string());
+    let client = KvClientBuilder::new(endpoints)
+        .connect_timeout(Duration::from_millis(200))
+        .attempt_timeout(Duration::from_millis(200))
+        .backoff(Duration::from_millis(10), Duration::from_millis(10))
+        .build()
+        .unwrap();
+
+    let started = Instant::now();
+    let opts = WriteOptions::default();
+    assert_eq!(client.put(b"k", b"v", &opts).await.unwrap(), None);
+    for _ in 0..4 {
+        assert!(client.get(b"k", &stale()).await.is_ok());
+    }
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn a_read_at_a_revision_not_written_yet_is_not_retried() {
+    let cluster = cluster::start(&[151, 152, 153]).await;
+    let client = KvClientBuilder::new(cluster.client_addrs.clone())
+        .backoff(Duration::from_secs(1), Duration::from_secs(1))
+        .build()
+        .unwrap();
+    client.leader().await.unwrap();
+    let opts = ReadOptions {
+        revision: Some(1000),
+        ..Default::default()
+    };
+    let started = Instant::now();
+    let err = client.get(b"k", &opts).await.unwrap_err();
+    let status = err.downcast_ref::<Status>().unwrap();
+    assert_eq!(status.code(), Code::OutOfRange, "{}", status);
+    assert!(started.elapsed() < Duration::from_secs(1));
+}
+
+#[tokio::test]
+async fn a_request_fails_past_its_deadline() {
+    let client = KvClientBuilder::new(vec![cluster::free_local_addr()])
+        .retries(1000)
+        .backoff(Duration::from_millis(10), Duration::from_millis(10))
+        .build()
+        .unwrap();
+    let opts = ReadOptions {
+        timeout: Some(Duration::from_millis(300)),
+        ..Default::default()
+    };
+    let started = Instant::now();
+    let err = client.get(b"k", &opts).await.unwrap_err();
+    assert!(err.to_string().contains("deadline"), "{}", err);
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
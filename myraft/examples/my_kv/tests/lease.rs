@@ -0,0 +1,134 @@
+mod common;
+
+use common::{next_index, temporary_app};
+use my_kv::kv_app::{KvApp, ReadRequest, TxnOp, WriteRequest, WriteResponse};
+use myraft::raft::RaftApp;
+
+async fn write(app: &KvApp, req: WriteRequest) -> WriteResponse {
+    app.handle_write(next_index(app), req).await.unwrap()
+}
+
+async fn grant(app: &KvApp, ttl: u64) -> u64 {
+    match write(app, WriteRequest::GrantLease { ttl }).await {
+        WriteResponse::Lease { lease, .. } => lease,
+        rsp => panic!("not a lease response {:?}", rsp),
+    }
+}
+
+// whether the lease was alive for the put
+async fn put_with_lease(app: &KvApp, key: &str, lease: u64) -> bool {
+    let (key, value) = (key.as_bytes().to_vec(), b"v".to_vec());
+    match write(app, WriteRequest::PutWithLease { key, value, lease }).await {
+        WriteResponse::Conditional { succeeded, .. } => succeeded,
+        rsp => panic!("not a conditional response {:?}", rsp),
+    }
+}
+
+async fn exists(app: &KvApp, key: &str) -> bool {
+    let key = key.as_bytes().to_vec();
+    let req = ReadRequest {
+        key,
+        revision: None,
+    };
+    app.handle_read(req).await.unwrap().data.is_some()
+}
+
+#[tokio::test]
+async fn revoking_deletes_the_keys_not_written_since() {
+    let app = temporary_app();
+    let lease = grant(&app, 60).await;
+    assert!(put_with_lease(&app, "a", lease).await);
+    assert!(put_with_lease(&app, "b", lease).await);
+    let (key, value) = (b"b".to_vec(), b"mine".to_vec());
+    write(&app, WriteRequest::Put { key, value }).await;
+
+    let rsp = write(&app, WriteRequest::RevokeLease { lease }).await;
+    assert!(matches!(rsp, WriteResponse::Lease { ttl: Some(60), .. }));
+    assert!(!exists(&app, "a").await);
+    assert!(exists(&app, "b").await);
+    assert!(!put_with_lease(&app, "c", lease).await);
+    assert!(!exists(&app, "c").await);
+}
+
+#[tokio::test]
+async fn the_leader_expires_the_leases_not_kept_alive() {
+    let app = temporary_app();
+    let lease = grant(&app, 0).await;
+    assert!(put_with_lease(&app, "k", lease).await);
+    let kept = grant(&app, 60).await;
+    let expired = app.expired_leases().unwrap();
+    assert_eq!(expired.len(), 1);
+
+    // an expiry decided before a keep alive does not end the lease
+    write(&app, WriteRequest::KeepAlive { lease }).await;
+    for req in expired {
+        write(&app, req).await;
+    }
+    assert!(exists(&app, "k").await);
+
+    for req in app.expired_leases().unwrap() {
+        write(&app, req).await;
+    }
+    assert!(!exists(&app, "k").await);
+    let rsp = write(&app, WriteRequest::KeepAlive { lease }).await;
+    assert!(matches!(rsp, WriteResponse::Lease { ttl: None, .. }));
+    let rsp = write(&app, WriteRequest::KeepAlive { lease: kept }).await;
+    assert!(matches!(rsp, WriteResponse::Lease { ttl: Some(60), .. }));
+}
+
+// whether the lease was alive for a transaction putting `key` with it
+async fn txn_with_lease(app: &KvApp, key: &str, lease: u64) -> bool {
+    let (key, value) = (key.as_bytes().to_vec(), b"v".to_vec());
+    let req = WriteRequest::Txn {
+        guards: vec![],
+        success: vec![TxnOp::PutWithLease { key, value, lease }],
+        failure: vec![],
+    };
+    match write(app, req).await {
+        WriteResponse::Txn {
+            succeeded,
+            lease_gone,
+            ..
+        } => {
+            assert_eq!(succeeded, !lease_gone);
+            !lease_gone
+        }
+        rsp => panic!("not a txn response {:?}", rsp),
+    }
+}
+
+#[tokio::test]
+async fn a_transaction_puts_with_a_lease_only_while_it_lives() {
+    let app = temporary_app();
+    let lease = grant(&app, 60).await;
+    assert!(txn_with_lease(&app, "a", lease).await);
+    write(&app, WriteRequest::RevokeLease { lease }).await;
+    assert!(!exists(&app, "a").await);
+
+    let revision = app.revision().unwrap();
+    assert!(!txn_with_lease(&app, "b", lease).await);
+    assert!(!exists(&app, "b").await);
+    assert_eq!(app.revision().unwrap(), revision + 1);
+}
+
+#[tokio::test]
+async fn leases_go_through_a_snapshot() {
+    let leader = temporary_app();
+    let follower = temporary_app();
+    // a lease of an id the leader does not have
+    let (key, value) = (b"x".to_vec(), b"x".to_vec());
+    write(&follower, WriteRequest::Put { key, value }).await;
+    let stale = grant(&follower, 60).await;
+    let lease = grant(&leader, 60).await;
+    assert!(put_with_lease(&leader, "k", lease).await);
+
+    let mut snapshot = vec![];
+    let view = leader.snapshot_view().unwrap();
+    leader.make_snapshot(view, &mut snapshot).await.unwrap();
+    follower.handle_snapshot(&mut &snapshot[..]).await.unwrap();
+    let rsp = write(&follower, WriteRequest::KeepAlive { lease: stale }).await;
+    assert!(matches!(rsp, WriteResponse::Lease { ttl: None, .. }));
+
+    write(&follower, WriteRequest::RevokeLease { lease }).await;
+    assert!(!exists(&follower, "k").await);
+}
@@ -0,0 +1,173 @@
+mod cluster;
+
+use my_kv::recipes::{Election, KvRpcClient, Mutex, RwLock, Session};
+use std::time::Duration;
+use tokio::time::{sleep, timeout};
+use tokio::{spawn, task::JoinHandle};
+
+// a client of the leader of a new cluster of `ids`
+async fn leader_client(ids: &[u64]) -> KvRpcClient {
+    let cluster = cluster::start(ids).await;
+    let addr = format!("http://{}", cluster.client_addrs[cluster.leader]);
+    KvRpcClient::connect(addr).await.unwrap()
+}
+
+// whether the task is still waiting after a while
+async fn waits<T>(task: &JoinHandle<T>) -> bool {
+    sleep(Duration::from_millis(500)).await;
+    !task.is_finished()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn a_mutex_is_held_by_one_session_at_a_time() {
+    let client = leader_client(&[11, 12, 13]).await;
+    let first = Session::new(client.clone(), 60).await.unwrap();
+    let second = Session::new(client.clone(), 60).await.unwrap();
+    let mutex = Mutex::new(client.clone(), b"mutex");
+
+    let held = mutex.lock(&first).await.unwrap();
+    assert!(mutex.try_lock(&second).await.unwrap().is_none());
+    let waiting = spawn(async move {
+        let mutex = Mutex::new(client, b"mutex");
+        let guard = mutex.lock(&second).await.unwrap();
+        (guard.fencing_token(), guard.unlock().await.unwrap())
+    });
+    assert!(waits(&waiting).await);
+
+    let token = held.fencing_token();
+    assert!(held.unlock().await.unwrap());
+    let (next_token, unlocked) = timeout(Duration::from_secs(10), waiting)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(next_token > token);
+    assert!(unlocked);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn a_lock_is_freed_when_its_session_expires() {
+    let client = leader_client(&[21, 22, 23]).await;
+    let mutex = Mutex::new(client.clone(), b"mutex");
+    let dead = Session::new(client.clone(), 1).await.unwrap();
+    let lost = mutex.lock(&dead).await.unwrap();
+    // no longer kept alive
+    drop(dead);
+
+    let live = Session::new(client.clone(), 60).await.unwrap();
+    let held = timeout(Duration::from_secs(10), mutex.lock(&live))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(held.fencing_token() > lost.fencing_token());
+    assert!(!lost.unlock().await.unwrap());
+    assert!(held.unlock().await.unwrap());
+    live.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn a_rwlock_has_readers_or_a_writer() {
+    let client = leader_client(&[31, 32, 33]).await;
+    let (first, second, writer) = (
+        Session::new(client.clone(), 60).await.unwrap(),
+        Session::new(client.clone(), 60).await.unwrap(),
+        Session::new(client.clone(), 60).await.unwrap(),
+    );
+    let lock = RwLock::new(client.clone(), b"rw");
+    let read = lock.read(&first).await.unwrap();
+    let also_read = lock.read(&second).await.unwrap();
+
+    let lock_client = client.clone();
+    let writing = spawn(async move {
+        let lock = RwLock::new(lock_client, b"rw");
+        let guard = lock.write(&writer).await.unwrap();
+        (guard, writer)
+    });
+    assert!(waits(&writing).await);
+    assert!(read.unlock().await.unwrap());
+    assert!(waits(&writing).await);
+    assert!(also_read.unlock().await.unwrap());
+    let (written, _writer) = timeout(Duration::from_secs(10), writing)
+        .await
+        .unwrap()
+        .unwrap();
+
+    let reading = spawn(async move {
+        let lock = RwLock::new(client, b"rw");
+        lock.read(&first).await.unwrap().fencing_token()
+    });
+    assert!(waits(&reading).await);
+    let token = written.fencing_token();
+    assert!(written.unlock().await.unwrap());
+    let read_token = timeout(Duration::from_secs(10), reading)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(read_token > token);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn a_session_cannot_take_a_lock_it_holds() {
+    let client = leader_client(&[141, 142, 143]).await;
+    let (session, other) = (
+        Session::new(client.clone(), 60).await.unwrap(),
+        Session::new(client.clone(), 60).await.unwrap(),
+    );
+    let mutex = Mutex::new(client.clone(), b"mutex");
+    let held = mutex.lock(&session).await.unwrap();
+    let again = timeout(Duration::from_secs(10), mutex.lock(&session)).await;
+    assert!(again.unwrap().is_err());
+    assert!(mutex.try_lock(&session).await.is_err());
+    assert!(held.unlock().await.unwrap());
+
+    let lock = RwLock::new(client.clone(), b"rw");
+    let read = lock.read(&session).await.unwrap();
+    let write = timeout(Duration::from_secs(10), lock.write(&session)).await;
+    assert!(write.unwrap().is_err());
+    // the failed write let go of the writer key, the readers are not kept out
+    let also_read = timeout(Duration::from_secs(10), lock.read(&other)).await;
+    assert!(also_read.unwrap().unwrap().unlock().await.unwrap());
+    assert!(read.unlock().await.unwrap());
+
+    let written = lock.write(&session).await.unwrap();
+    let again = timeout(Duration::from_secs(10), lock.write(&session)).await;
+    assert!(again.unwrap().is_err());
+    assert!(written.unlock().await.unwrap());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn an_election_has_one_leader_at_a_time() {
+    let client = leader_client(&[41, 42, 43]).await;
+    let first = Session::new(client.clone(), 60).await.unwrap();
+    let second = Session::new(client.clone(), 60).await.unwrap();
+    let election = Election::new(client.clone(), b"election");
+    assert_eq!(election.leader().await.unwrap(), None);
+    let mut observer = election.observe().await.unwrap();
+    assert_eq!(observer.next().await.unwrap(), None);
+
+    let mut leading = election.campaign(&first, b"a".to_vec()).await.unwrap();
+    assert_eq!(observer.next().await.unwrap(), Some(b"a".to_vec()));
+    let campaigning = spawn(async move {
+        let election = Election::new(client, b"election");
+        let leadership = election.campaign(&second, b"b".to_vec()).await.unwrap();
+        (leadership.fencing_token(), second)
+    });
+    assert!(waits(&campaigning).await);
+
+    leading.proclaim(b"a2".to_vec()).await.unwrap();
+    assert_eq!(observer.next().await.unwrap(), Some(b"a2".to_vec()));
+    assert_eq!(election.leader().await.unwrap(), Some(b"a2".to_vec()));
+    let token = leading.fencing_token();
+    assert!(leading.resign().await.unwrap());
+    let (next_token, _second) = timeout(Duration::from_secs(10), campaigning)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(next_token > token);
+
+    // the resign may show before the next leader, or not at all
+    let mut next = observer.next().await.unwrap();
+    if next.is_none() {
+        next = observer.next().await.unwrap();
+    }
+    assert_eq!(next, Some(b"b".to_vec()));
+}
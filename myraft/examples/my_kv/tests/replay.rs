@@ -0,0 +1,77 @@
+mod common;
+
+use common::{next_index, temporary_app};
+use my_kv::kv_app::{KvApp, ReadRequest, WriteRequest, WriteResponse};
+use myraft::raft::RaftApp;
+
+async fn read(app: &KvApp, key: &[u8]) -> (Option<Vec<u8>>, u64) {
+    let key = key.to_vec();
+    let req = ReadRequest {
+        key,
+        revision: None,
+    };
+    let rsp = app.handle_read(req).await.unwrap();
+    (rsp.data, rsp.version)
+}
+
+fn put_if_absent(value: &[u8]) -> WriteRequest {
+    WriteRequest::PutIfAbsent {
+        key: b"k".to_vec(),
+        value: value.to_vec(),
+    }
+}
+
+fn succeeded(rsp: &WriteResponse) -> bool {
+    match rsp {
+        WriteResponse::Conditional { succeeded, .. } => *succeeded,
+        rsp => panic!("unconditional response {:?}", rsp),
+    }
+}
+
+#[tokio::test]
+async fn a_write_applied_again_is_answered_as_it_was() {
+    let app = temporary_app();
+    let index = next_index(&app);
+    let first = app.handle_write(index, put_if_absent(b"a")).await.unwrap();
+    assert!(succeeded(&first));
+
+    // its guard no longer holds, but it is not checked again
+    let again = app.handle_write(index, put_if_absent(b"a")).await.unwrap();
+    assert!(succeeded(&again));
+    assert_eq!(read(&app, b"k").await, (Some(b"a".to_vec()), 1));
+    assert_eq!(app.revision().unwrap(), index);
+
+    let put = || WriteRequest::Put {
+        key: b"k".to_vec(),
+        value: b"b".to_vec(),
+    };
+    let index = next_index(&app);
+    app.handle_write(index, put()).await.unwrap();
+    let again = app.handle_write(index, put()).await.unwrap();
+    assert!(matches!(again, WriteResponse::Put { prev: Some(prev) } if prev == b"a"));
+    assert_eq!(read(&app, b"k").await, (Some(b"b".to_vec()), 2));
+}
+
+#[tokio::test]
+async fn a_replay_past_the_kept_responses_changes_nothing() {
+    let leader = temporary_app();
+    let follower = temporary_app();
+    let index = next_index(&leader);
+    leader
+        .handle_write(index, put_if_absent(b"a"))
+        .await
+        .unwrap();
+    let mut snapshot = vec![];
+    let view = leader.snapshot_view().unwrap();
+    leader.make_snapshot(view, &mut snapshot).await.unwrap();
+    follower.handle_snapshot(&mut &snapshot[..]).await.unwrap();
+
+    // the follower never applied it, it answers from the state it installed
+    let rsp = follower
+        .handle_write(index, put_if_absent(b"b"))
+        .await
+        .unwrap();
+    assert!(!succeeded(&rsp));
+    assert_eq!(read(&follower, b"k").await, (Some(b"a".to_vec()), 1));
+    assert_eq!(follower.revision().unwrap(), index);
+}
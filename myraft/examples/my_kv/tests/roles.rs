@@ -0,0 +1,179 @@
+mod cluster;
+
+use my_kv::kv_app::{KvApp, ReadRequest, WriteRequest};
+use my_kv::service::MyKvRaft;
+use myraft::chaos::{ChaosSchedule, ChaosStep, Fault};
+use myraft::raft::NodeRole;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+const ROLES: [NodeRole; 4] = [
+    NodeRole::Voter,
+    NodeRole::Voter,
+    NodeRole::Witness,
+    NodeRole::Learner,
+];
+const WITNESS: usize = 2;
+const LEARNER: usize = 3;
+
+fn put(key: &[u8], value: &[u8]) -> WriteRequest {
+    WriteRequest::Put {
+        key: key.to_vec(),
+        value: value.to_vec(),
+    }
+}
+
+fn read(key: &[u8]) -> ReadRequest {
+    ReadRequest {
+        key: key.to_vec(),
+        revision: None,
+    }
+}
+
+async fn wait_for(what: &str, mut done: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while !done() {
+        assert!(Instant::now() < deadline, "{} did not happen", what);
+        sleep(Duration::from_millis(100)).await;
+    }
+}
+
+// async-raft 0.6 catches a learner that fell behind up to the commit index
+// only, the entries after it go out with the next write: the leader keeps
+// writing until the learner has `key`
+async fn learner_has(leader: &MyKvRaft, learner: &KvApp, key: &[u8], value: &[u8]) {
+    let deadline = Instant::now() + Duration::from_secs(30);
+    loop {
+        let read = learner.handle_read(read(key)).await.unwrap();
+        if read.data.as_deref() == Some(value) {
+            return;
+        }
+        assert!(
+            Instant::now() < deadline,
+            "the learner did not get {:?}",
+            key
+        );
+        leader.client_write(put(b"tick", b"")).await.unwrap();
+        sleep(Duration::from_millis(100)).await;
+    }
+}
+
+// every call the node sends is lost
+fn isolated() -> ChaosSchedule {
+    ChaosSchedule {
+        steps: vec![ChaosStep {
+            duration_ms: 60_000,
+            faults: vec![Fault {
+                drop: 1.0,
+                ..Default::default()
+            }],
+        }],
+        repeat: false,
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn a_learner_gets_the_writes_and_a_witness_only_votes() {
+    let ids = [111, 112, 113, 114];
+    let nodes: Vec<_> = ids.iter().copied().zip(ROLES.iter().copied()).collect();
+    let cluster = cluster::start_with_roles(&nodes).await;
+    assert_ne!(cluster.leader, WITNESS);
+    let members = cluster.nodes[cluster.leader].metrics().membership_config;
+    assert_eq!(members.members, [111, 112, 113].iter().copied().collect());
+
+    let leader = &cluster.nodes[cluster.leader];
+    leader.client_write(put(b"k", b"a")).await.unwrap();
+    learner_has(leader, &cluster.apps[LEARNER], b"k", b"a").await;
+    // the witness has the log, not the data
+    let value = cluster.apps[WITNESS].handle_read(read(b"k")).await.unwrap();
+    assert_eq!(value.data, None);
+    assert!(cluster.nodes[WITNESS]
+        .client_write(put(b"k", b"b"))
+        .await
+        .is_err());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn a_new_leader_takes_the_learner_over_and_the_witness_never_leads() {
+    let ids = [121, 122, 123, 124];
+    let nodes: Vec<_> = ids.iter().copied().zip(ROLES.iter().copied()).collect();
+    let cluster = cluster::start_with_roles(&nodes).await;
+    let old = cluster.leader;
+    let other = 1 - old;
+    let term = cluster.nodes[old].metrics().current_term;
+
+    cluster.nodes[old].set_chaos(Some(isolated()));
+    let next = &cluster.nodes[other];
+    wait_for("an election", || {
+        next.metrics().current_leader == Some(ids[other])
+    })
+    .await;
+    cluster.nodes[old].set_chaos(None);
+    let witness = &cluster.nodes[WITNESS];
+    wait_for("the witness following the new leader", || {
+        let metrics = witness.metrics();
+        metrics.current_leader == Some(ids[other]) && metrics.current_term > term
+    })
+    .await;
+
+    // the new leader replicates to the learner its predecessor added
+    next.client_write(put(b"k", b"a")).await.unwrap();
+    learner_has(next, &cluster.apps[LEARNER], b"k", b"a").await;
+    let learner = cluster.nodes[LEARNER].metrics();
+    assert_eq!(learner.current_leader, Some(ids[other]));
+    assert!(!learner.membership_config.contains(&ids[LEARNER]));
+    for node in &cluster.nodes {
+        assert_ne!(node.metrics().current_leader, Some(ids[WITNESS]));
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn a_write_the_witness_acked_is_on_the_leader_alone_until_it_is_back() {
+    let ids = [131, 132, 133, 134];
+    let nodes: Vec<_> = ids.iter().copied().zip(ROLES.iter().copied()).collect();
+    let cluster = cluster::start_with_roles(&nodes).await;
+    let (old, other) = (cluster.leader, 1 - cluster.leader);
+    let leader = &cluster.nodes[old];
+
+    // the other voter is cut off, the leader commits with the witness's ack
+    cluster.nodes[other].set_chaos(Some(isolated()));
+    let mut cut = isolated();
+    cut.steps[0].faults[0].target = Some(ids[other]);
+    leader.set_chaos(Some(cut));
+    leader.client_write(put(b"k", b"a")).await.unwrap();
+    for i in [other, WITNESS] {
+        let value = cluster.apps[i].handle_read(read(b"k")).await.unwrap();
+        assert_eq!(value.data, None);
+    }
+
+    // without the leader, the witness's log keeps the voter missing the write
+    // from being elected
+    leader.set_chaos(Some(isolated()));
+    cluster.nodes[other].set_chaos(None);
+    sleep(Duration::from_secs(3)).await;
+    for node in &cluster.nodes {
+        assert_ne!(node.metrics().current_leader, Some(ids[other]));
+    }
+
+    // the write comes back with the leader's disk
+    leader.set_chaos(None);
+    let deadline = Instant::now() + Duration::from_secs(30);
+    loop {
+        let value = cluster.apps[other].handle_read(read(b"k")).await.unwrap();
+        if value.data.as_deref() == Some(&b"a"[..]) {
+            break;
+        }
+        assert!(
+            Instant::now() < deadline,
+            "the other voter did not get the write"
+        );
+        // the entries after the commit index go out with the next write
+        if let Some(i) = cluster.nodes.iter().position(|node| {
+            let metrics = node.metrics();
+            metrics.current_leader == Some(metrics.id)
+        }) {
+            let _ = cluster.nodes[i].client_write(put(b"tick", b"")).await;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+}
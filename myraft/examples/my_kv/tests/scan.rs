@@ -0,0 +1,97 @@
+mod common;
+
+use common::{next_index, temporary_app};
+use my_kv::kv_app::{KvApp, ScanRequest, WriteRequest};
+use myraft::raft::RaftApp;
+
+async fn app_with(keys: &[&str]) -> KvApp {
+    let app = temporary_app();
+    for key in keys {
+        let (key, value) = (key.as_bytes().to_vec(), key.as_bytes().to_vec());
+        app.handle_write(next_index(&app), WriteRequest::Put { key, value })
+            .await
+            .unwrap();
+    }
+    app
+}
+
+fn keys(app: &KvApp, req: &ScanRequest) -> Vec<String> {
+    app.scan(req)
+        .unwrap()
+        .map(|pair| String::from_utf8(pair.unwrap().0).unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn scans_a_prefix_within_the_bounds() {
+    let app = app_with(&["a", "b/1", "b/2", "b/3", "b\u{7f}", "c"]).await;
+    let req = ScanRequest {
+        prefix: b"b/".to_vec(),
+        ..Default::default()
+    };
+    assert_eq!(keys(&app, &req), ["b/1", "b/2", "b/3"]);
+    let req = ScanRequest {
+        start: b"b/2".to_vec(),
+        end: Some(b"c".to_vec()),
+        reverse: true,
+        ..req
+    };
+    assert_eq!(keys(&app, &req), ["b/3", "b/2"]);
+    let req = ScanRequest {
+        start: b"c".to_vec(),
+        end: Some(b"a".to_vec()),
+        ..Default::default()
+    };
+    assert!(keys(&app, &req).is_empty());
+}
+
+#[tokio::test]
+async fn pages_go_on_from_the_last_key() {
+    let app = app_with(&["1", "2", "3", "4", "5"]).await;
+    for (reverse, expected) in [
+        (false, ["1", "2", "3", "4", "5"]),
+        (true, ["5", "4", "3", "2", "1"]),
+    ] {
+        let mut req = ScanRequest {
+            limit: Some(2),
+            reverse,
+            ..Default::default()
+        };
+        let mut scanned = vec![];
+        loop {
+            let page = keys(&app, &req);
+            match page.last() {
+                Some(last) => req.after = Some(last.as_bytes().to_vec()),
+                None => break,
+            }
+            scanned.extend(page);
+        }
+        assert_eq!(scanned, expected);
+    }
+}
+
+#[tokio::test]
+async fn a_scan_reads_the_keys_as_they_were_when_it_started() {
+    let app = app_with(&["a", "b", "c"]).await;
+    let pairs = app.scan(&ScanRequest::default()).unwrap();
+    let writes = vec![
+        WriteRequest::Put {
+            key: b"a".to_vec(),
+            value: b"x".to_vec(),
+        },
+        WriteRequest::Delete { key: b"b".to_vec() },
+        WriteRequest::Put {
+            key: b"d".to_vec(),
+            value: b"d".to_vec(),
+        },
+    ];
+    for req in writes {
+        app.handle_write(next_index(&app), req).await.unwrap();
+    }
+    let pairs: Vec<_> = pairs.map(Result::unwrap).collect();
+    let expected: Vec<_> = ["a", "b", "c"]
+        .iter()
+        .map(|key| (key.as_bytes().to_vec(), key.as_bytes().to_vec()))
+        .collect();
+    assert_eq!(pairs, expected);
+}
@@ -0,0 +1,124 @@
+mod common;
+
+use common::{next_index, temporary_app};
+use my_kv::kv_app::{KvApp, ReadRequest, WriteRequest};
+use myraft::raft::RaftApp;
+use std::env;
+use std::fs;
+
+async fn insert(app: &KvApp, key: u64, value: &str) {
+    let value = value.to_string();
+    app.handle_write(next_index(app), WriteRequest::Insert { key, value })
+        .await
+        .unwrap();
+}
+
+async fn read(app: &KvApp, key: u64) -> Option<String> {
+    let req = ReadRequest::typed(&key).unwrap();
+    app.handle_read(req).await.unwrap().typed().unwrap()
+}
+
+async fn snapshot(app: &KvApp) -> Vec<u8> {
+    let mut snapshot = vec![];
+    let view = app.snapshot_view().unwrap();
+    app.make_snapshot(view, &mut snapshot).await.unwrap();
+    snapshot
+}
+
+#[tokio::test]
+async fn install_drops_keys_deleted_on_the_leader() {
+    let leader = temporary_app();
+    let follower = temporary_app();
+    for app in [&leader, &follower] {
+        insert(app, 1, "a").await;
+        insert(app, 2, "b").await;
+    }
+    leader
+        .handle_write(next_index(&leader), WriteRequest::Remove { key: 1 })
+        .await
+        .unwrap();
+    insert(&leader, 3, "c").await;
+
+    let snapshot = snapshot(&leader).await;
+    follower.handle_snapshot(&mut &snapshot[..]).await.unwrap();
+    assert_eq!(read(&follower, 1).await, None);
+    assert_eq!(read(&follower, 2).await, Some("b".to_string()));
+    assert_eq!(read(&follower, 3).await, Some("c".to_string()));
+}
+
+#[tokio::test]
+async fn installed_state_survives_a_restart() {
+    let path = env::temp_dir().join(format!("my_kv-snapshot-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&path);
+    let leader = temporary_app();
+    insert(&leader, 2, "b").await;
+    let snapshot = snapshot(&leader).await;
+    {
+        let follower = KvApp::new(sled::open(&path).unwrap()).unwrap();
+        insert(&follower, 1, "a").await;
+        follower.handle_snapshot(&mut &snapshot[..]).await.unwrap();
+    }
+    let follower = KvApp::new(sled::open(&path).unwrap()).unwrap();
+    assert_eq!(read(&follower, 1).await, None);
+    assert_eq!(read(&follower, 2).await, Some("b".to_string()));
+    drop(follower);
+    fs::remove_dir_all(&path).unwrap();
+}
+
+#[tokio::test]
+async fn a_store_of_before_revisions_is_migrated_at_open() {
+    let path = env::temp_dir().join(format!("my_kv-upgrade-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&path);
+    {
+        // bare values in the default tree, as the first releases kept them
+        let db = sled::open(&path).unwrap();
+        for (key, value) in [(1u64, "a"), (2, "b")] {
+            let k = bincode::serialize(&key).unwrap();
+            db.insert(k, bincode::serialize(value).unwrap()).unwrap();
+        }
+        db.flush().unwrap();
+    }
+    {
+        let app = KvApp::new(sled::open(&path).unwrap()).unwrap();
+        assert_eq!(read(&app, 1).await, Some("a".to_string()));
+        assert_eq!(read(&app, 2).await, Some("b".to_string()));
+        insert(&app, 3, "c").await;
+    }
+    let db = sled::open(&path).unwrap();
+    assert!(db.is_empty());
+    let app = KvApp::new(db).unwrap();
+    assert_eq!(read(&app, 1).await, Some("a".to_string()));
+    assert_eq!(read(&app, 3).await, Some("c".to_string()));
+    drop(app);
+    fs::remove_dir_all(&path).unwrap();
+}
+
+#[tokio::test]
+async fn byte_keys_and_values_go_through_a_snapshot() {
+    let leader = temporary_app();
+    let follower = temporary_app();
+    let (key, value) = (b"user/\x00\xff".to_vec(), vec![0, 0xff, 7]);
+    leader
+        .handle_write(
+            next_index(&leader),
+            WriteRequest::Put {
+                key: key.clone(),
+                value: value.clone(),
+            },
+        )
+        .await
+        .unwrap();
+    insert(&leader, 1, "a").await;
+
+    let snapshot = snapshot(&leader).await;
+    follower.handle_snapshot(&mut &snapshot[..]).await.unwrap();
+    let rsp = follower
+        .handle_read(ReadRequest {
+            key,
+            revision: None,
+        })
+        .await
+        .unwrap();
+    assert_eq!(rsp.data, Some(value));
+    assert_eq!(read(&follower, 1).await, Some("a".to_string()));
+}
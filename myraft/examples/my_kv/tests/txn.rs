@@ -0,0 +1,106 @@
+mod common;
+
+use common::{next_index, temporary_app};
+use my_kv::kv_app::WriteResponse;
+use my_kv::kv_app::{Condition, Guard, KvApp, ReadRequest, TxnOp, TxnResult, WriteRequest};
+use myraft::raft::RaftApp;
+
+fn put(key: &str, value: &str) -> TxnOp {
+    TxnOp::Put {
+        key: key.as_bytes().to_vec(),
+        value: value.as_bytes().to_vec(),
+    }
+}
+
+fn get(key: &str) -> TxnOp {
+    TxnOp::Get {
+        key: key.as_bytes().to_vec(),
+    }
+}
+
+fn guard(key: &str, condition: Condition) -> Guard {
+    let key = key.as_bytes().to_vec();
+    Guard { key, condition }
+}
+
+async fn txn(
+    app: &KvApp,
+    guards: Vec<Guard>,
+    success: Vec<TxnOp>,
+    failure: Vec<TxnOp>,
+) -> (bool, Vec<TxnResult>) {
+    let req = WriteRequest::Txn {
+        guards,
+        success,
+        failure,
+    };
+    match app.handle_write(next_index(app), req).await.unwrap() {
+        WriteResponse::Txn {
+            succeeded, results, ..
+        } => (succeeded, results),
+        rsp => panic!("not a txn response {:?}", rsp),
+    }
+}
+
+async fn read(app: &KvApp, key: &str) -> Option<Vec<u8>> {
+    let key = key.as_bytes().to_vec();
+    app.handle_read(ReadRequest {
+        key,
+        revision: None,
+    })
+    .await
+    .unwrap()
+    .data
+}
+
+#[tokio::test]
+async fn runs_the_branch_the_guards_choose() {
+    let app = temporary_app();
+    let (succeeded, _) = txn(&app, vec![], vec![put("a", "1"), put("b", "1")], vec![]).await;
+    assert!(succeeded);
+
+    // moves one from a to b only while a still has it
+    let guards = || {
+        vec![
+            guard("a", Condition::Value(Some(b"1".to_vec()))),
+            guard("b", Condition::Exists),
+        ]
+    };
+    let transfer = || vec![put("a", "0"), put("b", "2"), get("b")];
+    let (succeeded, results) = txn(&app, guards(), transfer(), vec![get("a")]).await;
+    assert!(succeeded);
+    assert_eq!(
+        results[2],
+        TxnResult::Get {
+            value: Some(b"2".to_vec()),
+            version: 2,
+        }
+    );
+    let (succeeded, results) = txn(&app, guards(), transfer(), vec![get("a")]).await;
+    assert!(!succeeded);
+    assert_eq!(
+        results,
+        vec![TxnResult::Get {
+            value: Some(b"0".to_vec()),
+            version: 2,
+        }]
+    );
+    assert_eq!(read(&app, "b").await, Some(b"2".to_vec()));
+}
+
+#[tokio::test]
+async fn a_snapshot_taken_before_sees_none_of_it() {
+    let app = temporary_app();
+    txn(&app, vec![], vec![put("a", "1"), put("b", "1")], vec![]).await;
+    let view = app.snapshot_view().unwrap();
+    let ops = vec![put("a", "2"), TxnOp::Delete { key: b"b".to_vec() }];
+    txn(&app, vec![guard("b", Condition::Version(1))], ops, vec![]).await;
+    let mut snapshot = vec![];
+    app.make_snapshot(view, &mut snapshot).await.unwrap();
+
+    let follower = temporary_app();
+    follower.handle_snapshot(&mut &snapshot[..]).await.unwrap();
+    assert_eq!(read(&follower, "a").await, Some(b"1".to_vec()));
+    assert_eq!(read(&follower, "b").await, Some(b"1".to_vec()));
+    assert_eq!(read(&app, "b").await, None);
+}
@@ -0,0 +1,161 @@
+mod common;
+
+use common::{next_index, temporary_app};
+use my_kv::kv_app::{KvApp, RevisionCompacted, TxnOp, WriteRequest};
+use my_kv::watch::{Watch, WatchRequest};
+use myraft::raft::RaftApp;
+
+async fn write(app: &KvApp, req: WriteRequest) -> u64 {
+    let index = next_index(app);
+    app.handle_write(index, req).await.unwrap();
+    index
+}
+
+fn put(key: &str, value: &str) -> WriteRequest {
+    let (key, value) = (key.as_bytes().to_vec(), value.as_bytes().to_vec());
+    WriteRequest::Put { key, value }
+}
+
+fn delete(key: &str) -> WriteRequest {
+    let key = key.as_bytes().to_vec();
+    WriteRequest::Delete { key }
+}
+
+fn watch(app: &KvApp, key: &str, prefix: bool, start_revision: Option<u64>) -> Watch {
+    let key = key.as_bytes().to_vec();
+    let req = WatchRequest {
+        key,
+        prefix,
+        start_revision,
+    };
+    app.watch(req).unwrap()
+}
+
+// the revision, and the key and value of each change, of the next write
+async fn next(watch: &mut Watch) -> (u64, Vec<(String, Option<String>)>) {
+    let events = watch.next().await.unwrap();
+    let changes = events
+        .iter()
+        .map(|event| {
+            let key = String::from_utf8(event.key.clone()).unwrap();
+            let value = event.value.clone().map(|v| String::from_utf8(v).unwrap());
+            (key, value)
+        })
+        .collect();
+    (events[0].revision, changes)
+}
+
+fn change(key: &str, value: Option<&str>) -> (String, Option<String>) {
+    (key.to_string(), value.map(str::to_string))
+}
+
+#[tokio::test]
+async fn replays_the_history_then_follows_the_writes() {
+    let app = temporary_app();
+    let first = write(&app, put("a/1", "x")).await;
+    write(&app, put("b/1", "y")).await;
+    let deleted = write(&app, delete("a/1")).await;
+
+    let mut from_start = watch(&app, "a/", true, Some(first));
+    let mut from_now = watch(&app, "a/", true, None);
+    let put_again = write(&app, put("a/2", "z")).await;
+
+    assert_eq!(
+        next(&mut from_start).await,
+        (first, vec![change("a/1", Some("x"))])
+    );
+    assert_eq!(
+        next(&mut from_start).await,
+        (deleted, vec![change("a/1", None)])
+    );
+    for watch in [&mut from_start, &mut from_now] {
+        assert_eq!(
+            next(watch).await,
+            (put_again, vec![change("a/2", Some("z"))])
+        );
+    }
+}
+
+#[tokio::test]
+async fn a_transaction_is_one_batch_sorted_by_key() {
+    let app = temporary_app();
+    let mut watch = watch(&app, "", true, None);
+    let txn = |value: &str| TxnOp::Put {
+        key: b"b".to_vec(),
+        value: value.as_bytes().to_vec(),
+    };
+    let req = WriteRequest::Txn {
+        guards: vec![],
+        success: vec![
+            txn("1"),
+            TxnOp::Put {
+                key: b"a".to_vec(),
+                value: b"1".to_vec(),
+            },
+            txn("2"),
+        ],
+        failure: vec![],
+    };
+    let revision = write(&app, req).await;
+
+    let changes = vec![change("a", Some("1")), change("b", Some("2"))];
+    assert_eq!(next(&mut watch).await, (revision, changes));
+}
+
+#[tokio::test]
+async fn a_key_watch_skips_the_other_keys() {
+    let app = temporary_app();
+    let mut watch = watch(&app, "k", false, None);
+    write(&app, put("k2", "x")).await;
+    let revision = write(&app, put("k", "y")).await;
+    assert_eq!(
+        next(&mut watch).await,
+        (revision, vec![change("k", Some("y"))])
+    );
+}
+
+#[tokio::test]
+async fn a_watch_from_a_compacted_revision_fails() {
+    let app = temporary_app();
+    let first = write(&app, put("k", "a")).await;
+    let second = write(&app, put("k", "b")).await;
+    let req = WriteRequest::Compact { revision: second };
+    write(&app, req).await;
+
+    let req = WatchRequest {
+        key: b"k".to_vec(),
+        prefix: false,
+        start_revision: Some(first),
+    };
+    let err = app.watch(req).err().unwrap();
+    let compacted = err.downcast_ref::<RevisionCompacted>().unwrap();
+    assert_eq!(compacted.oldest, second + 1);
+
+    let mut watch = watch(&app, "k", false, Some(second + 1));
+    let third = write(&app, put("k", "c")).await;
+    assert_eq!(
+        next(&mut watch).await,
+        (third, vec![change("k", Some("c"))])
+    );
+}
+
+#[tokio::test]
+async fn a_snapshot_install_cuts_the_watches_off() {
+    let leader = temporary_app();
+    let follower = temporary_app();
+    let mut watch = watch(&follower, "k", false, None);
+    let revision = write(&leader, put("k", "a")).await;
+
+    let mut snapshot = vec![];
+    let view = leader.snapshot_view().unwrap();
+    leader.make_snapshot(view, &mut snapshot).await.unwrap();
+    follower.handle_snapshot(&mut &snapshot[..]).await.unwrap();
+    assert!(watch.next().await.is_err());
+
+    // resumed, it replays what the snapshot brought
+    let mut watch = self::watch(&follower, "k", false, Some(revision));
+    assert_eq!(
+        next(&mut watch).await,
+        (revision, vec![change("k", Some("a"))])
+    );
+}
@@ -0,0 +1,259 @@
+//! Linearizability checking of histories, the way Knossos and Porcupine do it.
+//!
+//! The search is Wing & Gong's, with Lowe's memoization of the (linearized
+//! operations, state) pairs already explored. Operations of different
+//! partitions (e.g. keys) are checked independently.
+//!
+//! A failing partition is shrunk by dropping operations as long as it stays
+//! not linearizable, except those whose effect a remaining operation observed:
+//! dropping the write of a value that was read would only leave a read of a
+//! value nobody wrote, which explains nothing.
+
+use crate::history::{Operation, Outcome};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::{self, Debug, Display};
+use std::hash::Hash;
+
+/// The sequential specification histories are checked against.
+pub trait Model {
+    type State: Clone + Eq + Hash + Debug;
+    type Input: Clone + Debug;
+    type Output: Clone + Debug;
+
+    fn init(&self) -> Self::State;
+
+    /// Operations of different partitions never affect each other.
+    fn partition(&self, _input: &Self::Input) -> u64 {
+        0
+    }
+
+    /// State after running `input` on `state` returned `output`, or `None` if
+    /// it could not have. `output` is `None` for operations of unknown outcome.
+    fn step(
+        &self,
+        state: &Self::State,
+        input: &Self::Input,
+        output: Option<&Self::Output>,
+    ) -> Option<Self::State>;
+
+    /// Whether `output` may show that `other` took effect, e.g. it is the unique
+    /// value `other` wrote. Counterexamples only drop operations nothing
+    /// observes, so when in doubt this has to answer true.
+    fn observes(&self, _output: &Self::Output, _other: &Self::Input) -> bool {
+        false
+    }
+}
+
+// the operations of one partition, borrowed from the history
+type Ops<'a, M> = Vec<&'a Operation<<M as Model>::Input, <M as Model>::Output>>;
+
+/// Operations of one partition that no order explains.
+#[derive(Debug, Clone)]
+pub struct Counterexample<I, O> {
+    pub partition: u64,
+    /// in invoke order
+    pub operations: Vec<Operation<I, O>>,
+    /// operations of the partition dropped while shrinking
+    pub dropped: usize,
+}
+
+impl<I: Debug, O: Debug> Display for Counterexample<I, O> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "no linearization of partition {} ({} of its operations dropped):",
+            self.partition, self.dropped
+        )?;
+        for op in &self.operations {
+            let ret = match op.ret {
+                Some(ret) => ret.to_string(),
+                None => "-".to_string(),
+            };
+            writeln!(
+                f,
+                "  client {} [{}, {}] {:?} -> {:?}",
+                op.client, op.call, ret, op.input, op.outcome
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum CheckResult<I, O> {
+    Linearizable,
+    NotLinearizable(Counterexample<I, O>),
+}
+
+impl<I, O> CheckResult<I, O> {
+    pub fn is_linearizable(&self) -> bool {
+        matches!(self, CheckResult::Linearizable)
+    }
+}
+
+pub fn check<M: Model>(
+    model: &M,
+    history: &[Operation<M::Input, M::Output>],
+) -> CheckResult<M::Input, M::Output> {
+    let mut partitions: BTreeMap<u64, Ops<M>> = BTreeMap::new();
+    // failed operations took no effect, nothing has to explain them
+    for op in history
+        .iter()
+        .filter(|op| !matches!(op.outcome, Outcome::Fail))
+    {
+        partitions
+            .entry(model.partition(&op.input))
+            .or_default()
+            .push(op);
+    }
+    for (partition, mut ops) in partitions {
+        if linearizable(model, &ops) {
+            continue;
+        }
+        let total = ops.len();
+        ops = shrink(model, ops);
+        ops.sort_by_key(|op| op.call);
+        return CheckResult::NotLinearizable(Counterexample {
+            partition,
+            dropped: total - ops.len(),
+            operations: ops.into_iter().cloned().collect(),
+        });
+    }
+    CheckResult::Linearizable
+}
+
+fn shrink<'a, M: Model>(model: &M, mut ops: Ops<'a, M>) -> Ops<'a, M> {
+    let observed = |ops: &[&Operation<M::Input, M::Output>], i: usize| {
+        ops.iter().any(|op| match &op.outcome {
+            Outcome::Ok(output) => model.observes(output, &ops[i].input),
+            _ => false,
+        })
+    };
+    // later operations go first, they are the ones nothing depends on
+    loop {
+        let mut shrunk = false;
+        let mut i = ops.len();
+        while i > 0 {
+            i -= 1;
+            if observed(&ops, i) {
+                continue;
+            }
+            let mut fewer = ops.clone();
+            fewer.remove(i);
+            if !linearizable(model, &fewer) {
+                ops = fewer;
+                shrunk = true;
+            }
+        }
+        if !shrunk {
+            return ops;
+        }
+    }
+}
+
+const NIL: usize = usize::MAX;
+
+struct Event {
+    op: usize,
+    is_call: bool,
+}
+
+fn linearizable<M: Model>(model: &M, ops: &[&Operation<M::Input, M::Output>]) -> bool {
+    if ops.is_empty() {
+        return true;
+    }
+    let output = |i: usize| match &ops[i].outcome {
+        Outcome::Ok(output) => Some(output),
+        _ => None,
+    };
+    // an operation of unknown outcome may take effect any time after its call
+    let mut timed: Vec<(u64, Event)> = vec![];
+    for (i, op) in ops.iter().enumerate() {
+        let ret = match (&op.outcome, op.ret) {
+            (Outcome::Ok(_), Some(ret)) => ret,
+            _ => u64::MAX,
+        };
+        timed.push((
+            op.call,
+            Event {
+                op: i,
+                is_call: true,
+            },
+        ));
+        timed.push((
+            ret,
+            Event {
+                op: i,
+                is_call: false,
+            },
+        ));
+    }
+    timed.sort_by_key(|(time, event)| (*time, event.op));
+    let events: Vec<Event> = timed.into_iter().map(|(_, event)| event).collect();
+
+    // doubly linked list of the events left, `head` is its sentinel
+    let head = events.len();
+    let mut next: Vec<usize> = (1..=events.len()).collect();
+    next[events.len() - 1] = NIL;
+    next.push(0);
+    let mut prev: Vec<usize> = (0..events.len())
+        .map(|i| if i == 0 { head } else { i - 1 })
+        .collect();
+    let mut ret_of = vec![0; ops.len()];
+    for (i, event) in events.iter().enumerate() {
+        if !event.is_call {
+            ret_of[event.op] = i;
+        }
+    }
+    let unlink = |next: &mut Vec<usize>, prev: &mut Vec<usize>, i: usize| {
+        next[prev[i]] = next[i];
+        if next[i] != NIL {
+            prev[next[i]] = prev[i];
+        }
+    };
+    let relink = |next: &mut Vec<usize>, prev: &mut Vec<usize>, i: usize| {
+        if next[i] != NIL {
+            prev[next[i]] = i;
+        }
+        next[prev[i]] = i;
+    };
+
+    let mut linearized = vec![0u64; ops.len().div_ceil(64)];
+    let mut explored: HashSet<(Vec<u64>, M::State)> = HashSet::new();
+    let mut stack: Vec<(usize, M::State)> = vec![];
+    let mut state = model.init();
+    let mut cur = next[head];
+    while next[head] != NIL {
+        let event = &events[cur];
+        if event.is_call {
+            let op = ops[event.op];
+            if let Some(after) = model.step(&state, &op.input, output(event.op)) {
+                linearized[event.op / 64] |= 1 << (event.op % 64);
+                if explored.insert((linearized.clone(), after.clone())) {
+                    stack.push((cur, state));
+                    state = after;
+                    // lifts the operation out of the history
+                    unlink(&mut next, &mut prev, cur);
+                    unlink(&mut next, &mut prev, ret_of[event.op]);
+                    cur = next[head];
+                    continue;
+                }
+                linearized[event.op / 64] &= !(1 << (event.op % 64));
+            }
+            cur = next[cur];
+        } else {
+            // an operation returned before any order could place it: backtrack
+            let (call, before) = match stack.pop() {
+                Some(top) => top,
+                None => return false,
+            };
+            let op = events[call].op;
+            state = before;
+            linearized[op / 64] &= !(1 << (op % 64));
+            relink(&mut next, &mut prev, ret_of[op]);
+            relink(&mut next, &mut prev, call);
+            cur = next[call];
+        }
+    }
+    true
+}
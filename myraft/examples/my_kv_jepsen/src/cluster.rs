@@ -0,0 +1,126 @@
+//! A my_kv cluster whose nodes all run in the test process, without zookeeper.
+
+use anyhow::{anyhow, Result};
+use async_raft::{NodeId, State};
+use my_kv::kv_app::KvApp;
+use myraft::chaos::ChaosSchedule;
+use myraft::raft::{MyRaft, MyRaftBuilder, NodeInfo, NodeRole};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::io::ErrorKind;
+use std::net::TcpListener;
+use std::sync::{Arc, Once};
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+pub struct Node {
+    pub id: NodeId,
+    pub raft: MyRaft<KvApp>,
+    pub app: Arc<KvApp>,
+}
+
+pub struct Cluster {
+    pub nodes: Vec<Node>,
+}
+
+/// How long a leader has to keep leading for the cluster to be ready.
+const STABLE_FOR: Duration = Duration::from_millis(1000);
+
+static SCRATCH_DIR: Once = Once::new();
+
+// nodes keep their stores under the working directory; the tests of a process
+// share one scratch directory and tell their clusters apart by node ids
+fn enter_scratch_dir() {
+    SCRATCH_DIR.call_once(|| {
+        let dir = env::temp_dir().join(format!("my_kv_jepsen-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        env::set_current_dir(&dir).unwrap();
+    });
+}
+
+fn remove_dir(path: &str) -> Result<()> {
+    match fs::remove_dir_all(path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+fn free_local_addr() -> Result<String> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    Ok(listener.local_addr()?.to_string())
+}
+
+impl Cluster {
+    /// Starts a cluster of the voters `ids` on fresh stores. The first node
+    /// initializes it, the others are added once it leads.
+    pub async fn start(ids: &[NodeId]) -> Result<Self> {
+        enter_scratch_dir();
+        let mut infos = HashMap::new();
+        for id in ids {
+            let info = NodeInfo {
+                addr: free_local_addr()?,
+                role: NodeRole::Voter,
+            };
+            infos.insert(*id, info);
+        }
+        let mut nodes = vec![];
+        for (i, id) in ids.iter().enumerate() {
+            remove_dir(&format!("store/node_{}", id))?;
+            let kv_path = format!("kv_store/node_{}", id);
+            remove_dir(&kv_path)?;
+            let app = Arc::new(KvApp::new(sled::open(&kv_path)?)?);
+            let raft = MyRaftBuilder::new(*id, infos[id].addr.clone(), app.clone())
+                .build()
+                .await?;
+            raft.join_static(infos.clone(), i == 0).await;
+            nodes.push(Node { id: *id, raft, app });
+        }
+        Ok(Self { nodes })
+    }
+
+    /// Waits for a leader with every node in the membership that keeps leading
+    /// for a while, returns its id. The leader that grew the cluster steps
+    /// down right after, so the first one seen may not last.
+    pub async fn wait_ready(&self, timeout: Duration) -> Result<NodeId> {
+        let all: HashSet<NodeId> = self.nodes.iter().map(|node| node.id).collect();
+        let deadline = Instant::now() + timeout;
+        let mut stable = None;
+        while Instant::now() < deadline {
+            let leader = self.nodes.iter().find_map(|node| {
+                let metrics = node.raft.metrics();
+                let ready = metrics.state == State::Leader
+                    && metrics.membership_config.members == all
+                    && metrics.membership_config.members_after_consensus.is_none();
+                if ready {
+                    Some((node.id, metrics.current_term))
+                } else {
+                    None
+                }
+            });
+            stable = match (stable, leader) {
+                (Some((since, seen)), Some(now)) if seen == now => {
+                    if since + STABLE_FOR <= Instant::now() {
+                        return Ok(now.0);
+                    }
+                    Some((since, seen))
+                }
+                (_, Some(now)) => Some((Instant::now(), now)),
+                (_, None) => None,
+            };
+            sleep(Duration::from_millis(100)).await;
+        }
+        Err(anyhow!("cluster {:?} not ready after {:?}", all, timeout))
+    }
+
+    pub fn position(&self, id: NodeId) -> Option<usize> {
+        self.nodes.iter().position(|node| node.id == id)
+    }
+
+    /// Runs `schedule` on the raft calls of every node, `None` stops the chaos.
+    pub fn set_chaos(&self, schedule: Option<ChaosSchedule>) {
+        for node in &self.nodes {
+            node.raft.set_chaos(schedule.clone());
+        }
+    }
+}
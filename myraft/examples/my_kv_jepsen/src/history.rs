@@ -0,0 +1,76 @@
+//! Invoke/complete histories of the operations clients ran.
+
+use std::fmt::Debug;
+use std::sync::Mutex;
+
+/// How an operation ended, as seen by its client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome<O> {
+    /// it took effect and returned `O`
+    Ok(O),
+    /// it certainly took no effect
+    Fail,
+    /// it may or may not have taken effect, e.g. it timed out
+    Unknown,
+}
+
+/// One operation of a history. Times are the positions of the invoke and
+/// complete events in the history, so they are unique and ordered.
+#[derive(Debug, Clone)]
+pub struct Operation<I, O> {
+    pub client: u64,
+    pub input: I,
+    pub call: u64,
+    /// unset while the operation has not completed
+    pub ret: Option<u64>,
+    pub outcome: Outcome<O>,
+}
+
+/// A history recorded by concurrent clients.
+pub struct History<I, O> {
+    inner: Mutex<(u64, Vec<Operation<I, O>>)>,
+}
+
+impl<I: Clone + Debug, O: Clone + Debug> History<I, O> {
+    pub fn new() -> Self {
+        Self {
+            inner: Mutex::new((0, vec![])),
+        }
+    }
+
+    /// Records that `client` invoked `input`, returns the id to complete it with.
+    pub fn invoke(&self, client: u64, input: I) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        let call = inner.0;
+        inner.0 += 1;
+        inner.1.push(Operation {
+            client,
+            input,
+            call,
+            ret: None,
+            outcome: Outcome::Unknown,
+        });
+        inner.1.len() - 1
+    }
+
+    pub fn complete(&self, id: usize, outcome: Outcome<O>) {
+        let mut inner = self.inner.lock().unwrap();
+        let ret = inner.0;
+        inner.0 += 1;
+        let op = &mut inner.1[id];
+        op.ret = Some(ret);
+        op.outcome = outcome;
+    }
+
+    /// The operations recorded so far, in invoke order. Those not completed yet
+    /// have an unknown outcome.
+    pub fn operations(&self) -> Vec<Operation<I, O>> {
+        self.inner.lock().unwrap().1.clone()
+    }
+}
+
+impl<I: Clone + Debug, O: Clone + Debug> Default for History<I, O> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,105 @@
+//! my_kv as a map of independent registers, one per key.
+
+use crate::checker::Model;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KvInput {
+    Read {
+        key: u64,
+    },
+    Insert {
+        key: u64,
+        value: String,
+    },
+    Remove {
+        key: u64,
+    },
+    /// writes `value` if the key holds `expected`
+    Cas {
+        key: u64,
+        expected: Option<String>,
+        value: String,
+    },
+}
+
+impl KvInput {
+    pub fn key(&self) -> u64 {
+        match self {
+            KvInput::Read { key }
+            | KvInput::Insert { key, .. }
+            | KvInput::Remove { key }
+            | KvInput::Cas { key, .. } => *key,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KvOutput {
+    /// value read
+    Read(Option<String>),
+    /// value an insert or a remove replaced
+    Prev(Option<String>),
+    /// whether a cas wrote, and the value it found
+    Cas {
+        succeeded: bool,
+        current: Option<String>,
+    },
+}
+
+pub struct KvModel;
+
+impl Model for KvModel {
+    type State = Option<String>;
+    type Input = KvInput;
+    type Output = KvOutput;
+
+    fn init(&self) -> Self::State {
+        None
+    }
+
+    fn partition(&self, input: &KvInput) -> u64 {
+        input.key()
+    }
+
+    fn step(
+        &self,
+        state: &Option<String>,
+        input: &KvInput,
+        output: Option<&KvOutput>,
+    ) -> Option<Option<String>> {
+        let (after, expected) = match input {
+            KvInput::Read { .. } => (state.clone(), KvOutput::Read(state.clone())),
+            KvInput::Insert { value, .. } => (Some(value.clone()), KvOutput::Prev(state.clone())),
+            KvInput::Remove { .. } => (None, KvOutput::Prev(state.clone())),
+            KvInput::Cas {
+                expected, value, ..
+            } => {
+                let succeeded = state == expected;
+                let after = match succeeded {
+                    true => Some(value.clone()),
+                    false => state.clone(),
+                };
+                let current = state.clone();
+                (after, KvOutput::Cas { succeeded, current })
+            }
+        };
+        match output {
+            Some(output) if *output != expected => None,
+            _ => Some(after),
+        }
+    }
+
+    // the workload writes unique values, but every remove leaves the same None
+    fn observes(&self, output: &KvOutput, other: &KvInput) -> bool {
+        let seen = match output {
+            KvOutput::Read(seen) | KvOutput::Prev(seen) => seen,
+            KvOutput::Cas { current, .. } => current,
+        };
+        match (seen, other) {
+            (Some(seen), KvInput::Insert { value, .. })
+            | (Some(seen), KvInput::Cas { value, .. }) => seen == value,
+            (None, KvInput::Remove { .. }) => true,
+            _ => false,
+        }
+    }
+}
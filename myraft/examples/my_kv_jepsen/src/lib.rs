@@ -0,0 +1,9 @@
+//! Jepsen-style testing of my_kv: concurrent clients record a history of their
+//! operations against a cluster under injected faults, and the history is
+//! checked for linearizability.
+
+pub mod checker;
+pub mod cluster;
+pub mod history;
+pub mod kv_model;
+pub mod workload;
@@ -0,0 +1,220 @@
+//! Concurrent clients running random reads and writes against a cluster.
+
+use crate::cluster::Cluster;
+use crate::history::{History, Outcome};
+use crate::kv_model::{KvInput, KvOutput};
+use my_kv::kv_app::{decode_string, encode, ReadRequest, WriteRequest, WriteResponse};
+use myraft::raft::NotLeader;
+use myraft::ClientSession;
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{sleep, timeout};
+
+/// Attempts of an operation on different nodes before the client gives up.
+const ATTEMPTS: usize = 5;
+/// How long a client waits for one attempt.
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone)]
+pub struct Workload {
+    pub clients: u64,
+    pub ops_per_client: usize,
+    /// keys are drawn from `0..keys`; few keys make more contention
+    pub keys: u64,
+    /// share of reads, the rest are inserts, cas and a few removes
+    pub read_ratio: f64,
+    /// pause of a client between two operations, to spread them over the faults
+    pub think_time: Duration,
+}
+
+impl Default for Workload {
+    fn default() -> Self {
+        Self {
+            clients: 5,
+            ops_per_client: 50,
+            keys: 3,
+            read_ratio: 0.5,
+            think_time: Duration::from_millis(0),
+        }
+    }
+}
+
+/// Runs `workload` against `cluster` and returns the history it made.
+pub async fn run(cluster: Arc<Cluster>, workload: &Workload) -> History<KvInput, KvOutput> {
+    let history = Arc::new(History::new());
+    let mut handles = vec![];
+    for client in 0..workload.clients {
+        let (cluster, history, workload) = (cluster.clone(), history.clone(), workload.clone());
+        handles.push(tokio::spawn(async move {
+            let mut client = Client::new(client, client as usize % cluster.nodes.len());
+            for i in 0..workload.ops_per_client {
+                let input = client.next_input(&workload, i);
+                let id = history.invoke(client.id, input.clone());
+                let outcome = client.run(&cluster, input).await;
+                history.complete(id, outcome);
+                sleep(workload.think_time).await;
+            }
+        }));
+    }
+    for handle in handles {
+        handle.await.unwrap();
+    }
+    match Arc::try_unwrap(history) {
+        Ok(history) => history,
+        Err(_) => unreachable!("every client is done"),
+    }
+}
+
+struct Client {
+    id: u64,
+    // node the next attempt goes to
+    node: usize,
+    seq: u64,
+    // the last value the client saw of each key, what its cas expect
+    seen: HashMap<u64, Option<String>>,
+}
+
+impl Client {
+    fn new(id: u64, node: usize) -> Self {
+        Self {
+            id,
+            node,
+            seq: 0,
+            seen: HashMap::new(),
+        }
+    }
+
+    fn next_input(&self, workload: &Workload, i: usize) -> KvInput {
+        let mut rng = rand::thread_rng();
+        let key = rng.gen_range(0..workload.keys);
+        // unique values let the checker tell every write apart
+        let value = format!("{}-{}", self.id, i);
+        if rng.gen_bool(workload.read_ratio) {
+            KvInput::Read { key }
+        } else if rng.gen_bool(0.3) {
+            let expected = self.seen.get(&key).cloned().flatten();
+            KvInput::Cas {
+                key,
+                expected,
+                value,
+            }
+        } else if rng.gen_bool(0.85) {
+            KvInput::Insert { key, value }
+        } else {
+            KvInput::Remove { key }
+        }
+    }
+
+    async fn run(&mut self, cluster: &Cluster, input: KvInput) -> Outcome<KvOutput> {
+        let key = input.key();
+        let outcome = self.run_input(cluster, input).await;
+        if let Outcome::Ok(output) = &outcome {
+            let seen = match output {
+                KvOutput::Read(seen) | KvOutput::Prev(seen) => seen,
+                KvOutput::Cas { current, .. } => current,
+            };
+            self.seen.insert(key, seen.clone());
+        }
+        outcome
+    }
+
+    async fn run_input(&mut self, cluster: &Cluster, input: KvInput) -> Outcome<KvOutput> {
+        match input {
+            KvInput::Read { key } => self.read(cluster, key).await,
+            KvInput::Insert { key, value } => {
+                let req = WriteRequest::typed_insert(&key, &value).unwrap();
+                self.write(cluster, req).await
+            }
+            KvInput::Remove { key } => {
+                let req = WriteRequest::typed_remove(&key).unwrap();
+                self.write(cluster, req).await
+            }
+            KvInput::Cas {
+                key,
+                expected,
+                value,
+            } => {
+                let req = WriteRequest::CompareAndSwap {
+                    key: encode(&key).unwrap(),
+                    expected: expected.map(|expected| encode(&expected).unwrap()),
+                    new: encode(&value).unwrap(),
+                };
+                self.write(cluster, req).await
+            }
+        }
+    }
+
+    fn next_node(&mut self, cluster: &Cluster) {
+        self.node = (self.node + 1) % cluster.nodes.len();
+    }
+
+    // a read has no effect, so one that failed on every node simply failed
+    async fn read(&mut self, cluster: &Cluster, key: u64) -> Outcome<KvOutput> {
+        for _ in 0..ATTEMPTS {
+            let node = &cluster.nodes[self.node];
+            let read = async {
+                node.raft.read_barrier().await?;
+                node.app
+                    .handle_read(ReadRequest::typed(&key)?)
+                    .await?
+                    .typed()
+            };
+            match timeout(ATTEMPT_TIMEOUT, read).await {
+                Ok(Ok(data)) => return Outcome::Ok(KvOutput::Read(data)),
+                _ => self.next_node(cluster),
+            }
+        }
+        Outcome::Fail
+    }
+
+    // every attempt of a write carries the same session seq, so it is applied once
+    async fn write(&mut self, cluster: &Cluster, req: WriteRequest) -> Outcome<KvOutput> {
+        self.seq += 1;
+        let session = ClientSession {
+            client_id: self.id + 1,
+            seq: self.seq,
+        };
+        let mut maybe_applied = false;
+        for _ in 0..ATTEMPTS {
+            let node = &cluster.nodes[self.node];
+            let write = node.raft.client_write_in_session(session, req.clone());
+            match timeout(ATTEMPT_TIMEOUT, write).await {
+                Ok(Ok(WriteResponse::Put { prev })) | Ok(Ok(WriteResponse::Delete { prev })) => {
+                    return Outcome::Ok(KvOutput::Prev(prev.as_deref().map(decode_string)))
+                }
+                Ok(Ok(WriteResponse::Conditional {
+                    succeeded, value, ..
+                })) => {
+                    let current = value.as_deref().map(decode_string);
+                    return Outcome::Ok(KvOutput::Cas { succeeded, current });
+                }
+                Ok(Ok(rsp)) => unreachable!("typed response {:?} to a byte write", rsp),
+                Ok(Err(err)) => {
+                    match err.downcast_ref::<NotLeader>() {
+                        // never appended to any log
+                        Some(NotLeader { leader }) => {
+                            match leader.and_then(|leader| cluster.position(leader)) {
+                                Some(leader) if leader != self.node => self.node = leader,
+                                _ => self.next_node(cluster),
+                            }
+                            continue;
+                        }
+                        _ => maybe_applied = true,
+                    }
+                    self.next_node(cluster);
+                }
+                Err(_) => {
+                    maybe_applied = true;
+                    self.next_node(cluster);
+                }
+            }
+        }
+        if maybe_applied {
+            Outcome::Unknown
+        } else {
+            Outcome::Fail
+        }
+    }
+}
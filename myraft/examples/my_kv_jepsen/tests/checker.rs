@@ -0,0 +1,134 @@
+use my_kv_jepsen::checker::{check, CheckResult};
+use my_kv_jepsen::history::{Operation, Outcome};
+use my_kv_jepsen::kv_model::{KvInput, KvModel, KvOutput};
+
+fn op(
+    client: u64,
+    call: u64,
+    ret: Option<u64>,
+    input: KvInput,
+    outcome: Outcome<KvOutput>,
+) -> Operation<KvInput, KvOutput> {
+    Operation {
+        client,
+        input,
+        call,
+        ret,
+        outcome,
+    }
+}
+
+fn insert(key: u64, value: &str) -> KvInput {
+    KvInput::Insert {
+        key,
+        value: value.to_string(),
+    }
+}
+
+fn read(key: u64) -> KvInput {
+    KvInput::Read { key }
+}
+
+fn prev(value: Option<&str>) -> Outcome<KvOutput> {
+    Outcome::Ok(KvOutput::Prev(value.map(|v| v.to_string())))
+}
+
+fn got(value: Option<&str>) -> Outcome<KvOutput> {
+    Outcome::Ok(KvOutput::Read(value.map(|v| v.to_string())))
+}
+
+#[test]
+fn concurrent_read_may_see_either_value() {
+    for seen in [None, Some("a")] {
+        let history = vec![
+            op(0, 0, Some(3), insert(1, "a"), prev(None)),
+            op(1, 1, Some(2), read(1), got(seen)),
+        ];
+        assert!(check(&KvModel, &history).is_linearizable());
+    }
+}
+
+#[test]
+fn stale_read_is_caught() {
+    let history = vec![
+        op(0, 0, Some(1), insert(1, "a"), prev(None)),
+        op(1, 2, Some(3), read(1), got(None)),
+    ];
+    match check(&KvModel, &history) {
+        CheckResult::NotLinearizable(counterexample) => {
+            assert_eq!(counterexample.operations.len(), 2, "{}", counterexample)
+        }
+        CheckResult::Linearizable => panic!("a stale read went through"),
+    }
+}
+
+#[test]
+fn counterexample_is_minimal() {
+    let history = vec![
+        op(0, 0, Some(1), insert(1, "a"), prev(None)),
+        op(1, 2, Some(3), insert(2, "x"), prev(None)),
+        op(0, 4, Some(5), insert(1, "b"), prev(Some("a"))),
+        op(2, 6, Some(9), read(2), got(Some("x"))),
+        op(1, 7, Some(8), read(1), got(Some("a"))),
+        op(2, 10, Some(11), read(1), got(Some("b"))),
+    ];
+    match check(&KvModel, &history) {
+        CheckResult::NotLinearizable(counterexample) => {
+            assert_eq!(counterexample.partition, 1);
+            let calls: Vec<u64> = counterexample.operations.iter().map(|op| op.call).collect();
+            // "a" was read after "b" replaced it; the last read does not matter
+            assert_eq!(calls, vec![0, 4, 7], "{}", counterexample);
+            assert_eq!(counterexample.dropped, 1);
+        }
+        CheckResult::Linearizable => panic!("a stale read went through"),
+    }
+}
+
+#[test]
+fn unknown_write_may_take_effect_late() {
+    let history = vec![
+        op(0, 0, None, insert(1, "a"), Outcome::Unknown),
+        op(1, 1, Some(2), read(1), got(None)),
+        op(1, 3, Some(4), read(1), got(Some("a"))),
+    ];
+    assert!(check(&KvModel, &history).is_linearizable());
+}
+
+#[test]
+fn unknown_write_may_never_take_effect() {
+    let history = vec![
+        op(0, 0, Some(1), insert(1, "a"), Outcome::Unknown),
+        op(1, 2, Some(3), read(1), got(None)),
+    ];
+    assert!(check(&KvModel, &history).is_linearizable());
+}
+
+#[test]
+fn failed_write_takes_no_effect() {
+    let history = vec![
+        op(0, 0, Some(1), insert(1, "a"), Outcome::Fail),
+        op(1, 2, Some(3), read(1), got(Some("a"))),
+    ];
+    assert!(!check(&KvModel, &history).is_linearizable());
+}
+
+#[test]
+fn two_cas_on_the_same_value_cannot_both_win() {
+    let cas = |expected: Option<&str>, value: &str| KvInput::Cas {
+        key: 1,
+        expected: expected.map(|v| v.to_string()),
+        value: value.to_string(),
+    };
+    let won = |current: Option<&str>| {
+        Outcome::Ok(KvOutput::Cas {
+            succeeded: true,
+            current: current.map(|v| v.to_string()),
+        })
+    };
+    let history = vec![
+        op(0, 0, Some(1), insert(1, "a"), prev(None)),
+        op(1, 2, Some(5), cas(Some("a"), "b"), won(Some("a"))),
+        op(2, 3, Some(4), cas(Some("a"), "c"), won(Some("a"))),
+    ];
+    assert!(!check(&KvModel, &history).is_linearizable());
+}
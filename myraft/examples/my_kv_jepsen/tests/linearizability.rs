@@ -0,0 +1,98 @@
+use my_kv_jepsen::checker::{check, CheckResult};
+use my_kv_jepsen::cluster::Cluster;
+use my_kv_jepsen::history::Outcome;
+use my_kv_jepsen::kv_model::KvModel;
+use my_kv_jepsen::workload::{self, Workload};
+use myraft::chaos::{ChaosSchedule, ChaosStep, Fault};
+use std::sync::Arc;
+use std::time::Duration;
+
+// runs the workload while every node runs the chaos schedule given for it
+async fn check_workload(
+    ids: &[u64],
+    workload: Workload,
+    chaos: impl Fn(u64) -> Option<ChaosSchedule>,
+) {
+    let _ = env_logger::try_init();
+    let cluster = Arc::new(Cluster::start(ids).await.unwrap());
+    cluster.wait_ready(Duration::from_secs(30)).await.unwrap();
+    for node in &cluster.nodes {
+        node.raft.set_chaos(chaos(node.id));
+    }
+    let history = workload::run(cluster.clone(), &workload).await;
+    cluster.set_chaos(None);
+    let operations = history.operations();
+    let unknown = operations
+        .iter()
+        .filter(|op| op.outcome == Outcome::Unknown)
+        .count();
+    let failed = operations
+        .iter()
+        .filter(|op| op.outcome == Outcome::Fail)
+        .count();
+    println!(
+        "{} operations, {} unknown, {} failed",
+        operations.len(),
+        unknown,
+        failed
+    );
+    if let CheckResult::NotLinearizable(counterexample) = check(&KvModel, &operations) {
+        panic!("{}", counterexample);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn linearizable_without_faults() {
+    check_workload(&[101, 102, 103], Workload::default(), |_| None).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn linearizable_under_lossy_network() {
+    // lost, late, duplicated and reordered raft calls, then node 201 cut off
+    let lossy = Fault {
+        drop: 0.05,
+        duplicate: 0.05,
+        reorder: 0.05,
+        reorder_ms: 30,
+        jitter_ms: 20,
+        ..Default::default()
+    };
+    let cut_off = |target| Fault {
+        target,
+        drop: 1.0,
+        ..Default::default()
+    };
+    let schedule = |id| {
+        // node 201 loses all it sends, the others what they send to it
+        let partition = if id == 201 {
+            cut_off(None)
+        } else {
+            cut_off(Some(201))
+        };
+        Some(ChaosSchedule {
+            steps: vec![
+                ChaosStep {
+                    duration_ms: 3000,
+                    faults: vec![lossy.clone()],
+                },
+                ChaosStep {
+                    duration_ms: 2000,
+                    faults: vec![partition, lossy.clone()],
+                },
+                ChaosStep {
+                    duration_ms: 1000,
+                    faults: vec![],
+                },
+            ],
+            repeat: true,
+        })
+    };
+    // about 8s of operations, over every step of the schedule
+    let workload = Workload {
+        ops_per_client: 150,
+        keys: 5,
+        think_time: Duration::from_millis(50),
+        ..Default::default()
+    };
+    check_workload(&[201, 202, 203], workload, schedule).await;
+}
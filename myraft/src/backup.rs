@@ -0,0 +1,107 @@
+//! Point-in-time backups of a node, used to clone a cluster's data into a new one.
+//!
+//! A backup file is the magic, the u32 little endian version, then a
+//! length-prefixed `BackupHeader` followed by the state machine data up to the
+//! end of the file, so that neither writing nor reading it holds the data in
+//! memory. The version is 2, 1 was never released.
+
+use crate::session::SessionEntry;
+use anyhow::{anyhow, Result};
+use async_raft::raft::MembershipConfig;
+use async_raft::NodeId;
+use bincode::{deserialize, serialize};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+
+const BACKUP_MAGIC: &[u8; 8] = b"MYRAFTBK";
+pub(crate) const BACKUP_VERSION: u32 = 2;
+
+/// What a backup holds besides the state machine data, which follows it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BackupHeader {
+    /// unix time of the backup in seconds
+    pub created_at: u64,
+    /// node the backup was taken on
+    pub node_id: NodeId,
+    /// cluster of that node
+    pub cluster_id: u64,
+    /// last log applied to the state machine in the data
+    pub index: u64,
+    pub term: u64,
+    pub membership: MembershipConfig,
+    /// bincode of the log entries after `index`, in order
+    pub log_suffix: Vec<Vec<u8>>,
+    /// client sessions as of `index`
+    pub(crate) sessions: Vec<(u64, SessionEntry)>,
+}
+
+impl BackupHeader {
+    /// Writes what comes before the state machine data.
+    pub(crate) async fn write_to(&self, writer: &mut (impl AsyncWrite + Unpin)) -> Result<()> {
+        let header = serialize(self)?;
+        writer.write_all(BACKUP_MAGIC).await?;
+        writer.write_all(&BACKUP_VERSION.to_le_bytes()).await?;
+        writer.write_u64(header.len() as u64).await?;
+        writer.write_all(&header).await?;
+        Ok(())
+    }
+
+    /// Opens the backup at `path`: its header, and a reader of its data.
+    pub(crate) async fn open<P: AsRef<Path>>(
+        path: P,
+    ) -> Result<(Self, Box<dyn AsyncRead + Send + Unpin>)> {
+        let mut reader = BufReader::new(File::open(path).await?);
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic).await?;
+        if &magic != BACKUP_MAGIC {
+            return Err(anyhow!("not a myraft backup"));
+        }
+        let version = reader.read_u32_le().await?;
+        if version != BACKUP_VERSION {
+            return Err(anyhow!("unsupported backup version {}", version));
+        }
+        let len = reader.read_u64().await?;
+        let mut header = vec![0; len as usize];
+        reader.read_exact(&mut header).await?;
+        Ok((deserialize(&header)?, Box::new(reader)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::enter_scratch_dir;
+
+    async fn read_data(path: &str) -> (BackupHeader, Vec<u8>) {
+        let (header, mut data) = BackupHeader::open(path).await.unwrap();
+        let mut bytes = vec![];
+        data.read_to_end(&mut bytes).await.unwrap();
+        (header, bytes)
+    }
+
+    #[tokio::test]
+    async fn a_backup_is_read_back() {
+        enter_scratch_dir();
+        std::fs::create_dir_all("store/backups").unwrap();
+        let header = BackupHeader {
+            created_at: 1,
+            node_id: 3,
+            cluster_id: 42,
+            index: 5,
+            term: 2,
+            membership: MembershipConfig::new_initial(3),
+            log_suffix: vec![vec![1, 2]],
+            sessions: vec![],
+        };
+        let mut file = File::create("store/backups/v2").await.unwrap();
+        header.write_to(&mut file).await.unwrap();
+        file.write_all(b"data").await.unwrap();
+        drop(file);
+        let (read, data) = read_data("store/backups/v2").await;
+        assert_eq!((read.cluster_id, read.index, read.term), (42, 5, 2));
+        assert_eq!(read.log_suffix, header.log_suffix);
+        assert_eq!(data, b"data");
+    }
+}
@@ -0,0 +1,5 @@
+use myraft::inspect::{run, HexFormatter};
+
+fn main() -> anyhow::Result<()> {
+    run(&HexFormatter)
+}
@@ -0,0 +1,282 @@
+//! Fault injection between raft nodes, for chaos testing real processes.
+//!
+//! `ChaosNetwork` wraps a `RaftNetwork` and, while a `ChaosSchedule` runs,
+//! drops, delays, duplicates or reorders the calls going out of the node. With
+//! no schedule it passes every call straight through.
+
+use anyhow::{anyhow, Result};
+use async_raft::async_trait::async_trait;
+use async_raft::raft::{
+    AppendEntriesRequest, AppendEntriesResponse, InstallSnapshotRequest, InstallSnapshotResponse,
+    VoteRequest, VoteResponse,
+};
+use async_raft::{AppData, NodeId, RaftNetwork};
+use bincode::{deserialize, serialize};
+use rand::Rng;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::spawn;
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RpcKind {
+    AppendEntries,
+    Vote,
+    InstallSnapshot,
+}
+
+/// Faults done to the calls matching `target` and `rpcs`. Probabilities are
+/// drawn independently for every call.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Fault {
+    /// node the calls go to, every node if unset
+    pub target: Option<NodeId>,
+    /// kinds of calls hit, every kind if empty
+    pub rpcs: Vec<RpcKind>,
+    /// probability the call is lost
+    pub drop: f64,
+    /// probability the call is sent a second time, after the first one
+    pub duplicate: f64,
+    /// probability the call fails at once but still arrives `reorder_ms` later,
+    /// behind the calls sent in between
+    pub reorder: f64,
+    pub reorder_ms: u64,
+    /// every call waits `delay_ms` plus up to `jitter_ms` before being sent
+    pub delay_ms: u64,
+    pub jitter_ms: u64,
+}
+
+impl Fault {
+    fn matches(&self, target: NodeId, kind: RpcKind) -> bool {
+        let target_hit = match self.target {
+            Some(t) => t == target,
+            None => true,
+        };
+        target_hit && (self.rpcs.is_empty() || self.rpcs.contains(&kind))
+    }
+}
+
+/// Faults in force for `duration_ms`. The first fault matching a call applies.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ChaosStep {
+    pub duration_ms: u64,
+    pub faults: Vec<Fault>,
+}
+
+/// Steps run one after the other from the moment the schedule is set.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ChaosSchedule {
+    pub steps: Vec<ChaosStep>,
+    /// start over after the last step instead of stopping the chaos
+    pub repeat: bool,
+}
+
+impl ChaosSchedule {
+    fn total(&self) -> Duration {
+        Duration::from_millis(self.steps.iter().map(|step| step.duration_ms).sum())
+    }
+
+    fn step_at(&self, elapsed: Duration) -> Option<&ChaosStep> {
+        let total = self.total();
+        if total.as_millis() == 0 {
+            return None;
+        }
+        let mut at = elapsed.as_millis();
+        if self.repeat {
+            at %= total.as_millis();
+        }
+        for step in &self.steps {
+            if at < step.duration_ms as u128 {
+                return Some(step);
+            }
+            at -= step.duration_ms as u128;
+        }
+        None
+    }
+}
+
+// what happens to one call
+#[derive(Debug, Default)]
+struct Verdict {
+    drop: bool,
+    duplicate: bool,
+    reorder: Option<Duration>,
+    delay: Duration,
+}
+
+pub struct ChaosNetwork<N> {
+    inner: Arc<N>,
+    // fast path while no schedule is set
+    enabled: AtomicBool,
+    schedule: Mutex<Option<(ChaosSchedule, Instant)>>,
+}
+
+impl<N> ChaosNetwork<N> {
+    pub fn new(inner: Arc<N>) -> Self {
+        Self {
+            inner,
+            enabled: AtomicBool::new(false),
+            schedule: Mutex::new(None),
+        }
+    }
+
+    pub fn inner(&self) -> &Arc<N> {
+        &self.inner
+    }
+
+    /// Starts `schedule` now, replacing the running one. `None` stops the chaos.
+    pub fn set_schedule(&self, schedule: Option<ChaosSchedule>) {
+        info!("chaos schedule set to {:?}", schedule);
+        let mut current = self.schedule.lock().unwrap();
+        self.enabled.store(schedule.is_some(), Ordering::SeqCst);
+        *current = schedule.map(|schedule| (schedule, Instant::now()));
+    }
+
+    pub fn schedule(&self) -> Option<ChaosSchedule> {
+        self.schedule
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|(schedule, _)| schedule.clone())
+    }
+
+    fn verdict(&self, target: NodeId, kind: RpcKind) -> Verdict {
+        if !self.enabled.load(Ordering::SeqCst) {
+            return Verdict::default();
+        }
+        let current = self.schedule.lock().unwrap();
+        let fault = current.as_ref().and_then(|(schedule, started)| {
+            schedule
+                .step_at(started.elapsed())?
+                .faults
+                .iter()
+                .find(|fault| fault.matches(target, kind))
+        });
+        let fault = match fault {
+            Some(fault) => fault,
+            None => return Verdict::default(),
+        };
+        let mut rng = rand::thread_rng();
+        let jitter = match fault.jitter_ms {
+            0 => 0,
+            jitter_ms => rng.gen_range(0..=jitter_ms),
+        };
+        Verdict {
+            drop: rng.gen_bool(fault.drop.clamp(0.0, 1.0)),
+            duplicate: rng.gen_bool(fault.duplicate.clamp(0.0, 1.0)),
+            reorder: if rng.gen_bool(fault.reorder.clamp(0.0, 1.0)) {
+                Some(Duration::from_millis(fault.reorder_ms))
+            } else {
+                None
+            },
+            delay: Duration::from_millis(fault.delay_ms + jitter),
+        }
+    }
+}
+
+// the raft rpcs are not all Clone, but they all go through bincode anyway
+fn copy_rpc<R: Serialize + DeserializeOwned>(rpc: &R) -> Result<R> {
+    Ok(deserialize(&serialize(rpc)?)?)
+}
+
+impl<N> ChaosNetwork<N>
+where
+    N: Send + Sync + 'static,
+{
+    // runs `send` on `rpc` as the verdict for the call says
+    async fn inject<R, O, F, Fut>(
+        &self,
+        target: NodeId,
+        kind: RpcKind,
+        rpc: R,
+        send: F,
+    ) -> Result<O>
+    where
+        R: Serialize + DeserializeOwned + Send + 'static,
+        O: Send + 'static,
+        F: Fn(Arc<N>, R) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = Result<O>> + Send + 'static,
+    {
+        let verdict = self.verdict(target, kind);
+        if verdict.drop {
+            return Err(anyhow!("chaos dropped {:?} to {}", kind, target));
+        }
+        if !verdict.delay.is_zero() {
+            sleep(verdict.delay).await;
+        }
+        let send = Arc::new(send);
+        if let Some(late) = verdict.reorder {
+            let (inner, send) = (self.inner.clone(), send.clone());
+            spawn(async move {
+                sleep(late).await;
+                if let Err(err) = send(inner, rpc).await {
+                    warn!("chaos reordered {:?} to {} failed: {}", kind, target, err);
+                }
+            });
+            return Err(anyhow!("chaos reordered {:?} to {}", kind, target));
+        }
+        let duplicate = if verdict.duplicate {
+            Some(copy_rpc(&rpc)?)
+        } else {
+            None
+        };
+        let rsp = send(self.inner.clone(), rpc).await;
+        if let Some(duplicate) = duplicate {
+            let (inner, send) = (self.inner.clone(), send.clone());
+            spawn(async move {
+                if let Err(err) = send(inner, duplicate).await {
+                    warn!("chaos duplicated {:?} to {} failed: {}", kind, target, err);
+                }
+            });
+        }
+        rsp
+    }
+}
+
+#[async_trait]
+impl<D: AppData, N: RaftNetwork<D>> RaftNetwork<D> for ChaosNetwork<N> {
+    async fn append_entries(
+        &self,
+        target: NodeId,
+        rpc: AppendEntriesRequest<D>,
+    ) -> Result<AppendEntriesResponse> {
+        self.inject(
+            target,
+            RpcKind::AppendEntries,
+            rpc,
+            move |inner: Arc<N>, rpc| async move { inner.append_entries(target, rpc).await },
+        )
+        .await
+    }
+
+    async fn install_snapshot(
+        &self,
+        target: NodeId,
+        rpc: InstallSnapshotRequest,
+    ) -> Result<InstallSnapshotResponse> {
+        self.inject(
+            target,
+            RpcKind::InstallSnapshot,
+            rpc,
+            move |inner: Arc<N>, rpc| async move { inner.install_snapshot(target, rpc).await },
+        )
+        .await
+    }
+
+    async fn vote(&self, target: NodeId, rpc: VoteRequest) -> Result<VoteResponse> {
+        self.inject(
+            target,
+            RpcKind::Vote,
+            rpc,
+            move |inner: Arc<N>, rpc| async move { inner.vote(target, rpc).await },
+        )
+        .await
+    }
+}
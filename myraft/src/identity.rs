@@ -0,0 +1,127 @@
+//! The identity a node's store was created with.
+//!
+//! It is checked every time the node starts against the id and cluster it is
+//! started with, so that a node given the wrong id or store directory cannot
+//! run on another node's store, and a store cannot be started in another
+//! cluster than the one it was created in.
+
+use anyhow::{anyhow, Result};
+use async_raft::NodeId;
+use bincode::{deserialize, serialize};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+pub(crate) const IDENTITY_FILE: &str = "identity";
+/// Cluster of the stores created without a cluster id.
+pub const DEFAULT_CLUSTER_ID: u64 = 0;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct NodeIdentity {
+    pub node_id: NodeId,
+    pub cluster_id: u64,
+    /// unix time the store was created at, in seconds
+    pub created_at: u64,
+}
+
+/// Path of the identity file of the store in `dir`, next to its state db.
+pub fn identity_path(dir: &str) -> PathBuf {
+    Path::new(dir).join(IDENTITY_FILE)
+}
+
+impl NodeIdentity {
+    pub fn read_from<P: AsRef<Path>>(path: P) -> Result<Option<Self>> {
+        match fs::read(path) {
+            Ok(bytes) => Ok(Some(deserialize(&bytes)?)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Identity of the store in `dir`, if it has one. Fails if the store was
+    /// created for another node than `id`.
+    pub fn load(id: NodeId, dir: &str) -> Result<Option<Self>> {
+        let identity = Self::read_from(identity_path(dir))?;
+        if let Some(identity) = &identity {
+            if identity.node_id != id {
+                return Err(anyhow!(
+                    "the store at {} belongs to node {}, not {}",
+                    dir,
+                    identity.node_id,
+                    id
+                ));
+            }
+        }
+        Ok(identity)
+    }
+
+    /// Checks the store in `dir` belongs to node `id` and to `cluster_id`, and
+    /// creates the identity of a new store. Without a `cluster_id` the one
+    /// persisted is kept, and a new store gets `DEFAULT_CLUSTER_ID`.
+    pub(crate) fn load_or_create(id: NodeId, dir: &str, cluster_id: Option<u64>) -> Result<Self> {
+        match Self::load(id, dir)? {
+            Some(identity) => match cluster_id {
+                Some(cluster_id) if cluster_id != identity.cluster_id => Err(anyhow!(
+                    "the store of node {} belongs to cluster {}, not {}",
+                    id,
+                    identity.cluster_id,
+                    cluster_id
+                )),
+                _ => Ok(identity),
+            },
+            None => Self::create(id, dir, cluster_id.unwrap_or(DEFAULT_CLUSTER_ID)),
+        }
+    }
+
+    /// Creates the identity of the new store of node `id` in `dir`.
+    pub(crate) fn create(id: NodeId, dir: &str, cluster_id: u64) -> Result<Self> {
+        let identity = Self {
+            node_id: id,
+            cluster_id,
+            created_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        };
+        identity.write(dir)?;
+        info!("created identity {:?}", identity);
+        Ok(identity)
+    }
+
+    /// A random cluster id for a new cluster, neither the default one nor `old`.
+    pub(crate) fn new_cluster_id(old: u64) -> u64 {
+        loop {
+            let cluster_id = rand::random();
+            if cluster_id != DEFAULT_CLUSTER_ID && cluster_id != old {
+                return cluster_id;
+            }
+        }
+    }
+
+    fn write(&self, dir: &str) -> Result<()> {
+        fs::create_dir_all(dir)?;
+        let path = identity_path(dir);
+        // a crash while writing must not leave a truncated identity behind
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serialize(self)?)?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::enter_scratch_dir;
+
+    #[test]
+    fn a_store_is_kept_for_the_node_and_cluster_it_was_created_for() {
+        enter_scratch_dir();
+        let dir = "store/identity";
+        let created = NodeIdentity::load_or_create(1, dir, Some(5)).unwrap();
+        assert_eq!(NodeIdentity::load_or_create(1, dir, None).unwrap(), created);
+        // another node given this store, or the node started in another cluster
+        assert!(NodeIdentity::load_or_create(2, dir, Some(5)).is_err());
+        assert!(NodeIdentity::load_or_create(1, dir, Some(6)).is_err());
+    }
+}
@@ -0,0 +1,565 @@
+//! Offline, read-only access to the sled store of a stopped node.
+//!
+//! sled has no read-only mode, so the `Inspector` simply never writes. Entries
+//! are decoded without knowing the app's `WriteReq` type: the payload of normal
+//! entries is handed to a `DataFormatter`, so apps can plug in their own printer.
+
+use crate::identity::{NodeIdentity, IDENTITY_FILE};
+use crate::session::ClientRequest;
+use crate::storage::{
+    decode_log_key, default_store_dir, log_format, log_key, state_path, wrap_write,
+    MyStorageSnapshot, RecoveryRecord, CURRENT_SNAPSHOT_KEY, HARD_STATE_KEY, LAST_APPLIED_LOG_KEY,
+    LOG_FORMAT, LOG_TREE, RECOVERY_AUDIT_KEY, STATE_TREE,
+};
+use anyhow::{anyhow, Result};
+use async_raft::raft::{EntryConfigChange, EntrySnapshotPointer, MembershipConfig};
+use async_raft::storage::HardState;
+use async_raft::{AppData, NodeId};
+use bincode::deserialize;
+use sled::{Db, IVec, Tree};
+use std::convert::TryInto;
+use std::fmt::Debug;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+/// Turns the bincode bytes of a normal entry into something printable.
+pub trait DataFormatter {
+    fn format(&self, data: &[u8]) -> String;
+}
+
+/// Prints the raw bytes as hex, for stores of unknown apps.
+pub struct HexFormatter;
+
+impl DataFormatter for HexFormatter {
+    fn format(&self, data: &[u8]) -> String {
+        data.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+/// Decodes the bytes as a write of the app's `WriteReq` and prints it with `Debug`.
+pub struct AppFormatter<D: AppData + Debug>(PhantomData<D>);
+
+impl<D: AppData + Debug> AppFormatter<D> {
+    pub fn new() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<D: AppData + Debug> Default for AppFormatter<D> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<D: AppData + Debug> DataFormatter for AppFormatter<D> {
+    fn format(&self, data: &[u8]) -> String {
+        match deserialize::<ClientRequest<D>>(data) {
+            Ok(ClientRequest {
+                session: Some(session),
+                data,
+            }) => format!(
+                "{:?} (client {} seq {})",
+                data, session.client_id, session.seq
+            ),
+            Ok(ClientRequest {
+                session: None,
+                data,
+            }) => format!("{:?}", data),
+            Err(err) => format!("<undecodable: {}> {}", err, HexFormatter.format(data)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RawPayload {
+    Blank,
+    Normal(Vec<u8>),
+    ConfigChange(MembershipConfig),
+    SnapshotPointer {
+        id: String,
+        membership: MembershipConfig,
+    },
+}
+
+#[derive(Debug)]
+pub struct RawEntry {
+    pub term: u64,
+    pub index: u64,
+    pub payload: RawPayload,
+}
+
+impl RawEntry {
+    // bincode lays an `Entry<D>` out as term, index, then the u32 variant tag
+    // of the payload followed by the variant's fields
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < 20 {
+            return Err(anyhow!("log entry of {} bytes is truncated", bytes.len()));
+        }
+        let term = u64::from_le_bytes(bytes[0..8].try_into()?);
+        let index = u64::from_le_bytes(bytes[8..16].try_into()?);
+        let tag = u32::from_le_bytes(bytes[16..20].try_into()?);
+        let rest = &bytes[20..];
+        let payload = match tag {
+            0 => RawPayload::Blank,
+            1 => RawPayload::Normal(rest.to_vec()),
+            2 => {
+                let cfg: EntryConfigChange = deserialize(rest)?;
+                RawPayload::ConfigChange(cfg.membership)
+            }
+            3 => {
+                let snap: EntrySnapshotPointer = deserialize(rest)?;
+                RawPayload::SnapshotPointer {
+                    id: snap.id,
+                    membership: snap.membership,
+                }
+            }
+            _ => return Err(anyhow!("unknown payload tag {} at index {}", tag, index)),
+        };
+        Ok(Self {
+            term,
+            index,
+            payload,
+        })
+    }
+}
+
+/// What is known about the current snapshot without its data.
+#[derive(Debug)]
+pub struct SnapshotMeta {
+    pub index: u64,
+    pub term: u64,
+    pub membership: MembershipConfig,
+    /// file in the snapshot directory of the node
+    pub file: String,
+    /// bytes of the file
+    pub size: u64,
+}
+
+pub struct Inspector {
+    _db: Db,
+    identity_path: PathBuf,
+    log: Tree,
+    // layout of the log: a store not opened since has its keys little endian
+    // and its writes without a session still
+    log_format: u64,
+    state: Tree,
+}
+
+impl Inspector {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        // opening creates the db files and trees that are missing
+        if !path.join("db").exists() {
+            return Err(anyhow!("no store at {}", path.display()));
+        }
+        // the identity file sits next to the state db
+        let identity_path = path
+            .parent()
+            .unwrap_or_else(|| Path::new(""))
+            .join(IDENTITY_FILE);
+        let db = sled::open(path)?;
+        let trees = db.tree_names();
+        for tree in &[LOG_TREE, STATE_TREE] {
+            if !trees.iter().any(|name| name == tree.as_bytes()) {
+                return Err(anyhow!(
+                    "{} is not a store, it has no {} tree",
+                    path.display(),
+                    tree
+                ));
+            }
+        }
+        let log = db.open_tree(LOG_TREE)?;
+        let state = db.open_tree(STATE_TREE)?;
+        let log_format = log_format(&state)?;
+        if log_format != 0 && log_format != LOG_FORMAT {
+            return Err(anyhow!("unsupported log format {}", log_format));
+        }
+        Ok(Self {
+            _db: db,
+            identity_path,
+            log,
+            log_format,
+            state,
+        })
+    }
+
+    pub fn identity(&self) -> Result<Option<NodeIdentity>> {
+        NodeIdentity::read_from(&self.identity_path)
+    }
+
+    pub fn hard_state(&self) -> Result<Option<HardState>> {
+        match self.state.get(HARD_STATE_KEY)? {
+            Some(hs) => Ok(deserialize(&hs)?),
+            None => Ok(None),
+        }
+    }
+
+    pub fn last_applied_log(&self) -> Result<u64> {
+        match self.state.get(LAST_APPLIED_LOG_KEY)? {
+            Some(l) => Ok(deserialize(&l)?),
+            None => Ok(0),
+        }
+    }
+
+    pub fn current_snapshot(&self) -> Result<Option<SnapshotMeta>> {
+        let snap: Option<MyStorageSnapshot> = match self.state.get(CURRENT_SNAPSHOT_KEY)? {
+            Some(snap) => deserialize(&snap)?,
+            None => None,
+        };
+        Ok(snap.map(|snap| SnapshotMeta {
+            index: snap.index,
+            term: snap.term,
+            membership: snap.membership,
+            file: snap.file,
+            size: snap.size,
+        }))
+    }
+
+    /// Forced membership changes done on this store, oldest first.
+    pub fn recovery_audit(&self) -> Result<Vec<RecoveryRecord>> {
+        match self.state.get(RECOVERY_AUDIT_KEY)? {
+            Some(audit) => Ok(deserialize(&audit)?),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// First and last index present in the log.
+    pub fn log_bounds(&self) -> Result<Option<(u64, u64)>> {
+        if self.log_format == 0 {
+            let indexes = self.legacy_logs()?.into_iter().map(|(index, _)| index);
+            return Ok(indexes.clone().min().zip(indexes.max()));
+        }
+        match (self.log.first()?, self.log.last()?) {
+            (Some(first), Some(last)) => {
+                Ok(Some((decode_log_key(&first.0)?, decode_log_key(&last.0)?)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Entries with index in `[start, stop)`, ordered by index.
+    pub fn entries(&self, start: u64, stop: u64) -> Result<Vec<RawEntry>> {
+        if start >= stop {
+            return Ok(vec![]);
+        }
+        if self.log_format == 0 {
+            let mut logs = self.legacy_logs()?;
+            logs.retain(|(index, _)| *index >= start && *index < stop);
+            logs.sort_by_key(|(index, _)| *index);
+            return logs.iter().map(|(_, log)| self.decode(log)).collect();
+        }
+        self.log
+            .range(log_key(start)..log_key(stop))
+            .map(|kv| self.decode(&kv?.1))
+            .collect()
+    }
+
+    fn decode(&self, log: &[u8]) -> Result<RawEntry> {
+        if self.log_format == 0 {
+            return RawEntry::decode(&wrap_write(log.to_vec()));
+        }
+        RawEntry::decode(log)
+    }
+
+    // the logs of a store with little endian keys, in key order
+    fn legacy_logs(&self) -> Result<Vec<(u64, IVec)>> {
+        let mut logs = vec![];
+        for kv in self.log.iter() {
+            let (key, log) = kv?;
+            logs.push((u64::from_le_bytes(key.as_ref().try_into()?), log));
+        }
+        Ok(logs)
+    }
+
+    /// Every membership recorded in the log as `(index, term, membership)`, oldest first.
+    pub fn membership_history(&self) -> Result<Vec<(u64, u64, MembershipConfig)>> {
+        let mut history = vec![];
+        // one entry at a time, in key order: the writes are not kept
+        for kv in self.log.iter() {
+            let entry = self.decode(&kv?.1)?;
+            match entry.payload {
+                RawPayload::ConfigChange(membership)
+                | RawPayload::SnapshotPointer { membership, .. } => {
+                    history.push((entry.index, entry.term, membership))
+                }
+                _ => {}
+            }
+        }
+        // little endian keys are not in index order
+        history.sort_by_key(|(index, _, _)| *index);
+        Ok(history)
+    }
+
+    /// Prints everything known about the store, with the log entries in `[start, stop)`.
+    pub fn dump<F: DataFormatter>(&self, start: u64, stop: u64, formatter: &F) -> Result<()> {
+        println!("identity: {:?}", self.identity()?);
+        println!("hard state: {:?}", self.hard_state()?);
+        println!("last applied log: {}", self.last_applied_log()?);
+        println!("current snapshot: {:?}", self.current_snapshot()?);
+        println!("log bounds: {:?}", self.log_bounds()?);
+        println!("membership history:");
+        for (index, term, membership) in self.membership_history()? {
+            println!("  [{}@{}] {}", index, term, format_membership(&membership));
+        }
+        println!("recovery audit:");
+        for record in self.recovery_audit()? {
+            println!(
+                "  [{}@{}] at {}: {} -> {} ({})",
+                record.index,
+                record.term,
+                record.at,
+                format_membership(&record.prev_membership),
+                format_membership(&record.membership),
+                record.reason
+            );
+        }
+        println!("log entries [{}, {}):", start, stop);
+        for entry in self.entries(start, stop)? {
+            let payload = match entry.payload {
+                RawPayload::Blank => "blank".to_string(),
+                RawPayload::Normal(data) => format!("normal {}", formatter.format(&data)),
+                RawPayload::ConfigChange(membership) => {
+                    format!("config {}", format_membership(&membership))
+                }
+                RawPayload::SnapshotPointer { id, membership } => format!(
+                    "snapshot pointer {:?} {}",
+                    id,
+                    format_membership(&membership)
+                ),
+            };
+            println!("  [{}@{}] {}", entry.index, entry.term, payload);
+        }
+        Ok(())
+    }
+}
+
+fn format_membership(membership: &MembershipConfig) -> String {
+    let mut members: Vec<_> = membership.members.iter().collect();
+    members.sort();
+    match &membership.members_after_consensus {
+        Some(after) => {
+            let mut after: Vec<_> = after.iter().collect();
+            after.sort();
+            format!("{:?} -> {:?}", members, after)
+        }
+        None => format!("{:?}", members),
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct InspectOpt {
+    /// node whose store under the working directory is inspected
+    #[structopt(short, long)]
+    id: Option<NodeId>,
+    /// path of the state db, instead of looking it up by id
+    #[structopt(short, long)]
+    path: Option<String>,
+    /// first log index to dump
+    #[structopt(short, long, default_value = "0")]
+    start: u64,
+    /// log index to stop dumping at (exclusive), the end of the log by default
+    #[structopt(short = "e", long)]
+    stop: Option<u64>,
+}
+
+/// Entry point of the inspect binaries: parses the args and dumps the store.
+pub fn run<F: DataFormatter>(formatter: &F) -> Result<()> {
+    let opt = InspectOpt::from_args();
+    let path = match (opt.path, opt.id) {
+        (Some(path), _) => path,
+        (None, Some(id)) => state_path(&default_store_dir(id)),
+        (None, None) => return Err(anyhow!("either --id or --path is required")),
+    };
+    let inspector = Inspector::open(&path)?;
+    let stop = opt.stop.unwrap_or(u64::MAX);
+    inspector.dump(opt.start, stop, formatter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::LOG_FORMAT_KEY;
+    use crate::testing::{enter_scratch_dir, once_released, Add};
+    use async_raft::raft::{Entry, EntryNormal, EntryPayload};
+    use bincode::serialize;
+    use std::collections::HashSet;
+    use std::fs;
+
+    fn membership(members: &[NodeId]) -> MembershipConfig {
+        MembershipConfig {
+            members: members.iter().copied().collect::<HashSet<_>>(),
+            members_after_consensus: None,
+        }
+    }
+
+    fn entry<D: AppData>(index: u64, payload: EntryPayload<D>) -> Vec<u8> {
+        serialize(&Entry {
+            term: 2,
+            index,
+            payload,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn every_payload_is_decoded_without_the_app_type() {
+        let write = ClientRequest {
+            session: None,
+            data: Add(5),
+        };
+        let normal = entry(2, EntryPayload::Normal(EntryNormal { data: write }));
+        let raw = RawEntry::decode(&normal).unwrap();
+        assert_eq!((raw.index, raw.term), (2, 2));
+        match raw.payload {
+            RawPayload::Normal(data) => {
+                let write: ClientRequest<Add> = deserialize(&data).unwrap();
+                assert_eq!(write.data, Add(5));
+            }
+            payload => panic!("not a write: {:?}", payload),
+        }
+
+        let blank = entry::<Add>(1, EntryPayload::Blank);
+        assert!(matches!(
+            RawEntry::decode(&blank).unwrap().payload,
+            RawPayload::Blank
+        ));
+
+        let config = EntryConfigChange {
+            membership: membership(&[1, 2]),
+        };
+        let config = entry::<Add>(3, EntryPayload::ConfigChange(config));
+        match RawEntry::decode(&config).unwrap().payload {
+            RawPayload::ConfigChange(members) => assert_eq!(members, membership(&[1, 2])),
+            payload => panic!("not a config change: {:?}", payload),
+        }
+
+        let pointer = EntrySnapshotPointer {
+            id: "snapshot-4".to_string(),
+            membership: membership(&[1]),
+        };
+        let pointer = entry::<Add>(4, EntryPayload::SnapshotPointer(pointer));
+        match RawEntry::decode(&pointer).unwrap().payload {
+            RawPayload::SnapshotPointer {
+                id,
+                membership: members,
+            } => {
+                assert_eq!(id, "snapshot-4");
+                assert_eq!(members, membership(&[1]));
+            }
+            payload => panic!("not a snapshot pointer: {:?}", payload),
+        }
+
+        assert!(RawEntry::decode(&blank[..19]).is_err());
+        let mut unknown = blank;
+        unknown[16..20].copy_from_slice(&4u32.to_le_bytes());
+        assert!(RawEntry::decode(&unknown).is_err());
+    }
+
+    // a store of log `format` with a config change at 1 and 256 and a write at 2
+    fn store_of_format(format: u64) -> String {
+        let path = format!("store/inspect_format_{}/state", format);
+        let _ = fs::remove_dir_all(&path);
+        let db = sled::open(&path).unwrap();
+        let (log, state) = (
+            db.open_tree(LOG_TREE).unwrap(),
+            db.open_tree(STATE_TREE).unwrap(),
+        );
+        let configs = [(1, membership(&[1, 2])), (256, membership(&[1]))];
+        let mut logs: Vec<(u64, Vec<u8>)> = configs
+            .iter()
+            .map(|(index, membership)| {
+                let config = EntryConfigChange {
+                    membership: membership.clone(),
+                };
+                (
+                    *index,
+                    entry::<Add>(*index, EntryPayload::ConfigChange(config)),
+                )
+            })
+            .collect();
+        let write = match format {
+            // writes without a session
+            0 => entry(2, EntryPayload::Normal(EntryNormal { data: Add(5) })),
+            _ => {
+                let data = ClientRequest {
+                    session: None,
+                    data: Add(5),
+                };
+                entry(2, EntryPayload::Normal(EntryNormal { data }))
+            }
+        };
+        logs.push((2, write));
+        for (index, log_entry) in logs {
+            match format {
+                0 => log.insert(index.to_le_bytes(), log_entry),
+                _ => log.insert(log_key(index), log_entry),
+            }
+            .unwrap();
+        }
+        if format > 0 {
+            state
+                .insert(LOG_FORMAT_KEY, serialize(&format).unwrap())
+                .unwrap();
+        }
+        db.flush().unwrap();
+        path
+    }
+
+    #[test]
+    fn the_logs_of_every_format_are_read_in_index_order() {
+        enter_scratch_dir();
+        for format in [0, LOG_FORMAT] {
+            let path = store_of_format(format);
+            let inspector = once_released(|| Inspector::open(&path));
+            assert_eq!(
+                inspector.log_bounds().unwrap(),
+                Some((1, 256)),
+                "{}",
+                format
+            );
+            let entries = inspector.entries(0, u64::MAX).unwrap();
+            let indexes: Vec<u64> = entries.iter().map(|entry| entry.index).collect();
+            assert_eq!(indexes, vec![1, 2, 256], "format {}", format);
+            match &entries[1].payload {
+                RawPayload::Normal(data) => {
+                    let write: ClientRequest<Add> = deserialize(data).unwrap();
+                    assert!(write.session.is_none());
+                    assert_eq!(write.data, Add(5));
+                }
+                payload => panic!("format {}: not a write: {:?}", format, payload),
+            }
+            let history: Vec<(u64, MembershipConfig)> = inspector
+                .membership_history()
+                .unwrap()
+                .into_iter()
+                .map(|(index, _, membership)| (index, membership))
+                .collect();
+            let expected = vec![(1, membership(&[1, 2])), (256, membership(&[1]))];
+            assert_eq!(history, expected, "format {}", format);
+        }
+    }
+
+    #[test]
+    fn a_path_without_a_store_is_refused_and_left_as_it_was() {
+        enter_scratch_dir();
+        let (empty, other) = ("store/inspect_empty", "store/inspect_other");
+        fs::create_dir_all(empty).unwrap();
+        let err = Inspector::open(empty).err().unwrap();
+        assert!(err.to_string().contains("no store"), "{}", err);
+        assert_eq!(fs::read_dir(empty).unwrap().count(), 0);
+
+        // a sled db, but not of a node
+        let _ = fs::remove_dir_all(other);
+        sled::open(other).unwrap().open_tree("other").unwrap();
+        let refused = once_released(|| match Inspector::open(other) {
+            Ok(_) => Ok(None),
+            Err(err) if err.to_string().contains("is not a store") => Ok(Some(err)),
+            Err(err) => Err(err),
+        });
+        assert!(refused.is_some(), "a db without the trees was opened");
+        let trees = once_released(|| Ok(sled::open(other)?)).tree_names();
+        assert!(!trees.iter().any(|name| name == LOG_TREE.as_bytes()));
+        assert!(!trees.iter().any(|name| name == STATE_TREE.as_bytes()));
+    }
+}
@@ -1,6 +1,16 @@
+pub mod backup;
+#[cfg(feature = "chaos")]
+pub mod chaos;
+mod identity;
+pub mod inspect;
+pub mod metrics;
 mod network;
 pub mod raft;
+mod session;
 mod storage;
+pub mod telemetry;
+#[cfg(test)]
+mod testing;
 mod raftpb {
     tonic::include_proto!("raftpb");
 }
@@ -8,3 +18,7 @@ mod raftpb {
 pub use async_raft::async_trait;
 // pub use async_raft::raft::ClientWriteRequest;
 pub use async_raft::{AppData, AppDataResponse};
+pub use identity::{identity_path, NodeIdentity, DEFAULT_CLUSTER_ID};
+pub use network::ReplicationConfig;
+pub use session::{ClientRequest, ClientSession};
+pub use storage::{default_store_dir, RecoveryRecord};
@@ -0,0 +1,182 @@
+//! Prometheus metrics of the raft node of the process.
+//!
+//! They live in the prometheus default registry, where the application can
+//! register its own, and are served at `/metrics` on the address given to
+//! `MyRaftBuilder::metrics_addr`. Nodes sharing a process tell theirs apart by
+//! the `node` label.
+
+use anyhow::Result;
+use async_raft::{RaftMetrics, State};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Response, Server, StatusCode};
+use lazy_static::lazy_static;
+use prometheus::{
+    exponential_buckets, register_histogram_vec, register_int_counter_vec, register_int_gauge_vec,
+    Encoder, HistogramVec, IntCounterVec, IntGaugeVec, TextEncoder,
+};
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use tokio::sync::watch;
+use tracing::info;
+
+lazy_static! {
+    pub(crate) static ref RPC_DURATION: HistogramVec = register_histogram_vec!(
+        "myraft_rpc_duration_seconds",
+        "Latency of the raft RPCs sent, by node, peer and method.",
+        &["node", "peer", "method"],
+        exponential_buckets(0.0005, 2.0, 14).unwrap()
+    )
+    .unwrap();
+    pub(crate) static ref RPC_FAILURES: IntCounterVec = register_int_counter_vec!(
+        "myraft_rpc_failures_total",
+        "Raft RPCs sent that failed, by node, peer and method.",
+        &["node", "peer", "method"]
+    )
+    .unwrap();
+    pub(crate) static ref APPENDED_ENTRIES: IntCounterVec = register_int_counter_vec!(
+        "myraft_appended_entries_total",
+        "Entries written to the raft log.",
+        &["node"]
+    )
+    .unwrap();
+    pub(crate) static ref APPLIED_ENTRIES: IntCounterVec = register_int_counter_vec!(
+        "myraft_applied_entries_total",
+        "Entries applied to the state machine, not those answered as applied already.",
+        &["node"]
+    )
+    .unwrap();
+    pub(crate) static ref FSYNC_DURATION: HistogramVec = register_histogram_vec!(
+        "myraft_sled_fsync_duration_seconds",
+        "Latency of flushing the raft store to disk.",
+        &["node"],
+        exponential_buckets(0.0001, 2.0, 14).unwrap()
+    )
+    .unwrap();
+    pub(crate) static ref SNAPSHOT_BYTES: HistogramVec = register_histogram_vec!(
+        "myraft_snapshot_bytes",
+        "Size of the snapshots built and installed.",
+        &["node", "kind"],
+        exponential_buckets(1024.0, 4.0, 12).unwrap()
+    )
+    .unwrap();
+    pub(crate) static ref SNAPSHOT_DURATION: HistogramVec = register_histogram_vec!(
+        "myraft_snapshot_duration_seconds",
+        "Time to build or install a snapshot.",
+        &["node", "kind"],
+        exponential_buckets(0.001, 2.0, 14).unwrap()
+    )
+    .unwrap();
+    pub(crate) static ref CATCH_UP_LAG: IntGaugeVec = register_int_gauge_vec!(
+        "myraft_catch_up_lag",
+        "Log entries a peer joining as a member is behind the leader node, by peer.",
+        &["node", "peer"]
+    )
+    .unwrap();
+    static ref ELECTIONS: IntCounterVec = register_int_counter_vec!(
+        "myraft_elections_total",
+        "Elections the node started as a candidate.",
+        &["node"]
+    )
+    .unwrap();
+    static ref LEADER_CHANGES: IntCounterVec = register_int_counter_vec!(
+        "myraft_leader_changes_total",
+        "Leaders the node saw elected.",
+        &["node"]
+    )
+    .unwrap();
+    static ref TERM: IntGaugeVec =
+        register_int_gauge_vec!("myraft_term", "Current raft term.", &["node"]).unwrap();
+    static ref LAST_LOG_INDEX: IntGaugeVec = register_int_gauge_vec!(
+        "myraft_last_log_index",
+        "Index of the last log entry.",
+        &["node"]
+    )
+    .unwrap();
+    static ref LAST_APPLIED: IntGaugeVec = register_int_gauge_vec!(
+        "myraft_last_applied_index",
+        "Index of the last entry applied to the state machine.",
+        &["node"]
+    )
+    .unwrap();
+    static ref IS_LEADER: IntGaugeVec = register_int_gauge_vec!(
+        "myraft_is_leader",
+        "1 on the leader, 0 elsewhere.",
+        &["node"]
+    )
+    .unwrap();
+}
+
+/// Times the raft RPC `call` of `node` to `peer`, counting it as failed if it errors.
+pub(crate) async fn observe_rpc<R>(
+    node: u64,
+    peer: u64,
+    method: &str,
+    call: impl Future<Output = Result<R>>,
+) -> Result<R> {
+    let (node, peer) = (node.to_string(), peer.to_string());
+    let timer = RPC_DURATION
+        .with_label_values(&[&node, &peer, method])
+        .start_timer();
+    let rsp = call.await;
+    timer.observe_duration();
+    if rsp.is_err() {
+        RPC_FAILURES
+            .with_label_values(&[&node, &peer, method])
+            .inc();
+    }
+    rsp
+}
+
+/// Follows the raft metrics of a node: elections, leader changes and progress.
+pub(crate) async fn watch_raft(mut metrics: watch::Receiver<RaftMetrics>) {
+    let mut last = metrics.borrow().clone();
+    let id = last.id.to_string();
+    let node = [id.as_str()];
+    while metrics.changed().await.is_ok() {
+        let now = metrics.borrow().clone();
+        if now.state == State::Candidate
+            && (last.state != State::Candidate || now.current_term != last.current_term)
+        {
+            ELECTIONS.with_label_values(&node).inc();
+        }
+        if now.current_leader.is_some() && now.current_leader != last.current_leader {
+            LEADER_CHANGES.with_label_values(&node).inc();
+        }
+        TERM.with_label_values(&node).set(now.current_term as i64);
+        LAST_LOG_INDEX
+            .with_label_values(&node)
+            .set(now.last_log_index as i64);
+        LAST_APPLIED
+            .with_label_values(&node)
+            .set(now.last_applied as i64);
+        IS_LEADER
+            .with_label_values(&node)
+            .set((now.state == State::Leader) as i64);
+        last = now;
+    }
+}
+
+/// Serves the metrics of the default registry at `/metrics` on `addr`.
+pub async fn serve(addr: SocketAddr) -> Result<()> {
+    let make_service = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|req| async move {
+            let rsp = if req.method() == Method::GET && req.uri().path() == "/metrics" {
+                let mut buffer = vec![];
+                let encoder = TextEncoder::new();
+                encoder.encode(&prometheus::gather(), &mut buffer).unwrap();
+                Response::builder()
+                    .header(hyper::header::CONTENT_TYPE, encoder.format_type())
+                    .body(Body::from(buffer))
+            } else {
+                Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::empty())
+            };
+            Ok::<_, Infallible>(rsp.unwrap())
+        }))
+    });
+    info!("serving metrics at http://{}/metrics", addr);
+    Server::try_bind(&addr)?.serve(make_service).await?;
+    Ok(())
+}
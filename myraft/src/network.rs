@@ -2,40 +2,107 @@ mod raftpb {
     tonic::include_proto!("raftpb");
 }
 
-use crate::raft::RaftApp;
+#[cfg(feature = "chaos")]
+use crate::chaos::ChaosNetwork;
+use crate::metrics::observe_rpc;
+use crate::raft::{NodeRole, RaftApp, RaftData};
 use crate::raftpb::raft_rpc_client::RaftRpcClient;
 use crate::raftpb::raft_rpc_server::RaftRpc;
 use crate::raftpb::{RawDataReq, RawDataRsp};
 use crate::storage::MyRaftStorage;
-use anyhow::Result;
+use crate::telemetry::{inject_metadata, metadata_context, set_parent};
+use anyhow::{anyhow, Result};
 use async_raft::async_trait::async_trait;
 use async_raft::raft::{
     AppendEntriesRequest, AppendEntriesResponse, InstallSnapshotRequest, InstallSnapshotResponse,
     VoteRequest, VoteResponse,
 };
-use async_raft::{NodeId, Raft, RaftNetwork};
+use async_raft::{AppData, NodeId, Raft, RaftMetrics, RaftNetwork};
 use bincode::{deserialize, serialize};
-use log::info;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::marker::PhantomData;
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::spawn;
+use tokio::sync::{mpsc, RwLock};
+use tokio::time::timeout;
 use tonic::{Request, Response, Status};
+use tracing::{debug_span, info, info_span, instrument, warn, Instrument, Span};
+
+/// The network async-raft sends through, behind a `ChaosNetwork` with the
+/// `chaos` feature.
+#[cfg(feature = "chaos")]
+pub type CoreNetwork<T> = ChaosNetwork<MyRaftNetwork<T>>;
+#[cfg(not(feature = "chaos"))]
+pub type CoreNetwork<T> = MyRaftNetwork<T>;
+
+pub type MyRaftCore<T> =
+    Raft<RaftData<T>, <T as RaftApp>::WriteRsp, CoreNetwork<T>, MyRaftStorage<T>>;
+
+/// Tuning of the AppendEntries replication towards each follower.
+///
+/// The entries are batched, but not pipelined: async-raft 0.6 awaits every
+/// AppendEntries before it sends the next one to the same follower, so there
+/// is never more than one batch in flight towards it.
+#[derive(Clone, Debug)]
+pub struct ReplicationConfig {
+    /// max number of log entries carried by one AppendEntries batch
+    pub max_payload_entries: u64,
+}
+
+impl Default for ReplicationConfig {
+    fn default() -> Self {
+        Self {
+            max_payload_entries: 300,
+        }
+    }
+}
+
+/// gRPC metadata carrying the cluster id of the sender of every raft RPC.
+const CLUSTER_ID_HEADER: &str = "myraft-cluster-id";
+
+// tags an outgoing raft RPC with the cluster of its sender and the span calling
+fn cluster_request<R>(cluster_id: u64, msg: R) -> Request<R> {
+    let mut req = Request::new(msg);
+    req.metadata_mut()
+        .insert(CLUSTER_ID_HEADER, cluster_id.to_string().parse().unwrap());
+    inject_metadata(req.metadata_mut());
+    req
+}
+
+// the span of an AppendEntries on the follower
+fn recv_append_entries_span<D: AppData>(node: NodeId, rpc: &AppendEntriesRequest<D>) -> Span {
+    debug_span!(
+        "recv_append_entries",
+        node,
+        leader = rpc.leader_id,
+        term = rpc.term,
+        prev_index = rpc.prev_log_index,
+        entries = rpc.entries.len()
+    )
+}
 
 pub struct MyRaftNetwork<T: RaftApp> {
     routing_table: RwLock<HashMap<NodeId, String>>,
+    // the highest log index each node accepted from this one
+    matched: Mutex<HashMap<NodeId, u64>>,
     self_id: NodeId,
+    cluster_id: u64,
+    role: NodeRole,
     app_type: PhantomData<T>,
 }
 
 impl<T: RaftApp> MyRaftNetwork<T> {
-    pub fn new(id: u64, addr: String) -> Self {
+    pub fn new(id: u64, addr: String, role: NodeRole, cluster_id: u64) -> Self {
         let mut routing_table = HashMap::new();
         routing_table.insert(id, addr);
         let routing_table = RwLock::new(routing_table);
         Self {
             self_id: id,
             routing_table,
+            matched: Mutex::new(HashMap::new()),
+            cluster_id,
+            role,
             app_type: PhantomData,
         }
     }
@@ -45,7 +112,7 @@ impl<T: RaftApp> MyRaftNetwork<T> {
         let mut adds = vec![];
         for new_node in new_rt {
             let (new_id, new_addr) = new_node;
-            if let Some(old_addr) = rt.get(&new_id) {
+            if let Some(old_addr) = rt.get(new_id) {
                 if old_addr.eq(new_addr) {
                     continue;
                 }
@@ -57,69 +124,285 @@ impl<T: RaftApp> MyRaftNetwork<T> {
         }
         adds
     }
+
+    /// Highest log index `target` was seen to hold, from the replication calls
+    /// it accepted while this node led.
+    pub fn matched_index(&self, target: NodeId) -> u64 {
+        self.matched
+            .lock()
+            .unwrap()
+            .get(&target)
+            .cloned()
+            .unwrap_or(0)
+    }
+
+    fn record_match(&self, target: NodeId, index: u64) {
+        let mut matched = self.matched.lock().unwrap();
+        let entry = matched.entry(target).or_insert(0);
+        *entry = index.max(*entry);
+    }
+
+    async fn get_addr(&self, target: NodeId) -> Result<String> {
+        let rt = self.routing_table.read().await;
+        rt.get(&target)
+            .cloned()
+            .ok_or_else(|| anyhow!("no id {} in routing table", target))
+    }
+
+    /// Asks the leader `target` for the index a linearizable read has to wait for.
+    #[instrument(level = "debug", skip(self), fields(node = self.self_id))]
+    pub async fn read_index(&self, target: NodeId) -> Result<u64> {
+        observe_rpc(self.self_id, target, "read_index", async {
+            let addr = self.get_addr(target).await?;
+            let mut client = RaftRpcClient::connect(format!("http://{}", addr)).await?;
+            let rsp = client
+                .read_index(cluster_request(
+                    self.cluster_id,
+                    RawDataReq { data: vec![] },
+                ))
+                .await?;
+            Ok(deserialize(&rsp.get_ref().data)?)
+        })
+        .await
+    }
 }
 
 #[async_trait]
-impl<T: RaftApp> RaftNetwork<T::WriteReq> for MyRaftNetwork<T> {
+impl<T: RaftApp> RaftNetwork<RaftData<T>> for MyRaftNetwork<T> {
+    #[instrument(level = "debug", skip(self, rpc), fields(
+        node = self.self_id,
+        term = rpc.term,
+        prev_index = rpc.prev_log_index,
+        entries = rpc.entries.len()
+    ))]
     async fn append_entries(
         &self,
         target: NodeId,
-        rpc: AppendEntriesRequest<T::WriteReq>,
+        rpc: AppendEntriesRequest<RaftData<T>>,
     ) -> Result<AppendEntriesResponse> {
-        let rt = self.routing_table.read().await;
-        let addr = rt
-            .get(&target)
-            .expect(&format!("no id {} in routing table", target))
-            .clone();
-        drop(rt);
-        let mut client = RaftRpcClient::connect(format!("http://{}", addr)).await?;
-        let req = Request::new(RawDataReq {
-            data: serialize(&rpc)?,
-        });
-        let rsp = client.append_entries(req).await?;
-        let rsp = deserialize(&rsp.get_ref().data)?;
+        let last_index = rpc.prev_log_index + rpc.entries.len() as u64;
+        let rsp: AppendEntriesResponse =
+            observe_rpc(self.self_id, target, "append_entries", async {
+                let addr = self.get_addr(target).await?;
+                let mut client = RaftRpcClient::connect(format!("http://{}", addr)).await?;
+                let req = cluster_request(
+                    self.cluster_id,
+                    RawDataReq {
+                        data: serialize(&rpc)?,
+                    },
+                );
+                let rsp = client.append_entries(req).await?;
+                Ok(deserialize(&rsp.get_ref().data)?)
+            })
+            .await?;
+        if rsp.success {
+            self.record_match(target, last_index);
+        }
         Ok(rsp)
     }
 
+    #[instrument(skip(self, rpc), fields(
+        node = self.self_id,
+        term = rpc.term,
+        index = rpc.last_included_index,
+        offset = rpc.offset
+    ))]
     async fn install_snapshot(
         &self,
         target: NodeId,
         rpc: InstallSnapshotRequest,
     ) -> Result<InstallSnapshotResponse> {
-        let rt = self.routing_table.read().await;
-        let addr = rt
-            .get(&target)
-            .expect(&format!("no id {} in routing table", target))
-            .clone();
-        drop(rt);
-        let mut client = RaftRpcClient::connect(format!("http://{}", addr)).await?;
-        let req = Request::new(RawDataReq {
-            data: serialize(&rpc)?,
-        });
-        let rsp = client.install_snapshot(req).await?;
-        let rsp = deserialize(&rsp.get_ref().data)?;
+        let (term, index, done) = (rpc.term, rpc.last_included_index, rpc.done);
+        let rsp: InstallSnapshotResponse =
+            observe_rpc(self.self_id, target, "install_snapshot", async {
+                let addr = self.get_addr(target).await?;
+                let mut client = RaftRpcClient::connect(format!("http://{}", addr)).await?;
+                let req = cluster_request(
+                    self.cluster_id,
+                    RawDataReq {
+                        data: serialize(&rpc)?,
+                    },
+                );
+                let rsp = client.install_snapshot(req).await?;
+                Ok(deserialize(&rsp.get_ref().data)?)
+            })
+            .await?;
+        if done && rsp.term <= term {
+            self.record_match(target, index);
+        }
         Ok(rsp)
     }
 
+    #[instrument(skip(self, rpc), fields(node = self.self_id, term = rpc.term))]
     async fn vote(&self, target: NodeId, rpc: VoteRequest) -> Result<VoteResponse> {
-        let rt = self.routing_table.read().await;
-        let addr = rt
-            .get(&target)
-            .expect(&format!("no id {} in routing table", target))
-            .clone();
-        drop(rt);
-        let mut client = RaftRpcClient::connect(format!("http://{}", addr)).await?;
-        let req = Request::new(RawDataReq {
-            data: serialize(&rpc)?,
-        });
-        let rsp = client.vote(req).await?;
-        let rsp = deserialize(&rsp.get_ref().data)?;
-        Ok(rsp)
+        // a witness has no state machine to lead with, so it never gathers votes
+        if self.role == NodeRole::Witness {
+            return Err(anyhow!("a witness does not campaign"));
+        }
+        observe_rpc(self.self_id, target, "vote", async {
+            let addr = self.get_addr(target).await?;
+            let mut client = RaftRpcClient::connect(format!("http://{}", addr)).await?;
+            let req = cluster_request(
+                self.cluster_id,
+                RawDataReq {
+                    data: serialize(&rpc)?,
+                },
+            );
+            let rsp = client.vote(req).await?;
+            Ok(deserialize(&rsp.get_ref().data)?)
+        })
+        .await
     }
 }
 
+// A late or repeated AppendEntries carries entries the follower already has.
+// async-raft would truncate its log after the first of them, dropping the ones
+// appended since that the leader may already count as replicated, so the
+// request goes in as a plain heartbeat instead.
+async fn append_entries<T: RaftApp>(
+    core: &MyRaftCore<T>,
+    storage: &MyRaftStorage<T>,
+    mut rpc: AppendEntriesRequest<RaftData<T>>,
+) -> Result<AppendEntriesResponse> {
+    if let Some(last) = rpc.entries.last() {
+        if storage.has_entry(last.index, last.term).await? {
+            rpc.entries.clear();
+        }
+    }
+    Ok(core.append_entries(rpc).await?)
+}
+
 pub struct MyRaftRpc<T: RaftApp> {
-    pub core: Arc<Raft<T::WriteReq, T::WriteRsp, MyRaftNetwork<T>, MyRaftStorage<T>>>,
+    pub id: NodeId,
+    pub core: Arc<MyRaftCore<T>>,
+    pub cluster_id: u64,
+    pub storage: Arc<MyRaftStorage<T>>,
+    pub network: Arc<CoreNetwork<T>>,
+    /// how long the leader waits for the heartbeats confirming a read
+    pub heartbeat: Duration,
+}
+
+// a derived Clone would ask for `T: Clone`
+impl<T: RaftApp> Clone for MyRaftRpc<T> {
+    fn clone(&self) -> Self {
+        Self {
+            id: self.id,
+            core: self.core.clone(),
+            cluster_id: self.cluster_id,
+            storage: self.storage.clone(),
+            network: self.network.clone(),
+            heartbeat: self.heartbeat,
+        }
+    }
+}
+
+impl<T: RaftApp> MyRaftRpc<T> {
+    /// ReadIndex on the leader: the last log index, taken before leadership is
+    /// confirmed, covers every write committed before the read.
+    #[instrument(level = "debug", skip(self), fields(node = self.id, index))]
+    pub(crate) async fn leader_read_index(&self) -> Result<u64> {
+        let metrics = self.core.metrics().borrow().clone();
+        if metrics.current_leader != Some(metrics.id) {
+            return Err(anyhow!("node {} is not the leader", metrics.id));
+        }
+        // the raft metrics lag behind the writes already answered to clients
+        let index = self.storage.last_log_index().await?;
+        Span::current().record("index", index);
+        self.confirm_leadership(&metrics).await?;
+        Ok(index)
+    }
+
+    // a majority of the voters, of both configs during a joint consensus, still
+    // follow this node in its term, so no other leader can have been elected.
+    // `Raft::client_read` is not used: it counts the leader itself as a majority
+    // of a three node cluster.
+    async fn confirm_leadership(&self, metrics: &RaftMetrics) -> Result<()> {
+        let membership = &metrics.membership_config;
+        let configs: Vec<&HashSet<NodeId>> = std::iter::once(&membership.members)
+            .chain(membership.members_after_consensus.as_ref())
+            .collect();
+        let mut confirmed: HashSet<NodeId> = [metrics.id].iter().cloned().collect();
+        let has_quorum = |confirmed: &HashSet<NodeId>| {
+            configs.iter().all(|members| {
+                members.iter().filter(|id| confirmed.contains(id)).count() > members.len() / 2
+            })
+        };
+        let peers: HashSet<NodeId> = configs
+            .iter()
+            .flat_map(|members| members.iter().cloned())
+            .filter(|id| *id != metrics.id)
+            .collect();
+        let (sender, mut receiver) = mpsc::channel(peers.len().max(1));
+        for target in peers {
+            // a heartbeat: followers only check the term and reset their election
+            // timer. They take its commit index as is without matching their log
+            // against ours, so it must not make them apply anything.
+            let rpc = AppendEntriesRequest {
+                term: metrics.current_term,
+                leader_id: metrics.id,
+                prev_log_index: 0,
+                prev_log_term: 0,
+                entries: vec![],
+                leader_commit: 0,
+            };
+            let (network, sender, heartbeat) =
+                (self.network.clone(), sender.clone(), self.heartbeat);
+            spawn(async move {
+                let rsp = timeout(heartbeat, network.append_entries(target, rpc)).await;
+                let _ = sender.send((target, rsp)).await;
+            });
+        }
+        drop(sender);
+        while !has_quorum(&confirmed) {
+            match receiver.recv().await {
+                Some((target, Ok(Ok(rsp)))) if rsp.term == metrics.current_term => {
+                    confirmed.insert(target);
+                }
+                Some((target, Ok(Ok(rsp)))) => {
+                    return Err(anyhow!(
+                        "node {} is in term {}, past {}",
+                        target,
+                        rsp.term,
+                        metrics.current_term
+                    ))
+                }
+                Some(_) => {}
+                None => return Err(anyhow!("could not confirm leadership for a read")),
+            }
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn check_cluster<R>(&self, request: &Request<R>) -> Result<(), Status> {
+        check_cluster(self.cluster_id, request)
+    }
+}
+
+// a node of another cluster must not take part in our elections or overwrite
+// our log, neither may a caller that does not say which cluster it is in
+#[allow(clippy::result_large_err)]
+fn check_cluster<R>(cluster_id: u64, request: &Request<R>) -> Result<(), Status> {
+    let claimed = request.metadata().get(CLUSTER_ID_HEADER).ok_or_else(|| {
+        warn!("rejected a raft rpc without a cluster id");
+        Status::permission_denied(format!("no {} header", CLUSTER_ID_HEADER))
+    })?;
+    let claimed: u64 = claimed
+        .to_str()
+        .ok()
+        .and_then(|claimed| claimed.parse().ok())
+        .ok_or_else(|| Status::invalid_argument("malformed cluster id"))?;
+    if claimed != cluster_id {
+        warn!(
+            "rejected a raft rpc from cluster {}, this node is in cluster {}",
+            claimed, cluster_id
+        );
+        return Err(Status::permission_denied(format!(
+            "node is in cluster {}, not {}",
+            cluster_id, claimed
+        )));
+    }
+    Ok(())
 }
 
 #[async_trait]
@@ -128,17 +411,32 @@ impl<T: RaftApp> RaftRpc for MyRaftRpc<T> {
         &self,
         request: Request<RawDataReq>,
     ) -> Result<Response<RawDataRsp>, Status> {
+        self.check_cluster(&request)?;
         let req = deserialize(&request.get_ref().data).unwrap();
-        let rsp = self.core.append_entries(req).await.unwrap();
+        let span = recv_append_entries_span(self.id, &req);
+        set_parent(&span, &metadata_context(request.metadata()));
+        let rsp = append_entries(&self.core, &self.storage, req)
+            .instrument(span)
+            .await
+            .unwrap();
         let rsp = Response::new(RawDataRsp {
             data: serialize(&rsp).unwrap(),
         });
         Ok(rsp)
     }
+
     async fn vote(&self, request: Request<RawDataReq>) -> Result<Response<RawDataRsp>, Status> {
+        self.check_cluster(&request)?;
         let req: VoteRequest = deserialize(&request.get_ref().data).unwrap();
-        info!("recv vote from {}", req.candidate_id);
-        let rsp = self.core.vote(req).await.unwrap();
+        let span = info_span!(
+            "recv_vote",
+            node = self.id,
+            candidate = req.candidate_id,
+            term = req.term
+        );
+        set_parent(&span, &metadata_context(request.metadata()));
+        span.in_scope(|| info!("recv vote from {}", req.candidate_id));
+        let rsp = self.core.vote(req).instrument(span).await.unwrap();
         let rsp = Response::new(RawDataRsp {
             data: serialize(&rsp).unwrap(),
         });
@@ -148,11 +446,55 @@ impl<T: RaftApp> RaftRpc for MyRaftRpc<T> {
         &self,
         request: Request<RawDataReq>,
     ) -> Result<Response<RawDataRsp>, Status> {
-        let req = deserialize(&request.get_ref().data).unwrap();
-        let rsp = self.core.install_snapshot(req).await.unwrap();
+        self.check_cluster(&request)?;
+        let req: InstallSnapshotRequest = deserialize(&request.get_ref().data).unwrap();
+        let span = info_span!(
+            "recv_install_snapshot",
+            node = self.id,
+            leader = req.leader_id,
+            term = req.term,
+            index = req.last_included_index,
+            offset = req.offset
+        );
+        set_parent(&span, &metadata_context(request.metadata()));
+        let rsp = self
+            .core
+            .install_snapshot(req)
+            .instrument(span)
+            .await
+            .unwrap();
         let rsp = Response::new(RawDataRsp {
             data: serialize(&rsp).unwrap(),
         });
         Ok(rsp)
     }
+
+    async fn read_index(
+        &self,
+        request: Request<RawDataReq>,
+    ) -> Result<Response<RawDataRsp>, Status> {
+        self.check_cluster(&request)?;
+        let span = debug_span!("recv_read_index", node = self.id);
+        set_parent(&span, &metadata_context(request.metadata()));
+        match self.leader_read_index().instrument(span).await {
+            Ok(index) => Ok(Response::new(RawDataRsp {
+                data: serialize(&index).unwrap(),
+            })),
+            Err(err) => Err(Status::unavailable(err.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_calls_of_the_same_cluster_are_let_through() {
+        assert!(check_cluster(7, &cluster_request(7, ())).is_ok());
+        let other = check_cluster(7, &cluster_request(8, ())).unwrap_err();
+        assert_eq!(other.code(), tonic::Code::PermissionDenied);
+        let unknown = check_cluster(7, &Request::new(())).unwrap_err();
+        assert_eq!(unknown.code(), tonic::Code::PermissionDenied);
+    }
 }
@@ -1,32 +1,182 @@
-use crate::network::MyRaftRpc;
+#[cfg(feature = "chaos")]
+use crate::chaos::{ChaosNetwork, ChaosSchedule};
+use crate::identity::NodeIdentity;
+use crate::metrics::{self, CATCH_UP_LAG};
+use crate::network::{MyRaftCore, MyRaftRpc, ReplicationConfig};
 use crate::raftpb::raft_rpc_server::RaftRpcServer;
+use crate::session::{ClientRequest, ClientSession};
+use crate::storage::{default_store_dir, RecoveryRecord};
 use crate::{network::MyRaftNetwork, storage::MyRaftStorage};
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use async_raft::async_trait::async_trait;
-use async_raft::raft::ClientWriteRequest;
+use async_raft::error::{ChangeConfigError, ClientWriteError};
+use async_raft::raft::{ClientWriteRequest, VoteRequest};
 use async_raft::{AppData, AppDataResponse};
-use async_raft::{Config, NodeId, Raft};
-use log::{error, info};
-use std::collections::HashMap;
+use async_raft::{Config, NodeId, Raft, RaftMetrics};
+use bincode::{deserialize, serialize};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::env;
-use std::sync::mpsc::{channel, Sender};
-use std::sync::Arc;
-use std::time::Duration;
-use tokio::spawn;
-use tokio::sync::RwLock;
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::sync::{watch, RwLock, RwLockReadGuard};
+use tokio::time::{sleep, timeout};
+use tokio::{select, spawn};
 use tonic::transport::Server;
+use tracing::{error, field, info, info_span, Instrument};
 use zookeeper::{Acl, CreateMode, WatchedEvent, WatchedEventType, Watcher, ZkError, ZooKeeper};
 
+/// The replicated state machine. It is shared with the application, which
+/// reads it while raft applies to it, so it guards its own state.
 #[async_trait]
 pub trait RaftApp: Send + Sync + 'static {
     type WriteReq: AppData;
     type WriteRsp: AppDataResponse;
+    /// The state at one point in time, serialized into a snapshot later on.
+    type SnapshotView: Send;
 
-    async fn handle_write(&mut self, req: Self::WriteReq) -> Result<Self::WriteRsp>;
-    async fn make_snapshot(&self) -> Result<Vec<u8>>;
-    async fn handle_snapshot(&self, snap: &Vec<u8>) -> Result<()>;
+    /// Writes come one at a time, in log order, each with the index of its
+    /// log entry, the same on every node. A write applied already may come
+    /// again, e.g. after a restart lost the record of it having been applied:
+    /// it is not applied twice, but answered as it was.
+    async fn handle_write(&self, index: u64, req: Self::WriteReq) -> Result<Self::WriteRsp>;
+    /// Taken between two writes, which wait for it, so it should be cheap,
+    /// e.g. a copy-on-write handle.
+    fn snapshot_view(&self) -> Result<Self::SnapshotView>;
+    /// Serializes `view` into `sink` while the following writes go on.
+    async fn make_snapshot(
+        &self,
+        view: Self::SnapshotView,
+        sink: &mut SnapshotSink<'_>,
+    ) -> Result<()>;
+    /// Replaces the whole state with the snapshot read from `source`: nothing
+    /// of the state before may be left, e.g. keys the snapshot does not have.
+    async fn handle_snapshot(&self, source: &mut SnapshotSource<'_>) -> Result<()>;
 }
 
+/// Where a snapshot is streamed to, a file of the store or a backup.
+pub type SnapshotSink<'a> = dyn AsyncWrite + Send + Unpin + 'a;
+/// Where a snapshot is streamed from.
+pub type SnapshotSource<'a> = dyn AsyncRead + Send + Unpin + 'a;
+
+/// What the raft log carries for a write of app `T`.
+pub type RaftData<T> = ClientRequest<<T as RaftApp>::WriteReq>;
+
+/// What a node does in its cluster, declared when it joins.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NodeRole {
+    /// votes and keeps the whole state, may be leader
+    #[default]
+    Voter,
+    /// keeps the whole state to serve reads, never votes
+    Learner,
+    /// votes but keeps only the log metadata, never campaigns. Its ack counts
+    /// toward a commit like a voter's, so a write may be committed on the
+    /// leader's disk alone; no voter missing it can be elected meanwhile, the
+    /// witness's log has the entry, but the write is lost with that disk.
+    Witness,
+}
+
+impl NodeRole {
+    /// Whether the node counts in the cluster's membership.
+    pub fn is_member(&self) -> bool {
+        *self != NodeRole::Learner
+    }
+}
+
+impl FromStr for NodeRole {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "voter" => Ok(NodeRole::Voter),
+            "learner" => Ok(NodeRole::Learner),
+            "witness" => Ok(NodeRole::Witness),
+            _ => Err(anyhow!("unknown node role {}", s)),
+        }
+    }
+}
+
+/// What a node registers in zookeeper, or is listed as in a static cluster.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NodeInfo {
+    pub addr: String,
+    pub role: NodeRole,
+}
+
+impl NodeInfo {
+    fn decode(data: Vec<u8>) -> Result<Self> {
+        match deserialize(&data) {
+            Ok(info) => Ok(info),
+            // nodes registered before roles existed only wrote their address
+            Err(_) => Ok(NodeInfo {
+                addr: String::from_utf8(data)?,
+                role: NodeRole::Voter,
+            }),
+        }
+    }
+}
+
+/// A write sent to a node that does not lead; `leader` is the one it knows
+/// of, if any. The write was not appended to the log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NotLeader {
+    pub leader: Option<NodeId>,
+}
+
+impl fmt::Display for NotLeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.leader {
+            Some(leader) => write!(f, "not the leader, node {} leads", leader),
+            None => write!(f, "not the leader, no leader is known"),
+        }
+    }
+}
+
+impl Error for NotLeader {}
+
+/// When a node joining as a voter or witness is promoted from learner.
+#[derive(Clone, Debug)]
+pub struct PromotionConfig {
+    /// max log entries the node may be behind the leader's applied index
+    pub max_lag: u64,
+    /// how long the node gets to catch up before it is logged as behind; it
+    /// stays a learner until it catches up
+    pub timeout: Duration,
+    /// how often the catch-up progress is checked and logged
+    pub progress_interval: Duration,
+}
+
+impl Default for PromotionConfig {
+    fn default() -> Self {
+        Self {
+            max_lag: 100,
+            timeout: Duration::from_secs(300),
+            progress_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+// a witness draws its election timeouts up to a year, under the longest sleep
+// of tokio (about two years), so a voter always takes over first; its minimum
+// stays the voters', the time after a heartbeat it turns votes away
+const WITNESS_ELECTION_TIMEOUT_MAX: u64 = 365 * 24 * 3600 * 1000;
+// how long a read waits for the log it has to see to be applied
+const WAIT_APPLIED_TIMEOUT: Duration = Duration::from_secs(5);
+// how often a node of a static cluster checks the membership follows the node list
+const STATIC_RECONCILE_INTERVAL: Duration = Duration::from_millis(500);
+// how long the zookeeper watcher waits before reading the nodes again after a failure
+const ZK_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+// how often a leader that added members checks it can step down
+const STEP_DOWN_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
 fn zk_server_urls() -> String {
     let key = "ZOOKEEPER_SERVERS";
     match env::var(key) {
@@ -44,49 +194,178 @@ impl Watcher for NopWatcher {
 }
 
 struct NodeWatcher {
-    sender: Sender<()>,
+    sender: UnboundedSender<()>,
 }
 
 impl Watcher for NodeWatcher {
     fn handle(&self, e: WatchedEvent) {
         info!("watcher get event {:?}", e);
-        match e.event_type {
-            WatchedEventType::NodeChildrenChanged => {
-                self.sender.send(()).unwrap();
-            }
-            _ => todo!(),
+        if let WatchedEventType::NodeChildrenChanged = e.event_type {
+            // the watcher loop may be gone with the node
+            let _ = self.sender.send(());
+        }
+    }
+}
+
+// the nodes registered under `path`, watching it for the next change
+fn watch_registered(
+    zk: &ZooKeeper,
+    path: &str,
+    sender: &UnboundedSender<()>,
+) -> Result<HashMap<NodeId, NodeInfo>> {
+    let watcher = NodeWatcher {
+        sender: sender.clone(),
+    };
+    let nodes = zk.get_children_w(path, watcher)?;
+    info!("cluster change:{:?}", &nodes);
+    let mut registered = HashMap::new();
+    for node in nodes {
+        let data = zk.get_data(&format!("{}/{}", path, node), false)?.0;
+        registered.insert(node.parse()?, NodeInfo::decode(data)?);
+    }
+    Ok(registered)
+}
+
+// waits for the leader this node knows of to differ from `leader`, and returns
+// the new one, or None once the node shut down
+async fn leader_change(
+    metrics: &mut watch::Receiver<RaftMetrics>,
+    leader: Option<NodeId>,
+) -> Option<Option<NodeId>> {
+    loop {
+        metrics.changed().await.ok()?;
+        let now = metrics.borrow().current_leader;
+        if now != leader {
+            return Some(now);
         }
     }
 }
 
 pub struct MyRaft<T: RaftApp> {
     my_network: Arc<MyRaftNetwork<T>>,
+    #[cfg(feature = "chaos")]
+    my_chaos: Arc<ChaosNetwork<MyRaftNetwork<T>>>,
     pub my_storage: Arc<MyRaftStorage<T>>,
-    my_core: Arc<Raft<T::WriteReq, T::WriteRsp, MyRaftNetwork<T>, MyRaftStorage<T>>>,
+    my_core: Arc<MyRaftCore<T>>,
+    my_rpc: MyRaftRpc<T>,
     my_id: NodeId,
     my_addr: String,
+    my_role: NodeRole,
+    my_identity: NodeIdentity,
+    my_promotion: PromotionConfig,
+    my_held: HeldTerm,
 }
 
-impl<T: RaftApp> MyRaft<T> {
-    pub async fn new(id: NodeId, raft_addr: String, sm: Arc<RwLock<T>>) -> Self {
-        let my_network = Arc::new(MyRaftNetwork::<T>::new(id, raft_addr.clone()));
-        let my_storage = Arc::new(MyRaftStorage::<T>::new(id, sm));
-        let my_config = Arc::new(
-            Config::build("test".into())
-                .validate()
-                .expect("failed to build Raft config"),
-        );
+pub struct MyRaftBuilder<T: RaftApp> {
+    id: NodeId,
+    raft_addr: String,
+    sm: Arc<T>,
+    replication: ReplicationConfig,
+    promotion: PromotionConfig,
+    role: NodeRole,
+    cluster_id: Option<u64>,
+    store_dir: Option<String>,
+    metrics_addr: Option<String>,
+}
+
+impl<T: RaftApp> MyRaftBuilder<T> {
+    pub fn new(id: NodeId, raft_addr: String, sm: Arc<T>) -> Self {
+        Self {
+            id,
+            raft_addr,
+            sm,
+            replication: ReplicationConfig::default(),
+            promotion: PromotionConfig::default(),
+            role: NodeRole::default(),
+            cluster_id: None,
+            store_dir: None,
+            metrics_addr: None,
+        }
+    }
+
+    pub fn replication(mut self, replication: ReplicationConfig) -> Self {
+        self.replication = replication;
+        self
+    }
+
+    /// How far new members must catch up before this node, as leader, makes
+    /// them voters or witnesses.
+    pub fn promotion(mut self, promotion: PromotionConfig) -> Self {
+        self.promotion = promotion;
+        self
+    }
+
+    pub fn role(mut self, role: NodeRole) -> Self {
+        self.role = role;
+        self
+    }
+
+    /// Cluster the node belongs to. A new store is created in it, an existing
+    /// one must have been created in it. Left unset, the persisted one is used.
+    pub fn cluster_id(mut self, cluster_id: u64) -> Self {
+        self.cluster_id = Some(cluster_id);
+        self
+    }
+
+    /// Directory of the node's raft store, `default_store_dir` of its id
+    /// unless set. The store must have been created for the node's id.
+    pub fn store_dir(mut self, dir: String) -> Self {
+        self.store_dir = Some(dir);
+        self
+    }
+
+    /// Address to serve the prometheus metrics at, on `/metrics`.
+    pub fn metrics_addr(mut self, metrics_addr: String) -> Self {
+        self.metrics_addr = Some(metrics_addr);
+        self
+    }
+
+    /// Starts the node, failing if its store or its config is not usable.
+    pub async fn build(self) -> Result<MyRaft<T>> {
+        let (id, raft_addr, role) = (self.id, self.raft_addr, self.role);
+        let dir = self.store_dir.unwrap_or_else(|| default_store_dir(id));
+        let my_identity = NodeIdentity::load_or_create(id, &dir, self.cluster_id)
+            .map_err(|err| anyhow!("refusing to start node {}: {}", id, err))?;
+        let cluster_id = my_identity.cluster_id;
+        let max_payload_entries = self.replication.max_payload_entries;
+        let my_network = Arc::new(MyRaftNetwork::<T>::new(
+            id,
+            raft_addr.clone(),
+            role,
+            cluster_id,
+        ));
+        #[cfg(feature = "chaos")]
+        let my_chaos = Arc::new(ChaosNetwork::new(my_network.clone()));
+        #[cfg(feature = "chaos")]
+        let core_network = my_chaos.clone();
+        #[cfg(not(feature = "chaos"))]
+        let core_network = my_network.clone();
+        let my_storage = Arc::new(MyRaftStorage::<T>::new(id, dir, self.sm, role)?);
+        let mut my_config = Config::build("test".into()).max_payload_entries(max_payload_entries);
+        if role == NodeRole::Witness {
+            my_config = my_config.election_timeout_max(WITNESS_ELECTION_TIMEOUT_MAX);
+        }
+        let my_config = Arc::new(my_config.validate()?);
         let my_core = Arc::new(Raft::new(
             id,
             my_config.clone(),
-            my_network.clone(),
+            core_network.clone(),
             my_storage.clone(),
         ));
-        let raft_rpc = MyRaftRpc {
+        let my_rpc = MyRaftRpc {
+            id,
             core: my_core.clone(),
+            cluster_id,
+            storage: my_storage.clone(),
+            network: core_network,
+            heartbeat: Duration::from_millis(my_config.heartbeat_interval),
         };
-        let addr = raft_addr.clone().parse().unwrap();
-        info!("raft start listening at {}", addr);
+        let raft_rpc = my_rpc.clone();
+        let addr = raft_addr.parse()?;
+        info!(
+            "raft start listening at {} as {:?} of cluster {}",
+            addr, role, cluster_id
+        );
         let _handler = spawn(async move {
             Server::builder()
                 .add_service(RaftRpcServer::new(raft_rpc))
@@ -94,30 +373,75 @@ impl<T: RaftApp> MyRaft<T> {
                 .await
                 .unwrap();
         });
-        Self {
+        spawn(metrics::watch_raft(my_core.metrics()));
+        if let Some(metrics_addr) = self.metrics_addr {
+            let metrics_addr = metrics_addr.parse()?;
+            spawn(async move { metrics::serve(metrics_addr).await.unwrap() });
+        }
+        Ok(MyRaft {
             my_network,
+            #[cfg(feature = "chaos")]
+            my_chaos,
             my_storage,
             my_core,
+            my_rpc,
             my_id: id,
             my_addr: raft_addr,
-        }
+            my_role: role,
+            my_identity,
+            my_promotion: self.promotion,
+            my_held: HeldTerm::default(),
+        })
+    }
+}
+
+impl<T: RaftApp> MyRaft<T> {
+    pub async fn new(id: NodeId, raft_addr: String, sm: Arc<T>) -> Result<Self> {
+        MyRaftBuilder::new(id, raft_addr, sm).build().await
+    }
+
+    pub fn identity(&self) -> &NodeIdentity {
+        &self.my_identity
+    }
+
+    /// Starts injecting the faults of `schedule` into the raft calls this node
+    /// sends, replacing the running schedule. `None` stops the chaos.
+    #[cfg(feature = "chaos")]
+    pub fn set_chaos(&self, schedule: Option<ChaosSchedule>) {
+        self.my_chaos.set_schedule(schedule);
+    }
+
+    #[cfg(feature = "chaos")]
+    pub fn chaos(&self) -> Option<ChaosSchedule> {
+        self.my_chaos.schedule()
     }
 
-    pub async fn join_cluster(&self, cluster_id: u64, init: bool) {
+    /// Registers the node in the zookeeper directory of its cluster, and
+    /// follows the nodes registered there, as leader making them members.
+    pub async fn join_cluster(&self, cluster_id: u64, init: bool) -> Result<()> {
+        if cluster_id != self.my_identity.cluster_id {
+            return Err(anyhow!(
+                "node {} cannot join cluster {}, it was created in cluster {}",
+                self.my_id,
+                cluster_id,
+                self.my_identity.cluster_id
+            ));
+        }
         if init {
-            let _ = self
-                .my_core
-                .initialize([self.my_id].iter().cloned().collect())
-                .await;
+            self.initialize().await;
         }
         let zk_urls = zk_server_urls();
-        let zk = ZooKeeper::connect(&zk_urls, Duration::from_secs(5), NopWatcher).unwrap();
+        let zk = ZooKeeper::connect(&zk_urls, Duration::from_secs(5), NopWatcher)?;
         let zk = Arc::new(zk);
         let watch_path = format!("/raft/{}", &cluster_id.to_string());
         let node_path = format!("{}/{}", &watch_path, &self.my_id.to_string());
+        let node_info = NodeInfo {
+            addr: self.my_addr.clone(),
+            role: self.my_role,
+        };
         while let Err(err) = zk.create(
             &node_path,
-            self.my_addr.as_bytes().to_vec(),
+            serialize(&node_info)?,
             Acl::open_unsafe().clone(),
             CreateMode::Persistent,
         ) {
@@ -131,76 +455,858 @@ impl<T: RaftApp> MyRaft<T> {
                     ) {
                         match err {
                             ZkError::NoNode => {
-                                let p = zk
-                                    .create(
-                                        "/raft",
-                                        vec![],
-                                        Acl::open_unsafe().clone(),
-                                        CreateMode::Persistent,
-                                    )
-                                    .unwrap();
-                                info!("created zk node {}", p);
+                                let p = zk.create(
+                                    "/raft",
+                                    vec![],
+                                    Acl::open_unsafe().clone(),
+                                    CreateMode::Persistent,
+                                );
+                                match p {
+                                    Ok(p) => info!("created zk node {}", p),
+                                    Err(ZkError::NodeExists) => {}
+                                    Err(err) => return Err(err.into()),
+                                }
                             }
                             ZkError::NodeExists => break,
-                            _ => {
-                                error!("{:?}", err);
-                                todo!()
-                            }
+                            _ => return Err(err.into()),
                         }
                     }
                     info!("created zk node {}", watch_path);
                 }
                 ZkError::NodeExists => break,
-                _ => {
-                    error!("{:?}", err);
-                    todo!()
-                }
+                _ => return Err(err.into()),
             }
         }
         info!("created zk node {}", node_path);
         let my_network = self.my_network.clone();
         let my_core = self.my_core.clone();
         let my_id = self.my_id;
+        let promotion = self.my_promotion.clone();
+        let held = self.my_held.clone();
+        // a new leader knows nothing of the learners, so it reconciles too
+        let mut metrics = self.my_core.metrics();
         let _handler = spawn(async move {
+            let (sender, mut changes) = unbounded_channel();
+            let (caught_up, mut promotable) = unbounded_channel();
+            let catch_ups = CatchUps::new(caught_up);
+            let mut registered = None;
+            let mut leader = metrics.borrow().current_leader;
             loop {
-                let (sender, receiver) = channel();
-                let nodes = zk
-                    .get_children_w(&watch_path, NodeWatcher { sender })
-                    .unwrap();
-                info!("cluster change:{:?}", &nodes);
-                let mut new_rt = HashMap::new();
-                for node in nodes {
-                    let id = node.parse().unwrap();
-                    let path = format!("{}/{}", watch_path, node);
-                    let data = zk.get_data(&path, false).unwrap().0;
-                    let addr = String::from_utf8(data).unwrap();
-                    new_rt.insert(id, addr);
-                }
-                let adds = my_network.update_rt(&new_rt).await;
-                let members = new_rt.keys().cloned().collect();
-                let metrics = my_core.metrics().borrow().clone();
-                if let Some(leader_id) = metrics.current_leader {
-                    if leader_id == my_id {
-                        for add in adds {
-                            my_core.add_non_voter(add).await.unwrap();
-                            info!("added non voter {}", add);
+                if registered.is_none() {
+                    match watch_registered(&zk, &watch_path, &sender) {
+                        Ok(nodes) => registered = Some(nodes),
+                        Err(err) => {
+                            error!("failed to read the nodes of {}: {}", watch_path, err);
+                            sleep(ZK_RETRY_INTERVAL).await;
+                            continue;
                         }
-                        my_core.change_membership(members).await.unwrap();
                     }
-                } else {
-                    // panic!("no leader now!");
+                }
+                if let Some(registered) = &registered {
+                    reconcile_members(
+                        &my_network,
+                        &my_core,
+                        my_id,
+                        registered,
+                        &promotion,
+                        &catch_ups,
+                        &held,
+                    )
+                    .await;
                 }
                 info!("watching {}", watch_path);
-                let _ = receiver.recv().unwrap();
+                select! {
+                    _ = changes.recv() => registered = None,
+                    _ = promotable.recv() => {}
+                    now = leader_change(&mut metrics, leader) => match now {
+                        Some(now) => leader = now,
+                        None => return,
+                    },
+                }
             }
         });
+        Ok(())
+    }
+
+    /// Joins a cluster whose nodes are known up front, without zookeeper.
+    /// `nodes` lists every node of the cluster, this one included. As with
+    /// `join_cluster`, the node that initializes the cluster starts it alone
+    /// and adds the others once it leads.
+    pub async fn join_static(&self, nodes: HashMap<NodeId, NodeInfo>, init: bool) {
+        if init {
+            self.initialize().await;
+        }
+        let my_network = self.my_network.clone();
+        let my_core = self.my_core.clone();
+        let my_id = self.my_id;
+        let promotion = self.my_promotion.clone();
+        let held = self.my_held.clone();
+        // the node list never changes, but the leader that has to act on it may
+        let mut metrics = self.my_core.metrics();
+        let _handler = spawn(async move {
+            let (caught_up, mut promotable) = unbounded_channel();
+            let catch_ups = CatchUps::new(caught_up);
+            let mut leader = metrics.borrow().current_leader;
+            loop {
+                reconcile_members(
+                    &my_network,
+                    &my_core,
+                    my_id,
+                    &nodes,
+                    &promotion,
+                    &catch_ups,
+                    &held,
+                )
+                .await;
+                select! {
+                    _ = sleep(STATIC_RECONCILE_INTERVAL) => {}
+                    _ = promotable.recv() => {}
+                    now = leader_change(&mut metrics, leader) => match now {
+                        Some(now) => leader = now,
+                        None => return,
+                    },
+                }
+            }
+        });
+    }
+
+    async fn initialize(&self) {
+        if self.my_role != NodeRole::Voter {
+            error!(
+                "only a voter can initialize the cluster, not a {:?}",
+                self.my_role
+            );
+            return;
+        }
+        let _ = self
+            .my_core
+            .initialize([self.my_id].iter().cloned().collect())
+            .await;
+    }
+
+    pub fn metrics(&self) -> RaftMetrics {
+        self.my_core.metrics().borrow().clone()
+    }
+
+    /// Index of the log a linearizable read has to wait for, confirmed by the leader (ReadIndex).
+    pub async fn read_index(&self) -> Result<u64> {
+        let leader = self.my_core.metrics().borrow().current_leader;
+        match leader {
+            Some(leader) if leader == self.my_id => {
+                let _answering = self.answering().await?;
+                self.my_rpc.leader_read_index().await
+            }
+            Some(leader) => self.my_network.read_index(leader).await,
+            None => Err(anyhow!("no leader to get the read index from")),
+        }
+    }
+
+    /// Waits until the state machine has applied the log at `index`, failing
+    /// after `WAIT_APPLIED_TIMEOUT`.
+    pub async fn wait_applied(&self, index: u64) -> Result<()> {
+        let mut metrics = self.my_core.metrics();
+        let applied = async {
+            while metrics.borrow().last_applied < index {
+                metrics.changed().await?;
+            }
+            Ok::<_, anyhow::Error>(())
+        };
+        timeout(WAIT_APPLIED_TIMEOUT, applied).await.map_err(|_| {
+            anyhow!(
+                "log {} was not applied within {:?}",
+                index,
+                WAIT_APPLIED_TIMEOUT
+            )
+        })??;
+        // async-raft may count logs as applied the storage never got
+        self.my_storage.apply_committed(index).await
+    }
+
+    /// Makes the following local reads of the state machine linearizable,
+    /// on any node including learners.
+    pub async fn read_barrier(&self) -> Result<()> {
+        let index = self.read_index().await?;
+        self.wait_applied(index).await
+    }
+
+    /// Offline recovery of a node whose cluster lost its majority for good,
+    /// see `MyRaftStorage::force_membership`. The node must be stopped.
+    pub fn force_membership(
+        id: NodeId,
+        dir: &str,
+        members: HashSet<NodeId>,
+        reason: String,
+    ) -> Result<RecoveryRecord> {
+        MyRaftStorage::<T>::force_membership(id, dir, members, reason)
+    }
+
+    /// Removes the nodes not in `members` from the cluster's zookeeper
+    /// directory, so that a recovered leader does not add them back.
+    pub fn forget_lost_members(cluster_id: u64, members: &HashSet<NodeId>) -> Result<Vec<NodeId>> {
+        let zk = ZooKeeper::connect(&zk_server_urls(), Duration::from_secs(5), NopWatcher)?;
+        let watch_path = format!("/raft/{}", cluster_id);
+        let mut forgotten = vec![];
+        for node in zk.get_children(&watch_path, false)? {
+            let id: NodeId = node.parse()?;
+            if !members.contains(&id) {
+                zk.delete(&format!("{}/{}", watch_path, node), None)?;
+                info!("deleted zk node of lost member {}", id);
+                forgotten.push(id);
+            }
+        }
+        Ok(forgotten)
+    }
+
+    /// Registers node `id` in the cluster's zookeeper directory, as the node
+    /// does itself when it joins, for the leader to add it. False if it was
+    /// registered already.
+    pub fn register_node(cluster_id: u64, id: NodeId, info: &NodeInfo) -> Result<bool> {
+        let zk = ZooKeeper::connect(&zk_server_urls(), Duration::from_secs(5), NopWatcher)?;
+        let path = format!("/raft/{}/{}", cluster_id, id);
+        match zk.create(
+            &path,
+            serialize(info)?,
+            Acl::open_unsafe().clone(),
+            CreateMode::Persistent,
+        ) {
+            Ok(_) => {
+                info!("registered node {} as {:?}", id, info);
+                Ok(true)
+            }
+            Err(ZkError::NodeExists) => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Removes node `id` from the cluster's zookeeper directory, for the
+    /// leader to remove it from the membership. False if it was not there.
+    pub fn unregister_node(cluster_id: u64, id: NodeId) -> Result<bool> {
+        let zk = ZooKeeper::connect(&zk_server_urls(), Duration::from_secs(5), NopWatcher)?;
+        match zk.delete(&format!("/raft/{}/{}", cluster_id, id), None) {
+            Ok(()) => {
+                info!("unregistered node {}", id);
+                Ok(true)
+            }
+            Err(ZkError::NoNode) => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Writes a consistent backup of this node's state machine and log to `path`.
+    /// Returns the index and term the state machine was backed up at.
+    pub async fn backup<P: AsRef<Path>>(&self, path: P) -> Result<(u64, u64)> {
+        let backup = self
+            .my_storage
+            .backup(self.my_identity.cluster_id, path)
+            .await?;
+        info!("backed up at index {} term {}", backup.index, backup.term);
+        Ok((backup.index, backup.term))
+    }
+
+    /// Seeds the store in `dir` of the new node `id` and its empty state machine
+    /// from the backup at `path`. Once started, the node is the only member of
+    /// a new cluster holding the backup's data, which other nodes can then join.
+    /// The new cluster gets a random id, returned in the node's identity.
+    pub async fn restore<P: AsRef<Path>>(
+        id: NodeId,
+        dir: &str,
+        path: P,
+        sm: &T,
+    ) -> Result<NodeIdentity> {
+        if NodeIdentity::load(id, dir)?.is_some() {
+            return Err(anyhow!("node {} already has a store at {}", id, dir));
+        }
+        let backup = MyRaftStorage::<T>::restore(id, dir, path, sm).await?;
+        let identity =
+            NodeIdentity::create(id, dir, NodeIdentity::new_cluster_id(backup.cluster_id))?;
+        info!(
+            "restored node {} of cluster {} from a backup of node {} of cluster {} at index {} term {}",
+            id, identity.cluster_id, backup.node_id, backup.cluster_id, backup.index, backup.term
+        );
+        Ok(identity)
     }
 
     pub async fn client_write(&self, req: T::WriteReq) -> Result<T::WriteRsp> {
-        let rsp = self
+        self.write(None, req).await
+    }
+
+    /// Writes `req` at most once for `session`: retrying with the same
+    /// `seq` returns the response of the first successful attempt.
+    pub async fn client_write_in_session(
+        &self,
+        session: ClientSession,
+        req: T::WriteReq,
+    ) -> Result<T::WriteRsp> {
+        self.write(Some(session), req).await
+    }
+
+    async fn write(
+        &self,
+        session: Option<ClientSession>,
+        data: T::WriteReq,
+    ) -> Result<T::WriteRsp> {
+        if self.my_role == NodeRole::Witness {
+            let leader = self.metrics().current_leader;
+            return Err(NotLeader { leader }.into());
+        }
+        let _answering = self.answering().await?;
+        let span = info_span!("client_write", node = self.my_id, index = field::Empty);
+        let req = ClientRequest { session, data };
+        let rsp = match self
             .my_core
             .client_write(ClientWriteRequest::new(req))
-            .await?;
+            .instrument(span.clone())
+            .await
+        {
+            Ok(rsp) => rsp,
+            // not appended, so the client can send it again to the leader
+            Err(ClientWriteError::ForwardToLeader(_, leader)) => {
+                return Err(NotLeader { leader }.into())
+            }
+            Err(err) => return Err(err.into()),
+        };
+        // the log index the write can be found by on the other nodes
+        span.record("index", rsp.index);
         Ok(rsp.data)
     }
+
+    // a leader that added members answers nothing more in its term, the
+    // client tries again once the next leader is elected
+    async fn answering(&self) -> Result<RwLockReadGuard<'_, ()>> {
+        let answering = self.my_held.answering.read().await;
+        let metrics = self.metrics();
+        if metrics.current_leader == Some(self.my_id) && self.my_held.holds(metrics.current_term) {
+            return Err(NotLeader { leader: None }.into());
+        }
+        Ok(answering)
+    }
+}
+
+// makes the cluster's membership follow the registered nodes: learners are
+// only replicated to, voters and witnesses are members once they caught up.
+// A leader that adds members holds its term and steps down, see `HeldTerm`.
+async fn reconcile_members<T: RaftApp>(
+    my_network: &Arc<MyRaftNetwork<T>>,
+    my_core: &Arc<MyRaftCore<T>>,
+    my_id: NodeId,
+    registered: &HashMap<NodeId, NodeInfo>,
+    promotion: &PromotionConfig,
+    catch_ups: &CatchUps,
+    held: &HeldTerm,
+) {
+    let new_rt = registered
+        .iter()
+        .map(|(id, info)| (*id, info.addr.clone()))
+        .collect();
+    let adds = my_network.update_rt(&new_rt).await;
+    if !adds.is_empty() {
+        info!("new nodes {:?}", adds);
+    }
+    let metrics = my_core.metrics().borrow().clone();
+    if metrics.current_leader != Some(my_id) {
+        return;
+    }
+    // new voters and witnesses start as learners too, until they catch up; a
+    // new leader knows of no learners, and is run for as soon as it leads
+    for id in registered.keys() {
+        if *id == my_id || metrics.membership_config.contains(id) {
+            continue;
+        }
+        let (core, id) = (my_core.clone(), *id);
+        spawn(async move {
+            match core.add_non_voter(id).await {
+                Ok(()) => info!("added learner {}", id),
+                Err(ChangeConfigError::Noop) => {}
+                Err(err) => error!("failed to add learner {}: {}", id, err),
+            }
+        });
+    }
+    let mut members: HashSet<NodeId> = registered
+        .iter()
+        .filter(|(_, info)| info.role.is_member())
+        .map(|(id, _)| *id)
+        .collect();
+    let joining: HashSet<NodeId> = members
+        .difference(&metrics.membership_config.members)
+        .cloned()
+        .collect();
+    catch_ups.retain(&joining);
+    for id in joining {
+        let matched = my_network.matched_index(id);
+        let lag = metrics.last_applied.saturating_sub(matched);
+        // a node that took no entry yet may not be up at all
+        if matched == 0 || lag > promotion.max_lag {
+            // a learner until its catch-up reconciles again
+            members.remove(&id);
+            catch_ups.start(my_network, my_core, my_id, id, promotion);
+        }
+    }
+    if metrics.membership_config.members != members {
+        info!("changing membership to {:?}", members);
+        let added = !members.is_subset(&metrics.membership_config.members);
+        // held before the new members are in the log, where the leader
+        // starts counting commits without them
+        let was_held = if added {
+            held.hold(metrics.current_term).await
+        } else {
+            held.term()
+        };
+        match my_core.change_membership(members).await {
+            Ok(()) if added => {
+                let term = metrics.current_term;
+                step_down(my_network, my_core, my_id, term, promotion.timeout).await
+            }
+            Ok(()) => {}
+            Err(err) => {
+                // a change in progress that adds members held the term itself
+                held.restore(was_held);
+                match err {
+                    ChangeConfigError::ConfigChangeInProgress => {
+                        info!("membership change already in progress")
+                    }
+                    err => error!("failed to change membership: {}", err),
+                }
+            }
+        }
+    }
+}
+
+/// The term in which this node, leading, added members. async-raft 0.6
+/// keeps the members a leader added in its pool of non-voters, where they do
+/// not count towards commits, until another leader is elected: the leader
+/// could report entries committed a majority of the new membership does not
+/// have. So it takes no more writes or reads in its term, and steps down once
+/// the change is committed; the next leader counts every member.
+#[derive(Clone, Default)]
+struct HeldTerm {
+    term: Arc<AtomicU64>,
+    // taken by the writes and reads the leader answers, a hold waits for them
+    answering: Arc<RwLock<()>>,
+}
+
+impl HeldTerm {
+    // holds `term` once the writes and reads in flight are answered, returns
+    // the term held before
+    async fn hold(&self, term: u64) -> u64 {
+        let _answering = self.answering.write().await;
+        self.term.swap(term, Ordering::SeqCst)
+    }
+
+    fn restore(&self, term: u64) {
+        self.term.store(term, Ordering::SeqCst)
+    }
+
+    fn term(&self) -> u64 {
+        self.term.load(Ordering::SeqCst)
+    }
+
+    // no leader leads in term 0, so it holds nothing
+    fn holds(&self, term: u64) -> bool {
+        term != 0 && self.term() == term
+    }
+}
+
+// async-raft 0.6 has no way to hand over leadership: a vote request of the
+// next term makes the leader a follower of it, and one of the members is
+// elected in a later term. The request claims an empty log, so the leader
+// grants no vote and may be elected again itself. It is refused within the
+// minimum election timeout of the last heartbeat this node got as follower,
+// so it is sent again until the term moved on. The leader first waits, for
+// at most `wait`, for the members to hold its whole log: async-raft may drop
+// the first entry of a leader elected while a follower misses entries from
+// replication until the next write.
+async fn step_down<T: RaftApp>(
+    my_network: &MyRaftNetwork<T>,
+    my_core: &MyRaftCore<T>,
+    my_id: NodeId,
+    term: u64,
+    wait: Duration,
+) {
+    let deadline = Instant::now() + wait;
+    while Instant::now() < deadline {
+        let metrics = my_core.metrics().borrow().clone();
+        if metrics.current_leader != Some(my_id) {
+            return;
+        }
+        let behind = metrics
+            .membership_config
+            .members
+            .iter()
+            .any(|id| *id != my_id && my_network.matched_index(*id) < metrics.last_log_index);
+        if !behind {
+            break;
+        }
+        sleep(STEP_DOWN_RETRY_INTERVAL).await;
+    }
+    loop {
+        let metrics = my_core.metrics().borrow().clone();
+        if metrics.current_term > term || metrics.current_leader != Some(my_id) {
+            return;
+        }
+        info!("stepping down after adding members, term {}", term);
+        let rpc = VoteRequest {
+            term: term + 1,
+            candidate_id: my_id,
+            last_log_index: 0,
+            last_log_term: 0,
+        };
+        if let Err(err) = my_core.vote(rpc).await {
+            error!("failed to step down: {}", err);
+            return;
+        }
+        sleep(STEP_DOWN_RETRY_INTERVAL).await;
+    }
+}
+
+/// The nodes joining as members the leader waits for, each in a task of its
+/// own, so that one slow node holds up no other membership change.
+struct CatchUps {
+    waiting: Arc<Mutex<HashSet<NodeId>>>,
+    // tells the reconcile loop to run again, a node caught up
+    caught_up: UnboundedSender<()>,
+}
+
+impl CatchUps {
+    fn new(caught_up: UnboundedSender<()>) -> Self {
+        Self {
+            waiting: Arc::new(Mutex::new(HashSet::new())),
+            caught_up,
+        }
+    }
+
+    // stops waiting for the nodes no longer joining
+    fn retain(&self, joining: &HashSet<NodeId>) {
+        self.waiting
+            .lock()
+            .unwrap()
+            .retain(|id| joining.contains(id));
+    }
+
+    // waits for `id` unless it is waited for already
+    fn start<T: RaftApp>(
+        &self,
+        my_network: &Arc<MyRaftNetwork<T>>,
+        my_core: &Arc<MyRaftCore<T>>,
+        my_id: NodeId,
+        id: NodeId,
+        promotion: &PromotionConfig,
+    ) {
+        if !self.waiting.lock().unwrap().insert(id) {
+            return;
+        }
+        spawn(await_catch_up(
+            my_network.clone(),
+            my_core.clone(),
+            my_id,
+            id,
+            promotion.clone(),
+            self.waiting.clone(),
+            self.caught_up.clone(),
+        ));
+    }
+}
+
+// Waits for node `id` to be within `max_lag` of the leader's applied index,
+// which trails its commit index closely, then has the membership reconciled.
+// A node still behind at the timeout stays a learner and is waited for again;
+// the wait ends too once this node stops leading or `id` stops joining.
+async fn await_catch_up<T: RaftApp>(
+    my_network: Arc<MyRaftNetwork<T>>,
+    my_core: Arc<MyRaftCore<T>>,
+    my_id: NodeId,
+    id: NodeId,
+    promotion: PromotionConfig,
+    waiting: Arc<Mutex<HashSet<NodeId>>>,
+    caught_up: UnboundedSender<()>,
+) {
+    let (node, peer) = (my_id.to_string(), id.to_string());
+    let mut deadline = Instant::now() + promotion.timeout;
+    let caught = loop {
+        sleep(promotion.progress_interval).await;
+        let metrics = my_core.metrics().borrow().clone();
+        if metrics.current_leader != Some(my_id) || !waiting.lock().unwrap().contains(&id) {
+            break false;
+        }
+        let matched = my_network.matched_index(id);
+        let lag = metrics.last_applied.saturating_sub(matched);
+        if matched > 0 && lag <= promotion.max_lag {
+            info!("node {} caught up to {}", id, matched);
+            break true;
+        }
+        CATCH_UP_LAG
+            .with_label_values(&[&node, &peer])
+            .set(lag as i64);
+        info!(
+            "node {} caught up to {} of {}, {} behind",
+            id, matched, metrics.last_applied, lag
+        );
+        if Instant::now() >= deadline {
+            error!(
+                "node {} did not catch up within {:?}, it stays a learner until it does",
+                id, promotion.timeout
+            );
+            deadline = Instant::now() + promotion.timeout;
+        }
+    };
+    waiting.lock().unwrap().remove(&id);
+    let _ = CATCH_UP_LAG.remove_label_values(&[&node, &peer]);
+    if caught {
+        // the loop may be gone with the node
+        let _ = caught_up.send(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inspect::{Inspector, RawPayload};
+    use crate::storage::state_path;
+    use crate::testing::{enter_scratch_dir, once_released, Add, Counter, Sum};
+    use async_raft::raft::{Entry, EntryConfigChange, EntryNormal, EntryPayload, MembershipConfig};
+    use async_raft::storage::HardState;
+    use async_raft::{RaftStorage, State};
+    use std::fs;
+    use std::net::TcpListener;
+
+    fn free_local_addr() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    async fn node(id: NodeId, addr: &str) -> MyRaft<Counter> {
+        let _ = fs::remove_dir_all(default_store_dir(id));
+        let promotion = PromotionConfig {
+            max_lag: 10,
+            timeout: Duration::from_secs(1),
+            progress_interval: Duration::from_millis(100),
+        };
+        MyRaftBuilder::new(id, addr.to_string(), Arc::new(Counter::default()))
+            .promotion(promotion)
+            .build()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn an_empty_node_is_promoted_once_it_caught_up() {
+        enter_scratch_dir();
+        let (leader_id, joiner_id) = (301, 302);
+        let (leader_addr, joiner_addr) = (free_local_addr(), free_local_addr());
+        let leader = node(leader_id, &leader_addr).await;
+        let info = |addr: &str| NodeInfo {
+            addr: addr.to_string(),
+            role: NodeRole::Voter,
+        };
+        let mut registered: HashMap<_, _> =
+            vec![(leader_id, info(&leader_addr))].into_iter().collect();
+        // no static loop: it would reconcile to the registrations it started with
+        leader.initialize().await;
+        let deadline = Instant::now() + Duration::from_secs(30);
+        while leader.metrics().current_leader != Some(leader_id) {
+            assert!(Instant::now() < deadline, "node {} did not lead", leader_id);
+            sleep(Duration::from_millis(100)).await;
+        }
+        for _ in 0..100 {
+            leader.client_write(Add(1)).await.unwrap();
+        }
+
+        // the joiner is not up yet, the reconcile leaves it a learner without
+        // waiting for it
+        registered.insert(joiner_id, info(&joiner_addr));
+        let (caught_up, mut promotable) = unbounded_channel();
+        let catch_ups = CatchUps::new(caught_up);
+        let reconcile = reconcile_members(
+            &leader.my_network,
+            &leader.my_core,
+            leader_id,
+            &registered,
+            &leader.my_promotion,
+            &catch_ups,
+            &leader.my_held,
+        );
+        timeout(Duration::from_secs(1), reconcile).await.unwrap();
+        let members = leader.metrics().membership_config;
+        assert_eq!(members.members, [leader_id].iter().copied().collect());
+
+        let (node_label, peer_label) = (leader_id.to_string(), joiner_id.to_string());
+        let gauge = CATCH_UP_LAG.with_label_values(&[&node_label, &peer_label]);
+        let deadline = Instant::now() + Duration::from_secs(30);
+        while gauge.get() <= 10 {
+            assert!(
+                Instant::now() < deadline,
+                "the lag of node {} was not set",
+                joiner_id
+            );
+            sleep(Duration::from_millis(100)).await;
+        }
+
+        let _joiner = node(joiner_id, &joiner_addr).await;
+        timeout(Duration::from_secs(30), promotable.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        let applied = leader.metrics().last_applied;
+        assert!(applied <= leader.my_network.matched_index(joiner_id) + 10);
+        reconcile_members(
+            &leader.my_network,
+            &leader.my_core,
+            leader_id,
+            &registered,
+            &leader.my_promotion,
+            &catch_ups,
+            &leader.my_held,
+        )
+        .await;
+        let members = leader.metrics().membership_config;
+        assert!(members.contains(&joiner_id), "{:?}", members);
+        // cleared, not left at the last lag seen
+        assert!(CATCH_UP_LAG
+            .remove_label_values(&[&node_label, &peer_label])
+            .is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn a_leader_that_added_members_steps_down_before_it_answers_again() {
+        enter_scratch_dir();
+        let ids = [303, 304];
+        let addrs = [free_local_addr(), free_local_addr()];
+        let nodes: HashMap<_, _> = ids
+            .iter()
+            .zip(addrs.iter())
+            .map(|(id, addr)| {
+                let info = NodeInfo {
+                    addr: addr.clone(),
+                    role: NodeRole::Voter,
+                };
+                (*id, info)
+            })
+            .collect();
+        let first = node(ids[0], &addrs[0]).await;
+        first.join_static(nodes.clone(), true).await;
+        let deadline = Instant::now() + Duration::from_secs(30);
+        while first.metrics().current_leader != Some(ids[0]) {
+            assert!(Instant::now() < deadline, "node {} did not lead", ids[0]);
+            sleep(Duration::from_millis(100)).await;
+        }
+        let alone = first.metrics().current_term;
+        first.client_write(Add(1)).await.unwrap();
+
+        let second = node(ids[1], &addrs[1]).await;
+        second.join_static(nodes, false).await;
+        let all: HashSet<NodeId> = ids.iter().copied().collect();
+        let deadline = Instant::now() + Duration::from_secs(30);
+        let leader = loop {
+            assert!(Instant::now() < deadline, "node {} was not added", ids[1]);
+            let leader = [&first, &second].iter().copied().find(|node| {
+                let metrics = node.metrics();
+                metrics.state == State::Leader && metrics.membership_config.members == all
+            });
+            match leader {
+                Some(leader) if leader.metrics().current_term > alone => break leader,
+                // the term the second node was added in takes no more writes
+                Some(leader) => {
+                    let err = leader.client_write(Add(1)).await.unwrap_err();
+                    assert!(err.downcast_ref::<NotLeader>().is_some(), "{}", err);
+                }
+                None => {}
+            }
+            sleep(Duration::from_millis(100)).await;
+        };
+        assert!(first.my_held.holds(alone));
+        leader.client_write(Add(1)).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn a_node_forced_alone_elects_itself_and_takes_writes() {
+        enter_scratch_dir();
+        let id = 305;
+        let (dir, addr) = (default_store_dir(id), free_local_addr());
+        let _ = fs::remove_dir_all(&dir);
+        let lost: HashSet<NodeId> = [id, 306, 307].iter().copied().collect();
+        // the store of a stopped member of a three node cluster, voted in term 3
+        {
+            NodeIdentity::load_or_create(id, &dir, None).unwrap();
+            let counter = Arc::new(Counter::default());
+            let storage = MyRaftStorage::new(id, dir.clone(), counter, NodeRole::Voter).unwrap();
+            let config = EntryConfigChange {
+                membership: MembershipConfig {
+                    members: lost.clone(),
+                    members_after_consensus: None,
+                },
+            };
+            let data = ClientRequest {
+                session: None,
+                data: Add(1),
+            };
+            let entries = [
+                Entry {
+                    term: 1,
+                    index: 1,
+                    payload: EntryPayload::ConfigChange(config),
+                },
+                Entry {
+                    term: 2,
+                    index: 2,
+                    payload: EntryPayload::Normal(EntryNormal { data }),
+                },
+            ];
+            for entry in &entries {
+                storage.append_entry_to_log(entry).await.unwrap();
+            }
+            let hs = HardState {
+                current_term: 3,
+                voted_for: Some(306),
+            };
+            storage.save_hard_state(&hs).await.unwrap();
+        }
+
+        let alone: HashSet<NodeId> = [id].iter().copied().collect();
+        let reason = "306 and 307 are gone".to_string();
+        let record = once_released(|| {
+            MyRaft::<Counter>::force_membership(id, &dir, alone.clone(), reason.clone())
+        });
+        // past the term of the last vote, after the last entry
+        assert_eq!((record.index, record.term), (3, 4));
+        assert_eq!(record.prev_membership.members, lost);
+        assert_eq!(record.membership.members, alone);
+        {
+            let inspector = once_released(|| Inspector::open(state_path(&dir)));
+            let hs = inspector.hard_state().unwrap().unwrap();
+            assert_eq!((hs.current_term, hs.voted_for), (4, None));
+            let entries = inspector.entries(3, u64::MAX).unwrap();
+            assert_eq!(entries.len(), 1);
+            assert_eq!((entries[0].index, entries[0].term), (3, 4));
+            match &entries[0].payload {
+                RawPayload::ConfigChange(membership) => {
+                    assert_eq!(membership.members, alone);
+                    assert!(membership.members_after_consensus.is_none());
+                }
+                payload => panic!("entry 3 is not a config change: {:?}", payload),
+            }
+            let audit = inspector.recovery_audit().unwrap();
+            assert_eq!(audit.len(), 1);
+            assert_eq!((audit[0].index, audit[0].term), (3, 4));
+            assert_eq!(audit[0].membership.members, alone);
+            assert_eq!(audit[0].reason, reason);
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let node = loop {
+            let builder = MyRaftBuilder::new(id, addr.clone(), Arc::new(Counter::default()));
+            match builder.build().await {
+                Ok(node) => break node,
+                Err(err) => assert!(Instant::now() < deadline, "{}", err),
+            }
+            sleep(Duration::from_millis(50)).await;
+        };
+        let deadline = Instant::now() + Duration::from_secs(30);
+        while node.metrics().state != State::Leader {
+            assert!(Instant::now() < deadline, "node {} did not lead", id);
+            sleep(Duration::from_millis(100)).await;
+        }
+        assert!(node.metrics().current_term >= 4);
+        assert_eq!(node.metrics().membership_config.members, alone);
+        // the write of before the recovery is kept
+        assert_eq!(node.client_write(Add(2)).await.unwrap(), Sum(3));
+    }
 }
@@ -0,0 +1,257 @@
+//! Client sessions, so that a retried write is applied only once.
+//!
+//! A client numbers its writes with increasing `seq`s. The last `seq` applied
+//! for each client and its response are kept in the replicated state; a write
+//! coming again with that `seq` gets the saved response instead of being applied.
+
+use crate::storage::LAST_APPLIED_LOG_KEY;
+use anyhow::{anyhow, Result};
+use async_raft::AppData;
+use bincode::{deserialize, serialize};
+use serde::{Deserialize, Serialize};
+use sled::transaction::{TransactionResult, Transactional};
+use sled::{Db, Tree};
+use std::convert::TryInto;
+
+/// Sessions kept at most; the one idle for the most log entries goes first.
+pub(crate) const MAX_CLIENT_SESSIONS: u64 = 10_000;
+const SESSION_TREE: &str = "sessions";
+// the sessions keyed by the index of their last write, then their client id
+const SESSION_INDEX_TREE: &str = "sessions_by_index";
+const SESSION_COUNT_KEY: &str = "session_count";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientSession {
+    pub client_id: u64,
+    pub seq: u64,
+}
+
+/// What is replicated for every client write.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClientRequest<D: AppData> {
+    pub session: Option<ClientSession>,
+    #[serde(bound = "D: AppData")]
+    pub data: D,
+}
+
+impl<D: AppData> AppData for ClientRequest<D> {}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct SessionEntry {
+    pub seq: u64,
+    /// log index of the last write, to expire the idlest sessions first
+    pub index: u64,
+    /// bincode of the last write's response
+    pub rsp: Vec<u8>,
+}
+
+#[inline]
+fn session_key(client_id: u64) -> [u8; 8] {
+    client_id.to_be_bytes()
+}
+
+#[inline]
+fn index_key(index: u64, client_id: u64) -> [u8; 16] {
+    let mut key = [0; 16];
+    key[..8].copy_from_slice(&index.to_be_bytes());
+    key[8..].copy_from_slice(&client_id.to_be_bytes());
+    key
+}
+
+/// The sessions of a store, next to its raft state in the same db.
+pub(crate) struct Sessions {
+    entries: Tree,
+    by_index: Tree,
+    state: Tree,
+    max: u64,
+}
+
+impl Sessions {
+    /// Opens the sessions of `db`, keeping at most `max`.
+    pub fn open(db: &Db, state: Tree, max: u64) -> Result<Self> {
+        Ok(Self {
+            entries: db.open_tree(SESSION_TREE)?,
+            by_index: db.open_tree(SESSION_INDEX_TREE)?,
+            state,
+            max,
+        })
+    }
+
+    /// Response saved for a write of `session` that was already applied. Older
+    /// `seq`s get the latest response: the client has moved on and ignores them.
+    pub fn applied_response(&self, session: &ClientSession) -> Result<Option<Vec<u8>>> {
+        match self.entries.get(session_key(session.client_id))? {
+            Some(entry) => {
+                let entry: SessionEntry = deserialize(&entry)?;
+                if session.seq <= entry.seq {
+                    Ok(Some(entry.rsp))
+                } else {
+                    Ok(None)
+                }
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Saves the response of the write of `session` at log `index` and marks
+    /// `index` as the last applied log, all at once.
+    pub fn record_response(&self, session: &ClientSession, index: u64, rsp: Vec<u8>) -> Result<()> {
+        let key = session_key(session.client_id);
+        let prev = match self.entries.get(key)? {
+            Some(prev) => Some(deserialize::<SessionEntry>(&prev)?.index),
+            None => None,
+        };
+        let count: u64 = match self.state.get(SESSION_COUNT_KEY)? {
+            Some(count) => deserialize(&count)?,
+            None => 0,
+        };
+        // every replica applies the same entries, so they all expire the same one
+        let idlest = match prev {
+            None if count >= self.max => self.by_index.first()?.map(|(k, _)| k),
+            _ => None,
+        };
+        let count = match (prev, &idlest) {
+            (None, None) => count + 1,
+            _ => count,
+        };
+        let entry = serialize(&SessionEntry {
+            seq: session.seq,
+            index,
+            rsp,
+        })?;
+        let count = serialize(&count)?;
+        let last_applied = serialize(&index)?;
+        let done: TransactionResult<(), ()> = (&self.entries, &self.by_index, &self.state)
+            .transaction(|(entries, by_index, state)| {
+                if let Some(idlest) = &idlest {
+                    by_index.remove(idlest)?;
+                    entries.remove(&idlest[8..])?;
+                }
+                if let Some(prev) = prev {
+                    by_index.remove(&index_key(prev, session.client_id))?;
+                }
+                by_index.insert(&index_key(index, session.client_id), &[])?;
+                entries.insert(&key, entry.as_slice())?;
+                state.insert(SESSION_COUNT_KEY, count.as_slice())?;
+                state.insert(LAST_APPLIED_LOG_KEY, last_applied.as_slice())?;
+                Ok(())
+            });
+        done.map_err(|err| anyhow!("failed to record the response of {:?}: {:?}", session, err))
+    }
+
+    pub fn export(&self) -> Result<Vec<(u64, SessionEntry)>> {
+        self.entries
+            .iter()
+            .map(|kv| {
+                let (k, v) = kv?;
+                let client_id = u64::from_be_bytes(k.as_ref().try_into()?);
+                Ok((client_id, deserialize(&v)?))
+            })
+            .collect()
+    }
+
+    /// Replaces the sessions with `entries`, all at once.
+    pub fn import(&self, entries: &[(u64, SessionEntry)]) -> Result<()> {
+        let old: Vec<_> = self.entries.iter().keys().collect::<sled::Result<_>>()?;
+        let old_index: Vec<_> = self.by_index.iter().keys().collect::<sled::Result<_>>()?;
+        let new = entries
+            .iter()
+            .map(|(client_id, entry)| Ok((*client_id, entry.index, serialize(entry)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let count = serialize(&(entries.len() as u64))?;
+        let done: TransactionResult<(), ()> = (&self.entries, &self.by_index, &self.state)
+            .transaction(|(tx_entries, by_index, state)| {
+                for key in &old {
+                    tx_entries.remove(key)?;
+                }
+                for key in &old_index {
+                    by_index.remove(key)?;
+                }
+                for (client_id, index, entry) in &new {
+                    tx_entries.insert(&session_key(*client_id), entry.as_slice())?;
+                    by_index.insert(&index_key(*index, *client_id), &[])?;
+                }
+                state.insert(SESSION_COUNT_KEY, count.as_slice())?;
+                Ok(())
+            });
+        done.map_err(|err| anyhow!("failed to import the sessions: {:?}", err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(max: u64) -> (Db, Sessions) {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let state = db.open_tree("state").unwrap();
+        let sessions = Sessions::open(&db, state, max).unwrap();
+        (db, sessions)
+    }
+
+    fn record(sessions: &Sessions, client_id: u64, seq: u64, index: u64) {
+        let session = ClientSession { client_id, seq };
+        let rsp = serialize(&index).unwrap();
+        sessions.record_response(&session, index, rsp).unwrap();
+    }
+
+    fn response(sessions: &Sessions, client_id: u64, seq: u64) -> Option<u64> {
+        let session = ClientSession { client_id, seq };
+        let rsp = sessions.applied_response(&session).unwrap();
+        rsp.map(|rsp| deserialize(&rsp).unwrap())
+    }
+
+    fn clients(sessions: &Sessions) -> Vec<u64> {
+        let entries = sessions.export().unwrap();
+        entries
+            .into_iter()
+            .map(|(client_id, _)| client_id)
+            .collect()
+    }
+
+    #[test]
+    fn a_write_applied_already_gets_the_latest_response() {
+        let (_db, sessions) = open(MAX_CLIENT_SESSIONS);
+        record(&sessions, 1, 1, 10);
+        record(&sessions, 1, 2, 11);
+        assert_eq!(response(&sessions, 1, 2), Some(11));
+        assert_eq!(response(&sessions, 1, 1), Some(11));
+        assert_eq!(response(&sessions, 1, 3), None);
+        assert_eq!(response(&sessions, 2, 1), None);
+    }
+
+    #[test]
+    fn the_session_idle_for_the_most_entries_goes_first() {
+        let (_db, sessions) = open(2);
+        record(&sessions, 1, 1, 1);
+        record(&sessions, 2, 1, 2);
+        // client 1 is used again, so client 2 is now the idlest
+        record(&sessions, 1, 2, 3);
+        record(&sessions, 3, 1, 4);
+        assert_eq!(clients(&sessions), vec![1, 3]);
+        assert_eq!(response(&sessions, 2, 1), None);
+        record(&sessions, 4, 1, 5);
+        assert_eq!(clients(&sessions), vec![3, 4]);
+    }
+
+    #[test]
+    fn imported_sessions_expire_by_the_index_of_their_last_write() {
+        let (_db, sessions) = open(2);
+        record(&sessions, 1, 1, 2);
+        record(&sessions, 2, 1, 1);
+        let (_other_db, imported) = open(2);
+        record(&imported, 4, 1, 5);
+        imported.import(&sessions.export().unwrap()).unwrap();
+        assert_eq!(clients(&imported), vec![1, 2]);
+        record(&imported, 3, 1, 3);
+        assert_eq!(clients(&imported), vec![1, 3]);
+    }
+
+    #[test]
+    fn the_last_applied_log_moves_with_the_response() {
+        let (_db, sessions) = open(MAX_CLIENT_SESSIONS);
+        record(&sessions, 1, 1, 7);
+        let last_applied = sessions.state.get(LAST_APPLIED_LOG_KEY).unwrap().unwrap();
+        assert_eq!(deserialize::<u64>(&last_applied).unwrap(), 7);
+    }
+}
@@ -1,34 +1,93 @@
-use anyhow::Result;
-use async_raft::raft::{Entry, EntryPayload, MembershipConfig};
+use anyhow::{anyhow, Result};
+use async_raft::raft::{Entry, EntryConfigChange, EntryPayload, MembershipConfig};
 use async_raft::storage::{CurrentSnapshotData, HardState, InitialState};
 use async_raft::RaftStorage;
 use async_raft::{async_trait::async_trait, NodeId};
 use bincode::{deserialize, serialize};
 use serde::{Deserialize, Serialize};
+use sled::transaction::{TransactionResult, Transactional};
 use sled::{Db, IVec, Tree};
-use std::io::Cursor;
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::io::{ErrorKind, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 use thiserror::Error;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader, BufWriter};
 use tokio::sync::RwLock;
+use tracing::instrument;
 
-use crate::raft::RaftApp;
+use crate::backup::BackupHeader;
+use crate::identity::NodeIdentity;
+use crate::metrics::{
+    APPENDED_ENTRIES, APPLIED_ENTRIES, FSYNC_DURATION, SNAPSHOT_BYTES, SNAPSHOT_DURATION,
+};
+use crate::raft::{NodeRole, RaftApp, RaftData};
+use crate::session::{SessionEntry, Sessions, MAX_CLIENT_SESSIONS};
 
 const STORE_DIR: &str = "store";
+pub(crate) const LOG_TREE: &str = "log";
+pub(crate) const STATE_TREE: &str = "state";
+pub(crate) const LAST_APPLIED_LOG_KEY: &str = "last_applied_log";
+pub(crate) const HARD_STATE_KEY: &str = "hs";
+pub(crate) const CURRENT_SNAPSHOT_KEY: &str = "snapshot_file";
+// where the stores of before the snapshot files kept the whole snapshot
+const LEGACY_SNAPSHOT_KEY: &str = "current_snapshot";
+pub(crate) const RECOVERY_AUDIT_KEY: &str = "recovery_audit";
+pub(crate) const LOG_FORMAT_KEY: &str = "log_format";
+/// Layout of the log tree: 0 has little endian keys and the app's writes as
+/// they are, 2 big endian keys and the writes in a `ClientRequest`. 1 was never
+/// released.
+pub(crate) const LOG_FORMAT: u64 = 2;
 const ERR_INCONSISTENT_LOG: &str =
     "a query was received which was expecting data to be in place which does not exist in the log";
 
 #[derive(Clone, Debug, Error)]
 pub enum ShutdownError {
     // #[error("unsafe storage error")]
-// UnsafeStorageError,
+    // UnsafeStorageError,
 }
 
+/// The current snapshot, whose data is in `file` of the snapshot directory.
 #[derive(Serialize, Deserialize)]
-struct MyStorageSnapshot {
+pub(crate) struct MyStorageSnapshot {
+    pub index: u64,
+    pub term: u64,
+    pub membership: MembershipConfig,
+    pub file: String,
+    /// bytes of the file
+    pub size: u64,
+}
+
+/// What a snapshot file starts with, the state machine data follows.
+#[derive(Serialize, Deserialize)]
+struct SnapshotHeader {
     index: u64,
     term: u64,
     membership: MembershipConfig,
-    data: Vec<u8>,
+    sessions: Vec<(u64, SessionEntry)>,
+}
+
+// what the stores of before the snapshot files kept of their snapshot, its
+// membership and data follow
+#[derive(Deserialize)]
+struct LegacySnapshot {
+    index: u64,
+}
+
+/// One forced membership change done by an offline recovery.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RecoveryRecord {
+    /// unix time of the recovery in seconds
+    pub at: u64,
+    pub index: u64,
+    pub term: u64,
+    pub prev_membership: MembershipConfig,
+    pub membership: MembershipConfig,
+    pub reason: String,
 }
 
 struct MyStorageState {
@@ -37,19 +96,21 @@ struct MyStorageState {
     current_snapshot: Vec<u8>,
     log_tree: String,
     state_tree: String,
+    sessions: Sessions,
     db: Db,
 }
 
 impl MyStorageState {
     fn new(state_path: &str) -> Result<Self> {
-        let last_applied_log = "last_applied_log".as_bytes().to_vec();
-        let hs = "hs".as_bytes().to_vec();
-        let current_snapshot = "current_snapshot".as_bytes().to_vec();
-        let log_tree_name = "log".to_string();
-        let state_tree_name = "state".to_string();
+        let last_applied_log = LAST_APPLIED_LOG_KEY.as_bytes().to_vec();
+        let hs = HARD_STATE_KEY.as_bytes().to_vec();
+        let current_snapshot = CURRENT_SNAPSHOT_KEY.as_bytes().to_vec();
+        let log_tree_name = LOG_TREE.to_string();
+        let state_tree_name = STATE_TREE.to_string();
         let db = sled::open(state_path)?;
-        db.open_tree(&log_tree_name)?;
+        let log_tree = db.open_tree(&log_tree_name)?;
         let state_tree = db.open_tree(&state_tree_name)?;
+        migrate_log(&log_tree, &state_tree)?;
         if state_tree.get(&last_applied_log)?.is_none() {
             state_tree.insert(&last_applied_log, &0u64.to_ne_bytes())?;
         }
@@ -58,15 +119,16 @@ impl MyStorageState {
             state_tree.insert(&hs, serialize(&hs_none)?)?;
         }
         if state_tree.get(&current_snapshot)?.is_none() {
-            let snap_none: Option<MyStorageSnapshot> = None;
-            state_tree.insert(&current_snapshot, serialize(&snap_none)?)?;
+            migrate_snapshot(&state_tree)?;
         }
+        let sessions = Sessions::open(&db, state_tree, MAX_CLIENT_SESSIONS)?;
         Ok(Self {
             last_applied_log,
             hs,
             current_snapshot,
             log_tree: log_tree_name,
             state_tree: state_tree_name,
+            sessions,
             db,
         })
     }
@@ -122,24 +184,250 @@ impl MyStorageState {
         self.db
             .open_tree(&self.state_tree)?
             .insert(&self.current_snapshot, serialize(&snap)?)?;
-        todo!()
+        Ok(())
+    }
+
+    fn push_recovery_record(&mut self, record: RecoveryRecord) -> Result<()> {
+        let state_tree = self.db.open_tree(&self.state_tree)?;
+        let mut audit: Vec<RecoveryRecord> = match state_tree.get(RECOVERY_AUDIT_KEY)? {
+            Some(audit) => deserialize(&audit)?,
+            None => vec![],
+        };
+        audit.push(record);
+        state_tree.insert(RECOVERY_AUDIT_KEY, serialize(&audit)?)?;
+        Ok(())
     }
 }
 
 pub struct MyRaftStorage<T: RaftApp> {
     id: NodeId,
+    // the store directory
+    dir: String,
+    // its write lock orders the applies, and the state views taken between them
     state: RwLock<MyStorageState>,
-    sm: Arc<RwLock<T>>,
+    sm: Arc<T>,
+    // a witness keeps the log metadata only and never touches its state machine
+    witness: bool,
+}
+
+/// Key of a log entry. Big endian so that sled's byte order is the index order.
+#[inline]
+pub(crate) fn log_key(index: u64) -> [u8; 8] {
+    index.to_be_bytes()
+}
+
+#[inline]
+pub(crate) fn decode_log_key(key: &[u8]) -> Result<u64> {
+    Ok(u64::from_be_bytes(key.try_into()?))
+}
+
+/// Directory of everything node `id` stores about raft, unless given another.
+pub fn default_store_dir(id: NodeId) -> String {
+    format!("{}/node_{}", STORE_DIR, id)
+}
+
+/// Path of the sled db holding the raft log and state of the store in `dir`.
+pub fn state_path(dir: &str) -> String {
+    format!("{}/state", dir)
+}
+
+/// Directory of the snapshot files of the store in `dir`: the current one,
+/// named `<index>-<term>.snap`, and those being built or received, `*.part`.
+pub(crate) fn snapshot_dir(dir: &str) -> String {
+    format!("{}/snapshots", dir)
+}
+
+fn snapshot_file_name(index: u64, term: u64) -> String {
+    format!("{}-{}.snap", index, term)
+}
+
+// the header, led by its length
+fn encode_header(header: &SnapshotHeader) -> Result<Vec<u8>> {
+    let header = serialize(header)?;
+    let mut bytes = (header.len() as u64).to_be_bytes().to_vec();
+    bytes.extend(header);
+    Ok(bytes)
+}
+
+async fn read_header(reader: &mut (impl AsyncRead + Unpin)) -> Result<SnapshotHeader> {
+    let len = reader.read_u64().await?;
+    let mut header = vec![0; len as usize];
+    reader.read_exact(&mut header).await?;
+    Ok(deserialize(&header)?)
+}
+
+// a file for a snapshot being built or received, named apart from the finished ones
+async fn new_snapshot_file(dir: &str) -> Result<(String, File)> {
+    static SEQ: AtomicU64 = AtomicU64::new(0);
+    let name = format!(
+        "{}-{}.part",
+        SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos(),
+        SEQ.fetch_add(1, Ordering::Relaxed)
+    );
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(Path::new(dir).join(&name))
+        .await?;
+    Ok((name, file))
+}
+
+// snapshots left half built or received by a previous run
+fn remove_partial_snapshots(dir: &str) -> Result<()> {
+    std::fs::create_dir_all(dir)?;
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "part") {
+            std::fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+// renames `from` to `to`, or copies it through `<to>.part` when they are on
+// different devices
+async fn move_file(from: &Path, to: &Path) -> Result<()> {
+    match fs::rename(from, to).await {
+        Err(err) if err.kind() == ErrorKind::CrossesDevices => {
+            let mut part = to.as_os_str().to_owned();
+            part.push(".part");
+            fs::copy(from, &part).await?;
+            File::open(&part).await?.sync_all().await?;
+            fs::rename(&part, to).await?;
+            fs::remove_file(from).await?;
+            Ok(())
+        }
+        done => Ok(done?),
+    }
+}
+
+/// Layout of the log tree of a store, 0 for one of before the marker.
+pub(crate) fn log_format(state: &Tree) -> Result<u64> {
+    match state.get(LOG_FORMAT_KEY)? {
+        Some(format) => Ok(deserialize(&format)?),
+        None => Ok(0),
+    }
+}
+
+// brings the logs of a store of before the format marker to the current
+// layout: re-keys them big endian and wraps the writes as writes without a
+// session; and marks the store as done, all at once
+fn migrate_log(log: &Tree, state: &Tree) -> Result<()> {
+    match log_format(state)? {
+        LOG_FORMAT => return Ok(()),
+        0 => {}
+        format => return Err(anyhow!("unsupported log format {}", format)),
+    }
+    let mut logs = vec![];
+    for kv in log.iter() {
+        let (old, value) = kv?;
+        let key = log_key(u64::from_le_bytes(old.as_ref().try_into()?));
+        logs.push((old, key, wrap_write(value.to_vec())));
+    }
+    let format = serialize(&LOG_FORMAT)?;
+    let done: TransactionResult<(), ()> = (log, state).transaction(|(log, state)| {
+        for (old, _, _) in &logs {
+            log.remove(old)?;
+        }
+        for (_, key, value) in &logs {
+            log.insert(key, value.as_slice())?;
+        }
+        state.insert(LOG_FORMAT_KEY, format.as_slice())?;
+        Ok(())
+    });
+    done.map_err(|err| anyhow!("failed to migrate the log: {:?}", err))
+}
+
+// bincode lays an entry out as term, index, then the u32 tag of its payload:
+// the data of a normal one, tag 1, gets the `None` session of a `ClientRequest`
+pub(crate) fn wrap_write(mut entry: Vec<u8>) -> Vec<u8> {
+    if entry.len() >= 20 && entry[16..20] == 1u32.to_le_bytes() {
+        entry.insert(20, 0);
+    }
+    entry
+}
+
+// drops the snapshot a store of before the snapshot files kept in its state
+// tree. The app applied its data already, in a format of the app of then; a
+// new snapshot is made once one is needed. A node that installed it may have
+// stopped before it counted its entries as applied, which it does here.
+fn migrate_snapshot(state: &Tree) -> Result<()> {
+    let legacy: Option<LegacySnapshot> = match state.get(LEGACY_SNAPSHOT_KEY)? {
+        Some(legacy) => deserialize(&legacy)?,
+        None => None,
+    };
+    let mut batch = sled::Batch::default();
+    if let Some(legacy) = legacy {
+        let applied: u64 = match state.get(LAST_APPLIED_LOG_KEY)? {
+            Some(applied) => deserialize(&applied)?,
+            None => 0,
+        };
+        batch.insert(LAST_APPLIED_LOG_KEY, serialize(&applied.max(legacy.index))?);
+    }
+    let none: Option<MyStorageSnapshot> = None;
+    batch.insert(CURRENT_SNAPSHOT_KEY, serialize(&none)?);
+    batch.remove(LEGACY_SNAPSHOT_KEY);
+    Ok(state.apply_batch(batch)?)
+}
+
+// names the finished snapshot file `part` after its index and term and makes
+// it the current snapshot, deleting the older ones; returns its size
+async fn install_snapshot_file(
+    dir: &str,
+    state: &mut MyStorageState,
+    part: &str,
+    header: &SnapshotHeader,
+) -> Result<u64> {
+    let file = snapshot_file_name(header.index, header.term);
+    let path = Path::new(dir).join(&file);
+    fs::rename(Path::new(dir).join(part), &path).await?;
+    let size = fs::metadata(&path).await?.len();
+    state.set_current_snapshot(Some(MyStorageSnapshot {
+        index: header.index,
+        term: header.term,
+        membership: header.membership.clone(),
+        file: file.clone(),
+        size,
+    }))?;
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_some_and(|ext| ext == "snap") && entry.file_name() != *file {
+            fs::remove_file(path).await?;
+        }
+    }
+    Ok(size)
 }
 
 impl<T: RaftApp> MyRaftStorage<T> {
-    pub fn new(id: NodeId, sm: Arc<RwLock<T>>) -> Self {
-        let node_dir = format!("{}/node_{}", STORE_DIR, &id.to_string());
-        let state_path = format!("{}/state", &node_dir);
-        Self {
+    /// Opens the store of node `id` in `dir`.
+    pub fn new(id: NodeId, dir: String, sm: Arc<T>, role: NodeRole) -> Result<Self> {
+        let snapshot_dir = snapshot_dir(&dir);
+        remove_partial_snapshots(&snapshot_dir)?;
+        let state = MyStorageState::new(&state_path(&dir))?;
+        Ok(Self {
             id,
-            state: RwLock::new(MyStorageState::new(&state_path).unwrap()),
+            dir,
+            state: RwLock::new(state),
             sm,
+            witness: role == NodeRole::Witness,
+        })
+    }
+
+    fn snapshot_path(&self, file: &str) -> PathBuf {
+        Path::new(&snapshot_dir(&self.dir)).join(file)
+    }
+
+    // a witness drops the payload of normal entries, their term and index is all it needs to vote
+    fn encode_entry(&self, entry: &Entry<RaftData<T>>) -> Result<Vec<u8>> {
+        match &entry.payload {
+            EntryPayload::Normal(_) if self.witness => Ok(serialize(&Entry::<RaftData<T>> {
+                term: entry.term,
+                index: entry.index,
+                payload: EntryPayload::Blank,
+            })?),
+            _ => Ok(serialize(entry)?),
         }
     }
 
@@ -153,7 +441,7 @@ impl<T: RaftApp> MyRaftStorage<T> {
             .rev()
             .skip_while(|kv| {
                 let (_, entry) =
-                    MyRaftStorage::<T>::decode_log_entry(&kv.as_ref().unwrap()).unwrap();
+                    MyRaftStorage::<T>::decode_log_entry(kv.as_ref().unwrap()).unwrap();
                 entry.index > last_applied_log
             })
             .find_map(|kv| {
@@ -167,6 +455,10 @@ impl<T: RaftApp> MyRaftStorage<T> {
     }
 
     fn get_last_membership_config(&self, log: &Tree) -> MembershipConfig {
+        MyRaftStorage::<T>::last_membership_config(self.id, log)
+    }
+
+    fn last_membership_config(id: NodeId, log: &Tree) -> MembershipConfig {
         let cfg_opt = log.iter().rev().find_map(|kv| {
             let (_, entry) = MyRaftStorage::<T>::decode_log_entry(&kv.ok()?).ok()?;
             match entry.payload {
@@ -177,21 +469,310 @@ impl<T: RaftApp> MyRaftStorage<T> {
         });
         match cfg_opt {
             Some(cfg) => cfg,
-            None => MembershipConfig::new_initial(self.id),
+            None => MembershipConfig::new_initial(id),
+        }
+    }
+
+    /// Offline escape hatch for a cluster that lost its majority for good.
+    ///
+    /// Appends a config change to `members` in a new term to the stopped node's
+    /// log, so that the survivors can elect a leader among themselves. Whatever
+    /// only the lost nodes had committed is gone. The change is recorded in the
+    /// recovery audit of the store.
+    pub fn force_membership(
+        id: NodeId,
+        dir: &str,
+        members: HashSet<NodeId>,
+        reason: String,
+    ) -> Result<RecoveryRecord> {
+        if !members.contains(&id) {
+            return Err(anyhow!(
+                "node {} is not in the new members {:?}",
+                id,
+                members
+            ));
+        }
+        let path = state_path(dir);
+        if !Path::new(&path).exists() {
+            return Err(anyhow!("no store for node {} at {}", id, path));
         }
+        NodeIdentity::load(id, dir)?;
+        let mut state = MyStorageState::new(&path)?;
+        let hs = state
+            .get_hs()?
+            .ok_or_else(|| anyhow!("node {} has never started", id))?;
+        let log = state.get_log_tree()?;
+        let (last_index, last_term) = match log.last()? {
+            Some(kv) => {
+                let (_, entry) = MyRaftStorage::<T>::decode_log_entry(&kv)?;
+                (entry.index, entry.term)
+            }
+            None => (0, 0),
+        };
+        let prev_membership = MyRaftStorage::<T>::last_membership_config(id, &log);
+        let membership = MembershipConfig {
+            members,
+            members_after_consensus: None,
+        };
+        let term = hs.current_term.max(last_term) + 1;
+        let entry: Entry<RaftData<T>> = Entry {
+            term,
+            index: last_index + 1,
+            payload: EntryPayload::ConfigChange(EntryConfigChange {
+                membership: membership.clone(),
+            }),
+        };
+        log.insert(log_key(entry.index), serialize(&entry)?)?;
+        state.set_hs(HardState {
+            current_term: term,
+            voted_for: None,
+        })?;
+        let record = RecoveryRecord {
+            at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            index: entry.index,
+            term,
+            prev_membership,
+            membership,
+            reason,
+        };
+        state.push_recovery_record(record.clone())?;
+        state.db.flush()?;
+        Ok(record)
+    }
+
+    /// Writes a consistent copy of the state machine, of the log entries after
+    /// it and of the sessions to `path`, streaming the data into a file of the
+    /// store first so that `path` is only ever a whole backup.
+    pub async fn backup<P: AsRef<Path>>(&self, cluster_id: u64, path: P) -> Result<BackupHeader> {
+        let state = self.state.read().await;
+        let view = self.sm.snapshot_view()?;
+        let index = state.get_last_applied_log()?;
+        let log = state.get_log_tree()?;
+        let term = match log.get(log_key(index))? {
+            Some(entry) => deserialize::<Entry<RaftData<T>>>(&entry)?.term,
+            None if index == 0 => 0,
+            None => return Err(anyhow!(ERR_INCONSISTENT_LOG)),
+        };
+        let membership = self.get_last_applied_membership_config(&log, index);
+        let log_suffix = log
+            .range(log_key(index + 1)..)
+            .values()
+            .map(|entry| Ok(entry?.to_vec()))
+            .collect::<Result<_>>()?;
+        let sessions = state.sessions.export()?;
+        drop(state);
+        let header = BackupHeader {
+            created_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            node_id: self.id,
+            cluster_id,
+            index,
+            term,
+            membership,
+            log_suffix,
+            sessions,
+        };
+        let dir = snapshot_dir(&self.dir);
+        let (part, file) = new_snapshot_file(&dir).await?;
+        let part = Path::new(&dir).join(part);
+        let written = async {
+            let mut writer = BufWriter::new(file);
+            header.write_to(&mut writer).await?;
+            self.sm.make_snapshot(view, &mut writer).await?;
+            writer.flush().await?;
+            writer.get_ref().sync_all().await?;
+            drop(writer);
+            move_file(&part, path.as_ref()).await
+        }
+        .await;
+        if written.is_err() {
+            let _ = fs::remove_file(&part).await;
+        }
+        written.map(|_| header)
+    }
+
+    /// Seeds the empty store of node `id` in `dir` and its state machine with
+    /// the backup at `path`, making the node the only member of a new cluster.
+    /// The data goes to a snapshot file first, which the state machine then
+    /// loads, and the state is created last, so a failed restore can be retried.
+    pub async fn restore<P: AsRef<Path>>(
+        id: NodeId,
+        dir: &str,
+        path: P,
+        sm: &T,
+    ) -> Result<BackupHeader> {
+        let state_path = state_path(dir);
+        if Path::new(&state_path).exists() {
+            return Err(anyhow!("node {} already has a store at {}", id, state_path));
+        }
+        let (backup, mut data) = BackupHeader::open(path).await?;
+        let dir = snapshot_dir(dir);
+        remove_partial_snapshots(&dir)?;
+        let membership = MembershipConfig::new_initial(id);
+        let header = SnapshotHeader {
+            index: backup.index,
+            term: backup.term,
+            membership: membership.clone(),
+            sessions: backup.sessions.clone(),
+        };
+        let (part, file) = new_snapshot_file(&dir).await?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(&encode_header(&header)?).await?;
+        tokio::io::copy(&mut data, &mut writer).await?;
+        writer.flush().await?;
+        writer.get_ref().sync_all().await?;
+        let mut file = writer.into_inner();
+        file.seek(SeekFrom::Start(0)).await?;
+        let mut reader = BufReader::new(file);
+        read_header(&mut reader).await?;
+        sm.handle_snapshot(&mut reader).await?;
+        drop(reader);
+
+        let mut state = MyStorageState::new(&state_path)?;
+        let log = state.get_log_tree()?;
+        let pointer: Entry<RaftData<T>> = Entry::new_snapshot_pointer(
+            backup.index,
+            backup.term,
+            format!("backup-{}-{}", backup.node_id, backup.created_at),
+            membership,
+        );
+        log.insert(log_key(backup.index), serialize(&pointer)?)?;
+        let mut term = backup.term;
+        for entry in &backup.log_suffix {
+            let mut entry: Entry<RaftData<T>> = deserialize(entry)?;
+            // config changes of the old cluster would bring its members back
+            if let EntryPayload::ConfigChange(_) = entry.payload {
+                entry.payload = EntryPayload::Blank;
+            }
+            term = term.max(entry.term);
+            log.insert(log_key(entry.index), serialize(&entry)?)?;
+        }
+        state.set_hs(HardState {
+            current_term: term,
+            voted_for: None,
+        })?;
+        state.set_last_applied_log(backup.index)?;
+        // retries of writes the backup has must not be applied again
+        state.sessions.import(&backup.sessions)?;
+        install_snapshot_file(&dir, &mut state, &part, &header).await?;
+        state.db.flush()?;
+        Ok(backup)
+    }
+
+    // applies a write unless its session shows it was applied already
+    #[instrument(level = "debug", skip(self, state, req), fields(node = self.id))]
+    async fn apply_one(
+        &self,
+        state: &MyStorageState,
+        index: u64,
+        req: &RaftData<T>,
+    ) -> Result<T::WriteRsp> {
+        if let Some(session) = &req.session {
+            if let Some(rsp) = state.sessions.applied_response(session)? {
+                state.set_last_applied_log(index)?;
+                return Ok(deserialize(&rsp)?);
+            }
+        }
+        let rsp = self.sm.handle_write(index, req.data.clone()).await?;
+        APPLIED_ENTRIES
+            .with_label_values(&[&self.id.to_string()])
+            .inc();
+        match &req.session {
+            Some(session) => state
+                .sessions
+                .record_response(session, index, serialize(&rsp)?)?,
+            None => state.set_last_applied_log(index)?,
+        }
+        Ok(rsp)
+    }
+
+    // the response of a write applied already: the one kept for its session,
+    // or the app's, which answers a write it applied without applying it again
+    async fn applied_response(
+        &self,
+        state: &MyStorageState,
+        index: u64,
+        req: &RaftData<T>,
+    ) -> Result<T::WriteRsp> {
+        if let Some(session) = &req.session {
+            if let Some(rsp) = state.sessions.applied_response(session)? {
+                return Ok(deserialize(&rsp)?);
+            }
+        }
+        self.sm.handle_write(index, req.data.clone()).await
+    }
+
+    /// Applies the logs up to `index` the state machine has not applied yet.
+    /// Only for committed logs, e.g. those async-raft reports as applied.
+    pub(crate) async fn apply_committed(&self, index: u64) -> Result<()> {
+        if self.witness {
+            return Ok(());
+        }
+        let state = self.state.write().await;
+        self.apply_skipped(&state, index + 1).await
+    }
+
+    // A new leader marks the logs up to the blank one opening its term as
+    // applied without handing the older ones to the storage, so the writes
+    // committed late in the previous term would never reach the state machine.
+    // Applies the logs between the last applied one and `index` from the log.
+    async fn apply_skipped(&self, state: &MyStorageState, index: u64) -> Result<()> {
+        let last_applied = state.get_last_applied_log()?;
+        if index <= last_applied + 1 {
+            return Ok(());
+        }
+        let log = state.get_log_tree()?;
+        for kv in log.range(log_key(last_applied + 1)..log_key(index)) {
+            let (i, entry) = MyRaftStorage::<T>::decode_log_entry(&kv?)?;
+            if let EntryPayload::Normal(normal) = &entry.payload {
+                self.apply_one(state, i, &normal.data).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Index of the last entry in the log, committed or not. Unlike the raft
+    /// metrics it is up to date as soon as a client write is appended.
+    pub(crate) async fn last_log_index(&self) -> Result<u64> {
+        let state = self.state.read().await;
+        match state.get_log_tree()?.last()? {
+            Some((key, _)) => decode_log_key(&key),
+            None => state.get_last_applied_log(),
+        }
+    }
+
+    /// Whether the log holds the entry at `index` written in `term`, and so
+    /// every entry before it the leader has.
+    pub(crate) async fn has_entry(&self, index: u64, term: u64) -> Result<bool> {
+        let log = self.state.read().await.get_log_tree()?;
+        match log.get(log_key(index))? {
+            Some(entry) => {
+                let entry: Entry<RaftData<T>> = deserialize(&entry)?;
+                Ok(entry.term == term)
+            }
+            None => Ok(false),
+        }
+    }
+
+    // raft acks log entries and votes only once they are on disk
+    async fn flush(&self) -> Result<()> {
+        let db = self.state.read().await.db.clone();
+        let timer = FSYNC_DURATION
+            .with_label_values(&[&self.id.to_string()])
+            .start_timer();
+        db.flush_async().await?;
+        timer.observe_duration();
+        Ok(())
     }
 
     #[inline]
-    fn decode_log_entry(kv: &(IVec, IVec)) -> Result<(u64, Entry<T::WriteReq>)> {
-        Ok((deserialize(&kv.0)?, deserialize(&kv.1)?))
+    fn decode_log_entry(kv: &(IVec, IVec)) -> Result<(u64, Entry<RaftData<T>>)> {
+        Ok((decode_log_key(&kv.0)?, deserialize(&kv.1)?))
     }
 }
 
 #[async_trait]
-impl<T: RaftApp> RaftStorage<T::WriteReq, T::WriteRsp>
-    for MyRaftStorage<T>
-{
-    type Snapshot = Cursor<Vec<u8>>;
+impl<T: RaftApp> RaftStorage<RaftData<T>, T::WriteRsp> for MyRaftStorage<T> {
+    type Snapshot = File;
 
     type ShutdownError = ShutdownError;
 
@@ -208,7 +789,7 @@ impl<T: RaftApp> RaftStorage<T::WriteReq, T::WriteRsp>
                 let log = state.get_log_tree()?;
                 let membership = self.get_last_membership_config(&log);
                 let log = state.get_log_tree()?;
-                let (last_log_index, last_log_term) = match log.iter().rev().next() {
+                let (last_log_index, last_log_term) = match log.iter().next_back() {
                     Some(kv) => {
                         let (_, entry) = MyRaftStorage::<T>::decode_log_entry(&kv?)?;
                         (entry.index, entry.term)
@@ -233,17 +814,17 @@ impl<T: RaftApp> RaftStorage<T::WriteReq, T::WriteRsp>
     }
 
     async fn save_hard_state(&self, hs: &HardState) -> Result<()> {
-        let mut state = self.state.write().await;
-        state.set_hs(hs.clone())
+        self.state.write().await.set_hs(hs.clone())?;
+        self.flush().await
     }
 
-    async fn get_log_entries(&self, start: u64, stop: u64) -> Result<Vec<Entry<T::WriteReq>>> {
+    async fn get_log_entries(&self, start: u64, stop: u64) -> Result<Vec<Entry<RaftData<T>>>> {
         if start > stop {
             // TODO: log the error
             return Ok(vec![]);
         }
         let log = self.state.read().await.get_log_tree()?;
-        let range = IVec::from(serialize(&start)?)..IVec::from(serialize(&stop)?);
+        let range = log_key(start)..log_key(stop);
         log.range(range)
             .map(|kv| {
                 let (_, entry) = MyRaftStorage::<T>::decode_log_entry(&kv?)?;
@@ -262,85 +843,136 @@ impl<T: RaftApp> RaftStorage<T::WriteReq, T::WriteRsp>
             },
         };
         for key in start..stop {
-            log.remove(serialize(&key)?)?;
+            log.remove(log_key(key))?;
         }
         Ok(())
     }
 
-    async fn append_entry_to_log(&self, entry: &Entry<T::WriteReq>) -> anyhow::Result<()> {
+    #[instrument(level = "debug", skip(self, entry), fields(
+        node = self.id,
+        index = entry.index,
+        term = entry.term
+    ))]
+    async fn append_entry_to_log(&self, entry: &Entry<RaftData<T>>) -> anyhow::Result<()> {
         let log = self.state.write().await.get_log_tree()?;
-        log.insert(serialize(&entry.index)?, serialize(entry)?)?;
-        Ok(())
+        log.insert(log_key(entry.index), serialize(entry)?)?;
+        APPENDED_ENTRIES
+            .with_label_values(&[&self.id.to_string()])
+            .inc();
+        self.flush().await
     }
 
-    async fn replicate_to_log(&self, entries: &[Entry<T::WriteReq>]) -> anyhow::Result<()> {
+    #[instrument(level = "debug", skip(self, entries), fields(
+        node = self.id,
+        first = entries.first().map_or(0, |entry| entry.index),
+        last = entries.last().map_or(0, |entry| entry.index),
+        term = entries.last().map_or(0, |entry| entry.term)
+    ))]
+    async fn replicate_to_log(&self, entries: &[Entry<RaftData<T>>]) -> anyhow::Result<()> {
         let log = self.state.write().await.get_log_tree()?;
         for entry in entries {
-            log.insert(serialize(&entry.index)?, serialize(entry)?)?;
+            log.insert(log_key(entry.index), self.encode_entry(entry)?)?;
         }
-        Ok(())
+        APPENDED_ENTRIES
+            .with_label_values(&[&self.id.to_string()])
+            .inc_by(entries.len() as u64);
+        self.flush().await
     }
 
     async fn apply_entry_to_state_machine(
         &self,
         index: &u64,
-        data: &T::WriteReq,
+        data: &RaftData<T>,
     ) -> Result<T::WriteRsp> {
-        let mut sm = self.sm.write().await;
-        let rsp = sm.handle_write(data.clone()).await?;
+        if self.witness {
+            return Err(anyhow!("a witness has no state machine to apply to"));
+        }
         let state = self.state.write().await;
-        state.set_last_applied_log(*index)?;
-        Ok(rsp)
+        if *index <= state.get_last_applied_log()? {
+            // by `apply_skipped` for a read, ahead of async-raft
+            return self.applied_response(&state, *index, data).await;
+        }
+        self.apply_skipped(&state, *index).await?;
+        self.apply_one(&state, *index, data).await
     }
 
-    async fn replicate_to_state_machine(&self, entries: &[(&u64, &T::WriteReq)]) -> Result<()> {
-        // let mut sm = self.sm.write().await;
-        let mut sm = self.sm.write().await;
+    async fn replicate_to_state_machine(&self, entries: &[(&u64, &RaftData<T>)]) -> Result<()> {
+        if self.witness {
+            if let Some((index, _)) = entries.last() {
+                self.state.write().await.set_last_applied_log(**index)?;
+            }
+            return Ok(());
+        }
         let state = self.state.write().await;
         for (index, entry) in entries {
-            sm.handle_write((*entry).clone()).await?;
-            state.set_last_applied_log(**index)?;
+            if **index <= state.get_last_applied_log()? {
+                continue;
+            }
+            self.apply_skipped(&state, **index).await?;
+            self.apply_one(&state, **index, entry).await?;
         }
         Ok(())
     }
 
     async fn do_log_compaction(&self) -> Result<CurrentSnapshotData<Self::Snapshot>> {
-        //block the state
-        let mut state = self.state.write().await;
-        let sm = self.sm.read().await;
-        let data = sm.make_snapshot().await?;
+        let timer = SNAPSHOT_DURATION
+            .with_label_values(&[&self.id.to_string(), "build"])
+            .start_timer();
+        // the view is taken between two applies, then serialized while they go on
+        let state = self.state.write().await;
+        let view = self.sm.snapshot_view()?;
+        let sessions = state.sessions.export()?;
         let last_applied_log = state.get_last_applied_log()?;
         let log = state.get_log_tree()?;
 
         let membership = self.get_last_applied_membership_config(&log, last_applied_log);
 
         let term = log
-            .get(serialize(&last_applied_log)?)?
+            .get(log_key(last_applied_log))?
             .map(|entry| {
-                let entry: Entry<T::WriteReq> = deserialize(&entry).unwrap();
+                let entry: Entry<RaftData<T>> = deserialize(&entry).unwrap();
                 entry.term
             })
             .ok_or_else(|| anyhow::anyhow!(ERR_INCONSISTENT_LOG))?;
-
-        let snapshot = MyStorageSnapshot {
+        drop(state);
+        let header = SnapshotHeader {
             index: last_applied_log,
             term,
-            membership: membership.clone(),
-            data,
+            membership,
+            sessions,
         };
-        let snapshot_bytes = serialize(&snapshot)?;
-        state.set_current_snapshot(Some(snapshot))?;
+        let dir = snapshot_dir(&self.dir);
+        let (part, file) = new_snapshot_file(&dir).await?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(&encode_header(&header)?).await?;
+        self.sm.make_snapshot(view, &mut writer).await?;
+        writer.flush().await?;
+        writer.get_ref().sync_all().await?;
+        drop(writer);
 
-        Ok(CurrentSnapshotData {
-            term,
-            index: last_applied_log,
-            membership: membership,
-            snapshot: Box::new(Cursor::new(snapshot_bytes)),
-        })
+        let mut state = self.state.write().await;
+        // a snapshot from the leader may have been installed meanwhile
+        let installed = state
+            .get_current_snapshot()?
+            .map_or(0, |snapshot| snapshot.index);
+        if installed <= last_applied_log {
+            let size = install_snapshot_file(&dir, &mut state, &part, &header).await?;
+            SNAPSHOT_BYTES
+                .with_label_values(&[&self.id.to_string(), "build"])
+                .observe(size as f64);
+        } else {
+            fs::remove_file(Path::new(&dir).join(&part)).await?;
+        }
+        drop(state);
+        timer.observe_duration();
+        self.get_current_snapshot()
+            .await?
+            .ok_or_else(|| anyhow!(ERR_INCONSISTENT_LOG))
     }
 
     async fn create_snapshot(&self) -> anyhow::Result<(String, Box<Self::Snapshot>)> {
-        Ok((String::from(""), Box::new(Cursor::new(Vec::new()))))
+        let (part, file) = new_snapshot_file(&snapshot_dir(&self.dir)).await?;
+        Ok((part, Box::new(file)))
     }
 
     async fn finalize_snapshot_installation(
@@ -351,14 +983,32 @@ impl<T: RaftApp> RaftStorage<T::WriteReq, T::WriteRsp>
         id: String,
         snapshot: Box<Self::Snapshot>,
     ) -> anyhow::Result<()> {
+        // async-raft sends the last chunk again when the install outlasts its
+        // heartbeat timeout, which comes as a new, empty snapshot of an index
+        // applied already
+        if index <= self.state.read().await.get_last_applied_log()? {
+            fs::remove_file(self.snapshot_path(&id)).await?;
+            return Ok(());
+        }
+        let timer = SNAPSHOT_DURATION
+            .with_label_values(&[&self.id.to_string(), "install"])
+            .start_timer();
+        let mut snapshot = *snapshot;
+        snapshot.sync_all().await?;
+        SNAPSHOT_BYTES
+            .with_label_values(&[&self.id.to_string(), "install"])
+            .observe(snapshot.metadata().await?.len() as f64);
+        snapshot.seek(SeekFrom::Start(0)).await?;
+        let mut reader = BufReader::new(snapshot);
+        let mut header = read_header(&mut reader).await?;
         {
             let log = self.state.read().await.get_log_tree()?;
             let membership = self.get_last_applied_membership_config(&log, index);
             match delete_through {
                 Some(through) => {
-                    for (_, entry) in log.iter().enumerate() {
+                    for entry in log.iter() {
                         let key = entry?.0;
-                        let index: u64 = deserialize(&key)?;
+                        let index = decode_log_key(&key)?;
                         if index > through {
                             break;
                         }
@@ -367,18 +1017,29 @@ impl<T: RaftApp> RaftStorage<T::WriteReq, T::WriteRsp>
                 }
                 None => log.clear()?,
             }
-            let snap_entry: Entry<T::WriteReq> =
-                Entry::new_snapshot_pointer(index, term, id, membership);
-            log.insert(serialize(&index)?, serialize(&snap_entry)?)?;
+            let snap_entry: Entry<RaftData<T>> =
+                Entry::new_snapshot_pointer(index, term, id.clone(), membership);
+            log.insert(log_key(index), serialize(&snap_entry)?)?;
         }
-        let new_snapshot: MyStorageSnapshot = deserialize(snapshot.get_ref())?;
-        {
-            let sm = self.sm.write().await;
-            sm.handle_snapshot(&new_snapshot.data).await?;
-        }
-
+        let dir = snapshot_dir(&self.dir);
         let mut state = self.state.write().await;
-        state.set_current_snapshot(Some(new_snapshot))?;
+        let part = if self.witness {
+            // the witness keeps a snapshot of the metadata only
+            drop(reader);
+            fs::remove_file(Path::new(&dir).join(&id)).await?;
+            header.sessions.clear();
+            let (part, mut file) = new_snapshot_file(&dir).await?;
+            file.write_all(&encode_header(&header)?).await?;
+            file.sync_all().await?;
+            part
+        } else {
+            self.sm.handle_snapshot(&mut reader).await?;
+            state.sessions.import(&header.sessions)?;
+            id
+        };
+        state.set_last_applied_log(index)?;
+        install_snapshot_file(&dir, &mut state, &part, &header).await?;
+        timer.observe_duration();
         Ok(())
     }
 
@@ -388,15 +1049,201 @@ impl<T: RaftApp> RaftStorage<T::WriteReq, T::WriteRsp>
         let state = self.state.read().await;
         match state.get_current_snapshot()? {
             Some(snapshot) => {
-                let reader = serialize(&snapshot)?;
+                let file = File::open(self.snapshot_path(&snapshot.file)).await?;
                 Ok(Some(CurrentSnapshotData {
                     index: snapshot.index,
                     term: snapshot.term,
                     membership: snapshot.membership.clone(),
-                    snapshot: Box::new(Cursor::new(reader)),
+                    snapshot: Box::new(file),
                 }))
             }
             None => Ok(None),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::NodeRole;
+    use crate::session::{ClientRequest, ClientSession};
+    use crate::testing::{enter_scratch_dir, once_released, Add, Counter, Sum};
+    use async_raft::raft::EntryNormal;
+
+    fn write(index: u64, session: Option<ClientSession>, add: u64) -> Entry<RaftData<Counter>> {
+        let data = ClientRequest {
+            session,
+            data: Add(add),
+        };
+        Entry {
+            term: 1,
+            index,
+            payload: EntryPayload::Normal(EntryNormal { data }),
+        }
+    }
+
+    #[tokio::test]
+    async fn a_write_applied_ahead_of_raft_gets_its_response_again() {
+        enter_scratch_dir();
+        let counter = Arc::new(Counter::default());
+        let storage =
+            MyRaftStorage::new(1, default_store_dir(1), counter.clone(), NodeRole::Voter).unwrap();
+        let session = ClientSession {
+            client_id: 7,
+            seq: 1,
+        };
+        let writes = [write(1, None, 2), write(2, Some(session), 3)];
+        for entry in &writes {
+            storage.append_entry_to_log(entry).await.unwrap();
+        }
+        // a read applies them ahead of async-raft, which applies them after
+        storage.apply_committed(2).await.unwrap();
+        for (entry, sum) in writes.iter().zip([2, 5]) {
+            let data = match &entry.payload {
+                EntryPayload::Normal(normal) => &normal.data,
+                _ => unreachable!(),
+            };
+            let rsp = storage.apply_entry_to_state_machine(&entry.index, data);
+            assert_eq!(rsp.await.unwrap(), Sum(sum));
+        }
+        assert_eq!(counter.applied(), vec![1, 2]);
+        // the write of the session is answered by the storage, the other by the app
+        assert_eq!(counter.calls(), 3);
+    }
+
+    #[tokio::test]
+    async fn a_retried_write_of_a_session_is_answered_as_it_was() {
+        enter_scratch_dir();
+        let counter = Arc::new(Counter::default());
+        let storage =
+            MyRaftStorage::new(2, default_store_dir(2), counter.clone(), NodeRole::Voter).unwrap();
+        let session = ClientSession {
+            client_id: 7,
+            seq: 1,
+        };
+        // the retry is a new log entry with the session of the first
+        let writes = [write(1, Some(session), 2), write(2, Some(session), 2)];
+        storage.append_entry_to_log(&writes[0]).await.unwrap();
+        storage.apply_committed(1).await.unwrap();
+        storage.append_entry_to_log(&writes[1]).await.unwrap();
+        let data = match &writes[1].payload {
+            EntryPayload::Normal(normal) => &normal.data,
+            _ => unreachable!(),
+        };
+        let rsp = storage.apply_entry_to_state_machine(&2, data).await;
+        assert_eq!(rsp.unwrap(), Sum(2));
+        assert_eq!(counter.applied(), vec![1]);
+        assert_eq!(counter.calls(), 1);
+        // only the first counts as applied
+        assert_eq!(APPLIED_ENTRIES.with_label_values(&["2"]).get(), 1);
+        let state = storage.state.read().await;
+        assert_eq!(state.get_last_applied_log().unwrap(), 2);
+    }
+
+    #[test]
+    fn a_store_of_before_the_format_marker_is_migrated_at_open() {
+        enter_scratch_dir();
+        let path = "store/little_endian/state";
+        let _ = std::fs::remove_dir_all(path);
+        let indexes = [1u64, 2, 3, 256];
+        let membership = MembershipConfig::new_initial(9);
+        // laid out as the stores of then: little endian keys, the app's writes,
+        // and the snapshot in the state tree, taken at 3 but not counted as
+        // applied yet
+        {
+            #[derive(Serialize)]
+            struct Snapshot {
+                index: u64,
+                term: u64,
+                membership: MembershipConfig,
+                data: Vec<u8>,
+            }
+            let db = sled::open(path).unwrap();
+            let log = db.open_tree(LOG_TREE).unwrap();
+            for index in &indexes {
+                let entry = Entry {
+                    term: 1,
+                    index: *index,
+                    payload: EntryPayload::Normal(EntryNormal { data: Add(*index) }),
+                };
+                log.insert(serialize(index).unwrap(), serialize(&entry).unwrap())
+                    .unwrap();
+            }
+            let state = db.open_tree(STATE_TREE).unwrap();
+            state
+                .insert(LAST_APPLIED_LOG_KEY, &2u64.to_ne_bytes())
+                .unwrap();
+            let hs = Some(HardState {
+                current_term: 1,
+                voted_for: Some(9),
+            });
+            state
+                .insert(HARD_STATE_KEY, serialize(&hs).unwrap())
+                .unwrap();
+            let snapshot = Some(Snapshot {
+                index: 3,
+                term: 1,
+                membership: membership.clone(),
+                data: serialize(&[(vec![1u8], vec![2u8])]).unwrap(),
+            });
+            state
+                .insert(LEGACY_SNAPSHOT_KEY, serialize(&snapshot).unwrap())
+                .unwrap();
+        }
+        // 256 came before 1 in the little endian key order
+        let state = once_released(|| MyStorageState::new(path));
+        let log = state.get_log_tree().unwrap();
+        let keys: Vec<u64> = log
+            .iter()
+            .keys()
+            .map(|key| decode_log_key(&key.unwrap()).unwrap())
+            .collect();
+        assert_eq!(keys, indexes);
+        for index in &indexes {
+            let entry: Entry<RaftData<Counter>> =
+                deserialize(&log.get(log_key(*index)).unwrap().unwrap()).unwrap();
+            assert_eq!(entry.index, *index);
+            match entry.payload {
+                EntryPayload::Normal(normal) => {
+                    assert!(normal.data.session.is_none());
+                    assert_eq!(normal.data.data, Add(*index));
+                }
+                _ => panic!("entry {} is not a write", index),
+            }
+        }
+        let state_tree = state.db.open_tree(STATE_TREE).unwrap();
+        assert_eq!(log_format(&state_tree).unwrap(), LOG_FORMAT);
+        let hs = state.get_hs().unwrap().unwrap();
+        assert_eq!((hs.current_term, hs.voted_for), (1, Some(9)));
+        // the app has the data of the snapshot, a new one is made when needed
+        assert_eq!(state.get_last_applied_log().unwrap(), 3);
+        assert!(state.get_current_snapshot().unwrap().is_none());
+        assert!(state_tree.get(LEGACY_SNAPSHOT_KEY).unwrap().is_none());
+        drop((log, state_tree, state));
+
+        // opened again, nothing changes
+        let state = once_released(|| MyStorageState::new(path));
+        assert_eq!(state.get_log_tree().unwrap().len(), indexes.len());
+        assert_eq!(state.get_last_applied_log().unwrap(), 3);
+    }
+
+    #[test]
+    fn a_log_of_an_unknown_format_is_refused() {
+        enter_scratch_dir();
+        let path = "store/unknown_format/state";
+        let _ = std::fs::remove_dir_all(path);
+        {
+            let db = sled::open(path).unwrap();
+            let state = db.open_tree(STATE_TREE).unwrap();
+            state
+                .insert(LOG_FORMAT_KEY, serialize(&1u64).unwrap())
+                .unwrap();
+        }
+        let err = once_released(|| match MyStorageState::new(path) {
+            Err(err) if err.to_string().contains("lock") => Err(err),
+            opened => Ok(opened.err()),
+        });
+        let err = err.expect("a log of format 1 was opened");
+        assert!(err.to_string().contains("format 1"), "{}", err);
+    }
+}
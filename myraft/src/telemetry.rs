@@ -0,0 +1,120 @@
+//! Tracing of the raft RPCs, log writes and applies.
+//!
+//! The spans carry the node id, term and log index; a client write can be
+//! followed across the cluster by its index. Built with the `otel` feature and
+//! `OTEL_EXPORTER_OTLP_ENDPOINT` set, the spans are also exported to that
+//! OpenTelemetry collector, with the spans of an RPC's caller and callee linked
+//! through the W3C trace context sent along with the RPC.
+
+use anyhow::Result;
+use std::collections::HashMap;
+use tonic::metadata::{MetadataKey, MetadataMap};
+use tracing::Span;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter};
+
+/// gRPC metadata keys of the W3C trace context.
+const TRACE_HEADERS: [&str; 2] = ["traceparent", "tracestate"];
+
+/// Prints the events and closed spans filtered by `RUST_LOG`, the `log`
+/// records of the dependencies included, and exports the spans if asked to.
+pub fn init(service: &str) -> Result<()> {
+    let registry = tracing_subscriber::registry()
+        .with(EnvFilter::from_default_env())
+        .with(fmt::layer().with_span_events(FmtSpan::CLOSE));
+    #[cfg(feature = "otel")]
+    let registry = registry.with(otel::layer(service)?);
+    #[cfg(not(feature = "otel"))]
+    let _ = service;
+    registry.try_init()?;
+    Ok(())
+}
+
+#[cfg(feature = "otel")]
+mod otel {
+    use anyhow::Result;
+    use opentelemetry::sdk::propagation::TraceContextPropagator;
+    use opentelemetry::sdk::{trace, Resource};
+    use opentelemetry::{global, runtime, KeyValue};
+    use opentelemetry_otlp::WithExportConfig;
+    use std::env;
+    use tracing::Subscriber;
+    use tracing_opentelemetry::OpenTelemetryLayer;
+    use tracing_subscriber::registry::LookupSpan;
+
+    const ENDPOINT_KEY: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+
+    // None, exporting nothing, unless a collector is given
+    pub(super) fn layer<S>(service: &str) -> Result<Option<OpenTelemetryLayer<S, trace::Tracer>>>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        let endpoint = match env::var(ENDPOINT_KEY) {
+            Ok(endpoint) => endpoint,
+            Err(_) => return Ok(None),
+        };
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let tracer =
+            opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(
+                    opentelemetry_otlp::new_exporter()
+                        .tonic()
+                        .with_endpoint(endpoint),
+                )
+                .with_trace_config(trace::config().with_resource(Resource::new(vec![
+                    KeyValue::new("service.name", service.to_string()),
+                ])))
+                .install_batch(runtime::Tokio)?;
+        Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+    }
+}
+
+/// Trace context of the current span, to send along with an RPC.
+#[cfg(feature = "otel")]
+pub(crate) fn current_context() -> HashMap<String, String> {
+    use opentelemetry::global;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    let context = Span::current().context();
+    let mut carrier = HashMap::new();
+    global::get_text_map_propagator(|propagator| propagator.inject_context(&context, &mut carrier));
+    carrier
+}
+
+#[cfg(not(feature = "otel"))]
+pub(crate) fn current_context() -> HashMap<String, String> {
+    HashMap::new()
+}
+
+/// Makes `span` a child of the span of the remote caller that sent `carrier`.
+#[cfg(feature = "otel")]
+pub(crate) fn set_parent(span: &Span, carrier: &HashMap<String, String>) {
+    use opentelemetry::global;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    span.set_parent(global::get_text_map_propagator(|propagator| {
+        propagator.extract(carrier)
+    }));
+}
+
+#[cfg(not(feature = "otel"))]
+pub(crate) fn set_parent(_span: &Span, _carrier: &HashMap<String, String>) {}
+
+pub(crate) fn inject_metadata(metadata: &mut MetadataMap) {
+    for (key, value) in current_context() {
+        if let (Ok(key), Ok(value)) = (MetadataKey::from_bytes(key.as_bytes()), value.parse()) {
+            metadata.insert(key, value);
+        }
+    }
+}
+
+pub(crate) fn metadata_context(metadata: &MetadataMap) -> HashMap<String, String> {
+    TRACE_HEADERS
+        .iter()
+        .filter_map(|key| {
+            let value = metadata.get(*key)?.to_str().ok()?;
+            Some((key.to_string(), value.to_string()))
+        })
+        .collect()
+}
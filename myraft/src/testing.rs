@@ -0,0 +1,98 @@
+//! What the unit tests of the crate share: an app to apply to, and a working
+//! directory for the stores.
+
+use crate::raft::{RaftApp, SnapshotSink, SnapshotSource};
+use anyhow::Result;
+use async_raft::async_trait::async_trait;
+use async_raft::{AppData, AppDataResponse};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::sync::{Mutex, Once};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The stores are under the working directory; a scratch one for all the
+/// tests of the process, each test using node ids of its own.
+pub(crate) fn enter_scratch_dir() {
+    static ONCE: Once = Once::new();
+    ONCE.call_once(|| {
+        let dir = env::temp_dir().join(format!("myraft-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        env::set_current_dir(&dir).unwrap();
+    });
+}
+
+/// Runs `open` until it gets hold of a store whose db was dropped just before:
+/// sled lets go of its lock in the background.
+pub(crate) fn once_released<R>(mut open: impl FnMut() -> Result<R>) -> R {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        match open() {
+            Ok(opened) => return opened,
+            Err(err) => assert!(Instant::now() < deadline, "{}", err),
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Add(pub u64);
+
+impl AppData for Add {}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Sum(pub u64);
+
+impl AppDataResponse for Sum {}
+
+/// Adds up the writes, answering each with the sum after it.
+#[derive(Default)]
+pub(crate) struct Counter {
+    // the index and the sum of each write applied
+    sums: Mutex<Vec<(u64, u64)>>,
+    calls: Mutex<usize>,
+}
+
+impl Counter {
+    /// The writes applied, each once.
+    pub(crate) fn applied(&self) -> Vec<u64> {
+        let sums = self.sums.lock().unwrap();
+        sums.iter().map(|(index, _)| *index).collect()
+    }
+
+    /// The writes handed over, replays too.
+    pub(crate) fn calls(&self) -> usize {
+        *self.calls.lock().unwrap()
+    }
+}
+
+#[async_trait]
+impl RaftApp for Counter {
+    type WriteReq = Add;
+    type WriteRsp = Sum;
+    type SnapshotView = ();
+
+    async fn handle_write(&self, index: u64, req: Add) -> Result<Sum> {
+        *self.calls.lock().unwrap() += 1;
+        let mut sums = self.sums.lock().unwrap();
+        if let Some((_, sum)) = sums.iter().find(|(i, _)| *i == index) {
+            return Ok(Sum(*sum));
+        }
+        let sum = sums.last().map_or(0, |(_, sum)| *sum) + req.0;
+        sums.push((index, sum));
+        Ok(Sum(sum))
+    }
+
+    fn snapshot_view(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn make_snapshot(&self, _: (), _: &mut SnapshotSink<'_>) -> Result<()> {
+        Ok(())
+    }
+
+    async fn handle_snapshot(&self, _: &mut SnapshotSource<'_>) -> Result<()> {
+        Ok(())
+    }
+}